@@ -27,7 +27,7 @@ pub mod counter {
     }
 
     pub fn set(ctx: Context<Update>, value: u8) -> Result<()> {
-        ctx.accounts.counter.count = value.clone();
+        ctx.accounts.counter.count = value;
         Ok(())
     }
 }
@@ -1,10 +1,59 @@
 #![allow(clippy::result_large_err)]
 
 use anchor_lang::prelude::*;
-use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
+use anchor_lang::solana_program::hash::hashv;
+use anchor_lang::system_program;
+use anchor_spl::metadata::MetadataAccount;
+use anchor_spl::token::spl_token::native_mint;
+use anchor_spl::token_interface::{
+    self, CloseAccount, Mint, SyncNative, TokenAccount, TokenInterface, TransferChecked,
+};
 
 declare_id!("HcYkXa8AFyNEuigA3gsCbLVUNT5cVB6QM7ykTqjAsNJX");
 
+/// Fixed-point scale applied to `acc_reward_per_share`
+pub const ACC_REWARD_PRECISION: u128 = 1_000_000_000_000;
+
+/// Usual rate_scale: stake units a per-token reward_rate is quoted against when
+/// fixed_emission is off
+pub const PER_TOKEN_RATE_PRECISION: u128 = 1_000_000_000;
+
+/// Length of the year an APY is quoted over (365 days)
+pub const SECONDS_PER_YEAR: i64 = 31_536_000;
+
+/// Emission span MAX_REWARD_RATE is sized for (100 years)
+pub const MAX_EMISSION_SECONDS: u128 = 100 * SECONDS_PER_YEAR as u128;
+
+/// Largest reward_rate a pool accepts: that many seconds of it, scaled by
+/// ACC_REWARD_PRECISION, still fit the u128 accumulator math. Per-token emission also
+/// scales with the stake, which update_pool divides out before scaling, so the bound
+/// holds there too; a single position's owed total must still fit u128 once scaled
+pub const MAX_REWARD_RATE: u64 = (u128::MAX / ACC_REWARD_PRECISION / MAX_EMISSION_SECONDS) as u64;
+
+/// Basis point denominator; a multiplier of 10_000 bps is 1x
+pub const BPS_DENOMINATOR: u64 = 10_000;
+
+/// Number of lock tiers a pool can configure
+pub const MAX_LOCK_TIERS: usize = 4;
+
+/// Bytes reserved for a pool's name and metadata URI, zero-padded UTF-8
+pub const POOL_NAME_LEN: usize = 32;
+pub const POOL_METADATA_URI_LEN: usize = 128;
+
+/// Largest snapshot ring buffer, kept under the 10 KiB an account can be created with
+pub const MAX_SNAPSHOT_CAPACITY: u16 = 256;
+
+/// Early-unstake penalty setting that rejects early exits instead of charging for them
+pub const EARLY_UNSTAKE_DISABLED: u16 = u16::MAX;
+
+/// Emission curves over the reward window; every curve emits the same total
+pub const CURVE_LINEAR: u8 = 0;
+pub const CURVE_EASE_IN: u8 = 1;
+pub const CURVE_EASE_OUT: u8 = 2;
+
+/// Current UserStake layout; positions below it need migrate_user_stake
+pub const USER_STAKE_VERSION: u8 = 8;
+
 #[program]
 pub mod staking_program {
     use super::*;
@@ -13,115 +62,712 @@ pub mod staking_program {
     /// - pool_authority: The authority that controls the pool
     /// - stake_token_mint: Token A that users will stake
     /// - reward_token_mint: Token B that users will receive as rewards
-    /// - reward_rate: Rewards per second per staked token (scaled by 1e9)
-    pub fn initialize_pool(
+    /// - params: Pool configuration, see `InitializePoolParams`
+    /// Both mints must be owned by `token_program`, either SPL Token or Token-2022
+    pub fn initialize_pool(ctx: Context<InitializePool>, params: InitializePoolParams) -> Result<()> {
+        init_pool_state(
+            &mut ctx.accounts.pool,
+            ctx.accounts.authority.key(),
+            &ctx.accounts.stake_token_mint,
+            &ctx.accounts.reward_token_mint,
+            ctx.bumps.pool,
+            ctx.bumps.vault_authority,
+            params,
+        )
+    }
+
+    /// Initialize a staking pool that pays `apy_bps` a year on every staked token
+    /// reward_rate is stored as the equivalent per-token rate, rounded down, for display;
+    /// emission is computed from apy_bps itself so that rounding never compounds.
+    /// Yield is paid in reward token base units per stake token base unit
+    pub fn initialize_pool_apy(
         ctx: Context<InitializePool>,
-        reward_rate: u64,
-        min_stake_duration: i64,
+        apy_bps: u16,
+        params: InitializePoolParams,
+    ) -> Result<()> {
+        let mut params = params;
+        require!(apy_bps > 0, StakingError::InvalidAmount);
+        // A yield is flat over time, so it can't follow a halving schedule or curve
+        require!(
+            params.halving_interval == 0 && params.curve_type == CURVE_LINEAR,
+            StakingError::InvalidRewardSchedule
+        );
+
+        params.reward_rate = u64::try_from(
+            (apy_bps as u128) * params.rate_scale as u128
+                / (BPS_DENOMINATOR as u128 * SECONDS_PER_YEAR as u128),
+        )
+        .map_err(|_| StakingError::Overflow)?;
+        params.fixed_emission = false;
+        ctx.accounts.pool.apy_bps = apy_bps;
+
+        msg!("Pool yield set to {} bps a year", apy_bps);
+        initialize_pool(ctx, params)
+    }
+
+    /// Initialize a pool and fund its reward vault in one instruction, so it is never live
+    /// without rewards. Takes the same params as initialize_pool; the authority supplies
+    /// `fund_amount` reward tokens. A first stake goes in as a `stake` instruction after this
+    /// one in the same transaction, once the pool and its vaults exist
+    pub fn bootstrap_pool(
+        ctx: Context<BootstrapPool>,
+        params: InitializePoolParams,
+        fund_amount: u64,
     ) -> Result<()> {
+        require!(fund_amount > 0, StakingError::InvalidAmount);
+        init_pool_state(
+            &mut ctx.accounts.pool,
+            ctx.accounts.authority.key(),
+            &ctx.accounts.stake_token_mint,
+            &ctx.accounts.reward_token_mint,
+            ctx.bumps.pool,
+            ctx.bumps.vault_authority,
+            params,
+        )?;
+
+        // The vault was created above, so the authority signs the deposit as the funder
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.authority_reward_token.to_account_info(),
+            mint: ctx.accounts.reward_token_mint.to_account_info(),
+            to: ctx.accounts.pool_reward_vault.to_account_info(),
+            authority: ctx.accounts.authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token_interface::transfer_checked(cpi_ctx, fund_amount, ctx.accounts.reward_token_mint.decimals)?;
+
+        ctx.accounts.pool_reward_vault.reload()?;
+        let received = ctx.accounts.pool_reward_vault.amount;
         let pool = &mut ctx.accounts.pool;
-        pool.authority = ctx.accounts.authority.key();
-        pool.stake_token_mint = ctx.accounts.stake_token_mint.key();
-        pool.reward_token_mint = ctx.accounts.reward_token_mint.key();
-        pool.reward_rate = reward_rate;
-        pool.min_stake_duration = min_stake_duration;
-        pool.total_staked = 0;
-        pool.bump = ctx.bumps.pool;
-        
-        msg!("Staking pool initialized with reward rate: {} per second", reward_rate);
+        pool.total_rewards_funded = received;
+
+        let timestamp = Clock::get()?.unix_timestamp;
+        record_funding(
+            &mut ctx.accounts.funder_record,
+            pool.key(),
+            ctx.accounts.authority.key(),
+            ctx.bumps.funder_record,
+            received,
+            timestamp,
+        )?;
+
+        emit!(RewardsFundedEvent {
+            pool: pool.key(),
+            funder: ctx.accounts.authority.key(),
+            reward_mint: pool.reward_token_mint,
+            amount: received,
+            vault_balance: received,
+            total_rewards_funded: received,
+            timestamp,
+        });
+
+        msg!("Pool bootstrapped with {} reward tokens", received);
+        Ok(())
+    }
+
+    /// Create the program-wide config; the signer becomes its admin
+    /// - fee_bps: Share of every reward claim routed to the treasury
+    /// - fee_treasury: Owner of the token accounts that receive claim fees
+    pub fn initialize_config(ctx: Context<InitializeConfig>, fee_bps: u16, fee_treasury: Pubkey) -> Result<()> {
+        require!(fee_bps as u64 <= BPS_DENOMINATOR, StakingError::InvalidFeeConfig);
+
+        let config = &mut ctx.accounts.config;
+        config.admin = ctx.accounts.admin.key();
+        config.fee_bps = fee_bps;
+        config.fee_treasury = fee_treasury;
+        config.bump = ctx.bumps.config;
+
+        msg!("Protocol config initialized with {} bps claim fee to {}", fee_bps, fee_treasury);
+        Ok(())
+    }
+
+    /// Change the protocol fee or treasury (config admin only)
+    pub fn update_config(ctx: Context<UpdateConfig>, fee_bps: u16, fee_treasury: Pubkey) -> Result<()> {
+        require!(fee_bps as u64 <= BPS_DENOMINATOR, StakingError::InvalidFeeConfig);
+
+        let config = &mut ctx.accounts.config;
+        config.fee_bps = fee_bps;
+        config.fee_treasury = fee_treasury;
+
+        msg!("Protocol config updated to {} bps claim fee to {}", fee_bps, fee_treasury);
         Ok(())
     }
 
     /// Stake tokens into the pool
-    pub fn stake(ctx: Context<StakeTokens>, amount: u64) -> Result<()> {
+    /// - position_id: Identifies one of the user's independent positions in this pool
+    /// - lock_period: Seconds the position commits to; longer locks earn a tier multiplier
+    /// - beneficiary: Owner of the position; the signer only supplies the tokens.
+    ///   Anyone may open a position for someone else, but only the beneficiary may top it up
+    /// - proof: Merkle proof that the beneficiary is on the pool's allowlist; empty for open pools
+    ///
+    /// A position whose pending rewards have passed u64::MAX is paid what the vault covers
+    /// as part of the stake, which then needs the reward and treasury accounts
+    pub fn stake(
+        ctx: Context<StakeTokens>,
+        position_id: u64,
+        amount: u64,
+        lock_period: i64,
+        beneficiary: Pubkey,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        require!(!ctx.accounts.pool.paused, StakingError::PoolPaused);
         require!(amount > 0, StakingError::InvalidAmount);
-
-        let clock = Clock::get()?;
-        let user_stake = &mut ctx.accounts.user_stake;
-        let is_new = user_stake.amount == 0;
-
-        // If user has existing stake, claim pending rewards first
-        if user_stake.amount > 0 {
-            let rewards = calculate_rewards(
-                user_stake.amount,
-                ctx.accounts.pool.reward_rate,
-                user_stake.last_stake_time,
-                clock.unix_timestamp,
-            )?;
-            user_stake.pending_rewards = user_stake.pending_rewards.checked_add(rewards)
-                .ok_or(StakingError::Overflow)?;
-        }
+        require!(lock_period >= 0, StakingError::InvalidLockPeriod);
+        check_stake_gates(
+            &ctx.accounts.pool,
+            ctx.accounts.pool_reward_vault.amount,
+            &ctx.accounts.user.key(),
+            &beneficiary,
+            ctx.accounts.nft_token.as_deref(),
+            ctx.accounts.nft_metadata.as_deref(),
+            &proof,
+        )?;
 
         // Transfer stake tokens from user to pool vault
-        let cpi_accounts = Transfer {
+        let vault_balance_before = ctx.accounts.pool_stake_vault.amount;
+        let cpi_accounts = TransferChecked {
             from: ctx.accounts.user_stake_token.to_account_info(),
+            mint: ctx.accounts.stake_token_mint.to_account_info(),
             to: ctx.accounts.pool_stake_vault.to_account_info(),
             authority: ctx.accounts.user.to_account_info(),
         };
         let cpi_program = ctx.accounts.token_program.to_account_info();
         let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-        token::transfer(cpi_ctx, amount)?;
+        token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.stake_token_mint.decimals)?;
+
+        let received = stake_received(&mut ctx.accounts.pool_stake_vault, vault_balance_before)?;
+
+        credit_stake(
+            &mut ctx.accounts.pool,
+            &mut ctx.accounts.user_stake,
+            ctx.accounts.user.key(),
+            beneficiary,
+            position_id,
+            ctx.bumps.user_stake,
+            received,
+            lock_period,
+        )?;
+
+        // Rewards past u64::MAX can't leave in one transfer, so a top-up first pays out
+        // what the vault covers, fee included, instead of letting the balance keep growing
+        let clock = Clock::get()?;
+        let pool = &ctx.accounts.pool;
+        let user_stake = &ctx.accounts.user_stake;
+        if user_stake.pending_rewards <= u64::MAX as u128
+            || user_stake.frozen
+            || pool.parent_pool.is_some()
+            || claim_window_closed(pool, clock.unix_timestamp)
+        {
+            return Ok(());
+        }
+        let payout = capped_payout(pool, user_stake.pending_rewards, ctx.accounts.pool_reward_vault.amount);
+        if payout == 0 {
+            return Ok(());
+        }
+
+        let (Some(user_reward_token), Some(config), Some(treasury_reward_token), Some(reward_token_mint)) = (
+            ctx.accounts.user_reward_token.as_ref(),
+            ctx.accounts.config.as_ref(),
+            ctx.accounts.treasury_reward_token.as_ref(),
+            ctx.accounts.reward_token_mint.as_ref(),
+        ) else {
+            return err!(StakingError::RewardAccountsRequired);
+        };
+        require!(
+            treasury_reward_token.owner == config.fee_treasury
+                && treasury_reward_token.mint == pool.reward_token_mint,
+            StakingError::RewardAccountsRequired
+        );
+        let fee = bps_of(payout, config.fee_bps)?;
+        let user_payout = payout.checked_sub(fee)
+            .ok_or(StakingError::Underflow)?;
 
-        // Update user stake account
-        if is_new {
-            user_stake.user = ctx.accounts.user.key();
-            user_stake.pool = ctx.accounts.pool.key();
-            user_stake.bump = ctx.bumps.user_stake;
+        let pool_key = pool.key();
+        let seeds = &[
+            b"vault_authority",
+            pool_key.as_ref(),
+            &[pool.vault_authority_bump],
+        ];
+        let signer = &[&seeds[..]];
+        for (to, share) in [(user_reward_token, user_payout), (treasury_reward_token, fee)] {
+            if share == 0 {
+                continue;
+            }
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.pool_reward_vault.to_account_info(),
+                mint: reward_token_mint.to_account_info(),
+                to: to.to_account_info(),
+                authority: ctx.accounts.vault_authority.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token_interface::transfer_checked(cpi_ctx, share, reward_token_mint.decimals)?;
         }
-        user_stake.amount = user_stake.amount.checked_add(amount)
+
+        let user_stake = &mut ctx.accounts.user_stake;
+        user_stake.pending_rewards = user_stake.pending_rewards.checked_sub(payout as u128)
+            .ok_or(StakingError::Underflow)?;
+        user_stake.last_claim_time = clock.unix_timestamp;
+        let pool = &mut ctx.accounts.pool;
+        pool.total_rewards_distributed = pool.total_rewards_distributed.checked_add(payout)
             .ok_or(StakingError::Overflow)?;
-        user_stake.last_stake_time = clock.unix_timestamp;
 
-        // Update pool total
+        msg!(
+            "Paid out {} reward tokens ({} protocol fee) above the single-payout limit, {} remains pending",
+            payout,
+            fee,
+            user_stake.pending_rewards
+        );
+        Ok(())
+    }
+
+    /// Stake tokens the user has approved the vault authority to move as their SPL delegate
+    /// A relayer submits the transaction and pays for it and for the position's rent; the user
+    /// still signs to own the position. The approval must cover `amount` and is spent by it.
+    /// Arguments match `stake`, with the user as beneficiary
+    pub fn stake_delegated(
+        ctx: Context<StakeDelegated>,
+        position_id: u64,
+        amount: u64,
+        lock_period: i64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        require!(!ctx.accounts.pool.paused, StakingError::PoolPaused);
+        require!(amount > 0, StakingError::InvalidAmount);
+        require!(lock_period >= 0, StakingError::InvalidLockPeriod);
+        require!(
+            Option::from(ctx.accounts.user_stake_token.delegate) == Some(ctx.accounts.vault_authority.key())
+                && ctx.accounts.user_stake_token.delegated_amount >= amount,
+            StakingError::DelegateApprovalInsufficient
+        );
+        check_stake_gates(
+            &ctx.accounts.pool,
+            ctx.accounts.pool_reward_vault.amount,
+            &ctx.accounts.user.key(),
+            &ctx.accounts.user.key(),
+            ctx.accounts.nft_token.as_deref(),
+            ctx.accounts.nft_metadata.as_deref(),
+            &proof,
+        )?;
+
+        // The vault authority moves the tokens as the account's delegate
+        let pool_key = ctx.accounts.pool.key();
+        let seeds = &[
+            b"vault_authority",
+            pool_key.as_ref(),
+            &[ctx.accounts.pool.vault_authority_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let vault_balance_before = ctx.accounts.pool_stake_vault.amount;
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.user_stake_token.to_account_info(),
+            mint: ctx.accounts.stake_token_mint.to_account_info(),
+            to: ctx.accounts.pool_stake_vault.to_account_info(),
+            authority: ctx.accounts.vault_authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.stake_token_mint.decimals)?;
+
+        let received = stake_received(&mut ctx.accounts.pool_stake_vault, vault_balance_before)?;
+
+        let user = ctx.accounts.user.key();
+        credit_stake(
+            &mut ctx.accounts.pool,
+            &mut ctx.accounts.user_stake,
+            user,
+            user,
+            position_id,
+            ctx.bumps.user_stake,
+            received,
+            lock_period,
+        )
+    }
+
+    /// Stake native SOL into a wrapped-SOL pool
+    /// Lamports go straight into the stake vault and are synced into its token balance,
+    /// so no temporary wrapped-SOL account is needed. Arguments match `stake`
+    pub fn stake_sol(
+        ctx: Context<StakeSol>,
+        position_id: u64,
+        amount: u64,
+        lock_period: i64,
+        beneficiary: Pubkey,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        require!(!ctx.accounts.pool.paused, StakingError::PoolPaused);
+        require!(amount > 0, StakingError::InvalidAmount);
+        require!(lock_period >= 0, StakingError::InvalidLockPeriod);
+        check_stake_gates(
+            &ctx.accounts.pool,
+            ctx.accounts.pool_reward_vault.amount,
+            &ctx.accounts.user.key(),
+            &beneficiary,
+            ctx.accounts.nft_token.as_deref(),
+            ctx.accounts.nft_metadata.as_deref(),
+            &proof,
+        )?;
+
+        let vault_balance_before = ctx.accounts.pool_stake_vault.amount;
+        let cpi_accounts = system_program::Transfer {
+            from: ctx.accounts.user.to_account_info(),
+            to: ctx.accounts.pool_stake_vault.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.system_program.to_account_info(), cpi_accounts);
+        system_program::transfer(cpi_ctx, amount)?;
+
+        let cpi_accounts = SyncNative {
+            account: ctx.accounts.pool_stake_vault.to_account_info(),
+        };
+        let cpi_ctx = CpiContext::new(ctx.accounts.token_program.to_account_info(), cpi_accounts);
+        token_interface::sync_native(cpi_ctx)?;
+
+        let received = stake_received(&mut ctx.accounts.pool_stake_vault, vault_balance_before)?;
+
+        credit_stake(
+            &mut ctx.accounts.pool,
+            &mut ctx.accounts.user_stake,
+            ctx.accounts.user.key(),
+            beneficiary,
+            position_id,
+            ctx.bumps.user_stake,
+            received,
+            lock_period,
+        )
+    }
+
+    /// Re-lock an existing position in place for a new lock period
+    /// The new lock must run at least as long as what remains of the current one
+    pub fn restake(ctx: Context<Restake>, position_id: u64, new_lock_period: i64) -> Result<()> {
+        let user_stake = &mut ctx.accounts.user_stake;
+        require_earning_balance(user_stake, user_stake.amount)?;
+
+        let clock = Clock::get()?;
+        let remaining_lock = user_stake.lock_end.saturating_sub(clock.unix_timestamp).max(0);
+        require!(new_lock_period >= remaining_lock, StakingError::InvalidLockPeriod);
+
+        // Settle at the old multiplier before the weight changes
         let pool = &mut ctx.accounts.pool;
-        pool.total_staked = pool.total_staked.checked_add(amount)
+        update_pool(pool, clock.unix_timestamp)?;
+        let previous_weight = user_stake.weight(pool)?;
+        settle_user_rewards(pool, user_stake)?;
+
+        user_stake.lock_end = clock.unix_timestamp.checked_add(new_lock_period)
+            .ok_or(StakingError::Overflow)?;
+        user_stake.reward_multiplier_bps = pool.lock_multiplier_bps(new_lock_period);
+        sync_user_weight(pool, user_stake, previous_weight)?;
+
+        msg!(
+            "Re-locked position {} until {} at {} bps",
+            position_id,
+            user_stake.lock_end,
+            user_stake.reward_multiplier_bps
+        );
+        Ok(())
+    }
+
+    /// Move a position up to lock tier `new_tier`, 1 being the pool's first tier
+    /// The tier must pay more than the position's current multiplier. The lock runs at least
+    /// that tier's duration from now and never ends earlier than before; tokens stay put
+    pub fn upgrade_lock(ctx: Context<Restake>, position_id: u64, new_tier: u8) -> Result<()> {
+        let user_stake = &mut ctx.accounts.user_stake;
+        require!(user_stake.amount > 0, StakingError::InsufficientStake);
+
+        let pool = &mut ctx.accounts.pool;
+        let tier = (new_tier as usize).checked_sub(1)
+            .filter(|tier| *tier < MAX_LOCK_TIERS && pool.lock_tier_multipliers_bps[*tier] > 0)
+            .ok_or(StakingError::InvalidLockTier)?;
+        let multiplier_bps = pool.lock_tier_multipliers_bps[tier];
+        require!(
+            multiplier_bps > user_stake.reward_multiplier_bps,
+            StakingError::CannotDowngradeLock
+        );
+
+        // Settle at the old multiplier before the weight changes
+        let clock = Clock::get()?;
+        update_pool(pool, clock.unix_timestamp)?;
+        let previous_weight = user_stake.weight(pool)?;
+        settle_user_rewards(pool, user_stake)?;
+
+        let tier_end = clock.unix_timestamp.checked_add(pool.lock_tier_durations[tier])
             .ok_or(StakingError::Overflow)?;
+        user_stake.lock_end = user_stake.lock_end.max(tier_end);
+        user_stake.reward_multiplier_bps = multiplier_bps;
+        sync_user_weight(pool, user_stake, previous_weight)?;
+
+        msg!(
+            "Upgraded position {} to tier {}, locked until {} at {} bps",
+            position_id,
+            new_tier,
+            user_stake.lock_end,
+            user_stake.reward_multiplier_bps
+        );
+        Ok(())
+    }
+
+    /// Move part of a position into a new position of the same owner
+    /// The new position keeps the source's lock, multiplier and stake clocks and takes a
+    /// proportional share of its settled rewards; tokens stay in the vault
+    pub fn split_position(
+        ctx: Context<SplitPosition>,
+        position_id: u64,
+        split_amount: u64,
+        new_position_id: u64,
+    ) -> Result<()> {
+        let source = &mut ctx.accounts.user_stake;
+        require!(split_amount > 0, StakingError::InvalidAmount);
+        require!(split_amount < source.amount, StakingError::SplitExceedsStake);
+        let remaining = source.amount - split_amount;
+        let pool_min = ctx.accounts.pool.min_stake_amount;
+        require!(
+            remaining >= pool_min && split_amount >= pool_min,
+            StakingError::BelowMinimumStake
+        );
+
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.pool;
+        update_pool(pool, clock.unix_timestamp)?;
+        let previous_weight = source.weight(pool)?;
+        settle_user_rewards(pool, source)?;
+
+        let moved_rewards = source.pending_rewards
+            .checked_mul(split_amount as u128)
+            .ok_or(StakingError::Overflow)?
+            / source.amount as u128;
+        let moved_rewards_2 = source.pending_rewards_2
+            .checked_mul(split_amount as u128)
+            .ok_or(StakingError::Overflow)?
+            / source.amount as u128;
+
+        let split = &mut ctx.accounts.new_user_stake;
+        split.user = source.user;
+        split.pool = source.pool;
+        split.position_id = new_position_id;
+        split.amount = split_amount;
+        split.last_stake_time = source.last_stake_time;
+        split.warmup_start = source.warmup_start;
+        split.first_stake_time = source.first_stake_time;
+        split.last_claim_time = source.last_claim_time;
+        split.last_settle_time = source.last_settle_time;
+        split.locked_duration = source.locked_duration;
+        split.pending_rewards = moved_rewards;
+        split.pending_rewards_2 = moved_rewards_2;
+        split.lock_end = source.lock_end;
+        split.reward_multiplier_bps = source.reward_multiplier_bps;
+        split.bump = ctx.bumps.new_user_stake;
+        split.version = USER_STAKE_VERSION;
+        sync_user_weight(pool, split, 0)?;
+
+        source.amount = remaining;
+        source.pending_rewards = source.pending_rewards.checked_sub(moved_rewards)
+            .ok_or(StakingError::Underflow)?;
+        source.pending_rewards_2 = source.pending_rewards_2.checked_sub(moved_rewards_2)
+            .ok_or(StakingError::Underflow)?;
+        sync_user_weight(pool, source, previous_weight)?;
+
+        msg!(
+            "Split {} tokens from position {} into position {}",
+            split_amount,
+            position_id,
+            new_position_id
+        );
+        Ok(())
+    }
+
+    /// Hand a position to a new owner
+    /// Positions are addressed by owner, so the state moves to the new owner's PDA under the
+    /// same position id and the old account is closed; principal stays in the vault and
+    /// settled rewards and any unbonding travel with the position
+    pub fn transfer_stake(ctx: Context<TransferStake>, position_id: u64, new_owner: Pubkey) -> Result<()> {
+        require!(new_owner != ctx.accounts.user.key(), StakingError::SameOwner);
+
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.pool;
+        update_pool(pool, clock.unix_timestamp)?;
+        settle_user_rewards(pool, &mut ctx.accounts.user_stake)?;
+
+        let mut moved = (*ctx.accounts.user_stake).clone();
+        moved.user = new_owner;
+        moved.bump = ctx.bumps.new_user_stake;
+        ctx.accounts.new_user_stake.set_inner(moved);
+
+        msg!(
+            "Transferred position {} from {} to {}",
+            position_id,
+            ctx.accounts.user.key(),
+            new_owner
+        );
+        Ok(())
+    }
+
+    /// Move a whole position into another pool with the same authority and stake mint,
+    /// without routing the tokens through the user's wallet. Rewards earned in the old pool
+    /// are settled and stay claimable on the emptied old position. The new position carries
+    /// what remains of the lock and must pass the new pool's stake checks, with `proof` for
+    /// its allowlist as in `stake`
+    pub fn migrate_stake(
+        ctx: Context<MigrateStake>,
+        position_id: u64,
+        new_position_id: u64,
+        proof: Vec<[u8; 32]>,
+    ) -> Result<()> {
+        require!(!ctx.accounts.new_pool.paused, StakingError::PoolPaused);
+        let amount = ctx.accounts.user_stake.amount;
+        require!(amount > 0, StakingError::InsufficientStake);
+        check_stake_gates(
+            &ctx.accounts.new_pool,
+            ctx.accounts.new_pool_reward_vault.amount,
+            &ctx.accounts.user.key(),
+            &ctx.accounts.user.key(),
+            ctx.accounts.nft_token.as_deref(),
+            ctx.accounts.nft_metadata.as_deref(),
+            &proof,
+        )?;
+
+        let clock = Clock::get()?;
+        let withdrawn = stake_vault_share(&ctx.accounts.pool, ctx.accounts.pool_stake_vault.amount, amount)?;
+        let remaining_lock = ctx.accounts.user_stake.lock_end.saturating_sub(clock.unix_timestamp).max(0);
+
+        // Settle and empty the old position before its tokens leave
+        let pool = &mut ctx.accounts.pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+        update_pool(pool, clock.unix_timestamp)?;
+        let previous_weight = user_stake.weight(pool)?;
+        settle_user_rewards(pool, user_stake)?;
+        user_stake.amount = 0;
+        user_stake.last_stake_time = clock.unix_timestamp;
+        sync_user_weight(pool, user_stake, previous_weight)?;
+        pool.total_staked = pool.total_staked.checked_sub(amount)
+            .ok_or(StakingError::Underflow)?;
+
+        let pool_key = ctx.accounts.pool.key();
+        let seeds = &[
+            b"vault_authority",
+            pool_key.as_ref(),
+            &[ctx.accounts.pool.vault_authority_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let vault_balance_before = ctx.accounts.new_pool_stake_vault.amount;
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.pool_stake_vault.to_account_info(),
+            mint: ctx.accounts.stake_token_mint.to_account_info(),
+            to: ctx.accounts.new_pool_stake_vault.to_account_info(),
+            authority: ctx.accounts.vault_authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::transfer_checked(cpi_ctx, withdrawn, ctx.accounts.stake_token_mint.decimals)?;
 
-        msg!("Staked {} tokens. Total staked: {}", amount, user_stake.amount);
+        let received = stake_received(&mut ctx.accounts.new_pool_stake_vault, vault_balance_before)?;
+
+        let user = ctx.accounts.user.key();
+        credit_stake(
+            &mut ctx.accounts.new_pool,
+            &mut ctx.accounts.new_user_stake,
+            user,
+            user,
+            new_position_id,
+            ctx.bumps.new_user_stake,
+            received,
+            remaining_lock,
+        )?;
+
+        msg!(
+            "Migrated position {} ({} tokens) to position {} of pool {}",
+            position_id,
+            amount,
+            new_position_id,
+            ctx.accounts.new_pool.key()
+        );
         Ok(())
     }
 
     /// Unstake tokens from the pool
-    pub fn unstake(ctx: Context<Unstake>, amount: u64) -> Result<()> {
+    /// Leaving before min_stake_duration costs `early_unstake_penalty_bps` of the amount,
+    /// paid into the reward vault, unless the pool hard-rejects early exits.
+    /// `penalty_treasury_bps` of the penalty goes to the protocol treasury instead, which
+    /// then needs `config` and the treasury's stake-mint account
+    pub fn unstake(ctx: Context<Unstake>, position_id: u64, amount: u64) -> Result<()> {
         require!(amount > 0, StakingError::InvalidAmount);
         
         let user_stake = &mut ctx.accounts.user_stake;
-        require!(user_stake.amount >= amount, StakingError::InsufficientStake);
+        require_earning_balance(user_stake, amount)?;
 
         let clock = Clock::get()?;
-        let elapsed = clock.unix_timestamp - user_stake.last_stake_time;
+        let withdrawn = stake_vault_share(&ctx.accounts.pool, ctx.accounts.pool_stake_vault.amount, amount)?;
+        let penalty = early_unstake_penalty(&ctx.accounts.pool, user_stake, withdrawn, clock.unix_timestamp)?;
+        let net_amount = withdrawn.checked_sub(penalty)
+            .ok_or(StakingError::Underflow)?;
+        // Rounding dust stays with the reward vault
+        let treasury_cut = bps_of(penalty, ctx.accounts.pool.penalty_treasury_bps)?;
+        let reward_vault_cut = penalty - treasury_cut;
         require!(
-            elapsed >= ctx.accounts.pool.min_stake_duration,
-            StakingError::StakeDurationNotMet
+            clock.unix_timestamp >= user_stake.lock_end || ctx.accounts.pool.emergency_mode,
+            StakingError::LockNotExpired
         );
 
         // Calculate and add pending rewards
-        let rewards = calculate_rewards(
-            user_stake.amount,
-            ctx.accounts.pool.reward_rate,
-            user_stake.last_stake_time,
-            clock.unix_timestamp,
-        )?;
-        user_stake.pending_rewards = user_stake.pending_rewards.checked_add(rewards)
-            .ok_or(StakingError::Overflow)?;
+        let pool = &mut ctx.accounts.pool;
+        update_pool(pool, clock.unix_timestamp)?;
+        let previous_weight = user_stake.weight(pool)?;
+        settle_user_rewards(pool, user_stake)?;
 
         // Transfer stake tokens back to user
-        let authority = ctx.accounts.pool.authority;
+        let pool_key = ctx.accounts.pool.key();
         let seeds = &[
-            b"pool",
-            authority.as_ref(),
-            &[ctx.accounts.pool.bump],
+            b"vault_authority",
+            pool_key.as_ref(),
+            &[ctx.accounts.pool.vault_authority_bump],
         ];
         let signer = &[&seeds[..]];
 
-        let cpi_accounts = Transfer {
+        let cpi_accounts = TransferChecked {
             from: ctx.accounts.pool_stake_vault.to_account_info(),
+            mint: ctx.accounts.stake_token_mint.to_account_info(),
             to: ctx.accounts.user_stake_token.to_account_info(),
-            authority: ctx.accounts.pool.to_account_info(),
+            authority: ctx.accounts.vault_authority.to_account_info(),
         };
         let cpi_program = ctx.accounts.token_program.to_account_info();
         let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
-        token::transfer(cpi_ctx, amount)?;
+        token_interface::transfer_checked(cpi_ctx, net_amount, ctx.accounts.stake_token_mint.decimals)?;
+
+        // Route the early-exit penalty to the reward vault for remaining stakers
+        if reward_vault_cut > 0 {
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.pool_stake_vault.to_account_info(),
+                mint: ctx.accounts.stake_token_mint.to_account_info(),
+                to: ctx.accounts.pool_reward_vault.to_account_info(),
+                authority: ctx.accounts.vault_authority.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token_interface::transfer_checked(cpi_ctx, reward_vault_cut, ctx.accounts.stake_token_mint.decimals)?;
+        }
+
+        // and the treasury's cut to the protocol treasury
+        if treasury_cut > 0 {
+            let (Some(config), Some(treasury_stake_token)) =
+                (&ctx.accounts.config, &ctx.accounts.treasury_stake_token)
+            else {
+                return err!(StakingError::TreasuryAccountRequired);
+            };
+            require!(
+                treasury_stake_token.owner == config.fee_treasury
+                    && treasury_stake_token.mint == ctx.accounts.pool.stake_token_mint,
+                StakingError::TreasuryAccountRequired
+            );
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.pool_stake_vault.to_account_info(),
+                mint: ctx.accounts.stake_token_mint.to_account_info(),
+                to: treasury_stake_token.to_account_info(),
+                authority: ctx.accounts.vault_authority.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token_interface::transfer_checked(cpi_ctx, treasury_cut, ctx.accounts.stake_token_mint.decimals)?;
+        }
 
         // Update user stake
         user_stake.amount = user_stake.amount.checked_sub(amount)
@@ -130,265 +776,4455 @@ pub mod staking_program {
 
         // Update pool total
         let pool = &mut ctx.accounts.pool;
+        sync_user_weight(pool, user_stake, previous_weight)?;
         pool.total_staked = pool.total_staked.checked_sub(amount)
             .ok_or(StakingError::Underflow)?;
 
-        msg!("Unstaked {} tokens. Remaining: {}", amount, user_stake.amount);
+        msg!(
+            "Unstaked {} tokens from position {} ({} early-exit penalty, {} to treasury). Remaining: {}",
+            amount,
+            position_id,
+            penalty,
+            treasury_cut,
+            user_stake.amount
+        );
         Ok(())
     }
 
-    /// Claim accumulated reward tokens
-    pub fn claim_rewards(ctx: Context<ClaimRewards>) -> Result<()> {
+    /// Claim every pending reward and unstake `amount` in one instruction
+    /// Exits honor min_stake_duration outright rather than taking the early-exit penalty.
+    /// Rewards are paid as by claim_rewards, except that an auto-compounding position is paid
+    /// out, and rewards the exit can't pay yet, in the claim cooldown or past what the vault
+    /// holds, stay pending instead of failing it
+    pub fn exit(ctx: Context<Exit>, position_id: u64, amount: u64) -> Result<()> {
+        require!(amount > 0, StakingError::InvalidAmount);
+
         let user_stake = &mut ctx.accounts.user_stake;
-        
+        require_earning_balance(user_stake, amount)?;
+
         let clock = Clock::get()?;
-        
-        // Calculate current rewards
-        let current_rewards = calculate_rewards(
-            user_stake.amount,
-            ctx.accounts.pool.reward_rate,
-            user_stake.last_stake_time,
-            clock.unix_timestamp,
-        )?;
-        
-        let total_rewards = user_stake.pending_rewards.checked_add(current_rewards)
-            .ok_or(StakingError::Overflow)?;
-        
-        require!(total_rewards > 0, StakingError::NoRewardsToClaim);
+        let withdrawn = stake_vault_share(&ctx.accounts.pool, ctx.accounts.pool_stake_vault.amount, amount)?;
+        let elapsed = clock.unix_timestamp - user_stake.last_stake_time;
+        require!(
+            elapsed >= user_stake.min_stake_duration(&ctx.accounts.pool),
+            StakingError::StakeDurationNotMet
+        );
+        require!(clock.unix_timestamp >= user_stake.lock_end, StakingError::LockNotExpired);
 
-        // Transfer reward tokens to user
-        let authority = ctx.accounts.pool.authority;
+        let pool = &mut ctx.accounts.pool;
+        update_pool(pool, clock.unix_timestamp)?;
+        let previous_weight = user_stake.weight(pool)?;
+        settle_user_rewards(pool, user_stake)?;
+
+        // After the final claim window the rewards are left for sweep_expired_rewards,
+        // so the exit only returns the stake
+        if claim_window_closed(pool, clock.unix_timestamp) {
+            user_stake.pending_rewards = 0;
+            user_stake.pending_rewards_2 = 0;
+        }
+
+        // Inside the claim cooldown the rewards stay pending for a later claim
+        let cooled_down =
+            clock.unix_timestamp.saturating_sub(user_stake.last_claim_time) >= pool.claim_cooldown;
+        let paid = if cooled_down {
+            pay_rewards(
+                RewardAccounts {
+                    pool: &mut ctx.accounts.pool,
+                    user_stake: &mut ctx.accounts.user_stake,
+                    vault_authority: &ctx.accounts.vault_authority,
+                    user_reward_token: &ctx.accounts.user_reward_token,
+                    pool_reward_vault: &ctx.accounts.pool_reward_vault,
+                    config: &ctx.accounts.config,
+                    treasury_reward_token: &ctx.accounts.treasury_reward_token,
+                    reward_token_mint: &ctx.accounts.reward_token_mint,
+                    token_program: &ctx.accounts.token_program,
+                    boost_token: ctx.accounts.boost_token.as_deref(),
+                    parent_pool: ctx.accounts.parent_pool.as_mut(),
+                    parent_vault_authority: ctx.accounts.parent_vault_authority.as_ref(),
+                    parent_reward_vault: ctx.accounts.parent_reward_vault.as_ref(),
+                    pool_reward_vault_2: ctx.accounts.pool_reward_vault_2.as_ref(),
+                    user_reward_token_2: ctx.accounts.user_reward_token_2.as_ref(),
+                    reward_token_mint_2: ctx.accounts.reward_token_mint_2.as_ref(),
+                    compound_vault: None,
+                    claim_receipt: ctx.accounts.claim_receipt.as_mut(),
+                    claim_receipt_bump: ctx.bumps.claim_receipt,
+                    program_id: ctx.program_id,
+                },
+                position_id,
+                None,
+                clock.unix_timestamp,
+                false,
+            )?
+        } else {
+            RewardPayment::default()
+        };
+
+        let pool_key = ctx.accounts.pool.key();
         let seeds = &[
-            b"pool",
-            authority.as_ref(),
-            &[ctx.accounts.pool.bump],
+            b"vault_authority",
+            pool_key.as_ref(),
+            &[ctx.accounts.pool.vault_authority_bump],
         ];
         let signer = &[&seeds[..]];
 
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.pool_reward_vault.to_account_info(),
-            to: ctx.accounts.user_reward_token.to_account_info(),
-            authority: ctx.accounts.pool.to_account_info(),
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.pool_stake_vault.to_account_info(),
+            mint: ctx.accounts.stake_token_mint.to_account_info(),
+            to: ctx.accounts.user_stake_token.to_account_info(),
+            authority: ctx.accounts.vault_authority.to_account_info(),
         };
         let cpi_program = ctx.accounts.token_program.to_account_info();
         let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
-        token::transfer(cpi_ctx, total_rewards)?;
+        token_interface::transfer_checked(cpi_ctx, withdrawn, ctx.accounts.stake_token_mint.decimals)?;
 
-        // Reset rewards and update timestamp
-        user_stake.pending_rewards = 0;
+        let user_stake = &mut ctx.accounts.user_stake;
+        user_stake.amount = user_stake.amount.checked_sub(amount)
+            .ok_or(StakingError::Underflow)?;
         user_stake.last_stake_time = clock.unix_timestamp;
 
-        msg!("Claimed {} reward tokens", total_rewards);
+        let pool = &mut ctx.accounts.pool;
+        sync_user_weight(pool, user_stake, previous_weight)?;
+        pool.total_staked = pool.total_staked.checked_sub(amount)
+            .ok_or(StakingError::Underflow)?;
+
+        msg!(
+            "Exited {} tokens, {} reward tokens ({} protocol fee) and {} second reward tokens from position {}, {} remains pending",
+            amount,
+            paid.payout,
+            paid.fee,
+            paid.payout_2,
+            position_id,
+            user_stake.pending_rewards
+        );
         Ok(())
     }
 
-    /// Fund the reward vault (admin function)
-    pub fn fund_rewards(ctx: Context<FundRewards>, amount: u64) -> Result<()> {
+    /// Start unbonding tokens; they stop earning rewards immediately
+    /// and can be withdrawn with complete_unstake after the unbonding period
+    pub fn request_unstake(ctx: Context<RequestUnstake>, position_id: u64, amount: u64) -> Result<()> {
         require!(amount > 0, StakingError::InvalidAmount);
 
-        let cpi_accounts = Transfer {
-            from: ctx.accounts.funder_token_account.to_account_info(),
-            to: ctx.accounts.pool_reward_vault.to_account_info(),
-            authority: ctx.accounts.funder.to_account_info(),
-        };
-        let cpi_program = ctx.accounts.token_program.to_account_info();
-        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
-        token::transfer(cpi_ctx, amount)?;
+        let user_stake = &mut ctx.accounts.user_stake;
+        require_earning_balance(user_stake, amount)?;
+
+        let clock = Clock::get()?;
+        let elapsed = clock.unix_timestamp - user_stake.last_stake_time;
+        require!(
+            elapsed >= user_stake.min_stake_duration(&ctx.accounts.pool),
+            StakingError::StakeDurationNotMet
+        );
+        require!(clock.unix_timestamp >= user_stake.lock_end, StakingError::LockNotExpired);
+
+        // Settle rewards earned so far on the full amount
+        let pool = &mut ctx.accounts.pool;
+        update_pool(pool, clock.unix_timestamp)?;
+        let previous_weight = user_stake.weight(pool)?;
+        settle_user_rewards(pool, user_stake)?;
+
+        // Move the tokens out of the earning balance into the unbonding bucket
+        user_stake.amount = user_stake.amount.checked_sub(amount)
+            .ok_or(StakingError::Underflow)?;
+        user_stake.unbonding_amount = user_stake.unbonding_amount.checked_add(amount)
+            .ok_or(StakingError::Overflow)?;
+        user_stake.unbonding_start_time = clock.unix_timestamp;
+        sync_user_weight(pool, user_stake, previous_weight)?;
+
+        pool.total_staked = pool.total_staked.checked_sub(amount)
+            .ok_or(StakingError::Underflow)?;
+        pool.total_unbonding = pool.total_unbonding.checked_add(amount)
+            .ok_or(StakingError::Overflow)?;
 
-        msg!("Funded reward vault with {} tokens", amount);
+        msg!(
+            "Unbonding {} tokens from position {}. Total unbonding: {}",
+            amount,
+            position_id,
+            user_stake.unbonding_amount
+        );
         Ok(())
     }
-}
 
-// Helper function to calculate rewards
-fn calculate_rewards(
-    staked_amount: u64,
-    reward_rate: u64,
-    last_stake_time: i64,
-    current_time: i64,
-) -> Result<u64> {
-    let time_elapsed = current_time.checked_sub(last_stake_time)
-        .ok_or(StakingError::Underflow)? as u64;
-    
-    let rewards = (staked_amount as u128)
-        .checked_mul(reward_rate as u128)
-        .ok_or(StakingError::Overflow)?
-        .checked_mul(time_elapsed as u128)
-        .ok_or(StakingError::Overflow)?
-        .checked_div(1_000_000_000)
-        .ok_or(StakingError::DivisionByZero)? as u64;
-    
-    Ok(rewards)
+    /// Withdraw tokens whose unbonding period has elapsed
+    pub fn complete_unstake(ctx: Context<CompleteUnstake>, position_id: u64) -> Result<()> {
+        let user_stake = &mut ctx.accounts.user_stake;
+        let amount = user_stake.unbonding_amount;
+        require!(amount > 0, StakingError::InvalidAmount);
+
+        let clock = Clock::get()?;
+        let elapsed = clock.unix_timestamp - user_stake.unbonding_start_time;
+        require!(
+            elapsed >= ctx.accounts.pool.unbonding_period,
+            StakingError::UnbondingNotComplete
+        );
+
+        // Transfer unbonded tokens back to user
+        let withdrawn = stake_vault_share(&ctx.accounts.pool, ctx.accounts.pool_stake_vault.amount, amount)?;
+        let pool_key = ctx.accounts.pool.key();
+        let seeds = &[
+            b"vault_authority",
+            pool_key.as_ref(),
+            &[ctx.accounts.pool.vault_authority_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.pool_stake_vault.to_account_info(),
+            mint: ctx.accounts.stake_token_mint.to_account_info(),
+            to: ctx.accounts.user_stake_token.to_account_info(),
+            authority: ctx.accounts.vault_authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::transfer_checked(cpi_ctx, withdrawn, ctx.accounts.stake_token_mint.decimals)?;
+
+        user_stake.unbonding_amount = 0;
+        user_stake.unbonding_start_time = 0;
+
+        let pool = &mut ctx.accounts.pool;
+        pool.total_unbonding = pool.total_unbonding.checked_sub(amount)
+            .ok_or(StakingError::Underflow)?;
+
+        msg!("Completed unstake of {} tokens from position {}", amount, position_id);
+        Ok(())
+    }
+
+    /// Withdraw the full stake immediately, ignoring min_stake_duration
+    /// All pending rewards are forfeited and the reward vault is never touched
+    pub fn emergency_unstake(ctx: Context<EmergencyUnstake>, position_id: u64) -> Result<()> {
+        let user_stake = &mut ctx.accounts.user_stake;
+        let amount = user_stake.amount;
+        require_earning_balance(user_stake, amount)?;
+
+        // Bring the accumulator up to date before total_staked changes
+        let clock = Clock::get()?;
+        update_pool(&mut ctx.accounts.pool, clock.unix_timestamp)?;
+
+        // Transfer stake tokens back to user
+        let withdrawn = stake_vault_share(&ctx.accounts.pool, ctx.accounts.pool_stake_vault.amount, amount)?;
+        let pool_key = ctx.accounts.pool.key();
+        let seeds = &[
+            b"vault_authority",
+            pool_key.as_ref(),
+            &[ctx.accounts.pool.vault_authority_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.pool_stake_vault.to_account_info(),
+            mint: ctx.accounts.stake_token_mint.to_account_info(),
+            to: ctx.accounts.user_stake_token.to_account_info(),
+            authority: ctx.accounts.vault_authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::transfer_checked(cpi_ctx, withdrawn, ctx.accounts.stake_token_mint.decimals)?;
+
+        let forfeited = user_stake.pending_rewards;
+        let previous_weight = user_stake.weight(&ctx.accounts.pool)?;
+        user_stake.amount = 0;
+        user_stake.pending_rewards = 0;
+        user_stake.pending_rewards_2 = 0;
+        user_stake.last_stake_time = clock.unix_timestamp;
+
+        let pool = &mut ctx.accounts.pool;
+        sync_user_weight(pool, user_stake, previous_weight)?;
+        pool.total_staked = pool.total_staked.checked_sub(amount)
+            .ok_or(StakingError::Underflow)?;
+
+        msg!(
+            "WARNING: emergency unstake of {} tokens from position {}, forfeited {} settled rewards plus any unsettled accrual",
+            amount,
+            position_id,
+            forfeited
+        );
+        Ok(())
+    }
+
+    /// Claim accumulated reward tokens
+    /// - amount: None claims everything, Some(x) claims up to x and leaves the rest pending
+    /// Passing the beneficiary's boost_mint account adds the pool's governance boost on top,
+    /// read from its balance at claim time. Child pools pay from their parent's reward vault
+    /// and need the parent accounts passed alongside. Pools with a second reward token also
+    /// pay it, in the same share of its pending balance as is settled of the first; a full
+    /// claim that settles none of the first pays the second on its own
+    pub fn claim_rewards(ctx: Context<ClaimRewards>, position_id: u64, amount: Option<u64>) -> Result<()> {
+        let user_stake = &mut ctx.accounts.user_stake;
+        
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.pool;
+        require!(
+            clock.unix_timestamp.saturating_sub(user_stake.last_claim_time) >= pool.claim_cooldown,
+            StakingError::ClaimCooldownActive
+        );
+        require!(
+            !claim_window_closed(pool, clock.unix_timestamp),
+            StakingError::ClaimWindowClosed
+        );
+        update_pool(pool, clock.unix_timestamp)?;
+        
+        // Settle current rewards
+        settle_user_rewards(pool, user_stake)?;
+
+        // Auto-compounding positions are paid into the stake vault instead
+        let compound_vault = if user_stake.auto_compound {
+            let Some(pool_stake_vault) = ctx.accounts.pool_stake_vault.as_mut() else {
+                return err!(StakingError::StakeVaultRequired);
+            };
+            let pool_key = ctx.accounts.pool.key();
+            let (expected_vault, _) = Pubkey::find_program_address(&[b"stake_vault", pool_key.as_ref()], ctx.program_id);
+            require_keys_eq!(pool_stake_vault.key(), expected_vault, StakingError::StakeVaultRequired);
+            Some(pool_stake_vault)
+        } else {
+            None
+        };
+
+        let paid = pay_rewards(
+            RewardAccounts {
+                pool: &mut ctx.accounts.pool,
+                user_stake: &mut ctx.accounts.user_stake,
+                vault_authority: &ctx.accounts.vault_authority,
+                user_reward_token: &ctx.accounts.user_reward_token,
+                pool_reward_vault: &ctx.accounts.pool_reward_vault,
+                config: &ctx.accounts.config,
+                treasury_reward_token: &ctx.accounts.treasury_reward_token,
+                reward_token_mint: &ctx.accounts.reward_token_mint,
+                token_program: &ctx.accounts.token_program,
+                boost_token: ctx.accounts.boost_token.as_deref(),
+                parent_pool: ctx.accounts.parent_pool.as_mut(),
+                parent_vault_authority: ctx.accounts.parent_vault_authority.as_ref(),
+                parent_reward_vault: ctx.accounts.parent_reward_vault.as_ref(),
+                pool_reward_vault_2: ctx.accounts.pool_reward_vault_2.as_ref(),
+                user_reward_token_2: ctx.accounts.user_reward_token_2.as_ref(),
+                reward_token_mint_2: ctx.accounts.reward_token_mint_2.as_ref(),
+                compound_vault,
+                claim_receipt: ctx.accounts.claim_receipt.as_mut(),
+                claim_receipt_bump: ctx.bumps.claim_receipt,
+                program_id: ctx.program_id,
+            },
+            position_id,
+            amount,
+            clock.unix_timestamp,
+            true,
+        )?;
+
+        if (paid.payout as u128) < paid.owed {
+            msg!(
+                "Claim capped by the vault, budget or per-transaction limit: claimed {} of {} from position {}, {} remains pending",
+                paid.payout,
+                paid.owed,
+                position_id,
+                ctx.accounts.user_stake.pending_rewards
+            );
+        } else {
+            msg!(
+                "Claimed {} reward tokens from position {} ({} protocol fee, {} boost), {} remains pending",
+                paid.payout,
+                position_id,
+                paid.fee,
+                paid.boost_paid,
+                ctx.accounts.user_stake.pending_rewards
+            );
+        }
+        Ok(())
+    }
+
+    /// Restake accumulated rewards without a round trip through the user's wallet
+    /// Only available when the stake and reward mints are the same
+    pub fn compound_rewards(ctx: Context<CompoundRewards>, position_id: u64) -> Result<()> {
+        let user_stake = &mut ctx.accounts.user_stake;
+
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.pool;
+        require!(
+            !claim_window_closed(pool, clock.unix_timestamp),
+            StakingError::ClaimWindowClosed
+        );
+        update_pool(pool, clock.unix_timestamp)?;
+
+        settle_user_rewards(pool, user_stake)?;
+        let total_rewards = user_stake.pending_rewards;
+        require!(total_rewards > 0, StakingError::NoRewardsToClaim);
+
+        let available = ctx.accounts.pool_reward_vault.amount;
+        require!(available > 0, StakingError::InsufficientRewardFunds);
+        let compounded = capped_payout(&ctx.accounts.pool, total_rewards, available);
+        require!(compounded > 0, StakingError::NoRewardsToClaim);
+
+        // Move rewards from the reward vault into the stake vault
+        let stake_vault_before = ctx.accounts.pool_stake_vault.amount;
+        let pool_key = ctx.accounts.pool.key();
+        let seeds = &[
+            b"vault_authority",
+            pool_key.as_ref(),
+            &[ctx.accounts.pool.vault_authority_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.pool_reward_vault.to_account_info(),
+            mint: ctx.accounts.stake_token_mint.to_account_info(),
+            to: ctx.accounts.pool_stake_vault.to_account_info(),
+            authority: ctx.accounts.vault_authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::transfer_checked(cpi_ctx, compounded, ctx.accounts.stake_token_mint.decimals)?;
+
+        // Any transfer fee comes out of the restaked amount
+        ctx.accounts.pool_stake_vault.reload()?;
+        let restaked = ctx.accounts.pool_stake_vault.amount.checked_sub(stake_vault_before)
+            .ok_or(StakingError::Underflow)?;
+
+        let previous_weight = user_stake.weight(&ctx.accounts.pool)?;
+        user_stake.pending_rewards = total_rewards.checked_sub(compounded as u128)
+            .ok_or(StakingError::Underflow)?;
+        user_stake.amount = user_stake.amount.checked_add(restaked)
+            .ok_or(StakingError::Overflow)?;
+
+        let pool = &mut ctx.accounts.pool;
+        sync_user_weight(pool, user_stake, previous_weight)?;
+        pool.total_staked = pool.total_staked.checked_add(restaked)
+            .ok_or(StakingError::Overflow)?;
+        pool.total_rewards_distributed = pool.total_rewards_distributed.checked_add(compounded)
+            .ok_or(StakingError::Overflow)?;
+
+        msg!(
+            "Compounded {} reward tokens into position {}. Total staked: {}",
+            compounded,
+            position_id,
+            user_stake.amount
+        );
+        Ok(())
+    }
+
+    /// Have claim_rewards restake a position's rewards instead of paying them out
+    /// Only available when the stake and reward mints are the same; claims then also need
+    /// the pool's stake vault. The protocol fee and any boost apply as for a normal claim
+    pub fn set_auto_compound(ctx: Context<SetAutoCompound>, position_id: u64, enabled: bool) -> Result<()> {
+        ctx.accounts.user_stake.auto_compound = enabled;
+
+        msg!("Position {} auto-compound: {}", position_id, enabled);
+        Ok(())
+    }
+
+    /// Move a position's pending rewards, plus `reinvest_bonus_bps` of them, into a new
+    /// position locked for `lock_period` seconds. Only available when the stake and reward
+    /// mints are the same. The bonus is paid from the reward vault on top of emission, and the
+    /// whole amount must be covered or nothing moves. Above max_claim_per_tx the remainder
+    /// stays pending
+    pub fn reinvest_with_bonus(
+        ctx: Context<ReinvestWithBonus>,
+        position_id: u64,
+        new_position_id: u64,
+        lock_period: i64,
+    ) -> Result<()> {
+        require!(!ctx.accounts.pool.paused, StakingError::PoolPaused);
+        require!(lock_period > 0, StakingError::InvalidLockPeriod);
+
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.pool;
+        require!(
+            !claim_window_closed(pool, clock.unix_timestamp),
+            StakingError::ClaimWindowClosed
+        );
+        let user_stake = &mut ctx.accounts.user_stake;
+        update_pool(pool, clock.unix_timestamp)?;
+        settle_user_rewards(pool, user_stake)?;
+        // The vault is checked with the bonus below, so only the budget and per-transaction caps apply here
+        let rewards = capped_payout(pool, user_stake.pending_rewards, u64::MAX);
+        require!(rewards > 0, StakingError::NoRewardsToClaim);
+
+        let bonus = bps_of(rewards, pool.reinvest_bonus_bps)?;
+        let total = rewards.checked_add(bonus)
+            .ok_or(StakingError::Overflow)?;
+        require!(
+            total <= ctx.accounts.pool_reward_vault.amount && total <= pool.unpaid_reward_budget(),
+            StakingError::ReinvestBonusUnfunded
+        );
+
+        // Move rewards and bonus from the reward vault into the stake vault
+        let stake_vault_before = ctx.accounts.pool_stake_vault.amount;
+        let pool_key = ctx.accounts.pool.key();
+        let seeds = &[
+            b"vault_authority",
+            pool_key.as_ref(),
+            &[ctx.accounts.pool.vault_authority_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.pool_reward_vault.to_account_info(),
+            mint: ctx.accounts.stake_token_mint.to_account_info(),
+            to: ctx.accounts.pool_stake_vault.to_account_info(),
+            authority: ctx.accounts.vault_authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::transfer_checked(cpi_ctx, total, ctx.accounts.stake_token_mint.decimals)?;
+
+        // Any transfer fee comes out of the new position
+        ctx.accounts.pool_stake_vault.reload()?;
+        let received = ctx.accounts.pool_stake_vault.amount.checked_sub(stake_vault_before)
+            .ok_or(StakingError::Underflow)?;
+
+        let user_stake = &mut ctx.accounts.user_stake;
+        user_stake.pending_rewards = user_stake.pending_rewards.checked_sub(rewards as u128)
+            .ok_or(StakingError::Underflow)?;
+        let pool = &mut ctx.accounts.pool;
+        pool.total_rewards_distributed = pool.total_rewards_distributed.checked_add(total)
+            .ok_or(StakingError::Overflow)?;
+        // The bonus never went through the accumulator, so it is emitted here
+        pool.total_rewards_emitted = pool.total_rewards_emitted.saturating_add(bonus);
+
+        msg!(
+            "Reinvested {} reward tokens from position {} with a {} bonus",
+            rewards,
+            position_id,
+            bonus
+        );
+        let user = ctx.accounts.user.key();
+        credit_stake(
+            &mut ctx.accounts.pool,
+            &mut ctx.accounts.new_user_stake,
+            user,
+            user,
+            new_position_id,
+            ctx.bumps.new_user_stake,
+            received,
+            lock_period,
+        )
+    }
+
+    /// Slash part of a position's stake into an authority-chosen account (authority only)
+    /// Rewards owed on the slashed share are forfeited; the rest stay pending
+    pub fn slash(ctx: Context<Slash>, amount: u64) -> Result<()> {
+        require!(amount > 0, StakingError::InvalidAmount);
+
+        let user_stake = &mut ctx.accounts.user_stake;
+        require!(amount <= user_stake.amount, StakingError::SlashExceedsStake);
+
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.pool;
+        update_pool(pool, clock.unix_timestamp)?;
+
+        let previous_weight = user_stake.weight(pool)?;
+        settle_user_rewards(pool, user_stake)?;
+        let owed = user_stake.pending_rewards;
+        let forfeited = owed
+            .checked_mul(amount as u128)
+            .ok_or(StakingError::Overflow)?
+            .checked_div(user_stake.amount as u128)
+            .ok_or(StakingError::DivisionByZero)?;
+        user_stake.pending_rewards = owed.checked_sub(forfeited)
+            .ok_or(StakingError::Underflow)?;
+
+        // Move the slashed tokens out of the stake vault
+        let pool_key = ctx.accounts.pool.key();
+        let seeds = &[
+            b"vault_authority",
+            pool_key.as_ref(),
+            &[ctx.accounts.pool.vault_authority_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.pool_stake_vault.to_account_info(),
+            mint: ctx.accounts.stake_token_mint.to_account_info(),
+            to: ctx.accounts.slash_destination.to_account_info(),
+            authority: ctx.accounts.vault_authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.stake_token_mint.decimals)?;
+
+        user_stake.amount = user_stake.amount.checked_sub(amount)
+            .ok_or(StakingError::Underflow)?;
+
+        let pool = &mut ctx.accounts.pool;
+        sync_user_weight(pool, user_stake, previous_weight)?;
+        pool.total_staked = pool.total_staked.checked_sub(amount)
+            .ok_or(StakingError::Underflow)?;
+
+        msg!(
+            "Slashed {} tokens from position {} of {}, forfeited {} rewards. Remaining: {}",
+            amount,
+            user_stake.position_id,
+            user_stake.user,
+            forfeited,
+            user_stake.amount
+        );
+        Ok(())
+    }
+
+    /// Return a position's principal to its owner during a wind-down (authority only)
+    /// Rewards are settled and stay pending so the owner can still claim them
+    pub fn admin_return_stake(ctx: Context<AdminReturnStake>) -> Result<()> {
+        let user_stake = &mut ctx.accounts.user_stake;
+        let amount = user_stake.amount;
+        require!(amount > 0, StakingError::UserStakeAlreadyEmpty);
+
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.pool;
+        update_pool(pool, clock.unix_timestamp)?;
+        let previous_weight = user_stake.weight(pool)?;
+        settle_user_rewards(pool, user_stake)?;
+
+        // Transfer stake tokens back to the position owner
+        let pool_key = ctx.accounts.pool.key();
+        let seeds = &[
+            b"vault_authority",
+            pool_key.as_ref(),
+            &[ctx.accounts.pool.vault_authority_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.pool_stake_vault.to_account_info(),
+            mint: ctx.accounts.stake_token_mint.to_account_info(),
+            to: ctx.accounts.user_stake_token.to_account_info(),
+            authority: ctx.accounts.vault_authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.stake_token_mint.decimals)?;
+
+        user_stake.amount = 0;
+
+        let pool = &mut ctx.accounts.pool;
+        sync_user_weight(pool, user_stake, previous_weight)?;
+        pool.total_staked = pool.total_staked.checked_sub(amount)
+            .ok_or(StakingError::Underflow)?;
+
+        msg!(
+            "Authority returned {} tokens from position {} to {}, {} rewards remain claimable",
+            amount,
+            user_stake.position_id,
+            user_stake.user,
+            user_stake.pending_rewards
+        );
+        Ok(())
+    }
+
+    /// Advance the pool's reward accumulator to now (permissionless)
+    /// Keepers can call this during quiet periods so acc_reward_per_share stays fresh
+    pub fn poke(ctx: Context<Poke>) -> Result<()> {
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.pool;
+        update_pool(pool, clock.unix_timestamp)?;
+
+        msg!("Pool accumulator at {} as of {}", pool.acc_reward_per_share, pool.last_update_time);
+        Ok(())
+    }
+
+    /// Fail with PoolStale unless the accumulator was advanced within `max_staleness` seconds
+    /// Read-only and permissionless; transactions that read the pool's stored accounting put
+    /// it first, after a `poke` when the pool has been idle
+    pub fn assert_fresh(ctx: Context<AssertFresh>, max_staleness: i64) -> Result<()> {
+        let clock = Clock::get()?;
+        let age = clock.unix_timestamp.saturating_sub(ctx.accounts.pool.last_update_time);
+        require!(age <= max_staleness, StakingError::PoolStale);
+        Ok(())
+    }
+
+    /// Append the pool's current reward state to its snapshot ring buffer (permissionless)
+    /// The accumulator is advanced first; once the buffer is full the oldest entry is overwritten.
+    /// The caller pays rent for the buffer the first time it is written
+    pub fn snapshot(ctx: Context<Snapshot>) -> Result<()> {
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.pool;
+        require!(pool.snapshot_capacity > 0, StakingError::SnapshotsDisabled);
+        update_pool(pool, clock.unix_timestamp)?;
+
+        let entry = RewardSnapshot {
+            total_staked: pool.total_staked,
+            acc_reward_per_share: pool.acc_reward_per_share,
+            timestamp: pool.last_update_time,
+        };
+        record_snapshot(
+            &mut ctx.accounts.snapshots,
+            pool.key(),
+            pool.snapshot_capacity,
+            ctx.bumps.snapshots,
+            entry,
+        )?;
+
+        msg!("Snapshot {} recorded at {}", ctx.accounts.snapshots.total_recorded, pool.last_update_time);
+        Ok(())
+    }
+
+    /// Settle rewards for many positions at once without moving tokens (permissionless)
+    /// Positions are passed as writable remaining accounts; ones from other pools are skipped
+    pub fn settle_many<'info>(ctx: Context<'_, '_, 'info, 'info, SettleMany<'info>>) -> Result<()> {
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.pool;
+        update_pool(pool, clock.unix_timestamp)?;
+
+        let mut settled: u32 = 0;
+        for account_info in ctx.remaining_accounts.iter() {
+            let mut user_stake = Account::<UserStake>::try_from(account_info)?;
+            if user_stake.pool != pool.key() {
+                msg!("Skipping position {}: belongs to pool {}", account_info.key(), user_stake.pool);
+                continue;
+            }
+            settle_user_rewards(pool, &mut user_stake)?;
+            user_stake.exit(&crate::ID)?;
+            settled += 1;
+        }
+
+        msg!("Settled {} of {} positions", settled, ctx.remaining_accounts.len());
+        Ok(())
+    }
+
+    /// Read-only: the pool's reward-per-share accumulator at the current clock,
+    /// still scaled by ACC_REWARD_PRECISION, for checking accrual off-chain
+    pub fn rewards_per_token_stored(ctx: Context<RewardsPerTokenStored>) -> Result<u128> {
+        let clock = Clock::get()?;
+        let mut pool = (*ctx.accounts.pool).clone();
+        update_pool(&mut pool, clock.unix_timestamp)?;
+        Ok(pool.acc_reward_per_share)
+    }
+
+    /// Read-only: settled plus accrued rewards for a position at the current clock
+    /// Returned through return data so clients can simulate it instead of re-deriving the math
+    pub fn get_pending_rewards(ctx: Context<GetPendingRewards>) -> Result<u128> {
+        let clock = Clock::get()?;
+
+        // Advance and settle copies so nothing is written
+        let mut pool = (*ctx.accounts.pool).clone();
+        let mut user_stake = (*ctx.accounts.user_stake).clone();
+        update_pool(&mut pool, clock.unix_timestamp)?;
+        settle_user_rewards(&mut pool, &mut user_stake)?;
+
+        Ok(user_stake.pending_rewards)
+    }
+
+    /// Read-only: exactly what claim_rewards would transfer to the user right now,
+    /// after the reward window, halving, multiplier, budget cap, vault balance and protocol fee
+    pub fn quote_rewards(ctx: Context<QuoteRewards>) -> Result<u64> {
+        let clock = Clock::get()?;
+
+        let mut pool = (*ctx.accounts.pool).clone();
+        let mut user_stake = (*ctx.accounts.user_stake).clone();
+        update_pool(&mut pool, clock.unix_timestamp)?;
+        settle_user_rewards(&mut pool, &mut user_stake)?;
+
+        let payout = capped_payout(&pool, user_stake.pending_rewards, ctx.accounts.pool_reward_vault.amount);
+        let fee = bps_of(payout, ctx.accounts.config.fee_bps)?;
+        let quote = payout.checked_sub(fee)
+            .ok_or(StakingError::Underflow)?;
+
+        Ok(quote)
+    }
+
+    /// Read-only: the early-unstake penalty unstake would charge on `amount` right now,
+    /// 0 once min_stake_duration has passed or in emergency mode. Fails like unstake when
+    /// early exits are disabled
+    pub fn estimate_penalty(ctx: Context<EstimatePenalty>, amount: u64) -> Result<u64> {
+        require!(amount > 0, StakingError::InvalidAmount);
+        require!(ctx.accounts.user_stake.amount >= amount, StakingError::InsufficientStake);
+        let clock = Clock::get()?;
+
+        let withdrawn = stake_vault_share(&ctx.accounts.pool, ctx.accounts.pool_stake_vault.amount, amount)?;
+        early_unstake_penalty(&ctx.accounts.pool, &ctx.accounts.user_stake, withdrawn, clock.unix_timestamp)
+    }
+
+    /// Read-only: headline pool figures for dashboards in one simulatable call
+    pub fn pool_stats(ctx: Context<PoolStatsView>) -> Result<PoolStats> {
+        let clock = Clock::get()?;
+        let pool = &ctx.accounts.pool;
+
+        Ok(PoolStats {
+            total_staked: pool.total_staked,
+            reward_rate: pool.reward_rate,
+            total_rewards_distributed: pool.total_rewards_distributed,
+            reward_vault_balance: ctx.accounts.pool_reward_vault.amount,
+            seconds_remaining: pool.reward_end_time.saturating_sub(clock.unix_timestamp).max(0),
+            last_update_time: pool.last_update_time,
+        })
+    }
+
+    /// Read-only: the reward mint's decimals and supply, so clients can format reward amounts
+    /// without fetching the mint separately
+    pub fn reward_token_info(ctx: Context<RewardTokenInfoView>) -> Result<RewardTokenInfo> {
+        let mint = &ctx.accounts.reward_token_mint;
+
+        Ok(RewardTokenInfo {
+            mint: mint.key(),
+            decimals: mint.decimals,
+            supply: mint.supply,
+        })
+    }
+
+    /// Read-only: the pool's current yearly rate in bps of reward base units per stake base unit.
+    /// Emission pools split reward_rate over total_staked, so the rate falls as stake grows and
+    /// is 0 with nothing staked; per-token and apy_bps pools pay the same rate at any stake.
+    /// Paused or ended pools return 0
+    pub fn current_apr_bps(ctx: Context<CurrentAprView>) -> Result<u64> {
+        let clock = Clock::get()?;
+        let pool = &ctx.accounts.pool;
+
+        if pool.rewards_paused || clock.unix_timestamp >= pool.reward_end_time {
+            return Ok(0);
+        }
+        if pool.apy_bps > 0 {
+            return Ok(pool.apy_bps as u64);
+        }
+
+        let yearly = (pool.reward_rate as u128)
+            .checked_mul(SECONDS_PER_YEAR as u128)
+            .and_then(|v| v.checked_mul(BPS_DENOMINATOR as u128))
+            .ok_or(StakingError::Overflow)?;
+        let apr = if pool.fixed_emission {
+            if pool.total_staked == 0 {
+                return Ok(0);
+            }
+            yearly / pool.total_staked as u128
+        } else {
+            let (factor, scale) = per_token_scale(pool)?;
+            mul_div(yearly, factor, scale)?
+        };
+        Ok(u64::try_from(apr).unwrap_or(u64::MAX))
+    }
+
+    /// Read-only: rewards a new position of `amount` locked for `duration` seconds would
+    /// accrue over that lock, assuming no other stake or rate changes in the meantime.
+    /// Runs the same accrual and settlement as a real stake against copies of the state
+    pub fn preview_stake(ctx: Context<PreviewStake>, amount: u64, duration: i64) -> Result<u64> {
+        require!(amount > 0, StakingError::InvalidAmount);
+        require!(duration >= 0, StakingError::InvalidLockPeriod);
+        let clock = Clock::get()?;
+
+        let mut pool = (*ctx.accounts.pool).clone();
+        update_pool(&mut pool, clock.unix_timestamp)?;
+
+        let mut user_stake = UserStake {
+            user: Pubkey::default(),
+            pool: ctx.accounts.pool.key(),
+            position_id: 0,
+            amount,
+            last_stake_time: clock.unix_timestamp,
+            first_stake_time: clock.unix_timestamp,
+            last_claim_time: 0,
+            pending_rewards: 0,
+            reward_debt: 0,
+            reward_remainder: 0,
+            unbonding_amount: 0,
+            unbonding_start_time: 0,
+            lock_end: clock.unix_timestamp.checked_add(duration)
+                .ok_or(StakingError::Overflow)?,
+            reward_multiplier_bps: pool.lock_multiplier_bps(duration),
+            bump: 0,
+            version: USER_STAKE_VERSION,
+            last_settle_time: clock.unix_timestamp,
+            pending_rewards_2: 0,
+            reward_debt_2: 0,
+            reward_remainder_2: 0,
+            frozen: false,
+            auto_compound: false,
+            claim_count: 0,
+            warmup_start: clock.unix_timestamp,
+            locked_duration: Some(pool.min_stake_duration),
+        };
+        pool.total_staked = pool.total_staked.checked_add(amount)
+            .ok_or(StakingError::Overflow)?;
+        sync_user_weight(&mut pool, &mut user_stake, 0)?;
+
+        update_pool(&mut pool, user_stake.lock_end)?;
+        settle_user_rewards(&mut pool, &mut user_stake)?;
+
+        Ok(u64::try_from(user_stake.pending_rewards).unwrap_or(u64::MAX))
+    }
+
+    /// Change the pool's emission rate (authority only)
+    /// Accrual up to now is settled at the old rate before the new one applies.
+    /// With halving enabled this replaces the undecayed base rate of the schedule.
+    /// Curved pools keep the rate they were created with.
+    pub fn update_reward_rate(ctx: Context<UpdateRewardRate>, new_rate: u64) -> Result<()> {
+        require!(new_rate <= MAX_REWARD_RATE, StakingError::RewardRateTooHigh);
+        // A curve is shaped over its whole window, so a new rate would re-shape what already emitted
+        require!(ctx.accounts.pool.curve_type == CURVE_LINEAR, StakingError::CurveScheduleFixed);
+
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.pool;
+        update_pool(pool, clock.unix_timestamp)?;
+
+        let old_rate = pool.reward_rate;
+        pool.reward_rate = new_rate;
+        pool.initial_reward_rate = new_rate;
+        // An explicit rate replaces any yield the pool was created with
+        pool.apy_bps = 0;
+
+        msg!("Reward rate updated from {} to {} per second", old_rate, new_rate);
+        Ok(())
+    }
+
+    /// Change min_stake_duration for future stakes (authority only)
+    /// Existing positions keep the duration they staked under until they stake again
+    pub fn update_min_duration(ctx: Context<UpdateMinDuration>, new_duration: i64) -> Result<()> {
+        require!(new_duration >= 0, StakingError::InvalidDuration);
+        let pool = &mut ctx.accounts.pool;
+        let old_duration = pool.min_stake_duration;
+        pool.min_stake_duration = new_duration;
+
+        msg!("Minimum stake duration updated from {} to {} seconds", old_duration, new_duration);
+        Ok(())
+    }
+
+    /// Grow a position created before the `version` field to the current layout
+    /// Permissionless: the payer covers the extra rent and no stored value changes
+    pub fn migrate_user_stake(ctx: Context<MigrateUserStake>) -> Result<()> {
+        let info = ctx.accounts.user_stake.to_account_info();
+        require_keys_eq!(*info.owner, crate::ID, ErrorCode::AccountOwnedByWrongProgram);
+        require!(
+            info.try_borrow_data()?.starts_with(UserStake::DISCRIMINATOR),
+            ErrorCode::AccountDiscriminatorMismatch
+        );
+
+        // Top up rent first, then grow the account with the appended fields zero-filled
+        let new_len = 8 + UserStake::INIT_SPACE;
+        if info.data_len() < new_len {
+            let shortfall = Rent::get()?.minimum_balance(new_len).saturating_sub(info.lamports());
+            if shortfall > 0 {
+                let cpi_accounts = system_program::Transfer {
+                    from: ctx.accounts.payer.to_account_info(),
+                    to: info.clone(),
+                };
+                let cpi_ctx = CpiContext::new(ctx.accounts.system_program.to_account_info(), cpi_accounts);
+                system_program::transfer(cpi_ctx, shortfall)?;
+            }
+            info.realloc(new_len, true)?;
+        }
+
+        let mut data = info.try_borrow_mut_data()?;
+        let mut user_stake = UserStake::try_deserialize(&mut &data[..])?;
+        require!(user_stake.version < USER_STAKE_VERSION, StakingError::UserStakeAlreadyMigrated);
+        user_stake.version = USER_STAKE_VERSION;
+        user_stake.try_serialize(&mut &mut data[..])?;
+
+        msg!("Migrated position {} to layout version {}", info.key(), USER_STAKE_VERSION);
+        Ok(())
+    }
+
+    /// Close a fully exited stake account and return its rent to the user
+    pub fn close_user_stake(ctx: Context<CloseUserStake>, position_id: u64) -> Result<()> {
+        let user_stake = &ctx.accounts.user_stake;
+        require!(
+            user_stake.amount == 0
+                && user_stake.pending_rewards == 0
+                && user_stake.pending_rewards_2 == 0
+                && user_stake.unbonding_amount == 0,
+            StakingError::StakeNotEmpty
+        );
+
+        msg!("Closed position {} for {}", position_id, user_stake.user);
+        Ok(())
+    }
+
+    /// Replace the pool's name and metadata URI (authority only)
+    pub fn set_pool_metadata(
+        ctx: Context<SetPoolMetadata>,
+        name: [u8; POOL_NAME_LEN],
+        metadata_uri: [u8; POOL_METADATA_URI_LEN],
+    ) -> Result<()> {
+        validate_pool_metadata(&name, &metadata_uri)?;
+        let pool = &mut ctx.accounts.pool;
+        pool.name = name;
+        pool.metadata_uri = metadata_uri;
+
+        msg!("Updated metadata for pool {}", pool.key());
+        Ok(())
+    }
+
+    /// Configure the governance-token boost on claims (authority only)
+    /// - boost_per_token_bps: Extra reward per whole boost token held (100 = +1%)
+    /// - max_boost_bps: Cap on the boost however much is held
+    /// Passing no boost_mint turns the boost off. Boosts are paid from the reward vault
+    /// on top of emission, so they draw on its excess and count against reward_budget
+    pub fn set_boost_config(
+        ctx: Context<SetBoostConfig>,
+        boost_per_token_bps: u16,
+        max_boost_bps: u16,
+    ) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        match &ctx.accounts.boost_mint {
+            Some(boost_mint) => {
+                pool.boost_mint = Some(boost_mint.key());
+                pool.boost_decimals = boost_mint.decimals;
+            }
+            None => {
+                pool.boost_mint = None;
+                pool.boost_decimals = 0;
+            }
+        }
+        pool.boost_per_token_bps = boost_per_token_bps;
+        pool.max_boost_bps = max_boost_bps;
+
+        msg!(
+            "Boost set to {} bps per token, capped at {} bps",
+            boost_per_token_bps,
+            max_boost_bps
+        );
+        Ok(())
+    }
+
+    /// Make `child_pool` a child of `parent_pool` (both authorities)
+    /// The child's claims are then paid from the parent's reward vault and count against the
+    /// parent's budget; the child keeps its own stake and emission schedule. Only claim_rewards
+    /// draws on the parent, other reward paths keep using the child's own vault.
+    /// Hierarchies are one level deep and a link is permanent
+    pub fn link_child_pool(ctx: Context<LinkChildPool>) -> Result<()> {
+        let parent_key = ctx.accounts.parent_pool.key();
+        ctx.accounts.child_pool.parent_pool = Some(parent_key);
+
+        msg!("Pool {} linked under parent {}", ctx.accounts.child_pool.key(), parent_key);
+        Ok(())
+    }
+
+    /// Propose a new pool authority; it takes over only once it calls accept_authority
+    pub fn propose_authority(ctx: Context<ProposeAuthority>, new_authority: Pubkey) -> Result<()> {
+        ctx.accounts.pool.pending_authority = Some(new_authority);
+
+        msg!("Proposed new pool authority: {}", new_authority);
+        Ok(())
+    }
+
+    /// Withdraw a pending authority proposal before it is accepted (authority only)
+    pub fn cancel_authority_transfer(ctx: Context<ProposeAuthority>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        let pending_authority = pool.pending_authority.take().ok_or(StakingError::NoPendingAuthority)?;
+
+        msg!("Cancelled authority transfer to {}", pending_authority);
+        Ok(())
+    }
+
+    /// Accept a pending authority proposal (pending authority only)
+    pub fn accept_authority(ctx: Context<AcceptAuthority>) -> Result<()> {
+        let pool = &mut ctx.accounts.pool;
+        let pending_authority = pool.pending_authority.ok_or(StakingError::NoPendingAuthority)?;
+        require_keys_eq!(
+            pending_authority,
+            ctx.accounts.new_authority.key(),
+            StakingError::UnauthorizedAuthority
+        );
+
+        let old_authority = pool.authority;
+        pool.authority = pending_authority;
+        pool.pending_authority = None;
+
+        msg!("Pool authority transferred from {} to {}", old_authority, pending_authority);
+        Ok(())
+    }
+
+    /// Pause or resume new deposits (authority only)
+    /// Unstaking and claiming stay available so users are never trapped
+    pub fn set_pause(ctx: Context<SetPause>, paused: bool) -> Result<()> {
+        ctx.accounts.pool.paused = paused;
+
+        msg!("Pool paused: {}", paused);
+        Ok(())
+    }
+
+    /// Stop or resume reward emission (authority only)
+    /// Staking, unstaking and claiming what has already accrued stay available
+    pub fn set_rewards_pause(ctx: Context<SetPause>, paused: bool) -> Result<()> {
+        // Credit emission up to the switch so the paused window is the only one skipped
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.pool;
+        update_pool(pool, clock.unix_timestamp)?;
+        if paused && !pool.rewards_paused {
+            pool.rewards_paused_at = clock.unix_timestamp;
+        }
+        pool.rewards_paused = paused;
+
+        msg!("Rewards paused: {}", paused);
+        Ok(())
+    }
+
+    /// Turn pool-wide emergency mode on or off (authority only)
+    /// While it is on, every position can unstake at once: lock periods, min_stake_duration
+    /// and early-exit penalties are all waived. Rewards keep accruing and can still be claimed
+    pub fn set_emergency_mode(ctx: Context<SetPause>, enabled: bool) -> Result<()> {
+        ctx.accounts.pool.emergency_mode = enabled;
+
+        msg!("Emergency mode: {}", enabled);
+        Ok(())
+    }
+
+    /// Freeze or unfreeze a single position (authority only)
+    /// A frozen position can't unstake, exit, claim, compound, reinvest, restake, upgrade its
+    /// lock, split or be transferred, failing with UserFrozen; top-ups still work. It keeps accruing unless the
+    /// pool has freeze_stops_accrual, which takes its weight out of the pool while frozen
+    pub fn set_user_frozen(ctx: Context<SetUserFrozen>, frozen: bool) -> Result<()> {
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.pool;
+        let user_stake = &mut ctx.accounts.user_stake;
+        update_pool(pool, clock.unix_timestamp)?;
+
+        // Settle at the weight the position had before the switch
+        let previous_weight = user_stake.weight(pool)?;
+        settle_user_rewards(pool, user_stake)?;
+        user_stake.frozen = frozen;
+        sync_user_weight(pool, user_stake, previous_weight)?;
+
+        msg!("Position {} of {} frozen: {}", user_stake.position_id, user_stake.user, frozen);
+        Ok(())
+    }
+
+    /// Fund the reward vault
+    /// Open to anyone unless the pool restricts funding to its authority
+    pub fn fund_rewards(ctx: Context<FundRewards>, amount: u64) -> Result<()> {
+        require!(amount > 0, StakingError::InvalidAmount);
+
+        let vault_balance_before = ctx.accounts.pool_reward_vault.amount;
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.funder_token_account.to_account_info(),
+            mint: ctx.accounts.reward_token_mint.to_account_info(),
+            to: ctx.accounts.pool_reward_vault.to_account_info(),
+            authority: ctx.accounts.funder.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.reward_token_mint.decimals)?;
+
+        // Record what actually arrived so inflows reconcile against the vault
+        ctx.accounts.pool_reward_vault.reload()?;
+        let received = ctx.accounts.pool_reward_vault.amount.checked_sub(vault_balance_before)
+            .ok_or(StakingError::Underflow)?;
+        let pool = &mut ctx.accounts.pool;
+        pool.total_rewards_funded = pool.total_rewards_funded.checked_add(received)
+            .ok_or(StakingError::Overflow)?;
+
+        // Every funder gets an on-chain record of what they put in
+        let timestamp = Clock::get()?.unix_timestamp;
+        record_funding(
+            &mut ctx.accounts.funder_record,
+            pool.key(),
+            ctx.accounts.funder.key(),
+            ctx.bumps.funder_record,
+            received,
+            timestamp,
+        )?;
+
+        emit!(RewardsFundedEvent {
+            pool: pool.key(),
+            funder: ctx.accounts.funder.key(),
+            reward_mint: pool.reward_token_mint,
+            amount: received,
+            vault_balance: ctx.accounts.pool_reward_vault.amount,
+            total_rewards_funded: pool.total_rewards_funded,
+            timestamp,
+        });
+
+        msg!("Funded reward vault with {} tokens", received);
+        Ok(())
+    }
+
+    /// Fund the reward vault with a deposit that vests to stakers linearly over `stream_duration`
+    /// seconds, on top of reward_rate. A pool streams one deposit at a time: whatever is still
+    /// unvested is folded into the new deposit and restarts with it. Vesting is paid out like
+    /// emission, so it is skipped while nobody is staked or rewards are paused, and it counts
+    /// toward reward_budget
+    pub fn fund_rewards_streamed(ctx: Context<FundRewards>, amount: u64, stream_duration: i64) -> Result<()> {
+        require!(amount > 0, StakingError::InvalidAmount);
+        require!(stream_duration > 0, StakingError::InvalidStreamDuration);
+
+        // Vest the current stream up to now before it is replaced
+        let clock = Clock::get()?;
+        update_pool(&mut ctx.accounts.pool, clock.unix_timestamp)?;
+
+        let vault_balance_before = ctx.accounts.pool_reward_vault.amount;
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.funder_token_account.to_account_info(),
+            mint: ctx.accounts.reward_token_mint.to_account_info(),
+            to: ctx.accounts.pool_reward_vault.to_account_info(),
+            authority: ctx.accounts.funder.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.reward_token_mint.decimals)?;
+
+        ctx.accounts.pool_reward_vault.reload()?;
+        let received = ctx.accounts.pool_reward_vault.amount.checked_sub(vault_balance_before)
+            .ok_or(StakingError::Underflow)?;
+        let pool = &mut ctx.accounts.pool;
+        pool.total_rewards_funded = pool.total_rewards_funded.checked_add(received)
+            .ok_or(StakingError::Overflow)?;
+        pool.reward_stream = RewardStream {
+            amount: pool.reward_stream.unvested().checked_add(received)
+                .ok_or(StakingError::Overflow)?,
+            start_time: clock.unix_timestamp,
+            end_time: clock.unix_timestamp.checked_add(stream_duration)
+                .ok_or(StakingError::Overflow)?,
+            vested: 0,
+        };
+
+        record_funding(
+            &mut ctx.accounts.funder_record,
+            pool.key(),
+            ctx.accounts.funder.key(),
+            ctx.bumps.funder_record,
+            received,
+            clock.unix_timestamp,
+        )?;
+
+        emit!(RewardsFundedEvent {
+            pool: pool.key(),
+            funder: ctx.accounts.funder.key(),
+            reward_mint: pool.reward_token_mint,
+            amount: received,
+            vault_balance: ctx.accounts.pool_reward_vault.amount,
+            total_rewards_funded: pool.total_rewards_funded,
+            timestamp: clock.unix_timestamp,
+        });
+
+        msg!(
+            "Streaming {} reward tokens until {}",
+            pool.reward_stream.amount,
+            pool.reward_stream.end_time
+        );
+        Ok(())
+    }
+
+    /// Fund the reward vault from a token account owned by one of the pool's escrow PDAs
+    /// (permissionless). The escrow authority is `[b"reward_escrow", pool, seed]` with `bump`,
+    /// so automated emission contracts can top up the vault without a wallet signature.
+    /// Escrowed tokens can only ever move into their own pool's reward vault
+    pub fn fund_rewards_from_pda(
+        ctx: Context<FundRewardsFromPda>,
+        amount: u64,
+        seed: Vec<u8>,
+        bump: u8,
+    ) -> Result<()> {
+        require!(amount > 0, StakingError::InvalidAmount);
+
+        let pool_key = ctx.accounts.pool.key();
+        let bump_seed = [bump];
+        let seeds = &[b"reward_escrow".as_ref(), pool_key.as_ref(), seed.as_ref(), &bump_seed];
+        let escrow_authority = Pubkey::create_program_address(seeds, ctx.program_id)
+            .map_err(|_| StakingError::InvalidEscrowAuthority)?;
+        require_keys_eq!(
+            escrow_authority,
+            ctx.accounts.escrow_authority.key(),
+            StakingError::InvalidEscrowAuthority
+        );
+        let signer = &[&seeds[..]];
+
+        let vault_balance_before = ctx.accounts.pool_reward_vault.amount;
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.escrow_token.to_account_info(),
+            mint: ctx.accounts.reward_token_mint.to_account_info(),
+            to: ctx.accounts.pool_reward_vault.to_account_info(),
+            authority: ctx.accounts.escrow_authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.reward_token_mint.decimals)?;
+
+        ctx.accounts.pool_reward_vault.reload()?;
+        let received = ctx.accounts.pool_reward_vault.amount.checked_sub(vault_balance_before)
+            .ok_or(StakingError::Underflow)?;
+        let pool = &mut ctx.accounts.pool;
+        pool.total_rewards_funded = pool.total_rewards_funded.checked_add(received)
+            .ok_or(StakingError::Overflow)?;
+
+        // The escrow is the funder of record
+        let timestamp = Clock::get()?.unix_timestamp;
+        record_funding(
+            &mut ctx.accounts.funder_record,
+            pool.key(),
+            escrow_authority,
+            ctx.bumps.funder_record,
+            received,
+            timestamp,
+        )?;
+
+        emit!(RewardsFundedEvent {
+            pool: pool.key(),
+            funder: escrow_authority,
+            reward_mint: pool.reward_token_mint,
+            amount: received,
+            vault_balance: ctx.accounts.pool_reward_vault.amount,
+            total_rewards_funded: pool.total_rewards_funded,
+            timestamp,
+        });
+
+        msg!("Funded reward vault with {} tokens from escrow {}", received, escrow_authority);
+        Ok(())
+    }
+
+    /// Add a second reward token emitted alongside the first (authority only)
+    /// - reward_rate_2: Second reward tokens emitted per second, split over the same weighted
+    ///   stake and reward window as the first
+    /// It accrues from this call on and ignores apy_bps, halving, curves, reward_budget,
+    /// reward_warmup and boosts. Its mint must use the pool's token program
+    pub fn initialize_second_reward(ctx: Context<InitializeSecondReward>, reward_rate_2: u64) -> Result<()> {
+        require!(reward_rate_2 <= MAX_REWARD_RATE, StakingError::RewardRateTooHigh);
+
+        let clock = Clock::get()?;
+        let pool = &mut ctx.accounts.pool;
+        // Settle up to now so the second token has nothing to back-fill
+        update_pool(pool, clock.unix_timestamp)?;
+        pool.reward_token_mint_2 = Some(ctx.accounts.reward_token_mint_2.key());
+        pool.reward_decimals_2 = ctx.accounts.reward_token_mint_2.decimals;
+        pool.reward_rate_2 = reward_rate_2;
+
+        msg!(
+            "Second reward {} added at {} per second",
+            ctx.accounts.reward_token_mint_2.key(),
+            reward_rate_2
+        );
+        Ok(())
+    }
+
+    /// Deposit second reward tokens into the pool's second reward vault
+    /// Follows restrict_funders like fund_rewards
+    pub fn fund_second_reward(ctx: Context<FundSecondReward>, amount: u64) -> Result<()> {
+        require!(amount > 0, StakingError::InvalidAmount);
+
+        let vault_balance_before = ctx.accounts.pool_reward_vault_2.amount;
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.funder_token_account.to_account_info(),
+            mint: ctx.accounts.reward_token_mint_2.to_account_info(),
+            to: ctx.accounts.pool_reward_vault_2.to_account_info(),
+            authority: ctx.accounts.funder.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.reward_token_mint_2.decimals)?;
+
+        // Record what actually arrived so inflows reconcile against the vault
+        ctx.accounts.pool_reward_vault_2.reload()?;
+        let received = ctx.accounts.pool_reward_vault_2.amount.checked_sub(vault_balance_before)
+            .ok_or(StakingError::Underflow)?;
+        let pool = &mut ctx.accounts.pool;
+        pool.total_rewards_funded_2 = pool.total_rewards_funded_2.checked_add(received)
+            .ok_or(StakingError::Overflow)?;
+
+        // Second reward deposits are tallied in their own record per funder
+        let timestamp = Clock::get()?.unix_timestamp;
+        record_funding(
+            &mut ctx.accounts.funder_record,
+            pool.key(),
+            ctx.accounts.funder.key(),
+            ctx.bumps.funder_record,
+            received,
+            timestamp,
+        )?;
+
+        emit!(RewardsFundedEvent {
+            pool: pool.key(),
+            funder: ctx.accounts.funder.key(),
+            reward_mint: ctx.accounts.reward_token_mint_2.key(),
+            amount: received,
+            vault_balance: ctx.accounts.pool_reward_vault_2.amount,
+            total_rewards_funded: pool.total_rewards_funded_2,
+            timestamp,
+        });
+
+        msg!("Funded second reward vault with {} tokens", received);
+        Ok(())
+    }
+
+    /// Fund the reward vault and move reward_end_time later in one step (authority only)
+    /// Emission up to now is settled first, so a window that already closed resumes from
+    /// this call rather than back-filling the gap. Curved pools keep the window they were created with
+    pub fn extend_campaign(ctx: Context<ExtendCampaign>, amount: u64, new_end_time: i64) -> Result<()> {
+        require!(amount > 0, StakingError::InvalidAmount);
+        // Moving the end of a curve's window would re-shape what it already emitted
+        require!(ctx.accounts.pool.curve_type == CURVE_LINEAR, StakingError::CurveScheduleFixed);
+        require!(
+            new_end_time > ctx.accounts.pool.reward_end_time,
+            StakingError::InvalidEndTimeExtension
+        );
+
+        let clock = Clock::get()?;
+        update_pool(&mut ctx.accounts.pool, clock.unix_timestamp)?;
+
+        let vault_balance_before = ctx.accounts.pool_reward_vault.amount;
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.authority_reward_token.to_account_info(),
+            mint: ctx.accounts.reward_token_mint.to_account_info(),
+            to: ctx.accounts.pool_reward_vault.to_account_info(),
+            authority: ctx.accounts.authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new(cpi_program, cpi_accounts);
+        token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.reward_token_mint.decimals)?;
+
+        ctx.accounts.pool_reward_vault.reload()?;
+        let received = ctx.accounts.pool_reward_vault.amount.checked_sub(vault_balance_before)
+            .ok_or(StakingError::Underflow)?;
+        let pool = &mut ctx.accounts.pool;
+        pool.total_rewards_funded = pool.total_rewards_funded.checked_add(received)
+            .ok_or(StakingError::Overflow)?;
+        let old_end_time = pool.reward_end_time;
+        pool.reward_end_time = new_end_time;
+
+        record_funding(
+            &mut ctx.accounts.funder_record,
+            pool.key(),
+            ctx.accounts.authority.key(),
+            ctx.bumps.funder_record,
+            received,
+            clock.unix_timestamp,
+        )?;
+
+        emit!(RewardsFundedEvent {
+            pool: pool.key(),
+            funder: ctx.accounts.authority.key(),
+            reward_mint: pool.reward_token_mint,
+            amount: received,
+            vault_balance: ctx.accounts.pool_reward_vault.amount,
+            total_rewards_funded: pool.total_rewards_funded,
+            timestamp: clock.unix_timestamp,
+        });
+
+        msg!(
+            "Funded reward vault with {} tokens and moved the reward window end from {} to {}",
+            received,
+            old_end_time,
+            new_end_time
+        );
+        Ok(())
+    }
+
+    /// Withdraw reward tokens the pool doesn't owe anyone (authority only)
+    /// The vault keeps every emitted-but-unpaid reward plus the pool's reward_reserve
+    pub fn withdraw_excess_rewards(ctx: Context<WithdrawExcessRewards>, amount: u64) -> Result<()> {
+        require!(amount > 0, StakingError::InvalidAmount);
+
+        // Credit emissions up to now so they count as owed
+        let clock = Clock::get()?;
+        update_pool(&mut ctx.accounts.pool, clock.unix_timestamp)?;
+
+        // Emitted minus distributed bounds what positions are owed from above,
+        // and a stream's unvested deposit is already promised to them
+        let pool = &ctx.accounts.pool;
+        let floor = pool.total_rewards_emitted
+            .saturating_sub(pool.total_rewards_distributed)
+            .saturating_add(pool.reward_reserve)
+            .saturating_add(pool.reward_stream.unvested());
+        let excess = ctx.accounts.pool_reward_vault.amount.saturating_sub(floor);
+        require!(amount <= excess, StakingError::ExcessWithdrawTooLarge);
+
+        let pool_key = pool.key();
+        let seeds = &[
+            b"vault_authority",
+            pool_key.as_ref(),
+            &[pool.vault_authority_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        let cpi_accounts = TransferChecked {
+            from: ctx.accounts.pool_reward_vault.to_account_info(),
+            mint: ctx.accounts.reward_token_mint.to_account_info(),
+            to: ctx.accounts.authority_reward_token.to_account_info(),
+            authority: ctx.accounts.vault_authority.to_account_info(),
+        };
+        let cpi_program = ctx.accounts.token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.reward_token_mint.decimals)?;
+
+        msg!("Withdrew {} excess reward tokens, {} still withdrawable", amount, excess - amount);
+        Ok(())
+    }
+
+    /// Sweep the reward vault once the final claim window has closed (authority only)
+    /// Only pools with a final_claim_grace can be swept, and only from reward_end_time plus
+    /// that grace; claims and compounds are refused from then on and exits forfeit what is
+    /// still pending. A parent pool's vault also pays its
+    /// children, so sweeping it ends their claims too
+    pub fn sweep_expired_rewards(ctx: Context<WithdrawExcessRewards>) -> Result<()> {
+        let clock = Clock::get()?;
+        let pool = &ctx.accounts.pool;
+        require!(
+            claim_window_closed(pool, clock.unix_timestamp),
+            StakingError::SweepTooEarly
+        );
+
+        let amount = ctx.accounts.pool_reward_vault.amount;
+        if amount > 0 {
+            let pool_key = pool.key();
+            let seeds = &[
+                b"vault_authority",
+                pool_key.as_ref(),
+                &[pool.vault_authority_bump],
+            ];
+            let signer = &[&seeds[..]];
+
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.pool_reward_vault.to_account_info(),
+                mint: ctx.accounts.reward_token_mint.to_account_info(),
+                to: ctx.accounts.authority_reward_token.to_account_info(),
+                authority: ctx.accounts.vault_authority.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token_interface::transfer_checked(cpi_ctx, amount, ctx.accounts.reward_token_mint.decimals)?;
+        }
+
+        msg!("Swept {} unclaimed reward tokens after the final claim window", amount);
+        Ok(())
+    }
+
+    /// Close an emptied pool (authority only)
+    /// Leftover vault balances go to the authority's token accounts and all rent is returned.
+    /// A pool with a second reward token also needs its vault, mint and a destination passed.
+    /// Rewards still owed to exited positions keep it open until the final claim window closes
+    pub fn close_pool(ctx: Context<ClosePool>) -> Result<()> {
+        let pool = &ctx.accounts.pool;
+        require!(
+            pool.total_staked == 0 && pool.total_unbonding == 0,
+            StakingError::PoolNotEmpty
+        );
+        // Emitted minus distributed is what positions may still claim, see withdraw_excess_rewards
+        require!(
+            pool.total_rewards_emitted <= pool.total_rewards_distributed
+                || claim_window_closed(pool, Clock::get()?.unix_timestamp),
+            StakingError::RewardsStillOwed
+        );
+
+        let pool_key = pool.key();
+        let seeds = &[
+            b"vault_authority",
+            pool_key.as_ref(),
+            &[pool.vault_authority_bump],
+        ];
+        let signer = &[&seeds[..]];
+
+        // Sweep whatever is left in the vaults
+        let stake_leftover = ctx.accounts.pool_stake_vault.amount;
+        if stake_leftover > 0 {
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.pool_stake_vault.to_account_info(),
+                mint: ctx.accounts.stake_token_mint.to_account_info(),
+                to: ctx.accounts.authority_stake_token.to_account_info(),
+                authority: ctx.accounts.vault_authority.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token_interface::transfer_checked(cpi_ctx, stake_leftover, ctx.accounts.stake_token_mint.decimals)?;
+        }
+
+        let reward_leftover = ctx.accounts.pool_reward_vault.amount;
+        if reward_leftover > 0 {
+            let cpi_accounts = TransferChecked {
+                from: ctx.accounts.pool_reward_vault.to_account_info(),
+                mint: ctx.accounts.reward_token_mint.to_account_info(),
+                to: ctx.accounts.authority_reward_token.to_account_info(),
+                authority: ctx.accounts.vault_authority.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token_interface::transfer_checked(cpi_ctx, reward_leftover, ctx.accounts.reward_token_mint.decimals)?;
+        }
+
+        let mut vaults = vec![
+            ctx.accounts.pool_stake_vault.to_account_info(),
+            ctx.accounts.pool_reward_vault.to_account_info(),
+        ];
+
+        // A second reward token has its own vault, swept and closed alongside
+        let mut reward_leftover_2 = 0;
+        if let Some(reward_mint_2) = pool.reward_token_mint_2 {
+            let (Some(pool_reward_vault_2), Some(authority_reward_token_2), Some(reward_token_mint_2)) = (
+                ctx.accounts.pool_reward_vault_2.as_ref(),
+                ctx.accounts.authority_reward_token_2.as_ref(),
+                ctx.accounts.reward_token_mint_2.as_ref(),
+            ) else {
+                return err!(StakingError::SecondRewardAccountsRequired);
+            };
+            let (expected_vault, _) = Pubkey::find_program_address(&[b"reward_vault_2", pool_key.as_ref()], ctx.program_id);
+            require!(
+                pool_reward_vault_2.key() == expected_vault
+                    && reward_token_mint_2.key() == reward_mint_2
+                    && authority_reward_token_2.mint == reward_mint_2
+                    && authority_reward_token_2.owner == ctx.accounts.authority.key(),
+                StakingError::InvalidSecondReward
+            );
+
+            reward_leftover_2 = pool_reward_vault_2.amount;
+            if reward_leftover_2 > 0 {
+                let cpi_accounts = TransferChecked {
+                    from: pool_reward_vault_2.to_account_info(),
+                    mint: reward_token_mint_2.to_account_info(),
+                    to: authority_reward_token_2.to_account_info(),
+                    authority: ctx.accounts.vault_authority.to_account_info(),
+                };
+                let cpi_program = ctx.accounts.token_program.to_account_info();
+                let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+                token_interface::transfer_checked(cpi_ctx, reward_leftover_2, reward_token_mint_2.decimals)?;
+            }
+            vaults.push(pool_reward_vault_2.to_account_info());
+        }
+
+        // Close the vaults; the pool account itself is closed by its `close` constraint
+        for vault in vaults {
+            let cpi_accounts = CloseAccount {
+                account: vault,
+                destination: ctx.accounts.authority.to_account_info(),
+                authority: ctx.accounts.vault_authority.to_account_info(),
+            };
+            let cpi_program = ctx.accounts.token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+            token_interface::close_account(cpi_ctx)?;
+        }
+
+        msg!(
+            "Closed pool {}, swept {} stake, {} reward and {} second reward tokens",
+            ctx.accounts.pool.key(),
+            stake_leftover,
+            reward_leftover,
+            reward_leftover_2
+        );
+        Ok(())
+    }
+}
+
+// Whether `pool` has a final claim window and it had closed by `now`
+fn claim_window_closed(pool: &StakingPool, now: i64) -> bool {
+    pool.final_claim_grace > 0
+        && now >= pool.reward_end_time.saturating_add(pool.final_claim_grace)
+}
+
+// Validate `params` and write a new pool's initial state; shared by initialize_pool and bootstrap_pool
+fn init_pool_state(
+    pool: &mut Account<StakingPool>,
+    authority: Pubkey,
+    stake_token_mint: &InterfaceAccount<Mint>,
+    reward_token_mint: &InterfaceAccount<Mint>,
+    pool_bump: u8,
+    vault_authority_bump: u8,
+    params: InitializePoolParams,
+) -> Result<()> {
+    validate_lock_tiers(&params.lock_tier_durations, &params.lock_tier_multipliers_bps)?;
+    validate_pool_metadata(&params.name, &params.metadata_uri)?;
+    require!(
+        params.snapshot_capacity <= MAX_SNAPSHOT_CAPACITY,
+        StakingError::InvalidSnapshotCapacity
+    );
+    require!(
+        params.reward_start_time < params.reward_end_time
+            && params.halving_interval >= 0
+            && params.final_claim_grace >= 0,
+        StakingError::InvalidRewardSchedule
+    );
+    // Curves are shaped over the whole window, so they don't combine with halving
+    require!(
+        params.curve_type == CURVE_LINEAR
+            || (params.curve_type <= CURVE_EASE_OUT && params.halving_interval == 0),
+        StakingError::InvalidRewardSchedule
+    );
+    // A shared mint puts both vaults on one mint, so it has to be asked for
+    require!(
+        params.allow_same_mint
+            || stake_token_mint.key() != reward_token_mint.key(),
+        StakingError::SameMintNotAllowed
+    );
+    // Penalties are paid into the reward vault, so they must be in the reward mint
+    let penalty_bps = params.early_unstake_penalty_bps;
+    require!(
+        penalty_bps == EARLY_UNSTAKE_DISABLED
+            || penalty_bps == 0
+            || (penalty_bps as u64 <= BPS_DENOMINATOR
+                && stake_token_mint.key() == reward_token_mint.key()),
+        StakingError::InvalidPenaltyConfig
+    );
+    require!(
+        params.penalty_treasury_bps as u64 <= BPS_DENOMINATOR,
+        StakingError::InvalidPenaltyConfig
+    );
+    require!(is_power_of_ten(params.rate_scale), StakingError::InvalidRateScale);
+    require!(params.min_stake_duration >= 0, StakingError::InvalidDuration);
+
+    let reward_rate = params.reward_rate;
+    require!(reward_rate <= MAX_REWARD_RATE, StakingError::RewardRateTooHigh);
+    pool.authority = authority;
+    pool.creator = authority;
+    pool.pending_authority = None;
+    pool.name = params.name;
+    pool.metadata_uri = params.metadata_uri;
+    pool.stake_token_mint = stake_token_mint.key();
+    pool.reward_token_mint = reward_token_mint.key();
+    pool.stake_decimals = stake_token_mint.decimals;
+    pool.reward_decimals = reward_token_mint.decimals;
+    pool.reward_rate = reward_rate;
+    pool.min_stake_duration = params.min_stake_duration;
+    pool.unbonding_period = params.unbonding_period;
+    pool.claim_cooldown = params.claim_cooldown;
+    pool.reward_warmup = params.reward_warmup;
+    pool.lock_tier_durations = params.lock_tier_durations;
+    pool.lock_tier_multipliers_bps = params.lock_tier_multipliers_bps;
+    pool.early_unstake_penalty_bps = penalty_bps;
+    pool.penalty_treasury_bps = params.penalty_treasury_bps;
+    pool.reward_budget = params.reward_budget;
+    pool.reward_reserve = params.reward_reserve;
+    pool.max_total_stake = params.max_total_stake;
+    pool.max_user_stake = params.max_user_stake;
+    pool.min_stake_amount = params.min_stake_amount;
+    pool.restrict_funders = params.restrict_funders;
+    pool.allow_same_mint = params.allow_same_mint;
+    pool.reinvest_bonus_bps = params.reinvest_bonus_bps;
+    pool.round_up_rewards = params.round_up_rewards;
+    pool.max_claim_per_tx = params.max_claim_per_tx;
+    pool.fixed_emission = params.fixed_emission;
+    pool.require_prefunded = params.require_prefunded;
+    pool.gate_collection = params.gate_collection;
+    pool.early_staker_cutoff = params.early_staker_cutoff;
+    pool.early_staker_boost_bps = params.early_staker_boost_bps;
+    pool.freeze_stops_accrual = params.freeze_stops_accrual;
+    pool.max_rewarded_stake = params.max_rewarded_stake;
+    pool.rate_scale = params.rate_scale;
+    pool.merkle_root = params.merkle_root;
+    pool.emit_receipts = params.emit_receipts;
+    pool.final_claim_grace = params.final_claim_grace;
+    pool.total_rewards_funded = 0;
+    pool.reward_start_time = params.reward_start_time;
+    pool.reward_end_time = params.reward_end_time;
+    pool.halving_interval = params.halving_interval;
+    pool.curve_type = params.curve_type;
+    pool.initial_reward_rate = reward_rate;
+    pool.total_rewards_emitted = 0;
+    pool.total_rewards_distributed = 0;
+    pool.total_staked = 0;
+    pool.total_unbonding = 0;
+    pool.total_weighted_stake = 0;
+    pool.acc_reward_per_share = 0;
+    pool.acc_reward_remainder = 0;
+    pool.last_update_time = Clock::get()?.unix_timestamp;
+    pool.paused = false;
+    pool.snapshot_capacity = params.snapshot_capacity;
+    pool.bump = pool_bump;
+    pool.vault_authority_bump = vault_authority_bump;
+
+    // A curve's full-window emission must be computable or every later update would fail
+    if pool.curve_type != CURVE_LINEAR {
+        emission_between(pool, pool.reward_start_time, pool.reward_end_time)?;
+    }
+
+    msg!("Staking pool initialized with reward rate: {} per second", reward_rate);
+    Ok(())
+}
+
+// Entry checks every way into a pool shares: a prefunded reward vault, the collection
+// gate on `holder` and the allowlist on `member`. `reward_vault_balance` is the pool's
+// reward vault balance.
+fn check_stake_gates(
+    pool: &StakingPool,
+    reward_vault_balance: u64,
+    holder: &Pubkey,
+    member: &Pubkey,
+    nft_token: Option<&TokenAccount>,
+    nft_metadata: Option<&MetadataAccount>,
+    proof: &[[u8; 32]],
+) -> Result<()> {
+    // Stakers shouldn't be let into a pool that has nothing to pay them with
+    require!(
+        !pool.require_prefunded || pool.total_staked > 0 || reward_vault_balance > 0,
+        StakingError::RewardVaultNotFunded
+    );
+    if let Some(collection) = pool.gate_collection {
+        verify_collection_holder(nft_token, nft_metadata, holder, &collection)?;
+    }
+    verify_allowlist(pool, member, proof)
+}
+
+// Stake tokens that reached `stake_vault` since it held `balance_before`
+fn stake_received(stake_vault: &mut InterfaceAccount<TokenAccount>, balance_before: u64) -> Result<u64> {
+    // Credit what actually arrived; Token-2022 transfer fees are withheld from the vault
+    stake_vault.reload()?;
+    let received = stake_vault.amount.checked_sub(balance_before)
+        .ok_or(StakingError::Underflow)?;
+    require!(received > 0, StakingError::InvalidAmount);
+    Ok(received)
+}
+
+// Credit `received` freshly deposited tokens to a position: settle it, check the
+// pool's caps, extend its lock and update its weight. Shared by stake and stake_sol.
+#[allow(clippy::too_many_arguments)]
+fn credit_stake(
+    pool: &mut Account<StakingPool>,
+    user_stake: &mut Account<UserStake>,
+    depositor: Pubkey,
+    beneficiary: Pubkey,
+    position_id: u64,
+    bump: u8,
+    received: u64,
+    lock_period: i64,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    update_pool(pool, clock.unix_timestamp)?;
+
+    // init_if_needed also hands back existing accounts, which must keep their identity
+    let is_initialized = user_stake.user != Pubkey::default();
+    require!(
+        !is_initialized || (user_stake.user == beneficiary && user_stake.pool == pool.key()),
+        StakingError::StakeOwnerMismatch
+    );
+    let is_new = user_stake.amount == 0;
+    // Third parties can't reset someone else's lock or stake-duration clock
+    require!(is_new || depositor == beneficiary, StakingError::NotBeneficiary);
+    let previous_weight = user_stake.weight(pool)?;
+
+    // Settle pending rewards on any existing stake first.
+    // Invariant: pending_rewards + weight * acc_reward_per_share - reward_debt is
+    // everything the position is owed. Settling at the current accumulator before
+    // the weight changes means no interval is counted twice or dropped; the new
+    // tokens only earn from here because sync_user_weight re-snapshots the debt.
+    settle_user_rewards(pool, user_stake)?;
+
+    // Enforce the pool and position deposit caps on what was actually credited
+    let new_total_staked = pool.total_staked.checked_add(received)
+        .ok_or(StakingError::Overflow)?;
+    require!(
+        pool.max_total_stake == 0 || new_total_staked <= pool.max_total_stake,
+        StakingError::PoolCapExceeded
+    );
+    let new_user_amount = user_stake.amount.checked_add(received)
+        .ok_or(StakingError::Overflow)?;
+    // Small top-ups are fine as long as the position ends up above the minimum
+    require!(new_user_amount >= pool.min_stake_amount, StakingError::BelowMinimumStake);
+    require!(
+        pool.max_user_stake == 0 || new_user_amount <= pool.max_user_stake,
+        StakingError::UserCapExceeded
+    );
+
+    // Update user stake account; identity fields are only ever written once
+    if !is_initialized {
+        user_stake.user = beneficiary;
+        user_stake.pool = pool.key();
+        user_stake.position_id = position_id;
+        user_stake.bump = bump;
+        user_stake.version = USER_STAKE_VERSION;
+    }
+    if is_new {
+        user_stake.first_stake_time = clock.unix_timestamp;
+    }
+    user_stake.amount = new_user_amount;
+    user_stake.last_stake_time = clock.unix_timestamp;
+    user_stake.warmup_start = clock.unix_timestamp;
+    user_stake.locked_duration = Some(pool.min_stake_duration);
+
+    // A top-up never weakens an active lock: keep the later end and the higher multiplier
+    let lock_end = clock.unix_timestamp.checked_add(lock_period)
+        .ok_or(StakingError::Overflow)?;
+    let multiplier_bps = pool.lock_multiplier_bps(lock_period);
+    if user_stake.lock_end > clock.unix_timestamp {
+        user_stake.lock_end = user_stake.lock_end.max(lock_end);
+        user_stake.reward_multiplier_bps = user_stake.reward_multiplier_bps.max(multiplier_bps);
+    } else {
+        user_stake.lock_end = lock_end;
+        user_stake.reward_multiplier_bps = multiplier_bps;
+    }
+    sync_user_weight(pool, user_stake, previous_weight)?;
+
+    // Update pool total
+    pool.total_staked = new_total_staked;
+
+    msg!(
+        "Staked {} tokens in position {}. Total staked: {}, multiplier: {} bps, locked until {}",
+        received,
+        position_id,
+        user_stake.amount,
+        user_stake.reward_multiplier_bps,
+        user_stake.lock_end
+    );
+    Ok(())
+}
+
+// Advance the pool's reward-per-share accumulator up to the current time.
+// With fixed_emission, emission is `reward_rate` per second in total, split across
+// the pool's multiplier-weighted stake, so adding stakers dilutes each share instead
+// of growing the payout. Otherwise `reward_rate` is per rate_scale weighted units and
+// emission grows with the stake. Only time inside the pool's reward window counts.
+// Emission is in reward base units and the split is a ratio of stake base
+// units, so the two mints' decimals cancel there. Per-token rates are quoted
+// between whole tokens instead and go through per_token_scale.
+// Time with no weighted stake still moves last_update_time, so its emission is
+// skipped rather than paid to the next staker, and never counts against reward_budget.
+fn update_pool(pool: &mut StakingPool, current_time: i64) -> Result<()> {
+    // A clock reading at or before the last update, which forks and skipped leaders can
+    // produce, accrues nothing and leaves last_update_time alone instead of underflowing.
+    // Positions only ever settle against the accumulator, so they need no guard of their own
+    if current_time <= pool.last_update_time {
+        return Ok(());
+    }
+
+    let accrual_start = pool.last_update_time.max(pool.reward_start_time);
+    let accrual_end = current_time.min(pool.reward_end_time);
+
+    // Streamed deposits vest linearly on their own schedule, inside the reward window or not
+    let streamed = pool.reward_stream.vest(current_time);
+
+    // Nobody is staked, nothing is emitting or emission is paused, so nothing accrues
+    if !pool.rewards_paused && pool.total_weighted_stake > 0 && (accrual_end > accrual_start || streamed > 0) {
+        let mut emission = if accrual_end <= accrual_start {
+            0
+        } else if pool.apy_bps > 0 {
+            // Pay the yield on the stake directly instead of through the rounded reward_rate
+            (pool.total_weighted_stake as u128)
+                .checked_mul(pool.apy_bps as u128)
+                .and_then(|v| v.checked_mul((accrual_end - accrual_start) as u128))
+                .ok_or(StakingError::Overflow)?
+                / (BPS_DENOMINATOR as u128 * SECONDS_PER_YEAR as u128)
+        } else {
+            emission_between(pool, accrual_start, accrual_end)?
+        };
+        // Per-token rates scale with the stake; either way the split below is pro-rata
+        if !pool.fixed_emission && pool.apy_bps == 0 {
+            let (factor, scale) = per_token_scale(pool)?;
+            let scaled_weight = (pool.total_weighted_stake as u128).checked_mul(factor)
+                .ok_or(StakingError::Overflow)?;
+            emission = mul_div(emission, scaled_weight, scale)?;
+        }
+        emission = emission.checked_add(streamed as u128)
+            .ok_or(StakingError::Overflow)?;
+
+        // Once the budget is fully emitted accrual freezes instead of erroring
+        if pool.reward_budget > 0 {
+            let remaining_budget = pool.remaining_reward_budget() as u128;
+            if emission > remaining_budget {
+                emission = remaining_budget;
+                msg!("Reward budget exhausted, accrual frozen");
+            }
+        }
+
+        // Carry the division remainder forward so slow emission isn't truncated away
+        let (increment, remainder) = accumulator_increment(
+            emission,
+            pool.acc_reward_remainder,
+            pool.total_weighted_stake as u128,
+        )?;
+        pool.acc_reward_remainder = remainder;
+
+        pool.acc_reward_per_share = pool.acc_reward_per_share.checked_add(increment)
+            .ok_or(StakingError::Overflow)?;
+        // A budget keeps this within u64; uncapped pools only report it, so saturate
+        let emitted = u64::try_from(emission).unwrap_or(u64::MAX);
+        pool.total_rewards_emitted = pool.total_rewards_emitted.saturating_add(emitted);
+    }
+
+    // The second reward token splits reward_rate_2 over the same stake and window
+    if pool.reward_token_mint_2.is_some()
+        && !pool.rewards_paused
+        && pool.total_weighted_stake > 0
+        && accrual_end > accrual_start
+    {
+        let emission_2 = (pool.reward_rate_2 as u128)
+            .checked_mul((accrual_end - accrual_start) as u128)
+            .ok_or(StakingError::Overflow)?;
+        let (increment, remainder) = accumulator_increment(
+            emission_2,
+            pool.acc_reward_remainder_2,
+            pool.total_weighted_stake as u128,
+        )?;
+        pool.acc_reward_per_share_2 = pool.acc_reward_per_share_2
+            .checked_add(increment)
+            .ok_or(StakingError::Overflow)?;
+        pool.acc_reward_remainder_2 = remainder;
+    }
+
+    pool.last_update_time = current_time;
+    Ok(())
+}
+
+// A per-token reward_rate pays rate / rate_scale reward tokens per staked token, so per
+// stake base unit it pays 10^(reward_decimals - stake_decimals) times that in reward base
+// units. Returned as a multiplier and a divisor with the common power of ten cancelled,
+// the divisor taking rate_scale and any negative decimal difference
+fn per_token_scale(pool: &StakingPool) -> Result<(u128, u128)> {
+    let pow10 = |exp: u8| 10u128.checked_pow(exp as u32).ok_or(StakingError::Overflow);
+    let (factor, scale) = if pool.reward_decimals >= pool.stake_decimals {
+        (pow10(pool.reward_decimals - pool.stake_decimals)?, pool.rate_scale as u128)
+    } else {
+        let shift = pow10(pool.stake_decimals - pool.reward_decimals)?;
+        (1, (pool.rate_scale as u128).checked_mul(shift).ok_or(StakingError::Overflow)?)
+    };
+    // Both are powers of ten, so the smaller divides the larger
+    let common = factor.min(scale);
+    Ok((factor / common, scale / common))
+}
+
+// `emission * ACC_REWARD_PRECISION + remainder` split over `total_weight`, returned
+// as the accumulator increment and the new remainder. The whole multiples of the
+// weight are divided out before scaling, so only a value below the weight is ever
+// multiplied by the precision and large pools can't overflow the intermediate.
+fn accumulator_increment(emission: u128, remainder: u128, total_weight: u128) -> Result<(u128, u128)> {
+    require!(total_weight > 0, StakingError::DivisionByZero);
+    let whole = (emission / total_weight)
+        .checked_mul(ACC_REWARD_PRECISION)
+        .ok_or(StakingError::Overflow)?;
+    let scaled_part = (emission % total_weight)
+        .checked_mul(ACC_REWARD_PRECISION)
+        .and_then(|v| v.checked_add(remainder))
+        .ok_or(StakingError::Overflow)?;
+    let increment = whole.checked_add(scaled_part / total_weight)
+        .ok_or(StakingError::Overflow)?;
+    Ok((increment, scaled_part % total_weight))
+}
+
+// `a * b / d` rounded down, computed as `a / d * b + a % d * b / d` so that only
+// the remainder of `a` is multiplied at full width. Same result as the direct form.
+fn mul_div(a: u128, b: u128, d: u128) -> Result<u128> {
+    require!(d > 0, StakingError::DivisionByZero);
+    let whole = (a / d).checked_mul(b)
+        .ok_or(StakingError::Overflow)?;
+    let part = (a % d).checked_mul(b)
+        .ok_or(StakingError::Overflow)?
+        / d;
+    whole.checked_add(part)
+        .ok_or_else(|| error!(StakingError::Overflow))
+}
+
+// Total emission over `[from, to)`. With halving enabled the rate is
+// `initial_reward_rate >> epoch`, where epochs of `halving_interval` seconds
+// count from `reward_start_time`, so windows spanning a boundary are summed
+// piecewise. Curved pools difference their cumulative emission instead.
+// Callers keep `from` at or after `reward_start_time` and `to` at or before
+// `reward_end_time`.
+fn emission_between(pool: &StakingPool, from: i64, to: i64) -> Result<u128> {
+    if pool.curve_type != CURVE_LINEAR {
+        return curve_emitted_by(pool, to)?
+            .checked_sub(curve_emitted_by(pool, from)?)
+            .ok_or_else(|| error!(StakingError::Underflow));
+    }
+
+    if pool.halving_interval == 0 {
+        let time_elapsed = to.checked_sub(from)
+            .ok_or(StakingError::Underflow)? as u128;
+        return (pool.reward_rate as u128)
+            .checked_mul(time_elapsed)
+            .ok_or_else(|| error!(StakingError::Overflow));
+    }
+
+    let mut emission: u128 = 0;
+    let mut cursor = from;
+    while cursor < to {
+        let epoch = cursor.checked_sub(pool.reward_start_time)
+            .ok_or(StakingError::Underflow)?
+            / pool.halving_interval;
+        // After 64 halvings the rate is zero for good
+        if epoch >= 64 {
+            break;
+        }
+        let epoch_end = pool.reward_start_time
+            .saturating_add((epoch + 1).saturating_mul(pool.halving_interval));
+        let segment_end = epoch_end.min(to);
+        let rate = pool.initial_reward_rate >> epoch;
+        let segment = (rate as u128)
+            .checked_mul(segment_end.checked_sub(cursor).ok_or(StakingError::Underflow)? as u128)
+            .ok_or(StakingError::Overflow)?;
+        emission = emission.checked_add(segment)
+            .ok_or(StakingError::Overflow)?;
+        cursor = segment_end;
+    }
+    Ok(emission)
+}
+
+// Emission from `reward_start_time` to `t` on a curved pool. Over the full
+// window both curves emit `reward_rate * window`, the same as linear emission:
+// ease-in follows x^2 of the window and ease-out 1 - (1 - x)^2. Differencing
+// this cumulative total keeps rounding from ever adding up past that.
+fn curve_emitted_by(pool: &StakingPool, t: i64) -> Result<u128> {
+    let window = pool.reward_end_time.checked_sub(pool.reward_start_time)
+        .ok_or(StakingError::Underflow)? as u128;
+    let rate = pool.reward_rate as u128;
+    let squared = |span: i64| -> Result<u128> {
+        let span = span as u128;
+        Ok(rate.checked_mul(span)
+            .and_then(|v| v.checked_mul(span))
+            .ok_or(StakingError::Overflow)?
+            / window)
+    };
+
+    let since_start = t.checked_sub(pool.reward_start_time)
+        .ok_or(StakingError::Underflow)?;
+    let until_end = pool.reward_end_time.checked_sub(t)
+        .ok_or(StakingError::Underflow)?;
+    if pool.curve_type == CURVE_EASE_IN {
+        squared(since_start)
+    } else {
+        rate.checked_mul(window)
+            .ok_or(StakingError::Overflow)?
+            .checked_sub(squared(until_end)?)
+            .ok_or_else(|| error!(StakingError::Underflow))
+    }
+}
+
+// The one place a position's accrual is moved into pending_rewards. The pool
+// must already be advanced with update_pool. Re-snapshotting the reward debt
+// here makes a second settlement at the same accumulator a no-op, so no
+// instruction can credit the same interval twice. last_stake_time is left to
+// callers because it governs min_stake_duration; the warmup runs from
+// warmup_start, which only credit_stake moves.
+fn settle_user_rewards(pool: &mut StakingPool, user_stake: &mut UserStake) -> Result<()> {
+    let weight = user_stake.weight(pool)?;
+    let mut earned = calculate_rewards(weight, pool.acc_reward_per_share, user_stake.reward_debt)?;
+
+    // The share of the window since the last settlement that fell inside the warmup is
+    // forfeited, assuming emission was even across it, and handed back to the budget
+    let now = pool.last_update_time;
+    if pool.reward_warmup > 0 {
+        let window_start = user_stake.last_settle_time.max(user_stake.warmup_start);
+        let warmup_end = user_stake.warmup_start.saturating_add(pool.reward_warmup);
+        let window = now.saturating_sub(window_start);
+        let counted = now.saturating_sub(warmup_end.max(window_start));
+        if window > 0 && counted < window {
+            let kept = earned.checked_mul(counted as u128)
+                .ok_or(StakingError::Overflow)?
+                / window as u128;
+            let forfeited = u64::try_from((earned - kept) / ACC_REWARD_PRECISION).unwrap_or(u64::MAX);
+            pool.total_rewards_emitted = pool.total_rewards_emitted.saturating_sub(forfeited);
+            earned = kept;
+        }
+    }
+    user_stake.last_settle_time = now;
+
+    // Whole tokens become pending; the fraction below one token waits for the next
+    // settlement, or with round_up_rewards is credited now as a whole token
+    let accrued = earned
+        .checked_add(user_stake.reward_remainder)
+        .ok_or(StakingError::Overflow)?;
+    let mut whole = accrued / ACC_REWARD_PRECISION;
+    let mut remainder = accrued % ACC_REWARD_PRECISION;
+    if pool.round_up_rewards && remainder > 0 {
+        whole += 1;
+        remainder = 0;
+    }
+    user_stake.pending_rewards = user_stake.pending_rewards
+        .checked_add(whole)
+        .ok_or(StakingError::Overflow)?;
+    user_stake.reward_remainder = remainder;
+    user_stake.reward_debt = reward_debt(weight, pool.acc_reward_per_share)?;
+
+    // The second reward settles the same way, without warmup or rounding up
+    let accrued_2 = calculate_rewards(weight, pool.acc_reward_per_share_2, user_stake.reward_debt_2)?
+        .checked_add(user_stake.reward_remainder_2)
+        .ok_or(StakingError::Overflow)?;
+    user_stake.pending_rewards_2 = user_stake.pending_rewards_2
+        .checked_add(accrued_2 / ACC_REWARD_PRECISION)
+        .ok_or(StakingError::Overflow)?;
+    user_stake.reward_remainder_2 = accrued_2 % ACC_REWARD_PRECISION;
+    user_stake.reward_debt_2 = reward_debt(weight, pool.acc_reward_per_share_2)?;
+    Ok(())
+}
+
+// Direct withdrawals and re-locks draw on the earning balance only, which must hold
+// `amount` and at least one token. Tokens already requested with request_unstake can
+// only leave through complete_unstake.
+fn require_earning_balance(user_stake: &UserStake, amount: u64) -> Result<()> {
+    let needed = amount.max(1);
+    if needed <= user_stake.amount {
+        return Ok(());
+    }
+    require!(
+        needed > user_stake.amount.saturating_add(user_stake.unbonding_amount),
+        StakingError::AmountLockedInUnbonding
+    );
+    err!(StakingError::InsufficientStake)
+}
+
+// Part of an `amount` exit the stake vault can pay. If the vault holds less
+// than the earning and unbonding tokens it owes, every exit is scaled by the
+// same ratio so the shortfall is shared instead of landing on the last out.
+fn stake_vault_share(pool: &StakingPool, vault_balance: u64, amount: u64) -> Result<u64> {
+    let owed = pool.total_staked.checked_add(pool.total_unbonding)
+        .ok_or(StakingError::Overflow)?;
+    if vault_balance >= owed {
+        return Ok(amount);
+    }
+    let share = (amount as u128 * vault_balance as u128 / owed as u128) as u64;
+    msg!(
+        "WARNING: stake vault holds {} of {} owed, paying {} of {}",
+        vault_balance,
+        owed,
+        share,
+        amount
+    );
+    Ok(share)
+}
+
+// Accounts the reward leg of claim_rewards and exit pays through
+struct RewardAccounts<'a, 'info> {
+    pool: &'a mut Account<'info, StakingPool>,
+    user_stake: &'a mut Account<'info, UserStake>,
+    vault_authority: &'a UncheckedAccount<'info>,
+    user_reward_token: &'a InterfaceAccount<'info, TokenAccount>,
+    pool_reward_vault: &'a InterfaceAccount<'info, TokenAccount>,
+    config: &'a Account<'info, ProtocolConfig>,
+    treasury_reward_token: &'a InterfaceAccount<'info, TokenAccount>,
+    reward_token_mint: &'a InterfaceAccount<'info, Mint>,
+    token_program: &'a Interface<'info, TokenInterface>,
+    boost_token: Option<&'a TokenAccount>,
+    parent_pool: Option<&'a mut Account<'info, StakingPool>>,
+    parent_vault_authority: Option<&'a UncheckedAccount<'info>>,
+    parent_reward_vault: Option<&'a InterfaceAccount<'info, TokenAccount>>,
+    pool_reward_vault_2: Option<&'a InterfaceAccount<'info, TokenAccount>>,
+    user_reward_token_2: Option<&'a InterfaceAccount<'info, TokenAccount>>,
+    reward_token_mint_2: Option<&'a InterfaceAccount<'info, Mint>>,
+    compound_vault: Option<&'a mut InterfaceAccount<'info, TokenAccount>>, // Verified stake vault to restake into
+    claim_receipt: Option<&'a mut Account<'info, ClaimReceipt>>,
+    claim_receipt_bump: Option<u8>,
+    program_id: &'a Pubkey,
+}
+
+// What one reward leg paid out
+#[derive(Default)]
+struct RewardPayment {
+    owed: u128,                     // Requested rewards plus their boost
+    payout: u64,                    // Reward tokens taken from the source vault, fee included
+    fee: u64,
+    boost_paid: u64,
+    payout_2: u64,
+}
+
+// The reward leg shared by claim_rewards and exit, run on a settled position: pays
+// `amount` of its pending rewards (None = all) with the holder boost, protocol fee,
+// parent vault and second reward, records the claim and carries anything unpaid.
+// A strict leg fails when nothing can be paid; otherwise it pays what it can.
+fn pay_rewards(
+    accounts: RewardAccounts,
+    position_id: u64,
+    amount: Option<u64>,
+    now: i64,
+    strict: bool,
+) -> Result<RewardPayment> {
+    let RewardAccounts {
+        pool,
+        user_stake,
+        vault_authority,
+        user_reward_token,
+        pool_reward_vault,
+        config,
+        treasury_reward_token,
+        reward_token_mint,
+        token_program,
+        boost_token,
+        mut parent_pool,
+        parent_vault_authority,
+        parent_reward_vault,
+        pool_reward_vault_2,
+        user_reward_token_2,
+        reward_token_mint_2,
+        mut compound_vault,
+        claim_receipt,
+        claim_receipt_bump,
+        program_id,
+    } = accounts;
+
+    let total_rewards = user_stake.pending_rewards;
+    // A full claim still pays the second reward once the first has nothing left to pay
+    let second_pending = amount.is_none() && user_stake.pending_rewards_2 > 0;
+    if total_rewards == 0 && !second_pending {
+        require!(!strict, StakingError::NoRewardsToClaim);
+        return Ok(RewardPayment::default());
+    }
+
+    let requested = match amount {
+        Some(requested) => {
+            require!(requested > 0, StakingError::InvalidAmount);
+            require!(requested as u128 <= total_rewards, StakingError::ClaimAmountExceedsRewards);
+            requested as u128
+        }
+        None => total_rewards,
+    };
+
+    // The boost is paid on top of what the position is owed
+    let boost_bps = holder_boost_bps(pool, boost_token, &user_stake.user)?;
+    let boosted = requested
+        .checked_mul(BPS_DENOMINATOR as u128 + boost_bps as u128)
+        .ok_or(StakingError::Overflow)?
+        / BPS_DENOMINATOR as u128;
+
+    // Child pools are paid from their parent's vault and share the parent's budget
+    let (source_vault, source_authority, source_pool_key, source_bump, source_budget, available) =
+        match pool.parent_pool {
+            Some(parent_key) => {
+                let (Some(parent), Some(parent_vault_authority), Some(parent_reward_vault)) = (
+                    parent_pool.as_deref(),
+                    parent_vault_authority,
+                    parent_reward_vault,
+                ) else {
+                    return err!(StakingError::ParentPoolRequired);
+                };
+                verify_parent_vault(
+                    parent_key,
+                    parent,
+                    &parent_vault_authority.key(),
+                    &parent_reward_vault.key(),
+                    program_id,
+                )?;
+                (
+                    parent_reward_vault.to_account_info(),
+                    parent_vault_authority.to_account_info(),
+                    parent_key,
+                    parent.vault_authority_bump,
+                    parent.unpaid_reward_budget(),
+                    parent_reward_vault.amount,
+                )
+            }
+            None => (
+                pool_reward_vault.to_account_info(),
+                vault_authority.to_account_info(),
+                pool.key(),
+                pool.vault_authority_bump,
+                u64::MAX,
+                pool_reward_vault.amount,
+            ),
+        };
+
+    // Pay what the vault and the budget can cover and keep the rest owed
+    require!(!strict || available > 0 || second_pending, StakingError::InsufficientRewardFunds);
+    let payout = capped_payout(pool, boosted, available).min(source_budget);
+    require!(!strict || payout > 0 || second_pending, StakingError::NoRewardsToClaim);
+
+    // A short payout settles the owed part and its boost in proportion
+    let settled = if payout as u128 == boosted {
+        requested
+    } else {
+        (payout as u128 * BPS_DENOMINATOR as u128 / (BPS_DENOMINATOR as u128 + boost_bps as u128))
+            .min(requested)
+    };
+    let boost_paid = payout - settled as u64;
+
+    // The protocol fee comes out of the payout before the user is paid
+    let fee = bps_of(payout, config.fee_bps)?;
+    let user_payout = payout.checked_sub(fee)
+        .ok_or(StakingError::Underflow)?;
+
+    let stake_vault_before = compound_vault.as_ref().map(|vault| vault.amount);
+
+    // Transfer reward tokens to user
+    let seeds = &[
+        b"vault_authority",
+        source_pool_key.as_ref(),
+        &[source_bump],
+    ];
+    let signer = &[&seeds[..]];
+
+    if user_payout > 0 {
+        let destination = match compound_vault.as_ref() {
+            Some(pool_stake_vault) => pool_stake_vault.to_account_info(),
+            None => user_reward_token.to_account_info(),
+        };
+        let cpi_accounts = TransferChecked {
+            from: source_vault.clone(),
+            mint: reward_token_mint.to_account_info(),
+            to: destination,
+            authority: source_authority.clone(),
+        };
+        let cpi_program = token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::transfer_checked(cpi_ctx, user_payout, reward_token_mint.decimals)?;
+    }
+
+    if fee > 0 {
+        let cpi_accounts = TransferChecked {
+            from: source_vault,
+            mint: reward_token_mint.to_account_info(),
+            to: treasury_reward_token.to_account_info(),
+            authority: source_authority,
+        };
+        let cpi_program = token_program.to_account_info();
+        let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, signer);
+        token_interface::transfer_checked(cpi_ctx, fee, reward_token_mint.decimals)?;
+    }
+
+    // Any transfer fee comes out of the restaked amount
+    let mut restaked = 0;
+    if let (Some(before), Some(pool_stake_vault)) = (stake_vault_before, compound_vault.as_mut()) {
+        pool_stake_vault.reload()?;
+        restaked = pool_stake_vault.amount.checked_sub(before)
+            .ok_or(StakingError::Underflow)?;
+    }
+
+    // The second reward comes from the pool's own second vault, without a fee or boost
+    let mut payout_2 = 0;
+    if let Some(reward_mint_2) = pool.reward_token_mint_2 {
+        let (Some(pool_reward_vault_2), Some(user_reward_token_2), Some(reward_token_mint_2)) = (
+            pool_reward_vault_2,
+            user_reward_token_2,
+            reward_token_mint_2,
+        ) else {
+            return err!(StakingError::SecondRewardAccountsRequired);
+        };
+        let pool_key = pool.key();
+        let (expected_vault, _) = Pubkey::find_program_address(&[b"reward_vault_2", pool_key.as_ref()], program_id);
+        require!(
+            pool_reward_vault_2.key() == expected_vault
+                && reward_token_mint_2.key() == reward_mint_2
+                && user_reward_token_2.mint == reward_mint_2,
+            StakingError::InvalidSecondReward
+        );
+        require_keys_neq!(
+            user_reward_token_2.key(),
+            pool_reward_vault_2.key(),
+            StakingError::SelfTransferNotAllowed
+        );
+
+        let owed_2 = if settled == 0 && second_pending {
+            user_stake.pending_rewards_2
+        } else {
+            user_stake.pending_rewards_2
+                .checked_mul(settled)
+                .ok_or(StakingError::Overflow)?
+                / total_rewards
+        };
+        payout_2 = u64::try_from(owed_2).unwrap_or(u64::MAX).min(pool_reward_vault_2.amount);
+        if payout_2 > 0 {
+            let own_seeds = &[
+                b"vault_authority",
+                pool_key.as_ref(),
+                &[pool.vault_authority_bump],
+            ];
+            let own_signer = &[&own_seeds[..]];
+            let cpi_accounts = TransferChecked {
+                from: pool_reward_vault_2.to_account_info(),
+                mint: reward_token_mint_2.to_account_info(),
+                to: user_reward_token_2.to_account_info(),
+                authority: vault_authority.to_account_info(),
+            };
+            let cpi_program = token_program.to_account_info();
+            let cpi_ctx = CpiContext::new_with_signer(cpi_program, cpi_accounts, own_signer);
+            token_interface::transfer_checked(cpi_ctx, payout_2, reward_token_mint_2.decimals)?;
+        }
+        user_stake.pending_rewards_2 = user_stake.pending_rewards_2
+            .checked_sub(payout_2 as u128)
+            .ok_or(StakingError::Underflow)?;
+    }
+    let paid = payout > 0 || payout_2 > 0;
+    require!(!strict || paid, StakingError::InsufficientRewardFunds);
+
+    pool.total_rewards_distributed = pool.total_rewards_distributed.checked_add(payout)
+        .ok_or(StakingError::Overflow)?;
+    pool.total_rewards_distributed_2 = pool.total_rewards_distributed_2.checked_add(payout_2)
+        .ok_or(StakingError::Overflow)?;
+    // The boost never went through the accumulator, so it is emitted here
+    pool.total_rewards_emitted = pool.total_rewards_emitted.saturating_add(boost_paid);
+
+    // The parent never accrued what its child paid out, so it is emitted and paid here
+    if pool.parent_pool.is_some() {
+        if let Some(parent_pool) = parent_pool.as_mut() {
+            parent_pool.total_rewards_emitted = parent_pool.total_rewards_emitted.saturating_add(payout);
+            parent_pool.total_rewards_distributed = parent_pool.total_rewards_distributed.checked_add(payout)
+                .ok_or(StakingError::Overflow)?;
+        }
+    }
+
+    if restaked > 0 {
+        let previous_weight = user_stake.weight(pool)?;
+        user_stake.amount = user_stake.amount.checked_add(restaked)
+            .ok_or(StakingError::Overflow)?;
+        sync_user_weight(pool, user_stake, previous_weight)?;
+        pool.total_staked = pool.total_staked.checked_add(restaked)
+            .ok_or(StakingError::Overflow)?;
+        msg!("Restaked {} reward tokens into position {}", restaked, position_id);
+    }
+
+    // Carry any unpaid remainder forward and update timestamp
+    user_stake.pending_rewards = total_rewards.checked_sub(settled)
+        .ok_or(StakingError::Underflow)?;
+    user_stake.last_stake_time = now;
+    if paid {
+        user_stake.last_claim_time = now;
+    }
+
+    // Receipt pools record every paying claim under the position's claim counter
+    require!(
+        !paid || !pool.emit_receipts || claim_receipt.is_some(),
+        StakingError::ReceiptAccountRequired
+    );
+    let receipted = claim_receipt.is_some();
+    if let Some(claim_receipt) = claim_receipt {
+        claim_receipt.set_inner(ClaimReceipt {
+            pool: pool.key(),
+            user: user_stake.user,
+            position_id,
+            sequence: user_stake.claim_count,
+            reward_mint: pool.reward_token_mint,
+            amount: payout,
+            fee,
+            timestamp: now,
+            bump: claim_receipt_bump.ok_or(StakingError::ReceiptAccountRequired)?,
+        });
+    }
+    if paid || receipted {
+        user_stake.claim_count = user_stake.claim_count.checked_add(1)
+            .ok_or(StakingError::Overflow)?;
+    }
+
+    Ok(RewardPayment {
+        owed: boosted,
+        payout,
+        fee,
+        boost_paid,
+        payout_2,
+    })
+}
+
+// Largest payout for `owed` rewards that the vault holds, the budget still allows
+// and max_claim_per_tx lets one transaction withdraw. Owed rewards may exceed u64;
+// the payout never can, since it is capped by the vault balance.
+fn capped_payout(pool: &StakingPool, owed: u128, vault_balance: u64) -> u64 {
+    let payout = owed.min(vault_balance as u128)
+        .min(pool.unpaid_reward_budget() as u128) as u64;
+    if pool.max_claim_per_tx > 0 {
+        payout.min(pool.max_claim_per_tx)
+    } else {
+        payout
+    }
+}
+
+// Helper function to calculate rewards accrued since the last settlement
+// for a position of the given weight (see UserStake::weight), still scaled
+// by ACC_REWARD_PRECISION
+fn calculate_rewards(
+    weight: u64,
+    acc_reward_per_share: u128,
+    reward_debt: u128,
+) -> Result<u128> {
+    (weight as u128)
+        .checked_mul(acc_reward_per_share)
+        .ok_or(StakingError::Overflow)?
+        .checked_sub(reward_debt)
+        .ok_or_else(|| error!(StakingError::Underflow))
+}
+
+// Snapshot of the accumulator a position has already been credited for
+fn reward_debt(weight: u64, acc_reward_per_share: u128) -> Result<u128> {
+    (weight as u128)
+        .checked_mul(acc_reward_per_share)
+        .ok_or_else(|| error!(StakingError::Overflow))
+}
+
+// Stake weight used for reward accounting
+fn weighted_amount(staked_amount: u64, reward_multiplier_bps: u16) -> Result<u64> {
+    bps_of(staked_amount, reward_multiplier_bps)
+}
+
+// Penalty on `withdrawn` stake tokens leaving a position at `now`, or an error when the
+// position is still inside min_stake_duration and the pool rejects early exits.
+// Emergency mode waives both
+fn early_unstake_penalty(pool: &StakingPool, user_stake: &UserStake, withdrawn: u64, now: i64) -> Result<u64> {
+    let elapsed = now - user_stake.last_stake_time;
+    if pool.emergency_mode || elapsed >= user_stake.min_stake_duration(pool) {
+        return Ok(0);
+    }
+    require!(
+        pool.early_unstake_penalty_bps != EARLY_UNSTAKE_DISABLED,
+        StakingError::StakeDurationNotMet
+    );
+    bps_of(withdrawn, pool.early_unstake_penalty_bps)
+}
+
+// `amount * bps / 10_000`, rounded down
+fn bps_of(amount: u64, bps: u16) -> Result<u64> {
+    let scaled = (amount as u128)
+        .checked_mul(bps as u128)
+        .ok_or(StakingError::Overflow)?
+        .checked_div(BPS_DENOMINATOR as u128)
+        .ok_or(StakingError::DivisionByZero)?;
+
+    u64::try_from(scaled).map_err(|_| error!(StakingError::Overflow))
+}
+
+// Keep the pool's weighted total in step with a position whose amount or
+// multiplier just changed, and re-snapshot the position's reward debt.
+// Rewards must already be settled at the current accumulator.
+fn sync_user_weight(
+    pool: &mut StakingPool,
+    user_stake: &mut UserStake,
+    previous_weight: u64,
+) -> Result<()> {
+    let weight = user_stake.weight(pool)?;
+    pool.total_weighted_stake = pool.total_weighted_stake
+        .checked_sub(previous_weight)
+        .ok_or(StakingError::Underflow)?
+        .checked_add(weight)
+        .ok_or(StakingError::Overflow)?;
+    user_stake.reward_debt = reward_debt(weight, pool.acc_reward_per_share)?;
+    user_stake.reward_debt_2 = reward_debt(weight, pool.acc_reward_per_share_2)?;
+    Ok(())
+}
+
+// Add a funding to the funder's record, filling in its identity on first use
+fn record_funding(
+    record: &mut FunderRecord,
+    pool: Pubkey,
+    funder: Pubkey,
+    bump: u8,
+    received: u64,
+    now: i64,
+) -> Result<()> {
+    if record.funder == Pubkey::default() {
+        record.pool = pool;
+        record.funder = funder;
+        record.bump = bump;
+    }
+    record.cumulative_funded = record.cumulative_funded.checked_add(received)
+        .ok_or(StakingError::Overflow)?;
+    record.last_funded_time = now;
+    Ok(())
+}
+
+// Writes `entry` at the ring buffer's head, overwriting the oldest entry once it is full
+fn record_snapshot(
+    snapshots: &mut RewardSnapshots,
+    pool: Pubkey,
+    capacity: u16,
+    bump: u8,
+    entry: RewardSnapshot,
+) -> Result<()> {
+    if snapshots.pool == Pubkey::default() {
+        snapshots.pool = pool;
+        snapshots.capacity = capacity;
+        snapshots.bump = bump;
+    }
+    let head = snapshots.next_index as usize;
+    if head < snapshots.entries.len() {
+        snapshots.entries[head] = entry;
+    } else {
+        snapshots.entries.push(entry);
+    }
+    snapshots.next_index = ((head + 1) % snapshots.capacity as usize) as u16;
+    snapshots.total_recorded = snapshots.total_recorded.checked_add(1)
+        .ok_or(StakingError::Overflow)?;
+    Ok(())
+}
+
+// Claim boost for the holder's live boost_mint balance, capped at max_boost_bps.
+// Pools without a boost_mint, and claims that pass no boost account, get none
+fn holder_boost_bps(
+    pool: &StakingPool,
+    boost_token: Option<&TokenAccount>,
+    holder: &Pubkey,
+) -> Result<u16> {
+    let (Some(boost_mint), Some(boost_token)) = (pool.boost_mint, boost_token) else {
+        return Ok(0);
+    };
+    require!(boost_token.mint == boost_mint, StakingError::BoostAccountMintMismatch);
+    require!(boost_token.owner == *holder, StakingError::BoostAccountOwnerMismatch);
+
+    let boost = (boost_token.amount as u128)
+        .checked_mul(pool.boost_per_token_bps as u128)
+        .ok_or(StakingError::Overflow)?
+        / 10u128.pow(pool.boost_decimals as u32);
+    Ok(boost.min(pool.max_boost_bps as u128) as u16)
+}
+
+// A child pool's payout accounts must be its parent's own vault authority and reward vault
+fn verify_parent_vault(
+    parent_key: Pubkey,
+    parent_pool: &Account<StakingPool>,
+    vault_authority: &Pubkey,
+    reward_vault: &Pubkey,
+    program_id: &Pubkey,
+) -> Result<()> {
+    require_keys_eq!(parent_pool.key(), parent_key, StakingError::InvalidParentPool);
+    let expected_authority = Pubkey::create_program_address(
+        &[b"vault_authority", parent_key.as_ref(), &[parent_pool.vault_authority_bump]],
+        program_id,
+    )
+    .map_err(|_| StakingError::InvalidParentPool)?;
+    let (expected_vault, _) = Pubkey::find_program_address(&[b"reward_vault", parent_key.as_ref()], program_id);
+    require!(
+        *vault_authority == expected_authority && *reward_vault == expected_vault,
+        StakingError::InvalidParentPool
+    );
+    Ok(())
+}
+
+// Leaves are sha256(pubkey), each parent hashes its two children in sorted order
+fn verify_allowlist(pool: &StakingPool, user: &Pubkey, proof: &[[u8; 32]]) -> Result<()> {
+    let Some(root) = pool.merkle_root else {
+        return Ok(());
+    };
+    let mut node = hashv(&[user.as_ref()]).to_bytes();
+    for sibling in proof {
+        node = if node <= *sibling {
+            hashv(&[node.as_ref(), sibling.as_ref()])
+        } else {
+            hashv(&[sibling.as_ref(), node.as_ref()])
+        }
+        .to_bytes();
+    }
+    require!(node == root, StakingError::NotWhitelisted);
+    Ok(())
+}
+
+// Anchor has already checked that the metadata is owned by Token Metadata, so it can
+// only describe the mint it names; the token account must hold that mint
+fn verify_collection_holder(
+    nft_token: Option<&TokenAccount>,
+    nft_metadata: Option<&MetadataAccount>,
+    holder: &Pubkey,
+    collection: &Pubkey,
+) -> Result<()> {
+    let (Some(nft_token), Some(nft_metadata)) = (nft_token, nft_metadata) else {
+        return err!(StakingError::NotCollectionHolder);
+    };
+    let in_collection = nft_metadata
+        .collection
+        .as_ref()
+        .is_some_and(|c| c.verified && c.key == *collection);
+    require!(
+        in_collection
+            && nft_metadata.mint == nft_token.mint
+            && nft_token.owner == *holder
+            && nft_token.amount >= 1,
+        StakingError::NotCollectionHolder
+    );
+    Ok(())
+}
+
+// Tiers are ordered by duration; unused slots are all zero
+fn validate_lock_tiers(
+    durations: &[i64; MAX_LOCK_TIERS],
+    multipliers_bps: &[u16; MAX_LOCK_TIERS],
+) -> Result<()> {
+    let mut previous_duration = 0;
+    for (duration, multiplier_bps) in durations.iter().zip(multipliers_bps.iter()) {
+        if *multiplier_bps == 0 {
+            require!(*duration == 0, StakingError::InvalidLockTiers);
+            continue;
+        }
+        require!(
+            *duration > previous_duration && *multiplier_bps as u64 >= BPS_DENOMINATOR,
+            StakingError::InvalidLockTiers
+        );
+        previous_duration = *duration;
+    }
+    Ok(())
+}
+
+// 1, 10, 100 and so on; zero is not
+fn is_power_of_ten(mut value: u64) -> bool {
+    if value == 0 {
+        return false;
+    }
+    while value.is_multiple_of(10) {
+        value /= 10;
+    }
+    value == 1
+}
+
+// Names must be present; both fields are UTF-8 up to their zero padding
+fn validate_pool_metadata(
+    name: &[u8; POOL_NAME_LEN],
+    metadata_uri: &[u8; POOL_METADATA_URI_LEN],
+) -> Result<()> {
+    let unpadded = |bytes: &[u8]| {
+        let len = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+        std::str::from_utf8(&bytes[..len]).map(|text| text.len())
+    };
+    let name_len = unpadded(name).map_err(|_| StakingError::InvalidPoolMetadata)?;
+    require!(name_len > 0, StakingError::InvalidPoolMetadata);
+    unpadded(metadata_uri).map_err(|_| StakingError::InvalidPoolMetadata)?;
+    Ok(())
+}
+
+// Instruction parameters
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct InitializePoolParams {
+    /// Reward tokens per second paid per rate_scale weighted stake units, so emission grows
+    /// with TVL; with fixed_emission it is the whole pool's emission, split across stakers
+    pub reward_rate: u64,
+    /// Minimum time before unstaking allowed (seconds)
+    pub min_stake_duration: i64,
+    /// Wait between request_unstake and complete_unstake (seconds)
+    pub unbonding_period: i64,
+    /// Minimum seconds between two claims on the same position; 0 disables the cooldown
+    pub claim_cooldown: i64,
+    /// Seconds after each stake or top-up during which a position earns nothing; 0 disables
+    pub reward_warmup: i64,
+    /// Minimum lock period for each tier, ascending (seconds)
+    pub lock_tier_durations: [i64; 4],
+    /// Reward multiplier for each tier (10_000 = 1x); 0 marks an unused slot
+    pub lock_tier_multipliers_bps: [u16; 4],
+    /// Share of an early unstake paid into the reward vault; EARLY_UNSTAKE_DISABLED rejects early exits
+    pub early_unstake_penalty_bps: u16,
+    /// Share of each early-unstake penalty sent to the protocol treasury instead of the reward vault
+    pub penalty_treasury_bps: u16,
+    /// Cap on total rewards the pool will ever emit; 0 means uncapped
+    pub reward_budget: u64,
+    /// Reward tokens withdraw_excess_rewards must leave beyond what has been emitted but not paid
+    pub reward_reserve: u64,
+    /// Cap on the pool's total earning stake; 0 means unlimited
+    pub max_total_stake: u64,
+    /// Cap on a single position's stake; 0 means unlimited
+    pub max_user_stake: u64,
+    /// Smallest balance a position may hold after a stake
+    pub min_stake_amount: u64,
+    /// Only the pool authority may call fund_rewards when set
+    pub restrict_funders: bool,
+    /// Allows the stake and reward mints to be the same, as compound_rewards needs
+    pub allow_same_mint: bool,
+    /// reward_rate is the whole pool's emission when set; otherwise it is paid per
+    /// rate_scale weighted stake units, so total emission grows with TVL. Per-token rates
+    /// are scaled by 10^(reward_decimals - stake_decimals) between the mints' base units
+    pub fixed_emission: bool,
+    /// An empty pool rejects stakes until its reward vault holds something
+    pub require_prefunded: bool,
+    /// Stakers must hold an NFT from this verified Metaplex collection; None leaves staking open
+    pub gate_collection: Option<Pubkey>,
+    /// Positions opened before this unix timestamp earn the early-staker boost
+    pub early_staker_cutoff: i64,
+    /// Extra weight for early positions on top of their lock multiplier (2_000 = +20%)
+    pub early_staker_boost_bps: u16,
+    /// Rewards start accruing at this unix timestamp
+    pub reward_start_time: i64,
+    /// Rewards stop accruing at this unix timestamp; must be after reward_start_time
+    pub reward_end_time: i64,
+    /// Seconds between emission halvings, counted from reward_start_time; 0 disables halving
+    pub halving_interval: i64,
+    /// Shape of emission over the reward window: CURVE_LINEAR, CURVE_EASE_IN or CURVE_EASE_OUT
+    pub curve_type: u8,
+    /// Display name, zero-padded UTF-8; must not be empty
+    pub name: [u8; POOL_NAME_LEN],
+    /// Link to off-chain pool metadata, zero-padded UTF-8; may be empty
+    pub metadata_uri: [u8; POOL_METADATA_URI_LEN],
+    /// Entries kept by the snapshot ring buffer, at most MAX_SNAPSHOT_CAPACITY; 0 disables snapshots
+    pub snapshot_capacity: u16,
+    /// Extra share of pending rewards added when reinvest_with_bonus locks them into a new position
+    pub reinvest_bonus_bps: u16,
+    /// Round each settlement's fraction of a reward token up instead of carrying it forward.
+    /// Every settlement can then credit up to one base unit more than was emitted, so over a
+    /// pool's life payouts can exceed total_rewards_emitted by up to one unit per settlement.
+    /// That excess comes out of the reward vault's surplus and counts against reward_budget;
+    /// claims stay capped by the vault balance, so it can delay a payout but never overdraw
+    pub round_up_rewards: bool,
+    /// Most a single payout may withdraw, whether by claim, exit, compounding, reinvesting
+    /// or the stake-time drain, the rest staying pending; 0 = unlimited
+    pub max_claim_per_tx: u64,
+    /// Positions frozen by set_user_frozen stop earning until unfrozen instead of accruing
+    pub freeze_stops_accrual: bool,
+    /// Most of a position's balance that earns rewards; the rest still counts toward
+    /// total_staked and caps but earns nothing. 0 = no cap
+    pub max_rewarded_stake: u64,
+    /// Root of the Merkle tree of pubkeys allowed to stake, see verify_allowlist; None
+    /// leaves staking open
+    pub merkle_root: Option<[u8; 32]>,
+    /// Every claim_rewards creates a ClaimReceipt account, paid for by the claimer
+    pub emit_receipts: bool,
+    /// Seconds after reward_end_time that claims stay open, after which the authority may
+    /// sweep the reward vault with sweep_expired_rewards; 0 = claims never close
+    pub final_claim_grace: i64,
+    /// Weighted stake units a per-token reward_rate pays; a power of ten, usually
+    /// PER_TOKEN_RATE_PRECISION. A larger scale gives per-token rates finer steps
+    pub rate_scale: u64,
+}
+
+// Account structures
+
+#[derive(Accounts)]
+pub struct InitializePool<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + StakingPool::INIT_SPACE,
+        seeds = [b"pool", authority.key().as_ref()],
+        bump
+    )]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Signs for both vaults; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(mint::token_program = token_program)]
+    pub stake_token_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(mint::token_program = token_program)]
+    pub reward_token_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init,
+        payer = authority,
+        token::mint = stake_token_mint,
+        token::authority = vault_authority,
+        token::token_program = token_program,
+        seeds = [b"stake_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = authority,
+        token::mint = reward_token_mint,
+        token::authority = vault_authority,
+        token::token_program = token_program,
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+    pub rent: Sysvar<'info, Rent>,
+}
+
+#[derive(Accounts)]
+pub struct BootstrapPool<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + StakingPool::INIT_SPACE,
+        seeds = [b"pool", authority.key().as_ref()],
+        bump
+    )]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Signs for both vaults; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(mint::token_program = token_program)]
+    pub stake_token_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(mint::token_program = token_program)]
+    pub reward_token_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init,
+        payer = authority,
+        token::mint = stake_token_mint,
+        token::authority = vault_authority,
+        token::token_program = token_program,
+        seeds = [b"stake_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = authority,
+        token::mint = reward_token_mint,
+        token::authority = vault_authority,
+        token::token_program = token_program,
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// Source of the initial funding
+    #[account(
+        mut,
+        token::mint = reward_token_mint,
+        token::authority = authority,
+        token::token_program = token_program
+    )]
+    pub authority_reward_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = authority,
+        space = 8 + FunderRecord::INIT_SPACE,
+        seeds = [b"funder", pool.key().as_ref(), authority.key().as_ref()],
+        bump
+    )]
+    pub funder_record: Account<'info, FunderRecord>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(mut)]
+    pub admin: Signer<'info>,
+
+    #[account(
+        init,
+        payer = admin,
+        space = 8 + ProtocolConfig::INIT_SPACE,
+        seeds = [b"config"],
+        bump
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateConfig<'info> {
+    pub admin: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"config"],
+        bump = config.bump,
+        constraint = config.admin == admin.key() @ StakingError::UnauthorizedConfigAdmin
+    )]
+    pub config: Account<'info, ProtocolConfig>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64, amount: u64, lock_period: i64, beneficiary: Pubkey)]
+pub struct StakeTokens<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + UserStake::INIT_SPACE,
+        seeds = [b"user_stake", pool.key().as_ref(), beneficiary.as_ref(), &position_id.to_le_bytes()],
+        bump
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
+    #[account(
+        mut,
+        constraint = user_stake_token.key() != pool_stake_vault.key() @ StakingError::SelfTransferNotAllowed,
+        token::mint = pool.stake_token_mint,
+        token::authority = user,
+        token::token_program = token_program
+    )]
+    pub user_stake_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"stake_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    #[account(address = pool.stake_token_mint)]
+    pub stake_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+
+    /// The signer's token account for a collection NFT; required when the pool is gated
+    pub nft_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Token Metadata account describing nft_token's mint
+    pub nft_metadata: Option<Account<'info, MetadataAccount>>,
+
+    /// CHECK: Signs for the reward vault when pending rewards are paid out; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    /// The beneficiary's reward-mint account; required with the accounts below once
+    /// pending rewards pass u64::MAX and the stake pays them out
+    #[account(
+        mut,
+        token::mint = pool.reward_token_mint,
+        token::authority = beneficiary
+    )]
+    pub user_reward_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Option<Account<'info, ProtocolConfig>>,
+
+    /// The treasury's reward-mint account, verified against config in the handler
+    #[account(mut)]
+    pub treasury_reward_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(address = pool.reward_token_mint)]
+    pub reward_token_mint: Option<InterfaceAccount<'info, Mint>>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct StakeDelegated<'info> {
+    /// Submits the stake and pays for it
+    #[account(mut)]
+    pub relayer: Signer<'info>,
+
+    /// Owner of the stake tokens and of the position
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(
+        init_if_needed,
+        payer = relayer,
+        space = 8 + UserStake::INIT_SPACE,
+        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref(), &position_id.to_le_bytes()],
+        bump
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
+    #[account(
+        mut,
+        constraint = user_stake_token.key() != pool_stake_vault.key() @ StakingError::SelfTransferNotAllowed,
+        token::mint = pool.stake_token_mint,
+        token::authority = user,
+        token::token_program = token_program
+    )]
+    pub user_stake_token: InterfaceAccount<'info, TokenAccount>,
+
+    /// CHECK: PDA approved as the delegate of user_stake_token; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"stake_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    #[account(address = pool.stake_token_mint)]
+    pub stake_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+
+    /// The user's token account for a collection NFT; required when the pool is gated
+    pub nft_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Token Metadata account describing nft_token's mint
+    pub nft_metadata: Option<Account<'info, MetadataAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64, amount: u64, lock_period: i64, beneficiary: Pubkey)]
+pub struct StakeSol<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = pool.stake_token_mint == native_mint::ID @ StakingError::NotNativeMintPool
+    )]
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + UserStake::INIT_SPACE,
+        seeds = [b"user_stake", pool.key().as_ref(), beneficiary.as_ref(), &position_id.to_le_bytes()],
+        bump
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
+    #[account(
+        mut,
+        seeds = [b"stake_vault", pool.key().as_ref()],
+        bump,
+        token::token_program = token_program
+    )]
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+
+    /// The signer's token account for a collection NFT; required when the pool is gated
+    pub nft_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Token Metadata account describing nft_token's mint
+    pub nft_metadata: Option<Account<'info, MetadataAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64, new_position_id: u64)]
+pub struct MigrateStake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    /// Pool the position leaves
+    #[account(mut)]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Signs for the old pool's stake vault; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.key().as_ref(), user_stake.user.as_ref(), &position_id.to_le_bytes()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key() @ StakingError::NotBeneficiary,
+        constraint = !user_stake.frozen @ StakingError::UserFrozen
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
+    #[account(
+        mut,
+        seeds = [b"stake_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    /// Pool the position moves into
+    #[account(
+        mut,
+        constraint = new_pool.key() != pool.key()
+            && new_pool.authority == pool.authority
+            && new_pool.stake_token_mint == pool.stake_token_mint @ StakingError::InvalidMigrationTarget
+    )]
+    pub new_pool: Account<'info, StakingPool>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + UserStake::INIT_SPACE,
+        seeds = [b"user_stake", new_pool.key().as_ref(), user.key().as_ref(), &new_position_id.to_le_bytes()],
+        bump
+    )]
+    pub new_user_stake: Account<'info, UserStake>,
+
+    #[account(
+        mut,
+        seeds = [b"stake_vault", new_pool.key().as_ref()],
+        bump
+    )]
+    pub new_pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        seeds = [b"reward_vault", new_pool.key().as_ref()],
+        bump
+    )]
+    pub new_pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.stake_token_mint)]
+    pub stake_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+
+    /// The signer's token account for a collection NFT; required when the new pool is gated
+    pub nft_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Token Metadata account describing nft_token's mint
+    pub nft_metadata: Option<Account<'info, MetadataAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct Unstake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Signs for both vaults; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.key().as_ref(), user_stake.user.as_ref(), &position_id.to_le_bytes()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key() @ StakingError::NotBeneficiary,
+        constraint = !user_stake.frozen @ StakingError::UserFrozen
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
+    #[account(
+        mut,
+        constraint = user_stake_token.key() != pool_stake_vault.key() @ StakingError::SelfTransferNotAllowed,
+        constraint = user_stake_token.owner == user.key(),
+        constraint = user_stake_token.mint == pool.stake_token_mint
+    )]
+    pub user_stake_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"stake_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.stake_token_mint)]
+    pub stake_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+
+    /// Names the treasury; required with treasury_stake_token when a penalty has a treasury cut
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Option<Account<'info, ProtocolConfig>>,
+
+    /// The treasury's stake-mint account that receives penalty_treasury_bps of a penalty
+    #[account(mut)]
+    pub treasury_stake_token: Option<InterfaceAccount<'info, TokenAccount>>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct Exit<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Signs for both vaults; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.key().as_ref(), user_stake.user.as_ref(), &position_id.to_le_bytes()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key() @ StakingError::NotBeneficiary,
+        constraint = !user_stake.frozen @ StakingError::UserFrozen
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
+    #[account(
+        mut,
+        constraint = user_stake_token.key() != pool_stake_vault.key() @ StakingError::SelfTransferNotAllowed,
+        constraint = user_stake_token.owner == user.key(),
+        constraint = user_stake_token.mint == pool.stake_token_mint
+    )]
+    pub user_stake_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = user_reward_token.key() != pool_reward_vault.key() @ StakingError::SelfTransferNotAllowed,
+        constraint = user_reward_token.mint == pool.reward_token_mint
+    )]
+    pub user_reward_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"stake_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProtocolConfig>,
+
+    #[account(
+        mut,
+        constraint = treasury_reward_token.owner == config.fee_treasury,
+        constraint = treasury_reward_token.mint == pool.reward_token_mint
+    )]
+    pub treasury_reward_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.stake_token_mint)]
+    pub stake_token_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(address = pool.reward_token_mint)]
+    pub reward_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+
+    /// The reward leg takes the same optional accounts as claim_rewards, see ClaimRewards
+    pub boost_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub parent_pool: Option<Account<'info, StakingPool>>,
+
+    /// CHECK: Verified against parent_pool's vault authority seeds in the handler
+    pub parent_vault_authority: Option<UncheckedAccount<'info>>,
+
+    #[account(mut)]
+    pub parent_reward_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub pool_reward_vault_2: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub user_reward_token_2: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    pub reward_token_mint_2: Option<InterfaceAccount<'info, Mint>>,
+
+    /// Required when the pool has emit_receipts and the exit pays rewards
+    #[account(
+        init,
+        payer = user,
+        space = 8 + ClaimReceipt::INIT_SPACE,
+        seeds = [
+            b"claim_receipt",
+            pool.key().as_ref(),
+            user_stake.user.as_ref(),
+            &position_id.to_le_bytes(),
+            &user_stake.claim_count.to_le_bytes(),
+        ],
+        bump
+    )]
+    pub claim_receipt: Option<Account<'info, ClaimReceipt>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct EmergencyUnstake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Signs for both vaults; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref(), &position_id.to_le_bytes()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key(),
+        constraint = !user_stake.frozen @ StakingError::UserFrozen
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
+    #[account(
+        mut,
+        constraint = user_stake_token.key() != pool_stake_vault.key() @ StakingError::SelfTransferNotAllowed,
+        constraint = user_stake_token.owner == user.key(),
+        constraint = user_stake_token.mint == pool.stake_token_mint
+    )]
+    pub user_stake_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"stake_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.stake_token_mint)]
+    pub stake_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct RequestUnstake<'info> {
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref(), &position_id.to_le_bytes()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key(),
+        constraint = !user_stake.frozen @ StakingError::UserFrozen
+    )]
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64, split_amount: u64, new_position_id: u64)]
+pub struct SplitPosition<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.key().as_ref(), user_stake.user.as_ref(), &position_id.to_le_bytes()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key() @ StakingError::NotBeneficiary,
+        constraint = !user_stake.frozen @ StakingError::UserFrozen
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
+    #[account(
+        init,
+        payer = user,
+        space = 8 + UserStake::INIT_SPACE,
+        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref(), &new_position_id.to_le_bytes()],
+        bump
+    )]
+    pub new_user_stake: Account<'info, UserStake>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64, new_owner: Pubkey)]
+pub struct TransferStake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(
+        mut,
+        close = user,
+        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref(), &position_id.to_le_bytes()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key() @ StakingError::NotBeneficiary,
+        constraint = !user_stake.frozen @ StakingError::UserFrozen
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
+    #[account(
+        init,
+        payer = user,
+        space = 8 + UserStake::INIT_SPACE,
+        seeds = [b"user_stake", pool.key().as_ref(), new_owner.as_ref(), &position_id.to_le_bytes()],
+        bump
+    )]
+    pub new_user_stake: Account<'info, UserStake>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct Restake<'info> {
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref(), &position_id.to_le_bytes()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key(),
+        constraint = !user_stake.frozen @ StakingError::UserFrozen
+    )]
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct CompleteUnstake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Signs for both vaults; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref(), &position_id.to_le_bytes()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key(),
+        constraint = !user_stake.frozen @ StakingError::UserFrozen
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
+    #[account(
+        mut,
+        constraint = user_stake_token.key() != pool_stake_vault.key() @ StakingError::SelfTransferNotAllowed,
+        constraint = user_stake_token.owner == user.key(),
+        constraint = user_stake_token.mint == pool.stake_token_mint
+    )]
+    pub user_stake_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"stake_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.stake_token_mint)]
+    pub stake_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct ClaimRewards<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(mut)]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Signs for both vaults; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.key().as_ref(), user_stake.user.as_ref(), &position_id.to_le_bytes()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key() @ StakingError::NotBeneficiary,
+        constraint = !user_stake.frozen @ StakingError::UserFrozen
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
+    /// Where the rewards are paid; any reward-mint account, not necessarily the user's own
+    #[account(
+        mut,
+        constraint = user_reward_token.key() != pool_reward_vault.key() @ StakingError::SelfTransferNotAllowed,
+        constraint = user_reward_token.mint == pool.reward_token_mint
+    )]
+    pub user_reward_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProtocolConfig>,
+
+    #[account(
+        mut,
+        constraint = treasury_reward_token.owner == config.fee_treasury,
+        constraint = treasury_reward_token.mint == pool.reward_token_mint
+    )]
+    pub treasury_reward_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.reward_token_mint)]
+    pub reward_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+
+    /// The beneficiary's boost_mint account; omit it to claim without a boost
+    pub boost_token: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Required with the two accounts below when the pool has a parent_pool
+    #[account(mut)]
+    pub parent_pool: Option<Account<'info, StakingPool>>,
+
+    /// CHECK: Verified against parent_pool's vault authority seeds in the handler
+    pub parent_vault_authority: Option<UncheckedAccount<'info>>,
+
+    /// The parent's reward vault, verified against its seeds in the handler
+    #[account(mut)]
+    pub parent_reward_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Required with the two accounts below when the pool has a second reward token;
+    /// verified against its seeds in the handler
+    #[account(mut)]
+    pub pool_reward_vault_2: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Where the second reward is paid; any account of the second reward mint
+    #[account(mut)]
+    pub user_reward_token_2: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    pub reward_token_mint_2: Option<InterfaceAccount<'info, Mint>>,
+
+    /// Required when the position auto-compounds; verified against its seeds in the handler
+    #[account(mut)]
+    pub pool_stake_vault: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    /// Required when the pool has emit_receipts; created for this claim
+    #[account(
+        init,
+        payer = user,
+        space = 8 + ClaimReceipt::INIT_SPACE,
+        seeds = [
+            b"claim_receipt",
+            pool.key().as_ref(),
+            user_stake.user.as_ref(),
+            &position_id.to_le_bytes(),
+            &user_stake.claim_count.to_le_bytes(),
+        ],
+        bump
+    )]
+    pub claim_receipt: Option<Account<'info, ClaimReceipt>>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct SetAutoCompound<'info> {
+    pub user: Signer<'info>,
+
+    #[account(
+        constraint = pool.allow_same_mint && pool.stake_token_mint == pool.reward_token_mint
+            @ StakingError::CompoundNotSupported
+    )]
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref(), &position_id.to_le_bytes()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key() @ StakingError::NotBeneficiary,
+        constraint = !user_stake.frozen @ StakingError::UserFrozen
+    )]
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+pub struct FundRewards<'info> {
+    #[account(mut)]
+    pub funder: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = !pool.restrict_funders || pool.authority == funder.key() @ StakingError::FunderNotAuthorized
+    )]
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(
+        mut,
+        constraint = funder_token_account.owner == funder.key(),
+        constraint = funder_token_account.mint == pool.reward_token_mint
+    )]
+    pub funder_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = funder,
+        space = 8 + FunderRecord::INIT_SPACE,
+        seeds = [b"funder", pool.key().as_ref(), funder.key().as_ref()],
+        bump
+    )]
+    pub funder_record: Account<'info, FunderRecord>,
+
+    #[account(address = pool.reward_token_mint)]
+    pub reward_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitializeSecondReward<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = pool.authority == authority.key() @ StakingError::UnauthorizedAuthority,
+        constraint = pool.reward_token_mint_2.is_none() @ StakingError::SecondRewardAlreadySet
+    )]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Signs for the pool's vaults; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mint::token_program = token_program,
+        constraint = reward_token_mint_2.key() != pool.reward_token_mint @ StakingError::InvalidSecondReward
+    )]
+    pub reward_token_mint_2: InterfaceAccount<'info, Mint>,
+
+    #[account(
+        init,
+        payer = authority,
+        token::mint = reward_token_mint_2,
+        token::authority = vault_authority,
+        token::token_program = token_program,
+        seeds = [b"reward_vault_2", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault_2: InterfaceAccount<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct FundSecondReward<'info> {
+    #[account(mut)]
+    pub funder: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = !pool.restrict_funders || pool.authority == funder.key() @ StakingError::FunderNotAuthorized
+    )]
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(
+        mut,
+        constraint = funder_token_account.owner == funder.key(),
+        constraint = funder_token_account.mint == reward_token_mint_2.key()
+    )]
+    pub funder_token_account: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"reward_vault_2", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault_2: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = funder,
+        space = 8 + FunderRecord::INIT_SPACE,
+        seeds = [b"funder_2", pool.key().as_ref(), funder.key().as_ref()],
+        bump
+    )]
+    pub funder_record: Account<'info, FunderRecord>,
+
+    #[account(constraint = pool.reward_token_mint_2 == Some(reward_token_mint_2.key()) @ StakingError::InvalidSecondReward)]
+    pub reward_token_mint_2: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct FundRewardsFromPda<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = !pool.restrict_funders @ StakingError::FunderNotAuthorized
+    )]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Derived from the escrow seeds in the handler; signs for escrow_token
+    pub escrow_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = escrow_token.owner == escrow_authority.key(),
+        constraint = escrow_token.mint == pool.reward_token_mint
+    )]
+    pub escrow_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + FunderRecord::INIT_SPACE,
+        seeds = [b"funder", pool.key().as_ref(), escrow_authority.key().as_ref()],
+        bump
+    )]
+    pub funder_record: Account<'info, FunderRecord>,
+
+    #[account(address = pool.reward_token_mint)]
+    pub reward_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ExtendCampaign<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = pool.authority == authority.key() @ StakingError::UnauthorizedAuthority
+    )]
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(
+        mut,
+        constraint = authority_reward_token.owner == authority.key(),
+        constraint = authority_reward_token.mint == pool.reward_token_mint
+    )]
+    pub authority_reward_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = authority,
+        space = 8 + FunderRecord::INIT_SPACE,
+        seeds = [b"funder", pool.key().as_ref(), authority.key().as_ref()],
+        bump
+    )]
+    pub funder_record: Account<'info, FunderRecord>,
+
+    #[account(address = pool.reward_token_mint)]
+    pub reward_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateUserStake<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: An older layout can't be deserialized as UserStake; the handler checks the
+    /// owner and discriminator before growing it
+    #[account(mut)]
+    pub user_stake: UncheckedAccount<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct CloseUserStake<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(
+        mut,
+        close = user,
+        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref(), &position_id.to_le_bytes()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key()
+    )]
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64)]
+pub struct CompoundRewards<'info> {
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = pool.allow_same_mint && pool.stake_token_mint == pool.reward_token_mint
+            @ StakingError::CompoundNotSupported
+    )]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Signs for both vaults; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref(), &position_id.to_le_bytes()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key(),
+        constraint = !user_stake.frozen @ StakingError::UserFrozen
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
+    #[account(
+        mut,
+        seeds = [b"stake_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.stake_token_mint)]
+    pub stake_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+#[instruction(position_id: u64, new_position_id: u64)]
+pub struct ReinvestWithBonus<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = pool.allow_same_mint && pool.stake_token_mint == pool.reward_token_mint
+            @ StakingError::CompoundNotSupported
+    )]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Signs for both vaults; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref(), &position_id.to_le_bytes()],
+        bump = user_stake.bump,
+        constraint = user_stake.user == user.key() @ StakingError::NotBeneficiary,
+        constraint = !user_stake.frozen @ StakingError::UserFrozen
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
+    #[account(
+        init,
+        payer = user,
+        space = 8 + UserStake::INIT_SPACE,
+        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref(), &new_position_id.to_le_bytes()],
+        bump
+    )]
+    pub new_user_stake: Account<'info, UserStake>,
+
+    #[account(
+        mut,
+        seeds = [b"stake_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.stake_token_mint)]
+    pub stake_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawExcessRewards<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = pool.authority == authority.key() @ StakingError::UnauthorizedAuthority
+    )]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Signs for both vaults; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = authority_reward_token.owner == authority.key(),
+        constraint = authority_reward_token.mint == pool.reward_token_mint
+    )]
+    pub authority_reward_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.reward_token_mint)]
+    pub reward_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct ClosePool<'info> {
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        close = authority,
+        constraint = pool.authority == authority.key() @ StakingError::UnauthorizedAuthority
+    )]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Signs for both vaults; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"stake_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = authority_stake_token.owner == authority.key(),
+        constraint = authority_stake_token.mint == pool.stake_token_mint
+    )]
+    pub authority_stake_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = authority_reward_token.owner == authority.key(),
+        constraint = authority_reward_token.mint == pool.reward_token_mint
+    )]
+    pub authority_reward_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.stake_token_mint)]
+    pub stake_token_mint: InterfaceAccount<'info, Mint>,
+
+    #[account(address = pool.reward_token_mint)]
+    pub reward_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+
+    /// Required when the pool has a second reward token; verified in the handler
+    #[account(mut)]
+    pub pool_reward_vault_2: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    #[account(mut)]
+    pub authority_reward_token_2: Option<InterfaceAccount<'info, TokenAccount>>,
+
+    pub reward_token_mint_2: Option<InterfaceAccount<'info, Mint>>,
 }
 
-// Account structures
+#[derive(Accounts)]
+pub struct Slash<'info> {
+    pub authority: Signer<'info>,
+
+    #[account(
+        mut,
+        constraint = pool.authority == authority.key() @ StakingError::UnauthorizedAuthority
+    )]
+    pub pool: Account<'info, StakingPool>,
+
+    /// CHECK: Signs for both vaults; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        constraint = user_stake.pool == pool.key()
+    )]
+    pub user_stake: Account<'info, UserStake>,
+
+    #[account(
+        mut,
+        seeds = [b"stake_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        constraint = slash_destination.mint == pool.stake_token_mint
+    )]
+    pub slash_destination: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(address = pool.stake_token_mint)]
+    pub stake_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
+}
 
 #[derive(Accounts)]
-pub struct InitializePool<'info> {
-    #[account(mut)]
+pub struct AdminReturnStake<'info> {
     pub authority: Signer<'info>,
 
     #[account(
-        init,
-        payer = authority,
-        space = 8 + StakingPool::INIT_SPACE,
-        seeds = [b"pool", authority.key().as_ref()],
-        bump
+        mut,
+        constraint = pool.authority == authority.key() @ StakingError::UnauthorizedAuthority
     )]
     pub pool: Account<'info, StakingPool>,
 
-    pub stake_token_mint: Account<'info, Mint>,
-    pub reward_token_mint: Account<'info, Mint>,
+    /// CHECK: Signs for both vaults; holds no data
+    #[account(seeds = [b"vault_authority", pool.key().as_ref()], bump = pool.vault_authority_bump)]
+    pub vault_authority: UncheckedAccount<'info>,
 
     #[account(
-        init,
-        payer = authority,
-        token::mint = stake_token_mint,
-        token::authority = pool,
-        seeds = [b"stake_vault", pool.key().as_ref()],
-        bump
+        mut,
+        constraint = user_stake.pool == pool.key()
     )]
-    pub pool_stake_vault: Account<'info, TokenAccount>,
+    pub user_stake: Account<'info, UserStake>,
 
     #[account(
-        init,
-        payer = authority,
-        token::mint = reward_token_mint,
-        token::authority = pool,
-        seeds = [b"reward_vault", pool.key().as_ref()],
+        mut,
+        constraint = user_stake_token.key() != pool_stake_vault.key() @ StakingError::SelfTransferNotAllowed,
+        constraint = user_stake_token.owner == user_stake.user,
+        constraint = user_stake_token.mint == pool.stake_token_mint
+    )]
+    pub user_stake_token: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        seeds = [b"stake_vault", pool.key().as_ref()],
         bump
     )]
-    pub pool_reward_vault: Account<'info, TokenAccount>,
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
 
-    pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
-    pub rent: Sysvar<'info, Rent>,
+    #[account(address = pool.stake_token_mint)]
+    pub stake_token_mint: InterfaceAccount<'info, Mint>,
+
+    pub token_program: Interface<'info, TokenInterface>,
 }
 
 #[derive(Accounts)]
-pub struct StakeTokens<'info> {
+pub struct Poke<'info> {
     #[account(mut)]
-    pub user: Signer<'info>,
+    pub pool: Account<'info, StakingPool>,
+}
+
+#[derive(Accounts)]
+pub struct AssertFresh<'info> {
+    pub pool: Account<'info, StakingPool>,
+}
+
+#[derive(Accounts)]
+pub struct Snapshot<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
 
     #[account(mut)]
     pub pool: Account<'info, StakingPool>,
 
     #[account(
         init_if_needed,
-        payer = user,
-        space = 8 + UserStake::INIT_SPACE,
-        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref()],
+        payer = payer,
+        space = RewardSnapshots::space(pool.snapshot_capacity),
+        seeds = [b"snapshots", pool.key().as_ref()],
         bump
     )]
+    pub snapshots: Account<'info, RewardSnapshots>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SettleMany<'info> {
+    #[account(mut)]
+    pub pool: Account<'info, StakingPool>,
+}
+
+#[derive(Accounts)]
+pub struct RewardsPerTokenStored<'info> {
+    pub pool: Account<'info, StakingPool>,
+}
+
+#[derive(Accounts)]
+pub struct GetPendingRewards<'info> {
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(constraint = user_stake.pool == pool.key())]
+    pub user_stake: Account<'info, UserStake>,
+}
+
+#[derive(Accounts)]
+pub struct QuoteRewards<'info> {
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(constraint = user_stake.pool == pool.key())]
     pub user_stake: Account<'info, UserStake>,
 
     #[account(
-        mut,
-        token::mint = pool.stake_token_mint,
-        token::authority = user
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
     )]
-    pub user_stake_token: Account<'info, TokenAccount>,
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
+
+    #[account(seeds = [b"config"], bump = config.bump)]
+    pub config: Account<'info, ProtocolConfig>,
+}
+
+#[derive(Accounts)]
+pub struct EstimatePenalty<'info> {
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(constraint = user_stake.pool == pool.key())]
+    pub user_stake: Account<'info, UserStake>,
 
     #[account(
-        mut,
         seeds = [b"stake_vault", pool.key().as_ref()],
         bump
     )]
-    pub pool_stake_vault: Account<'info, TokenAccount>,
+    pub pool_stake_vault: InterfaceAccount<'info, TokenAccount>,
+}
 
-    pub system_program: Program<'info, System>,
-    pub token_program: Program<'info, Token>,
+#[derive(Accounts)]
+pub struct PoolStatsView<'info> {
+    pub pool: Account<'info, StakingPool>,
+
+    #[account(
+        seeds = [b"reward_vault", pool.key().as_ref()],
+        bump
+    )]
+    pub pool_reward_vault: InterfaceAccount<'info, TokenAccount>,
 }
 
 #[derive(Accounts)]
-pub struct Unstake<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
+pub struct RewardTokenInfoView<'info> {
+    pub pool: Account<'info, StakingPool>,
 
-    #[account(mut)]
+    #[account(address = pool.reward_token_mint)]
+    pub reward_token_mint: InterfaceAccount<'info, Mint>,
+}
+
+#[derive(Accounts)]
+pub struct CurrentAprView<'info> {
+    pub pool: Account<'info, StakingPool>,
+}
+
+#[derive(Accounts)]
+pub struct PreviewStake<'info> {
     pub pool: Account<'info, StakingPool>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateRewardRate<'info> {
+    pub authority: Signer<'info>,
 
     #[account(
         mut,
-        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref()],
-        bump = user_stake.bump,
-        constraint = user_stake.user == user.key()
+        constraint = pool.authority == authority.key() @ StakingError::UnauthorizedAuthority
     )]
-    pub user_stake: Account<'info, UserStake>,
+    pub pool: Account<'info, StakingPool>,
+}
+
+#[derive(Accounts)]
+pub struct UpdateMinDuration<'info> {
+    pub authority: Signer<'info>,
 
     #[account(
         mut,
-        constraint = user_stake_token.owner == user.key(),
-        constraint = user_stake_token.mint == pool.stake_token_mint
+        constraint = pool.authority == authority.key() @ StakingError::UnauthorizedAuthority
     )]
-    pub user_stake_token: Account<'info, TokenAccount>,
+    pub pool: Account<'info, StakingPool>,
+}
+
+#[derive(Accounts)]
+pub struct SetPoolMetadata<'info> {
+    pub authority: Signer<'info>,
 
     #[account(
         mut,
-        seeds = [b"stake_vault", pool.key().as_ref()],
-        bump
+        constraint = pool.authority == authority.key() @ StakingError::UnauthorizedAuthority
     )]
-    pub pool_stake_vault: Account<'info, TokenAccount>,
-
-    pub token_program: Program<'info, Token>,
+    pub pool: Account<'info, StakingPool>,
 }
 
 #[derive(Accounts)]
-pub struct ClaimRewards<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
+pub struct SetBoostConfig<'info> {
+    pub authority: Signer<'info>,
 
-    #[account(mut)]
+    #[account(
+        mut,
+        constraint = pool.authority == authority.key() @ StakingError::UnauthorizedAuthority
+    )]
     pub pool: Account<'info, StakingPool>,
 
+    /// Governance token holders are boosted for; None disables the boost
+    pub boost_mint: Option<InterfaceAccount<'info, Mint>>,
+}
+
+#[derive(Accounts)]
+pub struct LinkChildPool<'info> {
+    pub parent_authority: Signer<'info>,
+
+    pub child_authority: Signer<'info>,
+
     #[account(
-        mut,
-        seeds = [b"user_stake", pool.key().as_ref(), user.key().as_ref()],
-        bump = user_stake.bump,
-        constraint = user_stake.user == user.key()
+        constraint = parent_pool.authority == parent_authority.key() @ StakingError::UnauthorizedAuthority,
+        constraint = parent_pool.parent_pool.is_none() @ StakingError::InvalidParentPool
     )]
-    pub user_stake: Account<'info, UserStake>,
+    pub parent_pool: Account<'info, StakingPool>,
 
     #[account(
         mut,
-        constraint = user_reward_token.owner == user.key(),
-        constraint = user_reward_token.mint == pool.reward_token_mint
+        constraint = child_pool.authority == child_authority.key() @ StakingError::UnauthorizedAuthority,
+        constraint = child_pool.key() != parent_pool.key() @ StakingError::InvalidParentPool,
+        constraint = child_pool.parent_pool.is_none() @ StakingError::InvalidParentPool,
+        constraint = child_pool.reward_token_mint == parent_pool.reward_token_mint @ StakingError::InvalidParentPool
     )]
-    pub user_reward_token: Account<'info, TokenAccount>,
+    pub child_pool: Account<'info, StakingPool>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeAuthority<'info> {
+    pub authority: Signer<'info>,
 
     #[account(
         mut,
-        seeds = [b"reward_vault", pool.key().as_ref()],
-        bump
+        constraint = pool.authority == authority.key() @ StakingError::UnauthorizedAuthority
     )]
-    pub pool_reward_vault: Account<'info, TokenAccount>,
-
-    pub token_program: Program<'info, Token>,
+    pub pool: Account<'info, StakingPool>,
 }
 
 #[derive(Accounts)]
-pub struct FundRewards<'info> {
-    #[account(mut)]
-    pub funder: Signer<'info>,
+pub struct AcceptAuthority<'info> {
+    pub new_authority: Signer<'info>,
 
     #[account(mut)]
     pub pool: Account<'info, StakingPool>,
+}
+
+#[derive(Accounts)]
+pub struct SetPause<'info> {
+    pub authority: Signer<'info>,
 
     #[account(
         mut,
-        constraint = funder_token_account.owner == funder.key(),
-        constraint = funder_token_account.mint == pool.reward_token_mint
+        constraint = pool.authority == authority.key() @ StakingError::UnauthorizedAuthority
     )]
-    pub funder_token_account: Account<'info, TokenAccount>,
+    pub pool: Account<'info, StakingPool>,
+}
+
+#[derive(Accounts)]
+pub struct SetUserFrozen<'info> {
+    pub authority: Signer<'info>,
 
     #[account(
         mut,
-        seeds = [b"reward_vault", pool.key().as_ref()],
-        bump
+        constraint = pool.authority == authority.key() @ StakingError::UnauthorizedAuthority
     )]
-    pub pool_reward_vault: Account<'info, TokenAccount>,
+    pub pool: Account<'info, StakingPool>,
 
-    pub token_program: Program<'info, Token>,
+    #[account(mut, constraint = user_stake.pool == pool.key())]
+    pub user_stake: Account<'info, UserStake>,
 }
 
 // Data accounts
@@ -397,25 +5233,293 @@ pub struct FundRewards<'info> {
 #[derive(InitSpace)]
 pub struct StakingPool {
     pub authority: Pubkey,
+    pub creator: Pubkey,            // Original authority; seeds the pool PDA and never changes
+    pub pending_authority: Option<Pubkey>, // Proposed authority awaiting accept_authority
+    pub parent_pool: Option<Pubkey>, // Pool whose reward vault pays this pool's claims, see link_child_pool
+    pub name: [u8; POOL_NAME_LEN],  // Display name for pool listings, zero-padded UTF-8
+    pub metadata_uri: [u8; POOL_METADATA_URI_LEN], // Off-chain metadata for pool listings, zero-padded UTF-8
     pub stake_token_mint: Pubkey,
     pub reward_token_mint: Pubkey,
-    pub reward_rate: u64,           // Rewards per second per token (scaled by 1e9)
+    pub stake_decimals: u8,         // Recorded from the stake mint; scales per-token rates
+    pub reward_decimals: u8,        // Recorded from the reward mint; scales per-token rates
+    pub reward_rate: u64,           // Reward tokens emitted per second, see fixed_emission
+    pub reward_token_mint_2: Option<Pubkey>, // Second reward token, see initialize_second_reward
+    pub reward_decimals_2: u8,      // Recorded from reward_token_mint_2 for clients
+    pub reward_rate_2: u64,         // Second reward tokens emitted per second across the pool
+    pub apy_bps: u16,               // Yearly yield per staked token, 0 = emission follows reward_rate
     pub min_stake_duration: i64,    // Minimum time before unstaking allowed (seconds)
-    pub total_staked: u64,
+    pub unbonding_period: i64,      // Wait between request_unstake and complete_unstake (seconds)
+    pub claim_cooldown: i64,        // Minimum seconds between a position's claims, 0 = none
+    pub reward_warmup: i64,         // Seconds past a position's warmup_start that earn nothing, 0 = none
+    pub lock_tier_durations: [i64; 4],       // Minimum lock period per tier, ascending (seconds)
+    pub lock_tier_multipliers_bps: [u16; 4], // Reward multiplier per tier (10_000 = 1x), 0 = unused
+    pub early_unstake_penalty_bps: u16,      // Charged on exits before min_stake_duration, u16::MAX = reject
+    pub penalty_treasury_bps: u16,           // Share of each penalty paid to the protocol treasury
+    pub reward_budget: u64,         // Cap on total emission, 0 = uncapped
+    pub reward_reserve: u64,        // Reward tokens withdraw_excess_rewards always leaves behind
+    pub max_total_stake: u64,       // Cap on total_staked, 0 = unlimited
+    pub max_user_stake: u64,        // Cap on a single position's amount, 0 = unlimited
+    pub min_stake_amount: u64,      // Smallest position balance a stake may leave
+    pub restrict_funders: bool,     // Limits fund_rewards to the authority while set
+    pub allow_same_mint: bool,      // Stake and reward mints may match; required for compounding
+    pub reinvest_bonus_bps: u16,    // Bonus on rewards reinvest_with_bonus locks into a new position
+    pub round_up_rewards: bool,     // Settlements round the reward fraction up, see InitializePoolParams
+    pub max_claim_per_tx: u64,      // Cap on any single reward payout, 0 = unlimited
+    pub fixed_emission: bool,       // reward_rate is per pool when set, per rate_scale weighted stake otherwise
+    pub require_prefunded: bool,    // First stake into an empty pool needs a funded reward vault
+    pub gate_collection: Option<Pubkey>, // Verified collection a staker must hold an NFT from
+    pub early_staker_cutoff: i64,   // Positions opened before this time earn early_staker_boost_bps
+    pub early_staker_boost_bps: u16, // Extra weight for early positions, 0 = no boost
+    pub freeze_stops_accrual: bool, // Frozen positions carry no weight while set, see set_user_frozen
+    pub max_rewarded_stake: u64,    // Cap on the part of a position that earns, 0 = no cap
+    pub rate_scale: u64,            // Weighted stake units a per-token reward_rate pays
+    pub merkle_root: Option<[u8; 32]>, // Allowlist stakers must prove membership of, None = open
+    pub emit_receipts: bool,        // Claims create a ClaimReceipt while set
+    pub final_claim_grace: i64,     // Claims close this long after reward_end_time, 0 = never
+    pub boost_mint: Option<Pubkey>, // Governance token whose holders earn a claim boost
+    pub boost_decimals: u8,         // Recorded from boost_mint so whole tokens can be counted
+    pub boost_per_token_bps: u16,   // Claim boost per whole boost token held
+    pub max_boost_bps: u16,         // Cap on a claim's boost
+    pub reward_start_time: i64,     // No rewards accrue before this time
+    pub reward_end_time: i64,       // No rewards accrue after this time
+    pub halving_interval: i64,      // Seconds per halving epoch from reward_start_time, 0 = never
+    pub curve_type: u8,             // Emission curve over the reward window, see CURVE_LINEAR
+    pub initial_reward_rate: u64,   // Undecayed rate the halving schedule starts from
+    pub total_rewards_emitted: u64, // Rewards credited to the accumulator so far
+    pub total_rewards_distributed: u64, // Rewards paid out by claims and compounding
+    pub total_rewards_funded: u64,  // Reward tokens deposited through fund_rewards
+    pub total_staked: u64,          // Earning stake; excludes tokens that are unbonding
+    pub total_unbonding: u64,       // Tokens waiting on complete_unstake, still held in the stake vault
+    pub total_weighted_stake: u64,  // Earning stake after lock multipliers; rewards split across this
+    pub acc_reward_per_share: u128, // Accumulated rewards per staked token (scaled by ACC_REWARD_PRECISION)
+    pub acc_reward_remainder: u128, // Scaled emission left over from dividing into acc_reward_per_share
+    pub acc_reward_per_share_2: u128, // acc_reward_per_share for the second reward token
+    pub acc_reward_remainder_2: u128, // acc_reward_remainder for the second reward token
+    pub total_rewards_distributed_2: u64, // Second reward tokens paid out by claims
+    pub total_rewards_funded_2: u64, // Second reward tokens deposited through fund_second_reward
+    pub last_update_time: i64,      // Last time acc_reward_per_share was advanced
+    pub paused: bool,               // Blocks new stakes while set
+    pub rewards_paused: bool,       // Stops emission while set; stakes and exits are unaffected
+    pub rewards_paused_at: i64,     // When emission was last paused
+    pub emergency_mode: bool,       // Set by set_emergency_mode; unstake skips locks and penalties
+    pub reward_stream: RewardStream, // Deposit from fund_rewards_streamed still vesting, if any
+    pub snapshot_capacity: u16,     // Entries in the snapshot ring buffer, 0 = snapshots disabled
     pub bump: u8,
+    pub vault_authority_bump: u8,   // PDA that owns both vaults, seeds [b"vault_authority", pool]
 }
 
+// Layout note: pending_rewards was widened from u64 to u128, growing INIT_SPACE by
+// 8 bytes and shifting every later field. Positions created before that change
+// cannot be deserialized; they must be exited and closed under the old program
+// before upgrading. From `version` on, new fields are only ever appended, ahead of
+// the trailing locked_duration, so migrate_user_stake can grow older positions in place.
 #[account]
 #[derive(InitSpace)]
 pub struct UserStake {
-    pub user: Pubkey,
+    pub user: Pubkey,               // Beneficiary; owns the position and its rewards
     pub pool: Pubkey,
+    pub position_id: u64,           // Distinguishes a user's positions within one pool
     pub amount: u64,
-    pub last_stake_time: i64,
-    pub pending_rewards: u64,
+    pub last_stake_time: i64,       // Starts the min_stake_duration clock; accrual uses warmup_start
+    pub first_stake_time: i64,      // When the position was opened; top-ups leave it alone
+    pub last_claim_time: i64,       // Starts the claim_cooldown clock; other settlements don't touch it
+    pub pending_rewards: u128,      // Wider than any single payout so long-lived positions never overflow
+    pub reward_debt: u128,          // amount * acc_reward_per_share at the last settlement
+    pub reward_remainder: u128,     // Accrued fraction of a token (scaled) not yet moved to pending_rewards
+    pub unbonding_amount: u64,      // Tokens requested for withdrawal, no longer earning
+    pub unbonding_start_time: i64,
+    pub lock_end: i64,              // Unstaking is rejected before this time
+    pub reward_multiplier_bps: u16, // Lock tier multiplier (10_000 = 1x)
+    pub bump: u8,
+    pub version: u8,                // Layout version, see USER_STAKE_VERSION; append new fields below
+    pub last_settle_time: i64,      // Pool time of the last settlement, bounds the reward_warmup proration
+    pub pending_rewards_2: u128,    // pending_rewards for the pool's second reward token
+    pub reward_debt_2: u128,        // reward_debt against acc_reward_per_share_2
+    pub reward_remainder_2: u128,   // reward_remainder for the second reward token
+    pub frozen: bool,               // Set by set_user_frozen; blocks moving the stake or its rewards
+    pub auto_compound: bool,        // Set by set_auto_compound; claims restake instead of paying out
+    pub claim_count: u64,           // Claims made so far; numbers the position's ClaimReceipts
+    pub warmup_start: i64,          // When new stake was last credited; reward_warmup runs from here
+    // Borsh sizes an Option by its tag, so it must stay last or it would shift the fields after it
+    pub locked_duration: Option<i64>, // Pool min_stake_duration at the last stake, None = follow the pool
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct FunderRecord {
+    pub pool: Pubkey,
+    pub funder: Pubkey,
+    pub cumulative_funded: u64,     // Reward tokens this funder has put into the vault
+    pub last_funded_time: i64,
+    pub bump: u8,
+}
+
+// Permanent record of one claim, seeds [b"claim_receipt", pool, user, position_id, sequence]
+#[account]
+#[derive(InitSpace)]
+pub struct ClaimReceipt {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub position_id: u64,
+    pub sequence: u64,              // The position's claim_count before this claim, from 0
+    pub reward_mint: Pubkey,
+    pub amount: u64,                // Reward tokens paid out, including the protocol fee
+    pub fee: u64,                   // Protocol fee taken from amount
+    pub timestamp: i64,
+    pub bump: u8,
+}
+
+// Ring buffer of reward state for reconstructing APR history off-chain.
+// Sized from the pool's snapshot_capacity, so its space is computed rather than derived
+#[account]
+pub struct RewardSnapshots {
+    pub pool: Pubkey,
+    pub capacity: u16,
+    pub next_index: u16,            // Slot the next snapshot is written to
+    pub total_recorded: u64,        // Snapshots ever taken; entries holds the latest `capacity`
+    pub bump: u8,
+    pub entries: Vec<RewardSnapshot>,
+}
+
+impl RewardSnapshots {
+    pub fn space(capacity: u16) -> usize {
+        8 + 32 + 2 + 2 + 8 + 1 + 4 + capacity as usize * RewardSnapshot::INIT_SPACE
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct RewardSnapshot {
+    pub total_staked: u64,
+    pub acc_reward_per_share: u128,
+    pub timestamp: i64,             // Pool last_update_time the accumulator was read at
+}
+
+// Emitted on every reward vault deposit: fund_rewards, fund_rewards_streamed,
+// fund_rewards_from_pda, extend_campaign, bootstrap_pool and fund_second_reward
+#[event]
+pub struct RewardsFundedEvent {
+    pub pool: Pubkey,
+    pub funder: Pubkey,
+    pub reward_mint: Pubkey,        // Mint deposited; vault_balance and total_rewards_funded track it
+    pub amount: u64,                // What arrived in the vault, after any transfer fee
+    pub vault_balance: u64,         // Reward vault balance after the deposit
+    pub total_rewards_funded: u64,  // The pool's running total including this deposit
+    pub timestamp: i64,
+}
+
+// A fund_rewards_streamed deposit vesting linearly over [start_time, end_time)
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, InitSpace)]
+pub struct RewardStream {
+    pub amount: u64,                // Tokens the stream vests in total
+    pub start_time: i64,
+    pub end_time: i64,
+    pub vested: u64,                // Tokens already credited to the accumulator
+}
+
+impl RewardStream {
+    // Mark everything vested by `now` as credited and return the newly vested part
+    pub fn vest(&mut self, now: i64) -> u64 {
+        if self.vested >= self.amount || now <= self.start_time {
+            return 0;
+        }
+        let elapsed = now.min(self.end_time) - self.start_time;
+        let duration = self.end_time - self.start_time;
+        let vested = (self.amount as u128 * elapsed as u128 / duration as u128) as u64;
+        let newly_vested = vested.saturating_sub(self.vested);
+        self.vested = vested;
+        newly_vested
+    }
+
+    pub fn unvested(&self) -> u64 {
+        self.amount.saturating_sub(self.vested)
+    }
+}
+
+// Returned by pool_stats
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct PoolStats {
+    pub total_staked: u64,
+    pub reward_rate: u64,
+    pub total_rewards_distributed: u64,
+    pub reward_vault_balance: u64,
+    pub seconds_remaining: i64,     // Until reward_end_time, 0 once the window has closed
+    pub last_update_time: i64,      // When the stored accumulator was last advanced
+}
+
+// Returned by reward_token_info
+#[derive(AnchorSerialize, AnchorDeserialize, Clone)]
+pub struct RewardTokenInfo {
+    pub mint: Pubkey,
+    pub decimals: u8,
+    pub supply: u64,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct ProtocolConfig {
+    pub admin: Pubkey,
+    pub fee_bps: u16,               // Share of each reward claim sent to the treasury
+    pub fee_treasury: Pubkey,       // Owner of the token accounts that receive claim fees
     pub bump: u8,
 }
 
+impl StakingPool {
+    // Rewards the pool may still emit before hitting its budget
+    pub fn remaining_reward_budget(&self) -> u64 {
+        if self.reward_budget == 0 {
+            return u64::MAX;
+        }
+        self.reward_budget.saturating_sub(self.total_rewards_emitted)
+    }
+
+    // Rewards the pool may still pay out before hitting its budget
+    pub fn unpaid_reward_budget(&self) -> u64 {
+        if self.reward_budget == 0 {
+            return u64::MAX;
+        }
+        self.reward_budget.saturating_sub(self.total_rewards_distributed)
+    }
+
+    // Best tier multiplier a lock of `lock_period` seconds qualifies for
+    pub fn lock_multiplier_bps(&self, lock_period: i64) -> u16 {
+        let mut multiplier_bps = BPS_DENOMINATOR as u16;
+        for (duration, tier_bps) in self.lock_tier_durations.iter().zip(self.lock_tier_multipliers_bps.iter()) {
+            if *tier_bps > 0 && lock_period >= *duration {
+                multiplier_bps = multiplier_bps.max(*tier_bps);
+            }
+        }
+        multiplier_bps
+    }
+}
+
+impl UserStake {
+    // Share of the pool's weighted stake this position holds; positions opened
+    // before the pool's early-staker cutoff get the boost on top of their lock multiplier
+    pub fn weight(&self, pool: &StakingPool) -> Result<u64> {
+        if self.frozen && pool.freeze_stops_accrual {
+            return Ok(0);
+        }
+        // Balance above max_rewarded_stake is staked but earns nothing
+        let rewarded = match pool.max_rewarded_stake {
+            0 => self.amount,
+            cap => self.amount.min(cap),
+        };
+        let weight = weighted_amount(rewarded, self.reward_multiplier_bps)?;
+        if self.first_stake_time >= pool.early_staker_cutoff {
+            return Ok(weight);
+        }
+        weight
+            .checked_add(bps_of(weight, pool.early_staker_boost_bps)?)
+            .ok_or_else(|| error!(StakingError::Overflow))
+    }
+
+    // Minimum hold this position agreed to when it last staked. Positions
+    // migrated from before the term was recorded follow the pool's current one
+    pub fn min_stake_duration(&self, pool: &StakingPool) -> i64 {
+        self.locked_duration.unwrap_or(pool.min_stake_duration)
+    }
+}
+
 // Error codes
 
 #[error_code]
@@ -434,4 +5538,236 @@ pub enum StakingError {
     Underflow,
     #[msg("Division by zero")]
     DivisionByZero,
+    #[msg("Signer is not the pool authority")]
+    UnauthorizedAuthority,
+    #[msg("Pool is paused")]
+    PoolPaused,
+    #[msg("Stake account still holds a balance or unclaimed rewards")]
+    StakeNotEmpty,
+    #[msg("Unbonding period has not elapsed")]
+    UnbondingNotComplete,
+    #[msg("Reward vault has no funds to pay out")]
+    InsufficientRewardFunds,
+    #[msg("Requested claim exceeds available rewards")]
+    ClaimAmountExceedsRewards,
+    #[msg("Compounding requires the stake and reward mints to match")]
+    CompoundNotSupported,
+    #[msg("Lock period has not expired")]
+    LockNotExpired,
+    #[msg("Lock period must not be negative")]
+    InvalidLockPeriod,
+    #[msg("Lock tiers must be ascending with multipliers of at least 1x")]
+    InvalidLockTiers,
+    #[msg("Early-unstake penalty must be at most 10_000 bps and needs matching stake and reward mints")]
+    InvalidPenaltyConfig,
+    #[msg("No pending authority transfer")]
+    NoPendingAuthority,
+    #[msg("Stake would exceed the pool's total stake cap")]
+    PoolCapExceeded,
+    #[msg("Stake would exceed the per-user stake cap")]
+    UserCapExceeded,
+    #[msg("Reward schedule must start before it ends")]
+    InvalidRewardSchedule,
+    #[msg("Pool still holds user stake")]
+    PoolNotEmpty,
+    #[msg("Position would be below the pool's minimum stake")]
+    BelowMinimumStake,
+    #[msg("Only the pool authority may fund this pool")]
+    FunderNotAuthorized,
+    #[msg("Slash amount exceeds the position's stake")]
+    SlashExceedsStake,
+    #[msg("Signer is not the position's beneficiary")]
+    NotBeneficiary,
+    #[msg("Protocol fee must be at most 10_000 bps")]
+    InvalidFeeConfig,
+    #[msg("Signer is not the protocol config admin")]
+    UnauthorizedConfigAdmin,
+    #[msg("Position has no stake to return")]
+    UserStakeAlreadyEmpty,
+    #[msg("Signer does not hold an NFT from the pool's collection")]
+    NotCollectionHolder,
+    #[msg("Withdrawal would dip into rewards the pool still owes")]
+    ExcessWithdrawTooLarge,
+    #[msg("Existing position belongs to a different user or pool")]
+    StakeOwnerMismatch,
+    #[msg("Pool does not stake wrapped SOL")]
+    NotNativeMintPool,
+    #[msg("Claim cooldown has not elapsed")]
+    ClaimCooldownActive,
+    #[msg("Position is already on the current layout")]
+    UserStakeAlreadyMigrated,
+    #[msg("Reward vault must be funded before the first stake")]
+    RewardVaultNotFunded,
+    #[msg("Split must leave part of the position behind")]
+    SplitExceedsStake,
+    #[msg("Position already belongs to that owner")]
+    SameOwner,
+    #[msg("New reward end time must be later than the current one")]
+    InvalidEndTimeExtension,
+    #[msg("Stake and reward mints must differ unless allow_same_mint is set")]
+    SameMintNotAllowed,
+    #[msg("Reward rate is above MAX_REWARD_RATE")]
+    RewardRateTooHigh,
+    #[msg("Pool name must be non-empty UTF-8 and the metadata URI valid UTF-8")]
+    InvalidPoolMetadata,
+    #[msg("Snapshot capacity is above MAX_SNAPSHOT_CAPACITY")]
+    InvalidSnapshotCapacity,
+    #[msg("Pool was created without snapshots")]
+    SnapshotsDisabled,
+    #[msg("User token account cannot be one of the pool's vaults")]
+    SelfTransferNotAllowed,
+    #[msg("Boost account is not for the pool's boost mint")]
+    BoostAccountMintMismatch,
+    #[msg("Boost account is not owned by the position's beneficiary")]
+    BoostAccountOwnerMismatch,
+    #[msg("Penalty treasury cut needs the config and the treasury's stake-mint account")]
+    TreasuryAccountRequired,
+    #[msg("Escrow authority does not match the pool's escrow seeds")]
+    InvalidEscrowAuthority,
+    #[msg("Reward vault or budget cannot cover the rewards plus the reinvest bonus")]
+    ReinvestBonusUnfunded,
+    #[msg("Child pool claims need the parent pool, its vault authority and its reward vault")]
+    ParentPoolRequired,
+    #[msg("Parent pool accounts are invalid or the pools cannot be linked")]
+    InvalidParentPool,
+    #[msg("Stake token account has not approved the vault authority as delegate for this amount")]
+    DelegateApprovalInsufficient,
+    #[msg("Pools with a second reward token need its vault, mint and a payout account to claim")]
+    SecondRewardAccountsRequired,
+    #[msg("Second reward mint or vault does not match the pool")]
+    InvalidSecondReward,
+    #[msg("Pool already has a second reward token")]
+    SecondRewardAlreadySet,
+    #[msg("Stream duration must be positive")]
+    InvalidStreamDuration,
+    #[msg("Position is frozen")]
+    UserFrozen,
+    #[msg("Stake can only migrate to another pool with the same authority and stake mint")]
+    InvalidMigrationTarget,
+    #[msg("Pool accumulator is older than the allowed staleness; poke it first")]
+    PoolStale,
+    #[msg("Auto-compounding claims need the pool's stake vault")]
+    StakeVaultRequired,
+    #[msg("Rate scale must be a power of ten")]
+    InvalidRateScale,
+    #[msg("Staker is not on the pool's allowlist")]
+    NotWhitelisted,
+    #[msg("Pool emits claim receipts; pass the claim_receipt account")]
+    ReceiptAccountRequired,
+    #[msg("The final claim window has closed")]
+    ClaimWindowClosed,
+    #[msg("Rewards can only be swept once the final claim window has closed")]
+    SweepTooEarly,
+    #[msg("Lock tier is not configured on this pool")]
+    InvalidLockTier,
+    #[msg("New lock tier must pay more than the position's current multiplier")]
+    CannotDowngradeLock,
+    #[msg("Those tokens are unbonding; withdraw them with complete_unstake")]
+    AmountLockedInUnbonding,
+    #[msg("Pending rewards above u64::MAX are paid out on stake; pass the reward and treasury accounts")]
+    RewardAccountsRequired,
+    #[msg("Minimum stake duration must not be negative")]
+    InvalidDuration,
+    #[msg("Emitted rewards are still unclaimed and the final claim window is open")]
+    RewardsStillOwed,
+    #[msg("Curved pools keep the reward rate and window they were created with")]
+    CurveScheduleFixed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A pool emitting 1_000 reward units a second to 1_000 staked tokens, last updated at 100
+    fn pool() -> StakingPool {
+        let mut pool = StakingPool::deserialize(&mut &vec![0u8; StakingPool::INIT_SPACE][..]).unwrap();
+        pool.reward_rate = 1_000;
+        pool.fixed_emission = true;
+        pool.rate_scale = PER_TOKEN_RATE_PRECISION as u64;
+        pool.reward_end_time = i64::MAX;
+        pool.total_staked = 1_000;
+        pool.total_weighted_stake = 1_000;
+        pool.last_update_time = 100;
+        pool
+    }
+
+    fn position(amount: u64) -> UserStake {
+        let mut user_stake = UserStake::deserialize(&mut &vec![0u8; UserStake::INIT_SPACE][..]).unwrap();
+        user_stake.amount = amount;
+        user_stake.reward_multiplier_bps = BPS_DENOMINATOR as u16;
+        user_stake
+    }
+
+    fn bytes<T: AnchorSerialize>(value: &T) -> Vec<u8> {
+        let mut out = Vec::new();
+        value.serialize(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn update_pool_rejects_a_saturated_accumulator() {
+        let mut pool = pool();
+        pool.acc_reward_per_share = u128::MAX;
+        assert_eq!(update_pool(&mut pool, 101).unwrap_err(), StakingError::Overflow.into());
+    }
+
+    #[test]
+    fn settle_rejects_a_saturated_accumulator() {
+        let mut pool = pool();
+        pool.acc_reward_per_share = u128::MAX;
+        let mut user_stake = position(1_000);
+        assert_eq!(
+            settle_user_rewards(&mut pool, &mut user_stake).unwrap_err(),
+            StakingError::Overflow.into()
+        );
+    }
+
+    #[test]
+    fn update_pool_without_elapsed_time_changes_nothing() {
+        let mut pool = pool();
+        pool.acc_reward_per_share = 7 * ACC_REWARD_PRECISION;
+        let before = bytes(&pool);
+
+        update_pool(&mut pool, 100).unwrap();
+        assert_eq!(bytes(&pool), before);
+        // A clock reading behind the last update is treated the same way
+        update_pool(&mut pool, 99).unwrap();
+        assert_eq!(bytes(&pool), before);
+    }
+
+    #[test]
+    fn settle_without_elapsed_time_changes_nothing() {
+        let mut pool = pool();
+        let mut user_stake = position(1_000);
+        update_pool(&mut pool, 110).unwrap();
+        settle_user_rewards(&mut pool, &mut user_stake).unwrap();
+        assert_eq!(user_stake.pending_rewards, 10_000);
+
+        let (pool_before, stake_before) = (bytes(&pool), bytes(&user_stake));
+        update_pool(&mut pool, 110).unwrap();
+        settle_user_rewards(&mut pool, &mut user_stake).unwrap();
+        assert_eq!(bytes(&pool), pool_before);
+        assert_eq!(bytes(&user_stake), stake_before);
+    }
+
+    // One reward token per staked token per second, whichever mint has more decimals
+    #[test]
+    fn per_token_rate_scales_between_mint_decimals() {
+        for (stake_decimals, reward_decimals, amount, expected) in [
+            (6, 9, 100_000_000, 1_000_000_000_000u128),
+            (9, 6, 100_000_000_000, 1_000_000_000),
+        ] {
+            let mut pool = pool();
+            pool.fixed_emission = false;
+            pool.reward_rate = PER_TOKEN_RATE_PRECISION as u64;
+            pool.stake_decimals = stake_decimals;
+            pool.reward_decimals = reward_decimals;
+            pool.total_staked = amount;
+            pool.total_weighted_stake = amount;
+            let mut user_stake = position(amount);
+            update_pool(&mut pool, 110).unwrap();
+            settle_user_rewards(&mut pool, &mut user_stake).unwrap();
+            assert_eq!(user_stake.pending_rewards, expected);
+        }
+    }
 }
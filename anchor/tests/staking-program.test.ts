@@ -1,7 +1,25 @@
 import * as anchor from '@coral-xyz/anchor'
 import { Program, BN } from '@coral-xyz/anchor'
-import { Keypair, PublicKey } from '@solana/web3.js'
-import { createMint, getOrCreateAssociatedTokenAccount, mintTo, getAccount } from '@solana/spl-token'
+import { createHash } from 'crypto'
+import { readFileSync } from 'fs'
+import { Keypair, LAMPORTS_PER_SOL, PublicKey, SystemProgram, Transaction } from '@solana/web3.js'
+import {
+  createMint,
+  getOrCreateAssociatedTokenAccount,
+  mintTo,
+  getAccount,
+  getMint,
+  createInitializeMintInstruction,
+  createInitializeTransferFeeConfigInstruction,
+  createInitializePermanentDelegateInstruction,
+  transferChecked,
+  approve,
+  ExtensionType,
+  getMintLen,
+  TOKEN_PROGRAM_ID,
+  TOKEN_2022_PROGRAM_ID,
+  NATIVE_MINT,
+} from '@solana/spl-token'
 import { StakingProgram } from '../target/types/staking-program'
 
 describe('staking-program', () => {
@@ -20,11 +38,572 @@ describe('staking-program', () => {
   let poolStakeVault: PublicKey
   let poolRewardVault: PublicKey
   let userStakePda: PublicKey
+  let treasuryRewardTokenAccount: PublicKey
 
-  const rewardRate = new BN(1_000_000) // 0.001 tokens per second per staked token (1e9 scale)
+  // The protocol config is a program-wide singleton shared by every test pool
+  const treasury = Keypair.generate()
+  const [configPda] = PublicKey.findProgramAddressSync([Buffer.from('config')], program.programId)
+
+  const rewardRate = new BN(1_000_000) // 0.001 tokens per second shared across all stakers
   const minStakeDuration = new BN(5) // 5 seconds minimum stake duration
 
+  const ACC_REWARD_PRECISION = new BN(1_000_000_000_000)
+  const EARLY_UNSTAKE_DISABLED = 65_535
+  const I64_MAX = new BN('9223372036854775807')
+
+  const sleep = (ms: number) => new Promise((resolve) => setTimeout(resolve, ms))
+
+  async function airdrop(pubkey: PublicKey, lamports = 10 * LAMPORTS_PER_SOL) {
+    const signature = await provider.connection.requestAirdrop(pubkey, lamports)
+    const latest = await provider.connection.getLatestBlockhash()
+    await provider.connection.confirmTransaction({ signature, ...latest }, 'confirmed')
+  }
+
+  type PoolOptions = {
+    minStakeDuration: BN
+    unbondingPeriod: BN
+    claimCooldown: BN
+    rewardWarmup: BN
+    fundAmount: number
+    sameMint: boolean
+    allowSameMint: boolean | null
+    nativeStake: boolean
+    stakeDecimals: number
+    rewardDecimals: number
+    lockTierDurations: BN[]
+    lockTierMultipliersBps: number[]
+    earlyUnstakePenaltyBps: number
+    penaltyTreasuryBps: number
+    rewardBudget: BN
+    rewardReserve: BN
+    tokenProgram: PublicKey
+    stakeTransferFeeBps: number
+    stakePermanentDelegate: boolean
+    maxTotalStake: BN
+    maxUserStake: BN
+    rewardStartTime: BN
+    rewardEndTime: BN
+    minStakeAmount: BN
+    restrictFunders: boolean
+    fixedEmission: boolean
+    requirePrefunded: boolean
+    halvingInterval: BN
+    curveType: number
+    apyBps: number
+    gateCollection: PublicKey | null
+    earlyStakerCutoff: BN
+    earlyStakerBoostBps: number
+    name: string
+    metadataUri: string
+    snapshotCapacity: number
+    reinvestBonusBps: number
+    roundUpRewards: boolean
+    maxClaimPerTx: BN
+    freezeStopsAccrual: boolean
+    maxRewardedStake: BN
+    rateScale: BN
+    merkleRoot: number[] | null
+    emitReceipts: boolean
+    finalClaimGrace: BN
+    bootstrap: boolean
+    stakeMint: PublicKey | null
+    rewardMint: PublicKey | null
+  }
+
+  // Zero-pads text into the fixed-size byte fields the program stores
+  function fixedBytes(text: string, length: number) {
+    const bytes = Buffer.alloc(length)
+    bytes.write(text)
+    return [...bytes]
+  }
+
+  // Reads a zero-padded byte field back into text
+  function unpadded(bytes: number[]) {
+    return Buffer.from(bytes).toString('utf8').replace(/\0+$/, '')
+  }
+
+  // Creates a Token-2022 mint whose transfers withhold `feeBps` of every amount
+  async function createTransferFeeMint(feeBps: number) {
+    const mint = Keypair.generate()
+    const space = getMintLen([ExtensionType.TransferFeeConfig])
+    const lamports = await provider.connection.getMinimumBalanceForRentExemption(space)
+    const tx = new Transaction().add(
+      SystemProgram.createAccount({
+        fromPubkey: payer.publicKey,
+        newAccountPubkey: mint.publicKey,
+        space,
+        lamports,
+        programId: TOKEN_2022_PROGRAM_ID,
+      }),
+      createInitializeTransferFeeConfigInstruction(
+        mint.publicKey,
+        payer.publicKey,
+        payer.publicKey,
+        feeBps,
+        BigInt('18446744073709551615'),
+        TOKEN_2022_PROGRAM_ID
+      ),
+      createInitializeMintInstruction(mint.publicKey, 9, payer.publicKey, null, TOKEN_2022_PROGRAM_ID)
+    )
+    await provider.sendAndConfirm(tx, [mint])
+    return mint.publicKey
+  }
+
+  // Creates a Token-2022 mint the payer can move out of any account, standing in for a drained vault
+  async function createPermanentDelegateMint() {
+    const mint = Keypair.generate()
+    const space = getMintLen([ExtensionType.PermanentDelegate])
+    const lamports = await provider.connection.getMinimumBalanceForRentExemption(space)
+    const tx = new Transaction().add(
+      SystemProgram.createAccount({
+        fromPubkey: payer.publicKey,
+        newAccountPubkey: mint.publicKey,
+        space,
+        lamports,
+        programId: TOKEN_2022_PROGRAM_ID,
+      }),
+      createInitializePermanentDelegateInstruction(mint.publicKey, payer.publicKey, TOKEN_2022_PROGRAM_ID),
+      createInitializeMintInstruction(mint.publicKey, 9, payer.publicKey, null, TOKEN_2022_PROGRAM_ID)
+    )
+    await provider.sendAndConfirm(tx, [mint])
+    return mint.publicKey
+  }
+
+  // Creates a pool owned by a fresh authority so each scenario starts from clean accounting
+  async function createPool(rate: BN, options: Partial<PoolOptions> = {}) {
+    const {
+      minStakeDuration,
+      unbondingPeriod,
+      claimCooldown,
+      rewardWarmup,
+      fundAmount,
+      sameMint,
+      allowSameMint,
+      nativeStake,
+      stakeDecimals,
+      rewardDecimals,
+      lockTierDurations,
+      lockTierMultipliersBps,
+      earlyUnstakePenaltyBps,
+      penaltyTreasuryBps,
+      rewardBudget,
+      rewardReserve,
+      tokenProgram,
+      stakeTransferFeeBps,
+      stakePermanentDelegate,
+      maxTotalStake,
+      maxUserStake,
+      rewardStartTime,
+      rewardEndTime,
+      minStakeAmount,
+      restrictFunders,
+      fixedEmission,
+      requirePrefunded,
+      halvingInterval,
+      curveType,
+      apyBps,
+      gateCollection,
+      earlyStakerCutoff,
+      earlyStakerBoostBps,
+      name,
+      metadataUri,
+      snapshotCapacity,
+      reinvestBonusBps,
+      roundUpRewards,
+      maxClaimPerTx,
+      freezeStopsAccrual,
+      maxRewardedStake,
+      rateScale,
+      merkleRoot,
+      emitReceipts,
+      finalClaimGrace,
+      bootstrap,
+      stakeMint: existingStakeMint,
+      rewardMint: existingRewardMint,
+    } = {
+      minStakeDuration: new BN(0),
+      unbondingPeriod: new BN(0),
+      claimCooldown: new BN(0),
+      rewardWarmup: new BN(0),
+      fundAmount: 1_000_000_000_000,
+      sameMint: false,
+      // Follows sameMint unless set
+      allowSameMint: null,
+      nativeStake: false,
+      stakeDecimals: 9,
+      rewardDecimals: 9,
+      lockTierDurations: [new BN(0), new BN(0), new BN(0), new BN(0)],
+      lockTierMultipliersBps: [0, 0, 0, 0],
+      earlyUnstakePenaltyBps: EARLY_UNSTAKE_DISABLED,
+      penaltyTreasuryBps: 0,
+      rewardBudget: new BN(0),
+      rewardReserve: new BN(0),
+      tokenProgram: TOKEN_PROGRAM_ID,
+      stakeTransferFeeBps: 0,
+      stakePermanentDelegate: false,
+      maxTotalStake: new BN(0),
+      maxUserStake: new BN(0),
+      rewardStartTime: new BN(0),
+      rewardEndTime: I64_MAX,
+      minStakeAmount: new BN(0),
+      restrictFunders: false,
+      fixedEmission: false,
+      requirePrefunded: false,
+      halvingInterval: new BN(0),
+      curveType: 0,
+      apyBps: 0,
+      gateCollection: null,
+      earlyStakerCutoff: new BN(0),
+      earlyStakerBoostBps: 0,
+      name: 'Test Pool',
+      metadataUri: '',
+      snapshotCapacity: 0,
+      reinvestBonusBps: 0,
+      roundUpRewards: false,
+      maxClaimPerTx: new BN(0),
+      freezeStopsAccrual: false,
+      maxRewardedStake: new BN(0),
+      rateScale: new BN(1_000_000_000),
+      merkleRoot: null,
+      emitReceipts: false,
+      finalClaimGrace: new BN(0),
+      // Creates and funds the pool in one bootstrap_pool call, from the authority's tokens
+      bootstrap: false,
+      // Reuses an existing stake mint instead of creating one
+      stakeMint: null,
+      // Reuses an existing reward mint instead of creating one
+      rewardMint: null,
+      ...options,
+    }
+    const authority = Keypair.generate()
+    await airdrop(authority.publicKey)
+
+    const stakeMint = existingStakeMint
+      ? existingStakeMint
+      : nativeStake
+        ? NATIVE_MINT
+        : stakeTransferFeeBps > 0
+          ? await createTransferFeeMint(stakeTransferFeeBps)
+          : stakePermanentDelegate
+            ? await createPermanentDelegateMint()
+            : await createMint(
+                provider.connection,
+                payer.payer,
+                payer.publicKey,
+                null,
+                stakeDecimals,
+                undefined,
+                undefined,
+                tokenProgram
+              )
+    const rewardMint = sameMint
+      ? stakeMint
+      : existingRewardMint
+        ? existingRewardMint
+        : await createMint(
+          provider.connection,
+          payer.payer,
+          payer.publicKey,
+          null,
+          rewardDecimals,
+          undefined,
+          undefined,
+          tokenProgram
+        )
+
+    const [pool] = PublicKey.findProgramAddressSync(
+      [Buffer.from('pool'), authority.publicKey.toBuffer()],
+      program.programId
+    )
+    const [stakeVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from('stake_vault'), pool.toBuffer()],
+      program.programId
+    )
+    const [rewardVault] = PublicKey.findProgramAddressSync(
+      [Buffer.from('reward_vault'), pool.toBuffer()],
+      program.programId
+    )
+
+    const params = {
+      rewardRate: rate,
+      minStakeDuration,
+      unbondingPeriod,
+      claimCooldown,
+      rewardWarmup,
+      lockTierDurations,
+      lockTierMultipliersBps,
+      earlyUnstakePenaltyBps,
+      penaltyTreasuryBps,
+      rewardBudget,
+      rewardReserve,
+      maxTotalStake,
+      maxUserStake,
+      rewardStartTime,
+      rewardEndTime,
+      minStakeAmount,
+      restrictFunders,
+      allowSameMint: allowSameMint ?? sameMint,
+      fixedEmission,
+      requirePrefunded,
+      halvingInterval,
+      curveType,
+      gateCollection,
+      earlyStakerCutoff,
+      earlyStakerBoostBps,
+      name: fixedBytes(name, 32),
+      metadataUri: fixedBytes(metadataUri, 128),
+      snapshotCapacity,
+      reinvestBonusBps,
+      roundUpRewards,
+      maxClaimPerTx,
+      freezeStopsAccrual,
+      maxRewardedStake,
+      rateScale,
+      merkleRoot,
+      emitReceipts,
+      finalClaimGrace,
+    }
+    const accounts = {
+      authority: authority.publicKey,
+      pool,
+      stakeTokenMint: stakeMint,
+      rewardTokenMint: rewardMint,
+      poolStakeVault: stakeVault,
+      poolRewardVault: rewardVault,
+      tokenProgram,
+    }
+    // APY pools derive their rate on-chain, so `rate` is ignored for them
+    if (apyBps > 0) {
+      await program.methods.initializePoolApy(apyBps, params).accounts(accounts).signers([authority]).rpc()
+    } else if (bootstrap) {
+      const authorityRewardToken = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        rewardMint,
+        authority.publicKey,
+        false,
+        undefined,
+        undefined,
+        tokenProgram
+      )
+      await mintTo(
+        provider.connection,
+        payer.payer,
+        rewardMint,
+        authorityRewardToken.address,
+        payer.publicKey,
+        fundAmount,
+        [],
+        undefined,
+        tokenProgram
+      )
+      await program.methods
+        .bootstrapPool(params, new BN(fundAmount))
+        .accounts({ ...accounts, authorityRewardToken: authorityRewardToken.address })
+        .signers([authority])
+        .rpc()
+    } else {
+      await program.methods.initializePool(params).accounts(accounts).signers([authority]).rpc()
+    }
+
+    if (fundAmount > 0 && !bootstrap) {
+      await fundPool({ pool, rewardMint, rewardVault, tokenProgram }, fundAmount)
+    }
+
+    return { authority, pool, stakeMint, rewardMint, stakeVault, rewardVault, tokenProgram }
+  }
+
+  type TestPool = Awaited<ReturnType<typeof createPool>>
+
+  // Mints fresh reward tokens to the payer and deposits them into the pool's reward vault
+  async function fundPool(
+    testPool: { pool: PublicKey; rewardMint: PublicKey; rewardVault: PublicKey; tokenProgram: PublicKey },
+    amount: number
+  ) {
+    const funderAta = await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      payer.payer,
+      testPool.rewardMint,
+      payer.publicKey,
+      false,
+      undefined,
+      undefined,
+      testPool.tokenProgram
+    )
+    await mintTo(
+      provider.connection,
+      payer.payer,
+      testPool.rewardMint,
+      funderAta.address,
+      payer.publicKey,
+      amount,
+      [],
+      undefined,
+      testPool.tokenProgram
+    )
+    await program.methods
+      .fundRewards(new BN(amount))
+      .accounts({
+        funder: payer.publicKey,
+        pool: testPool.pool,
+        funderTokenAccount: funderAta.address,
+        poolRewardVault: testPool.rewardVault,
+        rewardTokenMint: testPool.rewardMint,
+        tokenProgram: testPool.tokenProgram,
+      })
+      .rpc()
+  }
+
+  function positionPda(pool: PublicKey, owner: PublicKey, positionId: BN) {
+    const [userStake] = PublicKey.findProgramAddressSync(
+      [Buffer.from('user_stake'), pool.toBuffer(), owner.toBuffer(), positionId.toArrayLike(Buffer, 'le', 8)],
+      program.programId
+    )
+    return userStake
+  }
+
+  // Creates a funded wallet holding stake tokens for the given pool
+  async function createStaker(testPool: TestPool, stakeBalance = 1_000_000_000_000) {
+    const keypair = Keypair.generate()
+    await airdrop(keypair.publicKey)
+
+    const stakeAta = await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      payer.payer,
+      testPool.stakeMint,
+      keypair.publicKey,
+      false,
+      undefined,
+      undefined,
+      testPool.tokenProgram
+    )
+    const rewardAta = await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      payer.payer,
+      testPool.rewardMint,
+      keypair.publicKey,
+      false,
+      undefined,
+      undefined,
+      testPool.tokenProgram
+    )
+    await mintTo(
+      provider.connection,
+      payer.payer,
+      testPool.stakeMint,
+      stakeAta.address,
+      payer.publicKey,
+      stakeBalance,
+      [],
+      undefined,
+      testPool.tokenProgram
+    )
+
+    const positionId = new BN(0)
+    const userStake = positionPda(testPool.pool, keypair.publicKey, positionId)
+
+    return { keypair, stakeToken: stakeAta.address, rewardToken: rewardAta.address, positionId, userStake }
+  }
+
+  type TestStaker = Awaited<ReturnType<typeof createStaker>>
+
+  // The treasury's token account for a reward mint, created on first use
+  async function treasuryAccount(mint: PublicKey, tokenProgram = TOKEN_PROGRAM_ID) {
+    const ata = await getOrCreateAssociatedTokenAccount(
+      provider.connection,
+      payer.payer,
+      mint,
+      treasury.publicKey,
+      false,
+      undefined,
+      undefined,
+      tokenProgram
+    )
+    return ata.address
+  }
+
+  async function setProtocolFee(feeBps: number) {
+    await program.methods
+      .updateConfig(feeBps, treasury.publicKey)
+      .accounts({ admin: payer.publicKey, config: configPda })
+      .rpc()
+  }
+
+  async function stakeAs(
+    testPool: TestPool,
+    staker: TestStaker,
+    amount: BN,
+    lockPeriod = new BN(0),
+    proof: number[][] = []
+  ) {
+    await program.methods
+      .stake(staker.positionId, amount, lockPeriod, staker.keypair.publicKey, proof)
+      .accounts({
+        user: staker.keypair.publicKey,
+        pool: testPool.pool,
+        userStake: staker.userStake,
+        userStakeToken: staker.stakeToken,
+        poolStakeVault: testPool.stakeVault,
+        poolRewardVault: testPool.rewardVault,
+        stakeTokenMint: testPool.stakeMint,
+        tokenProgram: testPool.tokenProgram,
+      })
+      .signers([staker.keypair])
+      .rpc()
+  }
+
+  async function unstakeAs(testPool: TestPool, staker: TestStaker, amount: BN) {
+    await program.methods
+      .unstake(staker.positionId, amount)
+      .accounts({
+        user: staker.keypair.publicKey,
+        pool: testPool.pool,
+        userStake: staker.userStake,
+        userStakeToken: staker.stakeToken,
+        poolStakeVault: testPool.stakeVault,
+        poolRewardVault: testPool.rewardVault,
+        stakeTokenMint: testPool.stakeMint,
+        tokenProgram: testPool.tokenProgram,
+      })
+      .signers([staker.keypair])
+      .rpc()
+  }
+
+  // Claims for the staker and returns the number of reward tokens received
+  async function claimAs(testPool: TestPool, staker: TestStaker, amount: BN | null = null) {
+    const treasuryRewardToken = await treasuryAccount(testPool.rewardMint, testPool.tokenProgram)
+    const before = await getAccount(provider.connection, staker.rewardToken, undefined, testPool.tokenProgram)
+    await program.methods
+      .claimRewards(staker.positionId, amount)
+      .accounts({
+        user: staker.keypair.publicKey,
+        pool: testPool.pool,
+        userStake: staker.userStake,
+        userRewardToken: staker.rewardToken,
+        poolRewardVault: testPool.rewardVault,
+        config: configPda,
+        treasuryRewardToken,
+        rewardTokenMint: testPool.rewardMint,
+        tokenProgram: testPool.tokenProgram,
+      })
+      .signers([staker.keypair])
+      .rpc()
+    const after = await getAccount(provider.connection, staker.rewardToken, undefined, testPool.tokenProgram)
+    return after.amount - before.amount
+  }
+
   beforeAll(async () => {
+    // Start every run with a zero protocol fee
+    const config = await program.account.protocolConfig.fetchNullable(configPda)
+    if (config) {
+      await setProtocolFee(0)
+    } else {
+      await program.methods
+        .initializeConfig(0, treasury.publicKey)
+        .accounts({ admin: payer.publicKey, config: configPda })
+        .rpc()
+    }
+
     // Create stake token (Token A)
     stakeTokenMint = await createMint(
       provider.connection,
@@ -59,6 +638,7 @@ describe('staking-program', () => {
       payer.publicKey
     )
     userRewardTokenAccount = userRewardAta.address
+    treasuryRewardTokenAccount = await treasuryAccount(rewardTokenMint)
 
     // Mint tokens to user
     await mintTo(
@@ -97,14 +677,52 @@ describe('staking-program', () => {
     )
 
     ;[userStakePda] = PublicKey.findProgramAddressSync(
-      [Buffer.from('user_stake'), poolPda.toBuffer(), payer.publicKey.toBuffer()],
+      [Buffer.from('user_stake'), poolPda.toBuffer(), payer.publicKey.toBuffer(), new BN(0).toArrayLike(Buffer, 'le', 8)],
       program.programId
     )
   })
 
   it('Initialize Staking Pool', async () => {
     await program.methods
-      .initializePool(rewardRate, minStakeDuration)
+      .initializePool({
+        rewardRate,
+        minStakeDuration,
+        unbondingPeriod: new BN(0),
+        claimCooldown: new BN(0),
+        rewardWarmup: new BN(0),
+        lockTierDurations: [new BN(0), new BN(0), new BN(0), new BN(0)],
+        lockTierMultipliersBps: [0, 0, 0, 0],
+        earlyUnstakePenaltyBps: EARLY_UNSTAKE_DISABLED,
+        penaltyTreasuryBps: 0,
+        rewardBudget: new BN(0),
+        rewardReserve: new BN(0),
+        maxTotalStake: new BN(0),
+        maxUserStake: new BN(0),
+        rewardStartTime: new BN(0),
+        rewardEndTime: I64_MAX,
+        minStakeAmount: new BN(0),
+        restrictFunders: false,
+        allowSameMint: false,
+        fixedEmission: true,
+        requirePrefunded: false,
+        halvingInterval: new BN(0),
+        curveType: 0,
+        gateCollection: null,
+        earlyStakerCutoff: new BN(0),
+        earlyStakerBoostBps: 0,
+        name: fixedBytes('Main Pool', 32),
+        metadataUri: fixedBytes('', 128),
+        snapshotCapacity: 0,
+        reinvestBonusBps: 0,
+        roundUpRewards: false,
+        maxClaimPerTx: new BN(0),
+        freezeStopsAccrual: false,
+        maxRewardedStake: new BN(0),
+        rateScale: new BN(1_000_000_000),
+        merkleRoot: null,
+        emitReceipts: false,
+        finalClaimGrace: new BN(0),
+      })
       .accounts({
         authority: payer.publicKey,
         pool: poolPda,
@@ -112,6 +730,7 @@ describe('staking-program', () => {
         rewardTokenMint: rewardTokenMint,
         poolStakeVault: poolStakeVault,
         poolRewardVault: poolRewardVault,
+        tokenProgram: TOKEN_PROGRAM_ID,
       })
       .rpc()
 
@@ -135,6 +754,8 @@ describe('staking-program', () => {
         pool: poolPda,
         funderTokenAccount: userRewardTokenAccount,
         poolRewardVault: poolRewardVault,
+        rewardTokenMint: rewardTokenMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
       })
       .rpc()
 
@@ -146,13 +767,16 @@ describe('staking-program', () => {
     const stakeAmount = new BN(100_000_000_000) // 100 tokens
 
     await program.methods
-      .stake(stakeAmount)
+      .stake(new BN(0), stakeAmount, new BN(0), payer.publicKey, [])
       .accounts({
         user: payer.publicKey,
         pool: poolPda,
         userStake: userStakePda,
         userStakeToken: userStakeTokenAccount,
         poolStakeVault: poolStakeVault,
+        poolRewardVault: poolRewardVault,
+        stakeTokenMint: stakeTokenMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
       })
       .rpc()
 
@@ -173,13 +797,16 @@ describe('staking-program', () => {
     const userStakeBefore = await program.account.userStake.fetch(userStakePda)
 
     await program.methods
-      .stake(additionalStake)
+      .stake(new BN(0), additionalStake, new BN(0), payer.publicKey, [])
       .accounts({
         user: payer.publicKey,
         pool: poolPda,
         userStake: userStakePda,
         userStakeToken: userStakeTokenAccount,
         poolStakeVault: poolStakeVault,
+        poolRewardVault: poolRewardVault,
+        stakeTokenMint: stakeTokenMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
       })
       .rpc()
 
@@ -198,13 +825,16 @@ describe('staking-program', () => {
 
     try {
       await program.methods
-        .unstake(unstakeAmount)
+        .unstake(new BN(0), unstakeAmount)
         .accounts({
           user: payer.publicKey,
           pool: poolPda,
           userStake: userStakePda,
           userStakeToken: userStakeTokenAccount,
           poolStakeVault: poolStakeVault,
+          poolRewardVault: poolRewardVault,
+          stakeTokenMint: stakeTokenMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
         })
         .rpc()
       
@@ -224,13 +854,16 @@ describe('staking-program', () => {
     const poolBefore = await program.account.stakingPool.fetch(poolPda)
 
     await program.methods
-      .unstake(unstakeAmount)
+      .unstake(new BN(0), unstakeAmount)
       .accounts({
         user: payer.publicKey,
         pool: poolPda,
         userStake: userStakePda,
         userStakeToken: userStakeTokenAccount,
         poolStakeVault: poolStakeVault,
+        poolRewardVault: poolRewardVault,
+        stakeTokenMint: stakeTokenMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
       })
       .rpc()
 
@@ -255,13 +888,17 @@ describe('staking-program', () => {
     const userStakeBefore = await program.account.userStake.fetch(userStakePda)
 
     await program.methods
-      .claimRewards()
+      .claimRewards(new BN(0), null)
       .accounts({
         user: payer.publicKey,
         pool: poolPda,
         userStake: userStakePda,
         userRewardToken: userRewardTokenAccount,
         poolRewardVault: poolRewardVault,
+        config: configPda,
+        treasuryRewardToken: treasuryRewardTokenAccount,
+        rewardTokenMint: rewardTokenMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
       })
       .rpc()
 
@@ -284,13 +921,16 @@ describe('staking-program', () => {
     const remainingStake = userStake.amount
 
     await program.methods
-      .unstake(remainingStake)
+      .unstake(new BN(0), remainingStake)
       .accounts({
         user: payer.publicKey,
         pool: poolPda,
         userStake: userStakePda,
         userStakeToken: userStakeTokenAccount,
         poolStakeVault: poolStakeVault,
+        poolRewardVault: poolRewardVault,
+        stakeTokenMint: stakeTokenMint,
+        tokenProgram: TOKEN_PROGRAM_ID,
       })
       .rpc()
 
@@ -304,13 +944,17 @@ describe('staking-program', () => {
   it('Cannot Claim When No Stake and No Pending Rewards', async () => {
     try {
       await program.methods
-        .claimRewards()
+        .claimRewards(new BN(0), null)
         .accounts({
           user: payer.publicKey,
           pool: poolPda,
           userStake: userStakePda,
           userRewardToken: userRewardTokenAccount,
           poolRewardVault: poolRewardVault,
+          config: configPda,
+          treasuryRewardToken: treasuryRewardTokenAccount,
+          rewardTokenMint: rewardTokenMint,
+          tokenProgram: TOKEN_PROGRAM_ID,
         })
         .rpc()
       
@@ -320,4 +964,5640 @@ describe('staking-program', () => {
       expect((error as Error).message).toMatch(/NoRewardsToClaim|Should have thrown an error/)
     }
   })
+
+  describe('reward-per-share accounting', () => {
+    it('Two Stakers Split The Same Emission', async () => {
+      const rate = new BN(1_000_000_000) // 1 token per second for the whole pool
+      const testPool = await createPool(rate, { fixedEmission: true })
+      const alice = await createStaker(testPool)
+      const bob = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, alice, amount)
+      await stakeAs(testPool, bob, amount)
+      const firstStakeTime = (await program.account.userStake.fetch(alice.userStake)).lastStakeTime
+
+      await sleep(4000)
+
+      const aliceRewards = await claimAs(testPool, alice)
+      const bobRewards = await claimAs(testPool, bob)
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      const aliceStake = await program.account.userStake.fetch(alice.userStake)
+
+      // Alice kept accruing between her claim and Bob's, which is still owed to her
+      const aliceUnclaimed = BigInt(
+        aliceStake.amount
+          .mul(pool.accRewardPerShare)
+          .sub(aliceStake.rewardDebt)
+          .div(ACC_REWARD_PRECISION)
+          .toString()
+      )
+
+      // Everything emitted since the first stake is accounted for, minus rounding dust,
+      // and no more than that regardless of how many stakers joined
+      const emitted = BigInt(rate.mul(pool.lastUpdateTime.sub(firstStakeTime)).toString())
+      const distributed = aliceRewards + bobRewards + aliceUnclaimed
+      expect(distributed).toBeLessThanOrEqual(emitted)
+      expect(emitted - distributed).toBeLessThanOrEqual(BigInt(3))
+
+      // Equal stakes share the overlapping window; neither captures the full emission
+      expect(aliceRewards).toBeGreaterThan(BigInt(0))
+      expect(bobRewards).toBeGreaterThan(BigInt(0))
+      expect(aliceRewards).toBeLessThan(emitted)
+      expect(bobRewards).toBeLessThan(emitted)
+    }, 30000)
+
+    it('Top-Up Keeps The Full Accrual Window', async () => {
+      const rate = new BN(1_000_000_000)
+      const testPool = await createPool(rate, { fixedEmission: true })
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      const start = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+      await sleep(2000)
+      await stakeAs(testPool, staker, new BN(300_000_000_000))
+      await sleep(2000)
+      const claimed = await claimAs(testPool, staker)
+      const end = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+
+      // A lone staker is owed every second of emission since the first deposit
+      const expected = BigInt(rate.mul(end.sub(start)).toString())
+      expect(claimed).toBeLessThanOrEqual(expected)
+      expect(expected - claimed).toBeLessThanOrEqual(BigInt(3))
+    }, 30000)
+
+    it('Claim Between Top-Ups Neither Drops Nor Repeats Accrual', async () => {
+      const rate = new BN(1_000_000_000)
+      const testPool = await createPool(rate, { fixedEmission: true })
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      const start = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+      await sleep(2000)
+      const firstClaim = await claimAs(testPool, staker)
+      await sleep(1000)
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      await sleep(2000)
+      const secondClaim = await claimAs(testPool, staker)
+      const end = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+
+      const expected = BigInt(rate.mul(end.sub(start)).toString())
+      const claimed = firstClaim + secondClaim
+      expect(claimed).toBeLessThanOrEqual(expected)
+      expect(expected - claimed).toBeLessThanOrEqual(BigInt(3))
+    }, 30000)
+
+    it('Mid-Window Top-Up Earns On Each Amount For Its Own Time', async () => {
+      // A fixed incumbent makes the share depend on the amount, so each tranche's time shows
+      const rate = new BN(3_000_000_000)
+      const windowStart = Math.floor(Date.now() / 1000) + 8
+      const windowEnd = windowStart + 4
+      const testPool = await createPool(rate, {
+        fixedEmission: true,
+        rewardStartTime: new BN(windowStart),
+        rewardEndTime: new BN(windowEnd),
+      })
+      const incumbent = await createStaker(testPool)
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, incumbent, amount)
+      await stakeAs(testPool, staker, amount)
+      await sleep(Math.max(0, (windowStart + 2) * 1000 - Date.now()))
+      await stakeAs(testPool, staker, amount)
+      const toppedUpAt = (await program.account.userStake.fetch(staker.userStake)).lastStakeTime.toNumber()
+      await sleep(Math.max(0, (windowEnd + 1) * 1000 - Date.now()))
+      await unstakeAs(testPool, staker, amount.muln(2))
+
+      // The first 100 earns half the emission for the whole window; the second 100
+      // earns its share of the rest only from the top-up on
+      const split = Math.min(Math.max(toppedUpAt, windowStart), windowEnd)
+      const before = rate.muln(split - windowStart).divn(2)
+      const after = rate.muln(windowEnd - split).muln(2).divn(3)
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.pendingRewards.toString()).toEqual(before.add(after).toString())
+    }, 60000)
+  })
+
+  describe('update_reward_rate', () => {
+    it('Rate Change Does Not Reprice Accrued Rewards', async () => {
+      const oldRate = new BN(1_000_000_000)
+      const newRate = new BN(10_000_000_000)
+      const testPool = await createPool(oldRate, { fixedEmission: true })
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      const stakeTime = (await program.account.userStake.fetch(staker.userStake)).lastStakeTime
+
+      await sleep(2000)
+
+      await program.methods
+        .updateRewardRate(newRate)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+      const poolAtUpdate = await program.account.stakingPool.fetch(testPool.pool)
+      expect(poolAtUpdate.rewardRate.toString()).toEqual(newRate.toString())
+
+      await sleep(2000)
+
+      const claimed = await claimAs(testPool, staker)
+      const poolAtClaim = await program.account.stakingPool.fetch(testPool.pool)
+
+      // The window before the update is paid at the old rate, only the remainder at the new one
+      const expected = BigInt(
+        oldRate
+          .mul(poolAtUpdate.lastUpdateTime.sub(stakeTime))
+          .add(newRate.mul(poolAtClaim.lastUpdateTime.sub(poolAtUpdate.lastUpdateTime)))
+          .toString()
+      )
+      expect(claimed).toBeLessThanOrEqual(expected)
+      expect(expected - claimed).toBeLessThanOrEqual(BigInt(2))
+    }, 30000)
+
+    it('Rejects Rate Change From Non-Authority', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const intruder = Keypair.generate()
+
+      try {
+        await program.methods
+          .updateRewardRate(new BN(1))
+          .accounts({ authority: intruder.publicKey, pool: testPool.pool })
+          .signers([intruder])
+          .rpc()
+
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UnauthorizedAuthority/)
+      }
+    })
+
+    it('Accepts MAX_REWARD_RATE And Rejects Anything Above It', async () => {
+      const maxRate = new BN('107902830708060141')
+      const testPool = await createPool(maxRate, { fundAmount: 0 })
+      expect((await program.account.stakingPool.fetch(testPool.pool)).rewardRate.toString()).toEqual(maxRate.toString())
+
+      try {
+        await createPool(maxRate.addn(1), { fundAmount: 0 })
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/RewardRateTooHigh/)
+      }
+
+      await program.methods
+        .updateRewardRate(maxRate)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+      try {
+        await program.methods
+          .updateRewardRate(maxRate.addn(1))
+          .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+          .signers([testPool.authority])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/RewardRateTooHigh/)
+      }
+    }, 30000)
+  })
+
+  describe('set_pause', () => {
+    async function setPause(testPool: TestPool, paused: boolean) {
+      await program.methods
+        .setPause(paused)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+    }
+
+    it('Blocks Stake But Allows Unstake While Paused', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      const amount = new BN(10_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await setPause(testPool, true)
+      expect((await program.account.stakingPool.fetch(testPool.pool)).paused).toEqual(true)
+
+      try {
+        await stakeAs(testPool, staker, amount)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/PoolPaused/)
+      }
+
+      await unstakeAs(testPool, staker, amount)
+      const userStake = await program.account.userStake.fetch(staker.userStake)
+      expect(userStake.amount.toNumber()).toEqual(0)
+
+      await setPause(testPool, false)
+      await stakeAs(testPool, staker, amount)
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalStaked.toString()).toEqual(amount.toString())
+    }, 30000)
+  })
+
+  describe('close_user_stake', () => {
+    async function closeAs(testPool: TestPool, staker: TestStaker) {
+      await program.methods
+        .closeUserStake(staker.positionId)
+        .accounts({ user: staker.keypair.publicKey, pool: testPool.pool, userStake: staker.userStake })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    it('Rejects Closing While Stake Remains', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(10_000_000_000))
+
+      try {
+        await closeAs(testPool, staker)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/StakeNotEmpty/)
+      }
+    })
+
+    it('Returns Rent After Full Exit', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      const amount = new BN(10_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(2000)
+      await unstakeAs(testPool, staker, amount)
+      await claimAs(testPool, staker)
+
+      const rent = (await provider.connection.getAccountInfo(staker.userStake))!.lamports
+      const balanceBefore = await provider.connection.getBalance(staker.keypair.publicKey)
+
+      await closeAs(testPool, staker)
+
+      const balanceAfter = await provider.connection.getBalance(staker.keypair.publicKey)
+      expect(balanceAfter - balanceBefore).toEqual(rent)
+      expect(await provider.connection.getAccountInfo(staker.userStake)).toBeNull()
+    }, 30000)
+  })
+
+  describe('unbonding', () => {
+    async function requestUnstakeAs(testPool: TestPool, staker: TestStaker, amount: BN) {
+      await program.methods
+        .requestUnstake(staker.positionId, amount)
+        .accounts({ user: staker.keypair.publicKey, pool: testPool.pool, userStake: staker.userStake })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    async function completeUnstakeAs(testPool: TestPool, staker: TestStaker) {
+      await program.methods
+        .completeUnstake(staker.positionId)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          userStakeToken: staker.stakeToken,
+          poolStakeVault: testPool.stakeVault,
+          stakeTokenMint: testPool.stakeMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    it('Two-Step Unstake Waits For The Unbonding Period', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), { unbondingPeriod: new BN(4) })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(1000)
+      await requestUnstakeAs(testPool, staker, amount)
+
+      const requested = await program.account.userStake.fetch(staker.userStake)
+      expect(requested.amount.toNumber()).toEqual(0)
+      expect(requested.unbondingAmount.toString()).toEqual(amount.toString())
+      expect((await program.account.stakingPool.fetch(testPool.pool)).totalStaked.toNumber()).toEqual(0)
+
+      try {
+        await completeUnstakeAs(testPool, staker)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UnbondingNotComplete/)
+      }
+
+      await sleep(5000)
+
+      const balanceBefore = await getAccount(provider.connection, staker.stakeToken)
+      await completeUnstakeAs(testPool, staker)
+      const balanceAfter = await getAccount(provider.connection, staker.stakeToken)
+
+      expect((balanceAfter.amount - balanceBefore.amount).toString()).toEqual(amount.toString())
+      const completed = await program.account.userStake.fetch(staker.userStake)
+      expect(completed.unbondingAmount.toNumber()).toEqual(0)
+
+      // Rewards settled at request time are still claimable, and nothing accrued while unbonding
+      expect(await claimAs(testPool, staker)).toEqual(BigInt(requested.pendingRewards.toString()))
+    }, 30000)
+
+    it('Direct Withdrawals Leave Unbonding Tokens Alone', async () => {
+      // request_unstake moves tokens out of `amount`, which is all unstake,
+      // emergency_unstake and restake can draw on
+      const testPool = await createPool(new BN(1_000_000_000), { unbondingPeriod: new BN(3600) })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+      const unbonding = new BN(40_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await requestUnstakeAs(testPool, staker, unbonding)
+
+      try {
+        await unstakeAs(testPool, staker, amount)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/AmountLockedInUnbonding/)
+      }
+
+      const emergencyUnstake = () =>
+        program.methods
+          .emergencyUnstake(staker.positionId)
+          .accounts({
+            user: staker.keypair.publicKey,
+            pool: testPool.pool,
+            userStake: staker.userStake,
+            userStakeToken: staker.stakeToken,
+            poolStakeVault: testPool.stakeVault,
+            stakeTokenMint: testPool.stakeMint,
+            tokenProgram: testPool.tokenProgram,
+          })
+          .signers([staker.keypair])
+          .rpc()
+      const before = await getAccount(provider.connection, staker.stakeToken)
+      await emergencyUnstake()
+      const after = await getAccount(provider.connection, staker.stakeToken)
+
+      expect((after.amount - before.amount).toString()).toEqual(amount.sub(unbonding).toString())
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.unbondingAmount.toString()).toEqual(unbonding.toString())
+      const vault = await getAccount(provider.connection, testPool.stakeVault)
+      expect(vault.amount.toString()).toEqual(unbonding.toString())
+
+      // Only unbonding tokens are left, so neither path can reach them
+      try {
+        await emergencyUnstake()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/AmountLockedInUnbonding/)
+      }
+      try {
+        await program.methods
+          .restake(staker.positionId, new BN(60))
+          .accounts({ user: staker.keypair.publicKey, pool: testPool.pool, userStake: staker.userStake })
+          .signers([staker.keypair])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/AmountLockedInUnbonding/)
+      }
+    }, 30000)
+  })
+
+  describe('emergency_unstake', () => {
+    it('Returns Principal Before Min Duration And Forfeits Rewards', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), { minStakeDuration: new BN(3600) })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(2000)
+
+      const stakeBefore = await getAccount(provider.connection, staker.stakeToken)
+      const rewardBefore = await getAccount(provider.connection, staker.rewardToken)
+      const vaultBefore = await getAccount(provider.connection, testPool.rewardVault)
+
+      await program.methods
+        .emergencyUnstake(staker.positionId)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          userStakeToken: staker.stakeToken,
+          poolStakeVault: testPool.stakeVault,
+          stakeTokenMint: testPool.stakeMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([staker.keypair])
+        .rpc()
+
+      const stakeAfter = await getAccount(provider.connection, staker.stakeToken)
+      const rewardAfter = await getAccount(provider.connection, staker.rewardToken)
+      const vaultAfter = await getAccount(provider.connection, testPool.rewardVault)
+      const userStake = await program.account.userStake.fetch(staker.userStake)
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+
+      expect((stakeAfter.amount - stakeBefore.amount).toString()).toEqual(amount.toString())
+      expect(rewardAfter.amount).toEqual(rewardBefore.amount)
+      expect(vaultAfter.amount).toEqual(vaultBefore.amount)
+      expect(userStake.amount.toNumber()).toEqual(0)
+      expect(userStake.pendingRewards.toNumber()).toEqual(0)
+      expect(pool.totalStaked.toNumber()).toEqual(0)
+    }, 30000)
+  })
+
+  describe('underfunded reward vault', () => {
+    it('Pays In Full When The Vault Covers Rewards', async () => {
+      const testPool = await createPool(new BN(1_000_000_000))
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      await sleep(2000)
+      const claimed = await claimAs(testPool, staker)
+
+      const userStake = await program.account.userStake.fetch(staker.userStake)
+      expect(claimed).toBeGreaterThan(BigInt(0))
+      expect(userStake.pendingRewards.toNumber()).toEqual(0)
+    }, 30000)
+
+    it('Rejects Claims Against An Empty Vault', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), { fundAmount: 0 })
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      await sleep(2000)
+
+      try {
+        await claimAs(testPool, staker)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InsufficientRewardFunds/)
+      }
+    }, 30000)
+
+    it('Pays Out The Available Balance And Keeps The Rest Pending', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), { fundAmount: 0 })
+      const staker = await createStaker(testPool)
+      const partialFunding = 500_000_000
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      await sleep(2000)
+      await fundPool(testPool, partialFunding)
+
+      const claimed = await claimAs(testPool, staker)
+      const userStake = await program.account.userStake.fetch(staker.userStake)
+      const vault = await getAccount(provider.connection, testPool.rewardVault)
+
+      expect(claimed).toEqual(BigInt(partialFunding))
+      expect(vault.amount).toEqual(BigInt(0))
+      expect(userStake.pendingRewards.toNumber()).toBeGreaterThan(0)
+
+      // Once topped up, the retained remainder is paid along with new accrual
+      await fundPool(testPool, 1_000_000_000_000)
+      const remainder = await claimAs(testPool, staker)
+      expect(remainder).toBeGreaterThanOrEqual(BigInt(userStake.pendingRewards.toString()))
+    }, 30000)
+  })
+
+  describe('partial claims', () => {
+    it('Claims Half Then The Rest', async () => {
+      const testPool = await createPool(new BN(1_000_000_000))
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(2000)
+      // Exit the position so the owed amount stops moving between calls
+      await unstakeAs(testPool, staker, amount)
+
+      const owed = (await program.account.userStake.fetch(staker.userStake)).pendingRewards
+      const half = owed.divn(2)
+
+      expect(await claimAs(testPool, staker, half)).toEqual(BigInt(half.toString()))
+      const afterHalf = await program.account.userStake.fetch(staker.userStake)
+      expect(afterHalf.pendingRewards.toString()).toEqual(owed.sub(half).toString())
+
+      try {
+        await claimAs(testPool, staker, owed)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/ClaimAmountExceedsRewards/)
+      }
+
+      expect(await claimAs(testPool, staker)).toEqual(BigInt(owed.sub(half).toString()))
+      const afterRest = await program.account.userStake.fetch(staker.userStake)
+      expect(afterRest.pendingRewards.toNumber()).toEqual(0)
+    }, 30000)
+
+    it('Unclaimed Portion Keeps Tracking Accrual', async () => {
+      const testPool = await createPool(new BN(1_000_000_000))
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      await sleep(2000)
+
+      const claimed = await claimAs(testPool, staker, new BN(1_000))
+      const afterPartial = await program.account.userStake.fetch(staker.userStake)
+      expect(claimed).toEqual(BigInt(1_000))
+
+      await sleep(2000)
+      const rest = await claimAs(testPool, staker)
+      expect(rest).toBeGreaterThan(BigInt(afterPartial.pendingRewards.toString()))
+    }, 30000)
+  })
+
+  describe('compound_rewards', () => {
+    async function compoundAs(testPool: TestPool, staker: TestStaker) {
+      await program.methods
+        .compoundRewards(staker.positionId)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          poolStakeVault: testPool.stakeVault,
+          poolRewardVault: testPool.rewardVault,
+          stakeTokenMint: testPool.stakeMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    it('Restakes Rewards In A Same-Mint Pool', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), { sameMint: true })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(2000)
+
+      const rewardVaultBefore = await getAccount(provider.connection, testPool.rewardVault)
+      const stakeVaultBefore = await getAccount(provider.connection, testPool.stakeVault)
+      await compoundAs(testPool, staker)
+      const rewardVaultAfter = await getAccount(provider.connection, testPool.rewardVault)
+      const stakeVaultAfter = await getAccount(provider.connection, testPool.stakeVault)
+
+      const compounded = rewardVaultBefore.amount - rewardVaultAfter.amount
+      expect(compounded).toBeGreaterThan(BigInt(0))
+      expect(stakeVaultAfter.amount - stakeVaultBefore.amount).toEqual(compounded)
+
+      const userStake = await program.account.userStake.fetch(staker.userStake)
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(userStake.amount.toString()).toEqual(amount.add(new BN(compounded.toString())).toString())
+      expect(pool.totalStaked.toString()).toEqual(userStake.amount.toString())
+      expect(userStake.pendingRewards.toNumber()).toEqual(0)
+    }, 30000)
+
+    it('Rejects Compounding When Mints Differ', async () => {
+      const testPool = await createPool(new BN(1_000_000_000))
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      await sleep(1000)
+
+      try {
+        await compoundAs(testPool, staker)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/CompoundNotSupported/)
+      }
+    }, 30000)
+  })
+
+  describe('lock tiers', () => {
+    const tierOptions = {
+      lockTierDurations: [new BN(2), new BN(4), new BN(0), new BN(0)],
+      lockTierMultipliersBps: [12_500, 15_000, 0, 0],
+    }
+
+    it('Boosts Accrual By Each Tier Multiplier', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), { ...tierOptions, fixedEmission: true })
+      const stakers = [await createStaker(testPool), await createStaker(testPool), await createStaker(testPool)]
+      const lockPeriods = [new BN(0), new BN(2), new BN(30)]
+      const amount = new BN(100_000_000_000)
+
+      // Stake and claim atomically so every position covers the identical window
+      const stakeTx = new anchor.web3.Transaction()
+      for (const [i, staker] of stakers.entries()) {
+        stakeTx.add(
+          await program.methods
+            .stake(staker.positionId, amount, lockPeriods[i], staker.keypair.publicKey, [])
+            .accounts({
+              user: staker.keypair.publicKey,
+              pool: testPool.pool,
+              userStake: staker.userStake,
+              userStakeToken: staker.stakeToken,
+              poolStakeVault: testPool.stakeVault,
+              poolRewardVault: testPool.rewardVault,
+              stakeTokenMint: testPool.stakeMint,
+              tokenProgram: testPool.tokenProgram,
+            })
+            .instruction()
+        )
+      }
+      await provider.sendAndConfirm(stakeTx, stakers.map((staker) => staker.keypair))
+
+      const positions = await Promise.all(stakers.map((staker) => program.account.userStake.fetch(staker.userStake)))
+      expect(positions.map((position) => position.rewardMultiplierBps)).toEqual([10_000, 12_500, 15_000])
+      expect(positions[2].lockEnd.sub(positions[2].lastStakeTime).toNumber()).toEqual(30)
+
+      await sleep(3000)
+
+      const treasuryRewardToken = await treasuryAccount(testPool.rewardMint)
+      const claimTx = new anchor.web3.Transaction()
+      for (const staker of stakers) {
+        claimTx.add(
+          await program.methods
+            .claimRewards(staker.positionId, null)
+            .accounts({
+              user: staker.keypair.publicKey,
+              pool: testPool.pool,
+              userStake: staker.userStake,
+              userRewardToken: staker.rewardToken,
+              poolRewardVault: testPool.rewardVault,
+              config: configPda,
+              treasuryRewardToken,
+              rewardTokenMint: testPool.rewardMint,
+              tokenProgram: testPool.tokenProgram,
+            })
+            .instruction()
+        )
+      }
+      await provider.sendAndConfirm(claimTx, stakers.map((staker) => staker.keypair))
+
+      const rewards = await Promise.all(
+        stakers.map(async (staker) => Number((await getAccount(provider.connection, staker.rewardToken)).amount))
+      )
+      // Rewards scale with the multiplier, give or take a unit of rounding
+      expect(Math.abs(rewards[1] - (rewards[0] * 12_500) / 10_000)).toBeLessThanOrEqual(2)
+      expect(Math.abs(rewards[2] - (rewards[0] * 15_000) / 10_000)).toBeLessThanOrEqual(2)
+    }, 30000)
+
+    it('Rejects Unstaking Before The Lock Ends', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), tierOptions)
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount, new BN(3))
+
+      try {
+        await unstakeAs(testPool, staker, amount)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/LockNotExpired/)
+      }
+
+      await sleep(4000)
+      await unstakeAs(testPool, staker, amount)
+      const userStake = await program.account.userStake.fetch(staker.userStake)
+      expect(userStake.amount.toNumber()).toEqual(0)
+    }, 30000)
+
+    async function restakeAs(testPool: TestPool, staker: TestStaker, newLockPeriod: BN) {
+      await program.methods
+        .restake(staker.positionId, newLockPeriod)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+        })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    it('Restake Extends The Lock And Raises The Multiplier In Place', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), tierOptions)
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount, new BN(2))
+      const before = await program.account.userStake.fetch(staker.userStake)
+      expect(before.rewardMultiplierBps).toEqual(12_500)
+
+      await sleep(1000)
+      await restakeAs(testPool, staker, new BN(30))
+
+      const after = await program.account.userStake.fetch(staker.userStake)
+      expect(after.rewardMultiplierBps).toEqual(15_000)
+      expect(after.lockEnd.toNumber()).toBeGreaterThan(before.lockEnd.toNumber())
+      expect(after.amount.toString()).toEqual(amount.toString())
+      expect(after.pendingRewards.toNumber()).toBeGreaterThan(0)
+    }, 30000)
+
+    it('Rejects A Restake Shorter Than The Remaining Lock', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), tierOptions)
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000), new BN(30))
+
+      try {
+        await restakeAs(testPool, staker, new BN(2))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InvalidLockPeriod/)
+      }
+    }, 30000)
+  })
+
+  describe('multiple positions', () => {
+    it('Operates Two Positions Independently', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), {
+        lockTierDurations: [new BN(30), new BN(0), new BN(0), new BN(0)],
+        lockTierMultipliersBps: [15_000, 0, 0, 0],
+      })
+      const staker = await createStaker(testPool)
+      const second = { ...staker, positionId: new BN(1) }
+      second.userStake = positionPda(testPool.pool, staker.keypair.publicKey, second.positionId)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      await stakeAs(testPool, second, new BN(40_000_000_000), new BN(60))
+
+      const first = await program.account.userStake.fetch(staker.userStake)
+      const locked = await program.account.userStake.fetch(second.userStake)
+      expect(first.positionId.toNumber()).toEqual(0)
+      expect(locked.positionId.toNumber()).toEqual(1)
+      expect(first.rewardMultiplierBps).toEqual(10_000)
+      expect(locked.rewardMultiplierBps).toEqual(15_000)
+
+      // The locked position cannot exit yet, the unlocked one can
+      try {
+        await unstakeAs(testPool, second, new BN(40_000_000_000))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/LockNotExpired/)
+      }
+      await sleep(1000)
+      await unstakeAs(testPool, staker, new BN(100_000_000_000))
+
+      // Claiming one position leaves the other's accrual untouched
+      expect(await claimAs(testPool, staker)).toBeGreaterThan(BigInt(0))
+      const lockedAfter = await program.account.userStake.fetch(second.userStake)
+      expect(lockedAfter.amount.toString()).toEqual('40000000000')
+      expect(lockedAfter.rewardDebt.toString()).toEqual(locked.rewardDebt.toString())
+
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalStaked.toString()).toEqual('40000000000')
+    }, 30000)
+  })
+
+  describe('early unstake penalty', () => {
+    it('Charges The Penalty Before Min Duration And Pays The Reward Vault', async () => {
+      const testPool = await createPool(new BN(1_000), {
+        sameMint: true,
+        minStakeDuration: new BN(3600),
+        earlyUnstakePenaltyBps: 1_000,
+      })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+
+      const walletBefore = await getAccount(provider.connection, staker.stakeToken)
+      const vaultBefore = await getAccount(provider.connection, testPool.rewardVault)
+      await unstakeAs(testPool, staker, amount)
+      const walletAfter = await getAccount(provider.connection, staker.stakeToken)
+      const vaultAfter = await getAccount(provider.connection, testPool.rewardVault)
+
+      // 10% of the withdrawal goes to the reward vault, the rest to the user
+      expect((walletAfter.amount - walletBefore.amount).toString()).toEqual('90000000000')
+      expect((vaultAfter.amount - vaultBefore.amount).toString()).toEqual('10000000000')
+      expect((await program.account.stakingPool.fetch(testPool.pool)).totalStaked.toNumber()).toEqual(0)
+    }, 30000)
+
+    it('Pays In Full Once Min Duration Has Passed', async () => {
+      const testPool = await createPool(new BN(1_000), {
+        sameMint: true,
+        minStakeDuration: new BN(2),
+        earlyUnstakePenaltyBps: 1_000,
+      })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(3000)
+
+      const walletBefore = await getAccount(provider.connection, staker.stakeToken)
+      await unstakeAs(testPool, staker, amount)
+      const walletAfter = await getAccount(provider.connection, staker.stakeToken)
+
+      expect((walletAfter.amount - walletBefore.amount).toString()).toEqual(amount.toString())
+    }, 30000)
+
+    it('Rejects Penalties On Pools With Different Mints', async () => {
+      try {
+        await createPool(new BN(1_000), { earlyUnstakePenaltyBps: 1_000 })
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InvalidPenaltyConfig/)
+      }
+    }, 30000)
+
+    async function unstakeWithTreasury(testPool: TestPool, staker: TestStaker, amount: BN, treasuryStakeToken: PublicKey) {
+      await program.methods
+        .unstake(staker.positionId, amount)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          userStakeToken: staker.stakeToken,
+          poolStakeVault: testPool.stakeVault,
+          poolRewardVault: testPool.rewardVault,
+          stakeTokenMint: testPool.stakeMint,
+          tokenProgram: testPool.tokenProgram,
+          config: configPda,
+          treasuryStakeToken,
+        })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    it('Splits The Penalty Between The Treasury And The Reward Vault', async () => {
+      const testPool = await createPool(new BN(1_000), {
+        sameMint: true,
+        minStakeDuration: new BN(3600),
+        earlyUnstakePenaltyBps: 1_000,
+        penaltyTreasuryBps: 2_500,
+      })
+      const staker = await createStaker(testPool)
+      const treasuryStakeToken = await treasuryAccount(testPool.stakeMint)
+      const amount = new BN(1_000_000_030)
+
+      await stakeAs(testPool, staker, amount)
+
+      const walletBefore = await getAccount(provider.connection, staker.stakeToken)
+      const vaultBefore = await getAccount(provider.connection, testPool.rewardVault)
+      const treasuryBefore = await getAccount(provider.connection, treasuryStakeToken)
+      await unstakeWithTreasury(testPool, staker, amount, treasuryStakeToken)
+      const walletAfter = await getAccount(provider.connection, staker.stakeToken)
+      const vaultAfter = await getAccount(provider.connection, testPool.rewardVault)
+      const treasuryAfter = await getAccount(provider.connection, treasuryStakeToken)
+
+      // A 100_000_003 penalty: a quarter rounded down to the treasury, the dust to the reward vault
+      expect((walletAfter.amount - walletBefore.amount).toString()).toEqual('900000027')
+      expect((treasuryAfter.amount - treasuryBefore.amount).toString()).toEqual('25000000')
+      expect((vaultAfter.amount - vaultBefore.amount).toString()).toEqual('75000003')
+    }, 30000)
+
+    it('Requires The Treasury Account When The Penalty Has A Treasury Cut', async () => {
+      const testPool = await createPool(new BN(1_000), {
+        sameMint: true,
+        minStakeDuration: new BN(3600),
+        earlyUnstakePenaltyBps: 1_000,
+        penaltyTreasuryBps: 2_500,
+      })
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+      await stakeAs(testPool, staker, amount)
+
+      try {
+        await unstakeAs(testPool, staker, amount)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/TreasuryAccountRequired/)
+      }
+    }, 30000)
+
+    it('Rejects A Treasury Cut Above 100%', async () => {
+      try {
+        await createPool(new BN(1_000), { sameMint: true, earlyUnstakePenaltyBps: 1_000, penaltyTreasuryBps: 10_001 })
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InvalidPenaltyConfig/)
+      }
+    }, 30000)
+  })
+
+  describe('reward budget', () => {
+    it('Freezes Accrual Once The Budget Is Distributed', async () => {
+      const budget = new BN(2_000_000_000) // two seconds of emission
+      const testPool = await createPool(new BN(1_000_000_000), { rewardBudget: budget, fixedEmission: true })
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      await sleep(4000)
+
+      const claimed = await claimAs(testPool, staker)
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+
+      // The payout is clamped at the budget, give or take rounding dust
+      expect(claimed).toBeLessThanOrEqual(BigInt(budget.toString()))
+      expect(BigInt(budget.toString()) - claimed).toBeLessThanOrEqual(BigInt(2))
+      expect(pool.totalRewardsEmitted.toString()).toEqual(budget.toString())
+      expect(pool.totalRewardsDistributed.toString()).toEqual(claimed.toString())
+
+      // Nothing accrues past the budget; staking and unstaking keep working
+      await sleep(2000)
+      try {
+        await claimAs(testPool, staker)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/NoRewardsToClaim/)
+      }
+      await unstakeAs(testPool, staker, new BN(100_000_000_000))
+    }, 30000)
+  })
+
+  describe('authority transfer', () => {
+    it('Hands Over Authority After Propose And Accept', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const newAuthority = Keypair.generate()
+      const staker = await createStaker(testPool)
+
+      await program.methods
+        .proposeAuthority(newAuthority.publicKey)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+      const proposed = await program.account.stakingPool.fetch(testPool.pool)
+      expect(proposed.authority.toString()).toEqual(testPool.authority.publicKey.toString())
+      expect(proposed.pendingAuthority?.toString()).toEqual(newAuthority.publicKey.toString())
+
+      await program.methods
+        .acceptAuthority()
+        .accounts({ newAuthority: newAuthority.publicKey, pool: testPool.pool })
+        .signers([newAuthority])
+        .rpc()
+      const accepted = await program.account.stakingPool.fetch(testPool.pool)
+      expect(accepted.authority.toString()).toEqual(newAuthority.publicKey.toString())
+      expect(accepted.pendingAuthority).toBeNull()
+
+      // The new key controls the pool, and vault transfers still sign with the original seeds
+      await program.methods
+        .setPause(true)
+        .accounts({ authority: newAuthority.publicKey, pool: testPool.pool })
+        .signers([newAuthority])
+        .rpc()
+      await program.methods
+        .setPause(false)
+        .accounts({ authority: newAuthority.publicKey, pool: testPool.pool })
+        .signers([newAuthority])
+        .rpc()
+      await stakeAs(testPool, staker, new BN(10_000_000_000))
+      await sleep(1000)
+      await unstakeAs(testPool, staker, new BN(10_000_000_000))
+    }, 30000)
+
+    it('Rejects Accept From The Wrong Signer', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const newAuthority = Keypair.generate()
+      const intruder = Keypair.generate()
+
+      try {
+        await program.methods
+          .acceptAuthority()
+          .accounts({ newAuthority: intruder.publicKey, pool: testPool.pool })
+          .signers([intruder])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/NoPendingAuthority/)
+      }
+
+      await program.methods
+        .proposeAuthority(newAuthority.publicKey)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+
+      try {
+        await program.methods
+          .acceptAuthority()
+          .accounts({ newAuthority: intruder.publicKey, pool: testPool.pool })
+          .signers([intruder])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UnauthorizedAuthority/)
+      }
+    }, 30000)
+
+    it('Cancelled Proposals Can No Longer Be Accepted', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const newAuthority = Keypair.generate()
+      const cancel = () =>
+        program.methods
+          .cancelAuthorityTransfer()
+          .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+          .signers([testPool.authority])
+          .rpc()
+
+      await program.methods
+        .proposeAuthority(newAuthority.publicKey)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+      await cancel()
+      expect((await program.account.stakingPool.fetch(testPool.pool)).pendingAuthority).toBeNull()
+
+      try {
+        await program.methods
+          .acceptAuthority()
+          .accounts({ newAuthority: newAuthority.publicKey, pool: testPool.pool })
+          .signers([newAuthority])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/NoPendingAuthority/)
+      }
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.authority.toString()).toEqual(testPool.authority.publicKey.toString())
+
+      // Nothing left to cancel
+      try {
+        await cancel()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/NoPendingAuthority/)
+      }
+    }, 30000)
+  })
+
+  describe('get_pending_rewards', () => {
+    async function viewPending(testPool: TestPool, staker: TestStaker): Promise<BN> {
+      return program.methods
+        .getPendingRewards()
+        .accounts({ pool: testPool.pool, userStake: staker.userStake })
+        .view()
+    }
+
+    it('Simulated Pending Rewards Match The Claim', async () => {
+      const testPool = await createPool(new BN(1_000_000_000))
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(2000)
+      expect((await viewPending(testPool, staker)).toNumber()).toBeGreaterThan(0)
+
+      // Exit so the owed amount stops moving, then compare the quote to the payout
+      await unstakeAs(testPool, staker, amount)
+      const quoted = await viewPending(testPool, staker)
+      const claimed = await claimAs(testPool, staker)
+      expect(claimed.toString()).toEqual(quoted.toString())
+    }, 30000)
+  })
+
+  describe('token-2022', () => {
+    it('Credits Stakes Net Of The Transfer Fee', async () => {
+      const feeBps = 100 // 1% withheld on every transfer of the stake mint
+      const testPool = await createPool(new BN(1_000_000_000), {
+        tokenProgram: TOKEN_2022_PROGRAM_ID,
+        stakeTransferFeeBps: feeBps,
+      })
+      const staker = await createStaker(testPool)
+      const amount = 1_000_000_000
+      const received = amount - (amount * feeBps) / 10_000
+
+      await stakeAs(testPool, staker, new BN(amount))
+
+      const position = await program.account.userStake.fetch(staker.userStake)
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      const vault = await getAccount(provider.connection, testPool.stakeVault, undefined, TOKEN_2022_PROGRAM_ID)
+      expect(position.amount.toNumber()).toEqual(received)
+      expect(pool.totalStaked.toNumber()).toEqual(received)
+      expect(Number(vault.amount)).toEqual(received)
+
+      await sleep(2000)
+
+      // The fee is charged again on the way out, so the wallet gets back less than it staked
+      const walletBefore = await getAccount(provider.connection, staker.stakeToken, undefined, TOKEN_2022_PROGRAM_ID)
+      await unstakeAs(testPool, staker, new BN(received))
+      const walletAfter = await getAccount(provider.connection, staker.stakeToken, undefined, TOKEN_2022_PROGRAM_ID)
+      expect(Number(walletAfter.amount - walletBefore.amount)).toEqual(received - (received * feeBps) / 10_000)
+
+      const poolAfter = await program.account.stakingPool.fetch(testPool.pool)
+      const vaultAfter = await getAccount(provider.connection, testPool.stakeVault, undefined, TOKEN_2022_PROGRAM_ID)
+      expect(poolAfter.totalStaked.toNumber()).toEqual(0)
+      expect(Number(vaultAfter.amount)).toEqual(0)
+
+      expect(Number(await claimAs(testPool, staker))).toBeGreaterThan(0)
+    }, 30000)
+  })
+
+  describe('transfer_checked', () => {
+    it('Rejects A Mint With The Wrong Decimals', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      const wrongMint = await createMint(provider.connection, payer.payer, payer.publicKey, null, 6)
+
+      try {
+        await program.methods
+          .stake(staker.positionId, new BN(1_000_000), new BN(0), staker.keypair.publicKey, [])
+          .accounts({
+            user: staker.keypair.publicKey,
+            pool: testPool.pool,
+            userStake: staker.userStake,
+            userStakeToken: staker.stakeToken,
+            poolStakeVault: testPool.stakeVault,
+            poolRewardVault: testPool.rewardVault,
+            stakeTokenMint: wrongMint,
+            tokenProgram: testPool.tokenProgram,
+          })
+          .signers([staker.keypair])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/ConstraintAddress/)
+      }
+
+      const vault = await getAccount(provider.connection, testPool.stakeVault)
+      expect(Number(vault.amount)).toEqual(0)
+    }, 30000)
+  })
+
+  describe('stake caps', () => {
+    it('Rejects Stakes Beyond The Pool Cap', async () => {
+      const cap = 1_000_000_000
+      const testPool = await createPool(new BN(1_000_000), { maxTotalStake: new BN(cap) })
+      const alice = await createStaker(testPool)
+      const bob = await createStaker(testPool)
+
+      await stakeAs(testPool, alice, new BN(cap - 100))
+
+      try {
+        await stakeAs(testPool, bob, new BN(101))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/PoolCapExceeded/)
+      }
+
+      // Exactly filling the remaining room is allowed
+      await stakeAs(testPool, bob, new BN(100))
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalStaked.toNumber()).toEqual(cap)
+    }, 30000)
+
+    it('Rejects Stakes Beyond The Per-User Cap', async () => {
+      const cap = 500_000_000
+      const testPool = await createPool(new BN(1_000_000), { maxUserStake: new BN(cap) })
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(cap / 2))
+
+      try {
+        await stakeAs(testPool, staker, new BN(cap / 2 + 1))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UserCapExceeded/)
+      }
+
+      await stakeAs(testPool, staker, new BN(cap / 2))
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.amount.toNumber()).toEqual(cap)
+    }, 30000)
+
+    it('Rejects A First Stake Below The Minimum', async () => {
+      const minimum = 1_000_000
+      const testPool = await createPool(new BN(1_000_000), { minStakeAmount: new BN(minimum) })
+      const staker = await createStaker(testPool)
+
+      try {
+        await stakeAs(testPool, staker, new BN(minimum - 1))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/BelowMinimumStake/)
+      }
+
+      // Once the position clears the minimum, small top-ups are accepted
+      await stakeAs(testPool, staker, new BN(minimum))
+      await stakeAs(testPool, staker, new BN(1))
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.amount.toNumber()).toEqual(minimum + 1)
+    }, 30000)
+  })
+
+  describe('reward schedule', () => {
+    const nowSeconds = () => Math.floor(Date.now() / 1000)
+
+    async function pendingOf(testPool: TestPool, staker: TestStaker): Promise<number> {
+      const pending: BN = await program.methods
+        .getPendingRewards()
+        .accounts({ pool: testPool.pool, userStake: staker.userStake })
+        .view()
+      return pending.toNumber()
+    }
+
+    it('Rejects A Schedule That Ends Before It Starts', async () => {
+      try {
+        await createPool(new BN(1_000_000), { rewardStartTime: new BN(100), rewardEndTime: new BN(100) })
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InvalidRewardSchedule/)
+      }
+    }, 30000)
+
+    it('Accrues Nothing Before The Start', async () => {
+      const start = nowSeconds() + 600
+      const testPool = await createPool(new BN(1_000_000_000), {
+        rewardStartTime: new BN(start),
+        rewardEndTime: new BN(start + 600),
+      })
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      await sleep(2000)
+
+      expect(await pendingOf(testPool, staker)).toEqual(0)
+    }, 30000)
+
+    it('Accrues Inside The Window', async () => {
+      const now = nowSeconds()
+      const testPool = await createPool(new BN(1_000_000_000), {
+        rewardStartTime: new BN(now - 60),
+        rewardEndTime: new BN(now + 600),
+      })
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      await sleep(2000)
+
+      expect(await pendingOf(testPool, staker)).toBeGreaterThan(0)
+    }, 30000)
+
+    it('Stops Accruing After The End', async () => {
+      const now = nowSeconds()
+      const testPool = await createPool(new BN(1_000_000_000), {
+        rewardStartTime: new BN(now - 60),
+        rewardEndTime: new BN(now + 4),
+      })
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      await sleep(6000)
+      const atEnd = await pendingOf(testPool, staker)
+      await sleep(2000)
+      const later = await pendingOf(testPool, staker)
+
+      expect(atEnd).toBeGreaterThan(0)
+      expect(later).toEqual(atEnd)
+    }, 30000)
+  })
+
+  describe('close_pool', () => {
+    async function authorityAccounts(testPool: TestPool) {
+      const stakeAta = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        testPool.stakeMint,
+        testPool.authority.publicKey
+      )
+      const rewardAta = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        testPool.rewardMint,
+        testPool.authority.publicKey
+      )
+      return { stakeToken: stakeAta.address, rewardToken: rewardAta.address }
+    }
+
+    async function closePool(testPool: TestPool, accounts: { stakeToken: PublicKey; rewardToken: PublicKey }) {
+      await program.methods
+        .closePool()
+        .accounts({
+          authority: testPool.authority.publicKey,
+          pool: testPool.pool,
+          poolStakeVault: testPool.stakeVault,
+          poolRewardVault: testPool.rewardVault,
+          authorityStakeToken: accounts.stakeToken,
+          authorityRewardToken: accounts.rewardToken,
+          stakeTokenMint: testPool.stakeMint,
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([testPool.authority])
+        .rpc()
+    }
+
+    it('Refuses To Close While Users Are Staked', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+
+      try {
+        await closePool(testPool, await authorityAccounts(testPool))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/PoolNotEmpty/)
+      }
+    }, 30000)
+
+    it('Refuses To Close While Exited Positions Are Still Owed Rewards', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(1000)
+      await unstakeAs(testPool, staker, amount)
+
+      try {
+        await closePool(testPool, await authorityAccounts(testPool))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/RewardsStillOwed/)
+      }
+
+      await claimAs(testPool, staker)
+      await closePool(testPool, await authorityAccounts(testPool))
+      expect(await provider.connection.getAccountInfo(testPool.pool)).toBeNull()
+    }, 30000)
+
+    it('Sweeps Leftovers And Returns Rent To The Authority', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(1000)
+      await unstakeAs(testPool, staker, amount)
+      await claimAs(testPool, staker)
+      await fundPool(testPool, 5_000_000)
+
+      const accounts = await authorityAccounts(testPool)
+      const leftover = (await getAccount(provider.connection, testPool.rewardVault)).amount
+      const rent =
+        (await provider.connection.getBalance(testPool.pool)) +
+        (await provider.connection.getBalance(testPool.stakeVault)) +
+        (await provider.connection.getBalance(testPool.rewardVault))
+      const lamportsBefore = await provider.connection.getBalance(testPool.authority.publicKey)
+
+      await closePool(testPool, accounts)
+
+      const lamportsAfter = await provider.connection.getBalance(testPool.authority.publicKey)
+      const rewardBalance = (await getAccount(provider.connection, accounts.rewardToken)).amount
+      expect(lamportsAfter - lamportsBefore).toEqual(rent)
+      expect(rewardBalance).toEqual(leftover)
+      expect(await provider.connection.getAccountInfo(testPool.pool)).toBeNull()
+      expect(await provider.connection.getAccountInfo(testPool.stakeVault)).toBeNull()
+      expect(await provider.connection.getAccountInfo(testPool.rewardVault)).toBeNull()
+    }, 30000)
+  })
+
+  describe('fund_rewards', () => {
+    it('Anyone Can Fund An Open Pool', async () => {
+      const testPool = await createPool(new BN(1_000_000), { fundAmount: 0 })
+
+      await fundPool(testPool, 3_000_000)
+      await fundPool(testPool, 2_000_000)
+
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalRewardsFunded.toNumber()).toEqual(5_000_000)
+    }, 30000)
+
+    it('Restricted Pool Only Accepts The Authority', async () => {
+      const testPool = await createPool(new BN(1_000_000), { fundAmount: 0, restrictFunders: true })
+
+      try {
+        await fundPool(testPool, 1_000_000)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/FunderNotAuthorized/)
+      }
+
+      const authorityAta = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        testPool.rewardMint,
+        testPool.authority.publicKey
+      )
+      await mintTo(provider.connection, payer.payer, testPool.rewardMint, authorityAta.address, payer.publicKey, 1_000_000)
+      await program.methods
+        .fundRewards(new BN(1_000_000))
+        .accounts({
+          funder: testPool.authority.publicKey,
+          pool: testPool.pool,
+          funderTokenAccount: authorityAta.address,
+          poolRewardVault: testPool.rewardVault,
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([testPool.authority])
+        .rpc()
+
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalRewardsFunded.toNumber()).toEqual(1_000_000)
+    }, 30000)
+  })
+
+  describe('slash', () => {
+    async function slashAs(testPool: TestPool, staker: TestStaker, destination: PublicKey, amount: BN) {
+      await program.methods
+        .slash(amount)
+        .accounts({
+          authority: testPool.authority.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          poolStakeVault: testPool.stakeVault,
+          slashDestination: destination,
+          stakeTokenMint: testPool.stakeMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([testPool.authority])
+        .rpc()
+    }
+
+    it('Slashing Reduces The Position And The Pool Total', async () => {
+      const testPool = await createPool(new BN(1_000_000_000))
+      const staker = await createStaker(testPool)
+      const other = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+      const slashed = new BN(25_000_000_000)
+      const destination = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        testPool.stakeMint,
+        testPool.authority.publicKey
+      )
+
+      await stakeAs(testPool, staker, amount)
+      await stakeAs(testPool, other, amount)
+      await sleep(2000)
+      await slashAs(testPool, staker, destination.address, slashed)
+
+      const position = await program.account.userStake.fetch(staker.userStake)
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      const received = await getAccount(provider.connection, destination.address)
+      expect(position.amount.toString()).toEqual(amount.sub(slashed).toString())
+      expect(pool.totalStaked.toString()).toEqual(amount.mul(new BN(2)).sub(slashed).toString())
+      expect(received.amount.toString()).toEqual(slashed.toString())
+      expect(position.pendingRewards.toNumber()).toBeGreaterThan(0)
+    }, 30000)
+
+    it('Rejects Slashing More Than The Stake', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      const destination = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        testPool.stakeMint,
+        testPool.authority.publicKey
+      )
+
+      await stakeAs(testPool, staker, new BN(1_000_000))
+
+      try {
+        await slashAs(testPool, staker, destination.address, new BN(1_000_001))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/SlashExceedsStake/)
+      }
+    }, 30000)
+  })
+
+  describe('reward halving', () => {
+    const interval = 4
+
+    // Mirrors the on-chain piecewise emission so the expected payout follows the same epochs
+    function halvedEmission(rate: bigint, start: number, from: number, to: number) {
+      let total = BigInt(0)
+      let cursor = from
+      while (cursor < to) {
+        const epoch = Math.floor((cursor - start) / interval)
+        const segmentEnd = Math.min(start + (epoch + 1) * interval, to)
+        total += (rate >> BigInt(epoch)) * BigInt(segmentEnd - cursor)
+        cursor = segmentEnd
+      }
+      return total
+    }
+
+    async function accrueAcrossHalvings(boundaries: number) {
+      const rate = 1_000_000_000
+      const start = Math.floor(Date.now() / 1000) + 6
+      const testPool = await createPool(new BN(rate), {
+        fixedEmission: true,
+        rewardStartTime: new BN(start),
+        halvingInterval: new BN(interval),
+      })
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      const stakedAt = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime.toNumber()
+
+      // Claim halfway through the epoch after the requested number of boundaries
+      const target = start + boundaries * interval + interval / 2
+      await sleep(Math.max(0, target * 1000 - Date.now()))
+      const claimed = await claimAs(testPool, staker)
+      const claimedAt = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime.toNumber()
+
+      const from = Math.max(stakedAt, start)
+      expect(Math.floor((from - start) / interval)).toEqual(0)
+      expect(Math.floor((claimedAt - start) / interval)).toEqual(boundaries)
+
+      const expected = halvedEmission(BigInt(rate), start, from, claimedAt)
+      expect(claimed).toBeLessThanOrEqual(expected)
+      expect(expected - claimed).toBeLessThanOrEqual(BigInt(3))
+    }
+
+    it('Accrues Piecewise Across One Halving', async () => {
+      await accrueAcrossHalvings(1)
+    }, 30000)
+
+    it('Accrues Piecewise Across Two Halvings', async () => {
+      await accrueAcrossHalvings(2)
+    }, 30000)
+  })
+
+  describe('delegated staking', () => {
+    it('Only The Beneficiary Can Claim And Unstake', async () => {
+      const testPool = await createPool(new BN(1_000_000_000))
+      const funder = await createStaker(testPool)
+      const beneficiary = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+      const delegated = { ...beneficiary, userStake: positionPda(testPool.pool, beneficiary.keypair.publicKey, new BN(0)) }
+
+      // The funder signs and pays, the position belongs to the beneficiary
+      await program.methods
+        .stake(new BN(0), amount, new BN(0), beneficiary.keypair.publicKey, [])
+        .accounts({
+          user: funder.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: delegated.userStake,
+          userStakeToken: funder.stakeToken,
+          poolStakeVault: testPool.stakeVault,
+          poolRewardVault: testPool.rewardVault,
+          stakeTokenMint: testPool.stakeMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([funder.keypair])
+        .rpc()
+
+      const position = await program.account.userStake.fetch(delegated.userStake)
+      expect(position.user.toString()).toEqual(beneficiary.keypair.publicKey.toString())
+      expect(position.amount.toString()).toEqual(amount.toString())
+
+      await sleep(2000)
+
+      // The funder holds no claim on the position it paid for
+      const asFunder = { ...funder, userStake: delegated.userStake }
+      try {
+        await claimAs(testPool, asFunder)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/NotBeneficiary/)
+      }
+      try {
+        await unstakeAs(testPool, asFunder, amount)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/NotBeneficiary/)
+      }
+
+      await unstakeAs(testPool, delegated, amount)
+      expect(await claimAs(testPool, delegated)).toBeGreaterThan(BigInt(0))
+      const wallet = await getAccount(provider.connection, beneficiary.stakeToken)
+      expect(wallet.amount).toEqual(BigInt(1_000_000_000_000) + BigInt(amount.toString()))
+    }, 30000)
+  })
+
+  describe('protocol fee', () => {
+    async function quoteAfterExit(testPool: TestPool, staker: TestStaker, amount: BN) {
+      await stakeAs(testPool, staker, amount)
+      await sleep(2000)
+      await unstakeAs(testPool, staker, amount)
+      const quoted: BN = await program.methods
+        .getPendingRewards()
+        .accounts({ pool: testPool.pool, userStake: staker.userStake })
+        .view()
+      return BigInt(quoted.toString())
+    }
+
+    it('Splits Each Claim Between The User And The Treasury', async () => {
+      const feeBps = 1_000
+      const testPool = await createPool(new BN(1_000_000_000))
+      const staker = await createStaker(testPool)
+      const treasuryToken = await treasuryAccount(testPool.rewardMint)
+
+      const owed = await quoteAfterExit(testPool, staker, new BN(100_000_000_000))
+      await setProtocolFee(feeBps)
+      try {
+        const received = await claimAs(testPool, staker)
+        const fee = (await getAccount(provider.connection, treasuryToken)).amount
+
+        expect(fee).toEqual((owed * BigInt(feeBps)) / BigInt(10_000))
+        expect(received + fee).toEqual(owed)
+      } finally {
+        await setProtocolFee(0)
+      }
+    }, 30000)
+
+    it('Zero Fee Pays The Full Amount', async () => {
+      const testPool = await createPool(new BN(1_000_000_000))
+      const staker = await createStaker(testPool)
+      const treasuryToken = await treasuryAccount(testPool.rewardMint)
+
+      const owed = await quoteAfterExit(testPool, staker, new BN(100_000_000_000))
+      const received = await claimAs(testPool, staker)
+
+      expect(received).toEqual(owed)
+      expect((await getAccount(provider.connection, treasuryToken)).amount).toEqual(BigInt(0))
+    }, 30000)
+
+    it('Only The Admin Can Update The Config', async () => {
+      const intruder = Keypair.generate()
+      try {
+        await program.methods
+          .updateConfig(10_000, intruder.publicKey)
+          .accounts({ admin: intruder.publicKey, config: configPda })
+          .signers([intruder])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UnauthorizedConfigAdmin/)
+      }
+    }, 30000)
+  })
+
+  describe('reward settlement', () => {
+    it('Settling Twice At The Same Time Credits Nothing Extra', async () => {
+      const rate = new BN(1_000_000_000)
+      const testPool = await createPool(rate, { fixedEmission: true })
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      const start = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+      await sleep(2000)
+
+      // Both top-ups settle in the same transaction, so the second sees zero elapsed time
+      const tx = new anchor.web3.Transaction()
+      for (let i = 0; i < 2; i++) {
+        tx.add(
+          await program.methods
+            .stake(staker.positionId, new BN(1_000_000), new BN(0), staker.keypair.publicKey, [])
+            .accounts({
+              user: staker.keypair.publicKey,
+              pool: testPool.pool,
+              userStake: staker.userStake,
+              userStakeToken: staker.stakeToken,
+              poolStakeVault: testPool.stakeVault,
+              poolRewardVault: testPool.rewardVault,
+              stakeTokenMint: testPool.stakeMint,
+              tokenProgram: testPool.tokenProgram,
+            })
+            .instruction()
+        )
+      }
+      await provider.sendAndConfirm(tx, [staker.keypair])
+
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      const position = await program.account.userStake.fetch(staker.userStake)
+      const expected = BigInt(rate.mul(pool.lastUpdateTime.sub(start)).toString())
+      const pending = BigInt(position.pendingRewards.toString())
+      expect(pending).toBeLessThanOrEqual(expected)
+      expect(expected - pending).toBeLessThanOrEqual(BigInt(3))
+    }, 30000)
+
+    it('Carries Rewards Beyond u64 And Pays Them Out Over Several Claims', async () => {
+      const u64Max = new BN('18446744073709551615')
+      const fundAmount = 1_000_000_000_000
+      const testPool = await createPool(new BN('50000000000000000'), { fundAmount, fixedEmission: false })
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(400_000_000_000))
+      await sleep(2000)
+
+      // A high per-token rate on a large stake owes more than a u64 can hold within seconds
+      const owed: BN = await program.methods
+        .getPendingRewards()
+        .accounts({ pool: testPool.pool, userStake: staker.userStake })
+        .view()
+      expect(owed.gt(u64Max)).toBe(true)
+
+      // Each claim is capped by the vault and the remainder stays pending
+      for (let i = 0; i < 2; i++) {
+        const claimed = await claimAs(testPool, staker)
+        expect(claimed).toEqual(BigInt(fundAmount))
+        const position = await program.account.userStake.fetch(staker.userStake)
+        expect(position.pendingRewards.gt(u64Max)).toBe(true)
+        await fundPool(testPool, fundAmount)
+      }
+    }, 30000)
+
+    it('Top-Up Pays Out Rewards Beyond u64 Before Adding Stake', async () => {
+      const u64Max = new BN('18446744073709551615')
+      const testPool = await createPool(new BN('50000000000000000'), { fixedEmission: false })
+      const staker = await createStaker(testPool)
+      const amount = new BN(400_000_000_000)
+      const topUp = (rewardAccounts: object) =>
+        program.methods
+          .stake(staker.positionId, amount, new BN(0), staker.keypair.publicKey, [])
+          .accounts({
+            user: staker.keypair.publicKey,
+            pool: testPool.pool,
+            userStake: staker.userStake,
+            userStakeToken: staker.stakeToken,
+            poolStakeVault: testPool.stakeVault,
+            poolRewardVault: testPool.rewardVault,
+            stakeTokenMint: testPool.stakeMint,
+            tokenProgram: testPool.tokenProgram,
+            ...rewardAccounts,
+          })
+          .signers([staker.keypair])
+          .rpc()
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(2000)
+
+      // Past the ceiling the stake has to pay out, so it needs somewhere to send it
+      try {
+        await topUp({})
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/RewardAccountsRequired/)
+      }
+
+      const vaultBefore = (await getAccount(provider.connection, testPool.rewardVault)).amount
+      await topUp({
+        userRewardToken: staker.rewardToken,
+        config: configPda,
+        treasuryRewardToken: await treasuryAccount(testPool.rewardMint, testPool.tokenProgram),
+        rewardTokenMint: testPool.rewardMint,
+      })
+
+      // The whole vault went out and the rest stays owed
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.amount.toString()).toEqual(amount.muln(2).toString())
+      expect(position.pendingRewards.gt(u64Max)).toBe(true)
+      expect((await getAccount(provider.connection, testPool.rewardVault)).amount).toEqual(BigInt(0))
+      expect((await getAccount(provider.connection, staker.rewardToken)).amount).toBeGreaterThan(BigInt(0))
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalRewardsDistributed.toString()).toEqual(vaultBefore.toString())
+    }, 30000)
+  })
+
+  describe('admin_return_stake', () => {
+    async function returnStake(testPool: TestPool, staker: TestStaker) {
+      await program.methods
+        .adminReturnStake()
+        .accounts({
+          authority: testPool.authority.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          userStakeToken: staker.stakeToken,
+          poolStakeVault: testPool.stakeVault,
+          stakeTokenMint: testPool.stakeMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([testPool.authority])
+        .rpc()
+    }
+
+    it('Returns Principal Without The User Signing And Keeps Rewards Claimable', async () => {
+      const testPool = await createPool(new BN(1_000_000_000))
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(2000)
+
+      const walletBefore = await getAccount(provider.connection, staker.stakeToken)
+      await returnStake(testPool, staker)
+      const walletAfter = await getAccount(provider.connection, staker.stakeToken)
+
+      const position = await program.account.userStake.fetch(staker.userStake)
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect((walletAfter.amount - walletBefore.amount).toString()).toEqual(amount.toString())
+      expect(position.amount.toNumber()).toEqual(0)
+      expect(pool.totalStaked.toNumber()).toEqual(0)
+      expect(position.pendingRewards.toNumber()).toBeGreaterThan(0)
+
+      // The owner claims what was earned before the return
+      const claimed = await claimAs(testPool, staker)
+      expect(claimed.toString()).toEqual(position.pendingRewards.toString())
+
+      try {
+        await returnStake(testPool, staker)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UserStakeAlreadyEmpty/)
+      }
+    }, 30000)
+  })
+
+  describe('quote_rewards', () => {
+    async function quote(testPool: TestPool, staker: TestStaker) {
+      const quoted: BN = await program.methods
+        .quoteRewards()
+        .accounts({
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          poolRewardVault: testPool.rewardVault,
+          config: configPda,
+        })
+        .view()
+      return BigInt(quoted.toString())
+    }
+
+    it('Quote Matches A Budget-Capped Claim', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), { rewardBudget: new BN(1_500_000_000) })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(3000)
+      await unstakeAs(testPool, staker, amount)
+
+      // Time keeps moving but nothing is earning, so the quote is stable
+      const quoted = await quote(testPool, staker)
+      await sleep(1000)
+      expect(await quote(testPool, staker)).toEqual(quoted)
+      expect(quoted).toEqual(BigInt(1_500_000_000))
+      expect(await claimAs(testPool, staker)).toEqual(quoted)
+    }, 30000)
+
+    it('Quote Matches An Underfunded Claim Net Of The Protocol Fee', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), { fundAmount: 1_000_000 })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(2000)
+      await unstakeAs(testPool, staker, amount)
+
+      await setProtocolFee(250)
+      try {
+        const quoted = await quote(testPool, staker)
+        expect(quoted).toEqual(BigInt(1_000_000 - 25_000))
+        expect(await claimAs(testPool, staker)).toEqual(quoted)
+      } finally {
+        await setProtocolFee(0)
+      }
+    }, 30000)
+  })
+
+  describe('collection gate', () => {
+    // Metadata accounts are loaded into the validator from tests/fixtures (see Anchor.toml);
+    // each names a mint whose keypair sits beside it, inside a verified collection
+    const GATED_COLLECTION = new PublicKey('4jn7zY3F47FeCEnchwfi4snM4HL1wjQN3m6scCyD2PPb')
+
+    function fixtureNft(name: string) {
+      const read = (file: string) => JSON.parse(readFileSync(`${__dirname}/fixtures/${file}`, 'utf8'))
+      const mint = Keypair.fromSecretKey(Uint8Array.from(read(`${name}-nft-mint.json`)))
+      const metadata = new PublicKey(read(`${name}-nft-metadata.json`).pubkey)
+      return { mint, metadata }
+    }
+
+    const memberNft = fixtureNft('gated')
+    const outsiderNft = fixtureNft('outsider')
+
+    beforeAll(async () => {
+      for (const nft of [memberNft, outsiderNft]) {
+        if (!(await provider.connection.getAccountInfo(nft.mint.publicKey))) {
+          await createMint(provider.connection, payer.payer, payer.publicKey, null, 0, nft.mint)
+        }
+      }
+    })
+
+    async function giveNft(staker: TestStaker, nft: ReturnType<typeof fixtureNft>) {
+      const ata = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        nft.mint.publicKey,
+        staker.keypair.publicKey
+      )
+      await mintTo(provider.connection, payer.payer, nft.mint.publicKey, ata.address, payer.publicKey, 1)
+      return ata.address
+    }
+
+    async function gatedStake(
+      testPool: TestPool,
+      staker: TestStaker,
+      amount: BN,
+      nftToken: PublicKey | null,
+      nftMetadata: PublicKey | null
+    ) {
+      await program.methods
+        .stake(staker.positionId, amount, new BN(0), staker.keypair.publicKey, [])
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          userStakeToken: staker.stakeToken,
+          poolStakeVault: testPool.stakeVault,
+          poolRewardVault: testPool.rewardVault,
+          stakeTokenMint: testPool.stakeMint,
+          tokenProgram: testPool.tokenProgram,
+          nftToken,
+          nftMetadata,
+        })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    it('Collection Holder Can Stake', async () => {
+      const testPool = await createPool(new BN(1_000_000), { gateCollection: GATED_COLLECTION })
+      const staker = await createStaker(testPool)
+      const nftToken = await giveNft(staker, memberNft)
+      const amount = new BN(1_000_000_000)
+
+      await gatedStake(testPool, staker, amount, nftToken, memberNft.metadata)
+
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.amount.toString()).toEqual(amount.toString())
+    }, 30000)
+
+    it('Rejects Stakers Outside The Collection', async () => {
+      const testPool = await createPool(new BN(1_000_000), { gateCollection: GATED_COLLECTION })
+      const staker = await createStaker(testPool)
+      const outsiderToken = await giveNft(staker, outsiderNft)
+      const amount = new BN(1_000_000_000)
+
+      try {
+        await gatedStake(testPool, staker, amount, outsiderToken, outsiderNft.metadata)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/NotCollectionHolder/)
+      }
+
+      // Metadata from the right collection doesn't help without holding its mint
+      try {
+        await gatedStake(testPool, staker, amount, outsiderToken, memberNft.metadata)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/NotCollectionHolder/)
+      }
+
+      try {
+        await gatedStake(testPool, staker, amount, null, null)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/NotCollectionHolder/)
+      }
+    }, 30000)
+  })
+
+  describe('early staker boost', () => {
+    const nowSeconds = () => Math.floor(Date.now() / 1000)
+    const sleepUntil = (seconds: number) => sleep(Math.max(0, seconds * 1000 - Date.now()))
+
+    it('Only Positions Opened Before The Cutoff Earn The Boost', async () => {
+      // Both positions are settled before rewards start, so the whole window is shared
+      const now = nowSeconds()
+      const cutoff = now + 8
+      const start = now + 15
+      const end = start + 3
+      const testPool = await createPool(new BN(1_000_000_000), {
+        fixedEmission: true,
+        earlyStakerCutoff: new BN(cutoff),
+        earlyStakerBoostBps: 10_000,
+        rewardStartTime: new BN(start),
+        rewardEndTime: new BN(end),
+      })
+      const early = await createStaker(testPool)
+      const late = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+
+      await stakeAs(testPool, early, amount)
+      const opened = (await program.account.userStake.fetch(early.userStake)).firstStakeTime
+
+      await sleepUntil(cutoff + 2)
+      await stakeAs(testPool, late, amount.muln(2))
+
+      // A top-up after the cutoff keeps the boost and the original entry time
+      await stakeAs(testPool, early, amount)
+      const topped = await program.account.userStake.fetch(early.userStake)
+      expect(topped.firstStakeTime.toString()).toEqual(opened.toString())
+
+      const poolAccount = await program.account.stakingPool.fetch(testPool.pool)
+      expect(poolAccount.totalWeightedStake.toString()).toEqual(amount.muln(6).toString())
+
+      await sleepUntil(end + 2)
+      await unstakeAs(testPool, early, amount.muln(2))
+      await unstakeAs(testPool, late, amount.muln(2))
+
+      // 3 seconds at 1_000 tokens/s split 4:2 between equal balances
+      const earlyPosition = await program.account.userStake.fetch(early.userStake)
+      const latePosition = await program.account.userStake.fetch(late.userStake)
+      expect(earlyPosition.pendingRewards.toString()).toEqual('2000000000')
+      expect(latePosition.pendingRewards.toString()).toEqual('1000000000')
+    }, 60000)
+  })
+
+  describe('withdraw_excess_rewards', () => {
+    async function withdrawExcess(testPool: TestPool, amount: BN) {
+      const authorityRewardToken = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        testPool.rewardMint,
+        testPool.authority.publicKey
+      )
+      await program.methods
+        .withdrawExcessRewards(amount)
+        .accounts({
+          authority: testPool.authority.publicKey,
+          pool: testPool.pool,
+          poolRewardVault: testPool.rewardVault,
+          authorityRewardToken: authorityRewardToken.address,
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([testPool.authority])
+        .rpc()
+      return authorityRewardToken.address
+    }
+
+    it('Withdraws Down To The Reserve', async () => {
+      const testPool = await createPool(new BN(1_000_000), {
+        fundAmount: 1_000_000_000,
+        rewardReserve: new BN(100_000_000),
+      })
+
+      const destination = await withdrawExcess(testPool, new BN(900_000_000))
+      expect((await getAccount(provider.connection, destination)).amount).toEqual(BigInt(900_000_000))
+
+      try {
+        await withdrawExcess(testPool, new BN(1))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/ExcessWithdrawTooLarge/)
+      }
+    }, 30000)
+
+    it('Leaves Owed Rewards Claimable', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), {
+        fixedEmission: true,
+        fundAmount: 10_000_000_000,
+        rewardReserve: new BN(100_000_000),
+      })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(2000)
+      await unstakeAs(testPool, staker, amount)
+
+      // Nothing is earning any more, so the floor is fixed at emitted + reserve
+      const poolAccount = await program.account.stakingPool.fetch(testPool.pool)
+      const excess = new BN(10_000_000_000).sub(poolAccount.totalRewardsEmitted).subn(100_000_000)
+
+      try {
+        await withdrawExcess(testPool, excess.addn(1))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/ExcessWithdrawTooLarge/)
+      }
+      await withdrawExcess(testPool, excess)
+
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect((await claimAs(testPool, staker)).toString()).toEqual(position.pendingRewards.toString())
+      const vault = await getAccount(provider.connection, testPool.rewardVault)
+      expect(vault.amount).toBeGreaterThanOrEqual(BigInt(100_000_000))
+    }, 30000)
+  })
+
+  describe('settle_many', () => {
+    it('Settles Every Position In One Transaction', async () => {
+      const testPool = await createPool(new BN(1_000_000_000))
+      const stakers = [await createStaker(testPool), await createStaker(testPool), await createStaker(testPool)]
+      for (const staker of stakers) {
+        await stakeAs(testPool, staker, new BN(100_000_000_000))
+      }
+
+      // A position from another pool is skipped rather than settled against this one
+      const otherPool = await createPool(new BN(1_000_000_000))
+      const outsider = await createStaker(otherPool)
+      await stakeAs(otherPool, outsider, new BN(100_000_000_000))
+
+      await sleep(2000)
+      await program.methods
+        .settleMany()
+        .accounts({ pool: testPool.pool })
+        .remainingAccounts(
+          [...stakers, outsider].map((staker) => ({ pubkey: staker.userStake, isWritable: true, isSigner: false }))
+        )
+        .rpc()
+
+      const poolAccount = await program.account.stakingPool.fetch(testPool.pool)
+      for (const staker of stakers) {
+        const position = await program.account.userStake.fetch(staker.userStake)
+        expect(position.pendingRewards.gtn(0)).toBe(true)
+        // Debt is re-snapshotted, so the position has nothing further to settle yet
+        expect(position.rewardDebt.toString()).toEqual(position.amount.mul(poolAccount.accRewardPerShare).toString())
+      }
+      const untouched = await program.account.userStake.fetch(outsider.userStake)
+      expect(untouched.pendingRewards.toString()).toEqual('0')
+    }, 30000)
+  })
+
+  describe('mint decimals', () => {
+    it('Pays The Scheduled Emission When Stake And Reward Decimals Differ', async () => {
+      // 1 reward token (9 decimals) per second to a pool staked in a 6-decimal token
+      const testPool = await createPool(new BN(1_000_000_000), {
+        stakeDecimals: 6,
+        rewardDecimals: 9,
+        fixedEmission: true,
+      })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000) // 100 stake tokens
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(3000)
+      await unstakeAs(testPool, staker, amount)
+
+      const poolAccount = await program.account.stakingPool.fetch(testPool.pool)
+      expect(poolAccount.stakeDecimals).toEqual(6)
+      expect(poolAccount.rewardDecimals).toEqual(9)
+
+      // A lone staker earns the whole emission: whole reward tokens per elapsed second
+      const emitted = poolAccount.totalRewardsEmitted
+      expect(emitted.gtn(0)).toBe(true)
+      expect(emitted.modn(1_000_000_000)).toEqual(0)
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(emitted.sub(position.pendingRewards).lten(1)).toBe(true)
+    }, 30000)
+
+    it('Pays A Per-Token Rate In Whole Tokens When Decimals Differ', async () => {
+      // 1 reward token (9 decimals) per staked token (6 decimals) per second over a 3 second window
+      const start = Math.floor(Date.now() / 1000) + 10
+      const testPool = await createPool(new BN(1_000_000_000), {
+        stakeDecimals: 6,
+        rewardDecimals: 9,
+        fixedEmission: false,
+        rewardStartTime: new BN(start),
+        rewardEndTime: new BN(start + 3),
+      })
+
+      const preview: BN = await program.methods
+        .previewStake(new BN(100_000_000), new BN(600))
+        .accounts({ pool: testPool.pool })
+        .view()
+      // 100 staked tokens for 3 seconds earn 300 reward tokens
+      expect(preview.toString()).toEqual('300000000000')
+    }, 30000)
+  })
+
+  describe('preview_stake', () => {
+    const nowSeconds = () => Math.floor(Date.now() / 1000)
+
+    it('Preview Matches An Executed Stake And Claim', async () => {
+      // Both stakes land before the window opens, so the preview sees the final weights
+      const start = nowSeconds() + 10
+      const end = start + 3
+      const testPool = await createPool(new BN(1_000_000_000), {
+        fixedEmission: true,
+        lockTierDurations: [new BN(60), new BN(0), new BN(0), new BN(0)],
+        lockTierMultipliersBps: [20_000, 0, 0, 0],
+        rewardStartTime: new BN(start),
+        rewardEndTime: new BN(end),
+      })
+      const incumbent = await createStaker(testPool)
+      const newcomer = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+      const lockPeriod = new BN(600)
+
+      await stakeAs(testPool, incumbent, amount)
+
+      const preview: BN = await program.methods
+        .previewStake(amount, lockPeriod)
+        .accounts({ pool: testPool.pool })
+        .view()
+      // The 2x tier takes two thirds of the 3 second window
+      expect(preview.toString()).toEqual('2000000000')
+
+      await stakeAs(testPool, newcomer, amount, lockPeriod)
+      await sleep(Math.max(0, (end + 2) * 1000 - Date.now()))
+
+      expect((await claimAs(testPool, newcomer)).toString()).toEqual(preview.toString())
+    }, 60000)
+  })
+
+  describe('position identity', () => {
+    it('Reopening An Emptied Position Keeps Its Owner', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const owner = await createStaker(testPool)
+      const funder = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+
+      await stakeAs(testPool, owner, amount)
+      await unstakeAs(testPool, owner, amount)
+      const emptied = await program.account.userStake.fetch(owner.userStake)
+
+      // A third party may refill the emptied position, but only for its owner
+      await program.methods
+        .stake(owner.positionId, amount, new BN(0), owner.keypair.publicKey, [])
+        .accounts({
+          user: funder.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: owner.userStake,
+          userStakeToken: funder.stakeToken,
+          poolStakeVault: testPool.stakeVault,
+          poolRewardVault: testPool.rewardVault,
+          stakeTokenMint: testPool.stakeMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([funder.keypair])
+        .rpc()
+
+      const reopened = await program.account.userStake.fetch(owner.userStake)
+      expect(reopened.user.toString()).toEqual(owner.keypair.publicKey.toString())
+      expect(reopened.pool.toString()).toEqual(emptied.pool.toString())
+      expect(reopened.bump).toEqual(emptied.bump)
+    }, 30000)
+
+    it("Rejects Staking Into Another User's Position", async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const owner = await createStaker(testPool)
+      const intruder = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+
+      await stakeAs(testPool, owner, amount)
+
+      // The intruder names themself as beneficiary but points at the owner's account
+      try {
+        await stakeAs(testPool, { ...intruder, userStake: owner.userStake }, amount)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/ConstraintSeeds|StakeOwnerMismatch/)
+      }
+
+      const position = await program.account.userStake.fetch(owner.userStake)
+      expect(position.user.toString()).toEqual(owner.keypair.publicKey.toString())
+      expect(position.amount.toString()).toEqual(amount.toString())
+    }, 30000)
+  })
+
+  describe('poke', () => {
+    it('Repeated Pokes Match A Single Large Update', async () => {
+      const rate = new BN(1_000_000_000)
+      const testPool = await createPool(rate, { fixedEmission: true })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      const staked = await program.account.stakingPool.fetch(testPool.pool)
+
+      for (let i = 0; i < 3; i++) {
+        await sleep(1000)
+        await program.methods.poke().accounts({ pool: testPool.pool }).rpc()
+      }
+
+      // Each step divides exactly here, so piecewise advances must add up to one update
+      const poked = await program.account.stakingPool.fetch(testPool.pool)
+      const elapsed = poked.lastUpdateTime.sub(staked.lastUpdateTime)
+      expect(elapsed.gtn(0)).toBe(true)
+      const expected = staked.accRewardPerShare.add(rate.mul(elapsed).mul(ACC_REWARD_PRECISION).div(amount))
+      expect(poked.accRewardPerShare.toString()).toEqual(expected.toString())
+      expect(poked.totalRewardsEmitted.toString()).toEqual(rate.mul(elapsed).toString())
+    }, 30000)
+  })
+
+  describe('stake_sol', () => {
+    async function stakeSol(testPool: TestPool, user: Keypair, amount: BN) {
+      await program.methods
+        .stakeSol(new BN(0), amount, new BN(0), user.publicKey, [])
+        .accounts({
+          user: user.publicKey,
+          pool: testPool.pool,
+          userStake: positionPda(testPool.pool, user.publicKey, new BN(0)),
+          poolStakeVault: testPool.stakeVault,
+          poolRewardVault: testPool.rewardVault,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([user])
+        .rpc()
+    }
+
+    it('Stakes Raw SOL Into A Wrapped-SOL Pool', async () => {
+      const testPool = await createPool(new BN(1_000_000), { nativeStake: true })
+      const user = Keypair.generate()
+      await airdrop(user.publicKey)
+      const userStake = positionPda(testPool.pool, user.publicKey, new BN(0))
+      const amount = new BN(LAMPORTS_PER_SOL)
+
+      const lamportsBefore = await provider.connection.getBalance(user.publicKey)
+      await stakeSol(testPool, user, amount)
+
+      const position = await program.account.userStake.fetch(userStake)
+      expect(position.amount.toString()).toEqual(amount.toString())
+      const vault = await getAccount(provider.connection, testPool.stakeVault)
+      expect(vault.amount).toEqual(BigInt(LAMPORTS_PER_SOL))
+
+      // The user paid the stake, the position's rent and the fee; no other account was left open
+      const spent = lamportsBefore - (await provider.connection.getBalance(user.publicKey))
+      const positionRent = (await provider.connection.getAccountInfo(userStake))!.lamports
+      expect(spent).toBeLessThanOrEqual(LAMPORTS_PER_SOL + positionRent + 10_000)
+
+      // Unstaking pays out wrapped SOL like any other stake token
+      const wsol = await getOrCreateAssociatedTokenAccount(provider.connection, payer.payer, NATIVE_MINT, user.publicKey)
+      const staker = { keypair: user, stakeToken: wsol.address, rewardToken: wsol.address, positionId: new BN(0), userStake }
+      await unstakeAs(testPool, staker, amount)
+      expect((await getAccount(provider.connection, wsol.address)).amount).toEqual(BigInt(LAMPORTS_PER_SOL))
+    }, 30000)
+
+    it('Rejects SOL Stakes In Other Pools', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const user = Keypair.generate()
+      await airdrop(user.publicKey)
+
+      try {
+        await stakeSol(testPool, user, new BN(LAMPORTS_PER_SOL))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/NotNativeMintPool/)
+      }
+    }, 30000)
+  })
+
+  describe('claim destination', () => {
+    it('Pays Rewards Into Another Wallet', async () => {
+      const testPool = await createPool(new BN(1_000_000_000))
+      const staker = await createStaker(testPool)
+      const recipient = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(2000)
+
+      // The staker signs; the recipient's account receives the payout
+      const claimed = await claimAs(testPool, { ...staker, rewardToken: recipient.rewardToken })
+      expect(claimed > BigInt(0)).toBe(true)
+
+      const ownAccount = await getAccount(provider.connection, staker.rewardToken)
+      expect(ownAccount.amount).toEqual(BigInt(0))
+    }, 30000)
+  })
+
+  describe('claim cooldown', () => {
+    it('Blocks A Claim Inside The Cooldown And Allows One After', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), { claimCooldown: new BN(4) })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(1000)
+      await claimAs(testPool, staker)
+
+      await sleep(1000)
+      try {
+        await claimAs(testPool, staker)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/ClaimCooldownActive/)
+      }
+
+      await sleep(4000)
+      expect((await claimAs(testPool, staker)) > BigInt(0)).toBe(true)
+    }, 30000)
+
+    it('Does Not Block Unstake Settlement', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), { claimCooldown: new BN(600) })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      await sleep(1000)
+      await claimAs(testPool, staker)
+      await sleep(1000)
+
+      // Rewards still settle into pending while the claim itself is on cooldown
+      await unstakeAs(testPool, staker, amount)
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.amount.toString()).toEqual('0')
+      expect(position.pendingRewards.gtn(0)).toBe(true)
+    }, 30000)
+  })
+
+  describe('accrual precision', () => {
+    it('Slow Emission Into A Large Pool Still Accrues', async () => {
+      // 2 units/s over 10_000 tokens is 0.2 scaled units per share each second,
+      // which a per-update floor would round to nothing on every poke
+      const testPool = await createPool(new BN(2), { fixedEmission: true })
+      const staker = await createStaker(testPool, 10_000_000_000_000)
+      const amount = new BN(10_000_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      for (let i = 0; i < 7; i++) {
+        await sleep(1000)
+        await program.methods.poke().accounts({ pool: testPool.pool }).rpc()
+      }
+
+      const stored: BN = await program.methods
+        .rewardsPerTokenStored()
+        .accounts({ pool: testPool.pool })
+        .view()
+      expect(stored.gtn(0)).toBe(true)
+
+      await unstakeAs(testPool, staker, amount)
+      const poolAccount = await program.account.stakingPool.fetch(testPool.pool)
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.pendingRewards.gtn(0)).toBe(true)
+      // What is still waiting is less than one scaled unit per share across the pool
+      const waiting = poolAccount.totalRewardsEmitted.sub(position.pendingRewards)
+      expect(waiting.lte(amount.div(ACC_REWARD_PRECISION))).toBe(true)
+      expect((await claimAs(testPool, staker)) > BigInt(0)).toBe(true)
+    }, 30000)
+  })
+
+  describe('migrate_user_stake', () => {
+    // A position in the layout from before the version field, loaded from tests/fixtures
+    const legacy = JSON.parse(readFileSync(`${__dirname}/fixtures/legacy-user-stake.json`, 'utf8'))
+    const legacyStake = new PublicKey(legacy.pubkey)
+
+    async function migrate(userStake: PublicKey) {
+      await program.methods.migrateUserStake().accounts({ payer: payer.publicKey, userStake }).rpc()
+    }
+
+    it('Grows A Legacy Position And Keeps Its Values', async () => {
+      const before = await provider.connection.getAccountInfo(legacyStake)
+      expect(before!.data.length).toEqual(legacy.account.space)
+
+      await migrate(legacyStake)
+
+      const after = await provider.connection.getAccountInfo(legacyStake)
+      // version, last_settle_time, the second reward fields, frozen, auto_compound,
+      // claim_count, warmup_start and locked_duration are appended zeroed
+      expect(after!.data.length).toEqual(legacy.account.space + 84)
+      const position = await program.account.userStake.fetch(legacyStake)
+      expect(position.version).toEqual(8)
+      expect(position.user.toString()).toEqual('9KAFWqyMN1kr6hRsBvyENem8fxcuCz1iZaaUgYRY9SWK')
+      expect(position.pool.toString()).toEqual('FynGwUzdSafbcjvCVyQZDD4yJrdWVYhFzAeq1p3F39Zo')
+      expect(position.positionId.toNumber()).toEqual(7)
+      expect(position.amount.toString()).toEqual('5000000000')
+      expect(position.pendingRewards.toString()).toEqual('123456789')
+      expect(position.rewardDebt.toString()).toEqual('42')
+      expect(position.lockEnd.toNumber()).toEqual(1_800_000_000)
+      expect(position.rewardMultiplierBps).toEqual(15_000)
+      expect(position.bump).toEqual(254)
+      expect(position.lastSettleTime.toNumber()).toEqual(0)
+      expect(position.lockedDuration).toBeNull()
+      expect(position.pendingRewards2.toNumber()).toEqual(0)
+      expect(position.frozen).toEqual(false)
+      expect(position.autoCompound).toEqual(false)
+      expect(position.claimCount.toNumber()).toEqual(0)
+      expect(position.warmupStart.toNumber()).toEqual(0)
+
+      try {
+        await migrate(legacyStake)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UserStakeAlreadyMigrated/)
+      }
+    }, 30000)
+
+    it('Keeps The Legacy Bytes And Zero-Fills The Appended Fields', async () => {
+      // The fixture is raw bytes in the old layout, which ended at the bump
+      const legacyBytes = Buffer.from(legacy.account.data[0], 'base64')
+      expect(legacyBytes.length).toEqual(187)
+      expect(legacyBytes.readBigUInt64LE(72)).toEqual(BigInt(7))
+      expect(legacyBytes.readBigUInt64LE(80)).toEqual(BigInt(5_000_000_000))
+      expect(legacyBytes.readUInt16LE(184)).toEqual(15_000)
+      expect(legacyBytes[186]).toEqual(254)
+
+      // Migrated by the test above
+      const after = await provider.connection.getAccountInfo(legacyStake)
+      expect(after!.owner.toString()).toEqual(program.programId.toString())
+      expect(after!.data.subarray(0, 187).equals(legacyBytes)).toBe(true)
+      expect(after!.data[187]).toEqual(8)
+      expect(after!.data.subarray(188).every((byte) => byte === 0)).toBe(true)
+      const rent = await provider.connection.getMinimumBalanceForRentExemption(after!.data.length)
+      expect(after!.lamports).toBeGreaterThanOrEqual(rent)
+    }, 30000)
+
+    it('New Positions Start On The Current Layout', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.version).toEqual(8)
+      try {
+        await migrate(staker.userStake)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UserStakeAlreadyMigrated/)
+      }
+    }, 30000)
+  })
+
+  describe('emission modes', () => {
+    // Accumulator growth per second while a second, equal stake joins the pool
+    async function accrualWithTwoStakers(testPool: TestPool, amount: BN) {
+      const first = await createStaker(testPool)
+      const second = await createStaker(testPool)
+      await stakeAs(testPool, first, amount)
+      await stakeAs(testPool, second, amount)
+      const before = await program.account.stakingPool.fetch(testPool.pool)
+      await sleep(2000)
+      await program.methods.poke().accounts({ pool: testPool.pool }).rpc()
+      const after = await program.account.stakingPool.fetch(testPool.pool)
+      return {
+        perShare: after.accRewardPerShare.sub(before.accRewardPerShare),
+        elapsed: after.lastUpdateTime.sub(before.lastUpdateTime),
+      }
+    }
+
+    it('Fixed Emission Is Split Across TVL', async () => {
+      const rate = new BN(1_000_000_000)
+      const amount = new BN(100_000_000_000)
+      const testPool = await createPool(rate, { fixedEmission: true })
+
+      const { perShare, elapsed } = await accrualWithTwoStakers(testPool, amount)
+      // Doubling the stake halves what each token earns
+      expect(perShare.toString()).toEqual(rate.mul(elapsed).mul(ACC_REWARD_PRECISION).div(amount.muln(2)).toString())
+    }, 30000)
+
+    it('Per-Token Rate Holds As TVL Grows', async () => {
+      // 0.001 reward units per stake unit per second
+      const rate = new BN(1_000_000)
+      const amount = new BN(100_000_000_000)
+      const testPool = await createPool(rate, { fixedEmission: false })
+
+      const { perShare, elapsed } = await accrualWithTwoStakers(testPool, amount)
+      // Each token earns rate / 1e9 per second no matter how many others are staked
+      const perToken = rate.mul(ACC_REWARD_PRECISION).divn(1_000_000_000)
+      expect(perShare.toString()).toEqual(perToken.mul(elapsed).toString())
+    }, 30000)
+  })
+
+  describe('require_prefunded', () => {
+    it('Rejects The First Stake Until The Reward Vault Is Funded', async () => {
+      const testPool = await createPool(new BN(1_000_000), { fundAmount: 0, requirePrefunded: true })
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+
+      try {
+        await stakeAs(testPool, staker, amount)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/RewardVaultNotFunded/)
+      }
+
+      await fundPool(testPool, 1_000_000_000)
+      await stakeAs(testPool, staker, amount)
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.amount.toString()).toEqual(amount.toString())
+    }, 30000)
+  })
+
+  describe('split_position', () => {
+    async function split(testPool: TestPool, staker: TestStaker, splitAmount: BN, newPositionId: BN) {
+      await program.methods
+        .splitPosition(staker.positionId, splitAmount, newPositionId)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          newUserStake: positionPda(testPool.pool, staker.keypair.publicKey, newPositionId),
+        })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    it('Rewards Continue Across A Split', async () => {
+      const rate = new BN(1_000_000_000)
+      const testPool = await createPool(rate, { fixedEmission: true })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      const start = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+      await sleep(2000)
+
+      const newPositionId = new BN(1)
+      await split(testPool, staker, amount.divn(4), newPositionId)
+      const splitOff = { ...staker, positionId: newPositionId, userStake: positionPda(testPool.pool, staker.keypair.publicKey, newPositionId) }
+
+      const source = await program.account.userStake.fetch(staker.userStake)
+      const created = await program.account.userStake.fetch(splitOff.userStake)
+      expect(source.amount.toString()).toEqual(amount.muln(3).divn(4).toString())
+      expect(created.amount.toString()).toEqual(amount.divn(4).toString())
+      expect(created.lockEnd.toString()).toEqual(source.lockEnd.toString())
+      // Settled rewards follow the tokens
+      expect(created.pendingRewards.muln(3).sub(source.pendingRewards).abs().lten(3)).toBe(true)
+
+      await sleep(2000)
+      const sourceClaimed = await claimAs(testPool, staker)
+      const firstClaimTime = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+      const claimed = sourceClaimed + (await claimAs(testPool, splitOff))
+      const end = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+
+      // Every second up to the first claim is paid out once across the two positions
+      const owedAtFirstClaim = BigInt(rate.mul(firstClaimTime.sub(start)).toString())
+      expect(claimed).toBeLessThanOrEqual(BigInt(rate.mul(end.sub(start)).toString()))
+      expect(claimed + BigInt(5)).toBeGreaterThanOrEqual(owedAtFirstClaim)
+    }, 30000)
+
+    it('Rejects Splitting Off The Whole Position', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+      await stakeAs(testPool, staker, amount)
+
+      try {
+        await split(testPool, staker, amount, new BN(1))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/SplitExceedsStake/)
+      }
+    }, 30000)
+  })
+
+  describe('transfer_stake', () => {
+    it('Hands The Position To The New Owner', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const alice = await createStaker(testPool)
+      const bob = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+      await stakeAs(testPool, alice, amount)
+
+      await program.methods
+        .transferStake(alice.positionId, bob.keypair.publicKey)
+        .accounts({
+          user: alice.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: alice.userStake,
+          newUserStake: bob.userStake,
+        })
+        .signers([alice.keypair])
+        .rpc()
+
+      expect(await provider.connection.getAccountInfo(alice.userStake)).toBeNull()
+      const moved = await program.account.userStake.fetch(bob.userStake)
+      expect(moved.user.toString()).toEqual(bob.keypair.publicKey.toString())
+      expect(moved.amount.toString()).toEqual(amount.toString())
+
+      // The principal never left the vault
+      const vault = await getAccount(provider.connection, testPool.stakeVault, undefined, testPool.tokenProgram)
+      expect(vault.amount.toString()).toEqual(amount.toString())
+
+      try {
+        await unstakeAs(testPool, { ...alice, userStake: bob.userStake }, amount)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/ConstraintSeeds|NotBeneficiary/)
+      }
+
+      await sleep(1000)
+      expect(await claimAs(testPool, bob)).toBeGreaterThan(BigInt(0))
+      await unstakeAs(testPool, bob, amount)
+      const bobStake = await getAccount(provider.connection, bob.stakeToken, undefined, testPool.tokenProgram)
+      expect(bobStake.amount.toString()).toEqual(new BN(1_000_000_000_000).add(amount).toString())
+    }, 30000)
+
+    it('Rejects Transferring To The Current Owner', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+
+      try {
+        await program.methods
+          .transferStake(staker.positionId, staker.keypair.publicKey)
+          .accounts({
+            user: staker.keypair.publicKey,
+            pool: testPool.pool,
+            userStake: staker.userStake,
+            newUserStake: staker.userStake,
+          })
+          .signers([staker.keypair])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/SameOwner|already in use/)
+      }
+    }, 30000)
+  })
+
+  describe('funder records', () => {
+    function funderRecordPda(pool: PublicKey, funder: PublicKey) {
+      const [record] = PublicKey.findProgramAddressSync(
+        [Buffer.from('funder'), pool.toBuffer(), funder.toBuffer()],
+        program.programId
+      )
+      return record
+    }
+
+    async function fundAs(testPool: TestPool, funder: Keypair, amount: number) {
+      await airdrop(funder.publicKey)
+      const funderAta = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        testPool.rewardMint,
+        funder.publicKey,
+        false,
+        undefined,
+        undefined,
+        testPool.tokenProgram
+      )
+      await mintTo(
+        provider.connection,
+        payer.payer,
+        testPool.rewardMint,
+        funderAta.address,
+        payer.publicKey,
+        amount,
+        [],
+        undefined,
+        testPool.tokenProgram
+      )
+      await program.methods
+        .fundRewards(new BN(amount))
+        .accounts({
+          funder: funder.publicKey,
+          pool: testPool.pool,
+          funderTokenAccount: funderAta.address,
+          poolRewardVault: testPool.rewardVault,
+          funderRecord: funderRecordPda(testPool.pool, funder.publicKey),
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([funder])
+        .rpc()
+    }
+
+    it('Tracks Each Funder Independently', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const first = Keypair.generate()
+      const second = Keypair.generate()
+
+      await fundAs(testPool, first, 1_000_000)
+      await fundAs(testPool, second, 250_000)
+      await fundAs(testPool, first, 500_000)
+
+      const firstRecord = await program.account.funderRecord.fetch(funderRecordPda(testPool.pool, first.publicKey))
+      const secondRecord = await program.account.funderRecord.fetch(funderRecordPda(testPool.pool, second.publicKey))
+      expect(firstRecord.funder.toString()).toEqual(first.publicKey.toString())
+      expect(firstRecord.pool.toString()).toEqual(testPool.pool.toString())
+      expect(firstRecord.cumulativeFunded.toNumber()).toEqual(1_500_000)
+      expect(secondRecord.cumulativeFunded.toNumber()).toEqual(250_000)
+      expect(firstRecord.lastFundedTime.gte(secondRecord.lastFundedTime)).toBe(true)
+    }, 30000)
+  })
+
+  describe('exit', () => {
+    async function exitAs(testPool: TestPool, staker: TestStaker, amount: BN) {
+      const treasuryRewardToken = await treasuryAccount(testPool.rewardMint, testPool.tokenProgram)
+      await program.methods
+        .exit(staker.positionId, amount)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          userStakeToken: staker.stakeToken,
+          userRewardToken: staker.rewardToken,
+          poolStakeVault: testPool.stakeVault,
+          poolRewardVault: testPool.rewardVault,
+          config: configPda,
+          treasuryRewardToken,
+          stakeTokenMint: testPool.stakeMint,
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    it('Claims And Unstakes In One Call', async () => {
+      const testPool = await createPool(new BN(1_000_000), { minStakeDuration: new BN(1) })
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+      await stakeAs(testPool, staker, amount)
+      await sleep(2000)
+
+      const stakeBefore = await getAccount(provider.connection, staker.stakeToken, undefined, testPool.tokenProgram)
+      const rewardBefore = await getAccount(provider.connection, staker.rewardToken, undefined, testPool.tokenProgram)
+      await exitAs(testPool, staker, amount)
+      const stakeAfter = await getAccount(provider.connection, staker.stakeToken, undefined, testPool.tokenProgram)
+      const rewardAfter = await getAccount(provider.connection, staker.rewardToken, undefined, testPool.tokenProgram)
+
+      expect((stakeAfter.amount - stakeBefore.amount).toString()).toEqual(amount.toString())
+      expect(rewardAfter.amount).toBeGreaterThan(rewardBefore.amount)
+
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.amount.toString()).toEqual('0')
+      expect(position.pendingRewards.toString()).toEqual('0')
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalStaked.toString()).toEqual('0')
+    }, 30000)
+
+    it('Rejects Exiting Before The Minimum Duration', async () => {
+      const testPool = await createPool(new BN(1_000_000), { minStakeDuration: new BN(3600) })
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+      await stakeAs(testPool, staker, amount)
+
+      try {
+        await exitAs(testPool, staker, amount)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/StakeDurationNotMet/)
+      }
+    }, 30000)
+
+    it('Keeps Rewards Pending When Exiting Inside The Claim Cooldown', async () => {
+      const testPool = await createPool(new BN(1_000_000), { minStakeDuration: new BN(1), claimCooldown: new BN(600) })
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+      await stakeAs(testPool, staker, amount)
+      await sleep(1000)
+      await claimAs(testPool, staker)
+      await sleep(2000)
+
+      const rewardBefore = await getAccount(provider.connection, staker.rewardToken, undefined, testPool.tokenProgram)
+      await exitAs(testPool, staker, amount)
+      const rewardAfter = await getAccount(provider.connection, staker.rewardToken, undefined, testPool.tokenProgram)
+
+      expect(rewardAfter.amount).toEqual(rewardBefore.amount)
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.amount.toString()).toEqual('0')
+      expect(position.pendingRewards.gtn(0)).toBe(true)
+    }, 30000)
+  })
+
+  describe('reward warmup', () => {
+    it('Earns Nothing Inside The Warmup', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), { rewardWarmup: new BN(600) })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      await sleep(2000)
+
+      try {
+        await claimAs(testPool, staker)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/NoRewardsToClaim/)
+      }
+    }, 30000)
+
+    it('Earns Only The Accrual After The Warmup', async () => {
+      const rate = new BN(1_000_000_000)
+      const warmup = new BN(2)
+      const testPool = await createPool(rate, { rewardWarmup: warmup, fixedEmission: true })
+      const staker = await createStaker(testPool)
+
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      const start = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+      await sleep(5000)
+      const claimed = await claimAs(testPool, staker)
+      const end = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+
+      // A lone staker is owed every second after the warmup and none before it
+      const expected = BigInt(rate.mul(end.sub(start).sub(warmup)).toString())
+      expect(claimed).toBeLessThanOrEqual(expected)
+      expect(expected - claimed).toBeLessThanOrEqual(BigInt(3))
+    }, 30000)
+
+    it('Does Not Restart The Warmup On A Claim', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), { rewardWarmup: new BN(3), fixedEmission: true })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      await sleep(5000)
+      expect((await claimAs(testPool, staker)) > BigInt(0)).toBe(true)
+
+      // Still earning straight after the claim, well inside a fresh warmup
+      await sleep(1500)
+      expect((await claimAs(testPool, staker)) > BigInt(0)).toBe(true)
+    }, 30000)
+  })
+
+  describe('set_rewards_pause', () => {
+    async function setRewardsPause(testPool: TestPool, paused: boolean) {
+      await program.methods
+        .setRewardsPause(paused)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+    }
+
+    it('Skips The Paused Interval But Keeps Staking Open', async () => {
+      const rate = new BN(1_000_000_000)
+      const testPool = await createPool(rate, { fixedEmission: true })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      const start = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+      await sleep(2000)
+
+      await setRewardsPause(testPool, true)
+      const paused = await program.account.stakingPool.fetch(testPool.pool)
+      expect(paused.rewardsPaused).toEqual(true)
+      expect(paused.rewardsPausedAt.toString()).toEqual(paused.lastUpdateTime.toString())
+      await sleep(2000)
+
+      // Deposits still go through while nothing accrues
+      await stakeAs(testPool, staker, amount)
+      const duringPause = await program.account.stakingPool.fetch(testPool.pool)
+      expect(duringPause.totalStaked.toString()).toEqual(amount.muln(2).toString())
+      expect(duringPause.accRewardPerShare.toString()).toEqual(paused.accRewardPerShare.toString())
+      expect(duringPause.totalRewardsEmitted.toString()).toEqual(paused.totalRewardsEmitted.toString())
+      await sleep(1000)
+
+      await setRewardsPause(testPool, false)
+      const resumedAt = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+      await sleep(2000)
+      const claimed = await claimAs(testPool, staker)
+      const end = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+
+      const expected = BigInt(
+        rate.mul(paused.lastUpdateTime.sub(start).add(end.sub(resumedAt))).toString()
+      )
+      expect(claimed).toBeLessThanOrEqual(expected)
+      expect(expected - claimed).toBeLessThanOrEqual(BigInt(3))
+    }, 30000)
+  })
+
+  describe('extend_campaign', () => {
+    async function extendAs(testPool: TestPool, amount: number, newEndTime: BN) {
+      const authorityAta = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        testPool.rewardMint,
+        testPool.authority.publicKey,
+        false,
+        undefined,
+        undefined,
+        testPool.tokenProgram
+      )
+      await mintTo(
+        provider.connection,
+        payer.payer,
+        testPool.rewardMint,
+        authorityAta.address,
+        payer.publicKey,
+        amount,
+        [],
+        undefined,
+        testPool.tokenProgram
+      )
+      await program.methods
+        .extendCampaign(new BN(amount), newEndTime)
+        .accounts({
+          authority: testPool.authority.publicKey,
+          pool: testPool.pool,
+          authorityRewardToken: authorityAta.address,
+          poolRewardVault: testPool.rewardVault,
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([testPool.authority])
+        .rpc()
+    }
+
+    it('Funds The Vault And Moves The End Time Together', async () => {
+      const endTime = new BN(Math.floor(Date.now() / 1000) + 3600)
+      const testPool = await createPool(new BN(1_000_000), { rewardEndTime: endTime })
+      const vaultBefore = await getAccount(provider.connection, testPool.rewardVault, undefined, testPool.tokenProgram)
+      const poolBefore = await program.account.stakingPool.fetch(testPool.pool)
+
+      const newEndTime = endTime.addn(86_400)
+      await extendAs(testPool, 2_000_000, newEndTime)
+
+      const vaultAfter = await getAccount(provider.connection, testPool.rewardVault, undefined, testPool.tokenProgram)
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect((vaultAfter.amount - vaultBefore.amount).toString()).toEqual('2000000')
+      expect(pool.rewardEndTime.toString()).toEqual(newEndTime.toString())
+      expect(pool.totalRewardsFunded.sub(poolBefore.totalRewardsFunded).toNumber()).toEqual(2_000_000)
+    }, 30000)
+
+    it('Rejects An End Time That Does Not Move Later', async () => {
+      const endTime = new BN(Math.floor(Date.now() / 1000) + 3600)
+      const testPool = await createPool(new BN(1_000_000), { rewardEndTime: endTime })
+
+      try {
+        await extendAs(testPool, 1_000_000, endTime)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InvalidEndTimeExtension/)
+      }
+    }, 30000)
+  })
+
+  describe('vault authority', () => {
+    function vaultAuthorityPda(pool: PublicKey) {
+      const [vaultAuthority] = PublicKey.findProgramAddressSync(
+        [Buffer.from('vault_authority'), pool.toBuffer()],
+        program.programId
+      )
+      return vaultAuthority
+    }
+
+    it('Vaults Are Owned By The Vault Authority And Still Pay Out', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const vaultAuthority = vaultAuthorityPda(testPool.pool)
+      const stakeVault = await getAccount(provider.connection, testPool.stakeVault, undefined, testPool.tokenProgram)
+      const rewardVault = await getAccount(provider.connection, testPool.rewardVault, undefined, testPool.tokenProgram)
+      expect(stakeVault.owner.toString()).toEqual(vaultAuthority.toString())
+      expect(rewardVault.owner.toString()).toEqual(vaultAuthority.toString())
+
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+      await stakeAs(testPool, staker, amount)
+      await sleep(1000)
+      expect(await claimAs(testPool, staker)).toBeGreaterThan(BigInt(0))
+      await unstakeAs(testPool, staker, amount)
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.amount.toNumber()).toEqual(0)
+    }, 30000)
+
+    it('Rejects The Pool Account As Vault Signer', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+      await stakeAs(testPool, staker, amount)
+
+      try {
+        await program.methods
+          .unstake(staker.positionId, amount)
+          .accounts({
+            user: staker.keypair.publicKey,
+            pool: testPool.pool,
+            vaultAuthority: testPool.pool,
+            userStake: staker.userStake,
+            userStakeToken: staker.stakeToken,
+            poolStakeVault: testPool.stakeVault,
+            poolRewardVault: testPool.rewardVault,
+            stakeTokenMint: testPool.stakeMint,
+            tokenProgram: testPool.tokenProgram,
+          })
+          .signers([staker.keypair])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/ConstraintSeeds/)
+      }
+    }, 30000)
+  })
+
+  describe('emission curves', () => {
+    const CURVE_LINEAR = 0
+    const CURVE_EASE_IN = 1
+    const CURVE_EASE_OUT = 2
+
+    it('Curves Share A Total But Not The Midpoint', async () => {
+      const rate = new BN(1_000_000_000)
+      const windowStart = Math.floor(Date.now() / 1000) + 25
+      const windowEnd = windowStart + 6
+      const curves = [CURVE_EASE_OUT, CURVE_LINEAR, CURVE_EASE_IN]
+
+      const entries: { testPool: TestPool; staker: TestStaker }[] = []
+      for (const curveType of curves) {
+        const testPool = await createPool(rate, {
+          fixedEmission: true,
+          rewardStartTime: new BN(windowStart),
+          rewardEndTime: new BN(windowEnd),
+          curveType,
+        })
+        const staker = await createStaker(testPool)
+        await stakeAs(testPool, staker, new BN(100_000_000_000))
+        entries.push({ testPool, staker })
+      }
+
+      await sleep(Math.max(0, (windowStart + 3) * 1000 - Date.now()))
+      const midpoint: bigint[] = []
+      for (const { testPool, staker } of entries) {
+        midpoint.push(await claimAs(testPool, staker))
+      }
+      // Front-loaded ahead of linear ahead of back-loaded, claimed in that order
+      expect(midpoint[0]).toBeGreaterThan(midpoint[1])
+      expect(midpoint[1]).toBeGreaterThan(midpoint[2])
+
+      await sleep(Math.max(0, (windowEnd + 1) * 1000 - Date.now()))
+      const expected = BigInt(rate.muln(windowEnd - windowStart).toString())
+      for (const [i, { testPool, staker }] of entries.entries()) {
+        const total = midpoint[i] + (await claimAs(testPool, staker))
+        expect(total).toBeLessThanOrEqual(expected)
+        expect(expected - total).toBeLessThanOrEqual(BigInt(3))
+      }
+    }, 90000)
+
+    it('Keeps A Curved Schedule Fixed', async () => {
+      const now = Math.floor(Date.now() / 1000)
+      const testPool = await createPool(new BN(1_000_000), {
+        rewardStartTime: new BN(now),
+        rewardEndTime: new BN(now + 3600),
+        curveType: CURVE_EASE_OUT,
+      })
+
+      try {
+        await program.methods
+          .updateRewardRate(new BN(2_000_000))
+          .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+          .signers([testPool.authority])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/CurveScheduleFixed/)
+      }
+
+      const authorityAta = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        testPool.rewardMint,
+        testPool.authority.publicKey,
+        false,
+        undefined,
+        undefined,
+        testPool.tokenProgram
+      )
+      try {
+        await program.methods
+          .extendCampaign(new BN(1_000_000), new BN(now + 7200))
+          .accounts({
+            authority: testPool.authority.publicKey,
+            pool: testPool.pool,
+            authorityRewardToken: authorityAta.address,
+            poolRewardVault: testPool.rewardVault,
+            rewardTokenMint: testPool.rewardMint,
+            tokenProgram: testPool.tokenProgram,
+          })
+          .signers([testPool.authority])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/CurveScheduleFixed/)
+      }
+    }, 30000)
+
+    it('Rejects A Curve Combined With Halving', async () => {
+      try {
+        await createPool(new BN(1_000_000), {
+          rewardStartTime: new BN(100),
+          rewardEndTime: new BN(1_000),
+          halvingInterval: new BN(100),
+          curveType: CURVE_EASE_IN,
+        })
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InvalidRewardSchedule/)
+      }
+    }, 30000)
+  })
+
+  describe('same-mint pools', () => {
+    it('Rejects A Shared Mint Unless Allowed', async () => {
+      try {
+        await createPool(new BN(1_000_000), { sameMint: true, allowSameMint: false })
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/SameMintNotAllowed/)
+      }
+    }, 30000)
+
+    it('Records The Flag When A Shared Mint Is Allowed', async () => {
+      const shared = await createPool(new BN(1_000_000), { sameMint: true })
+      const sharedPool = await program.account.stakingPool.fetch(shared.pool)
+      expect(sharedPool.allowSameMint).toEqual(true)
+      expect(sharedPool.stakeTokenMint.toString()).toEqual(sharedPool.rewardTokenMint.toString())
+
+      const separate = await createPool(new BN(1_000_000))
+      expect((await program.account.stakingPool.fetch(separate.pool)).allowSameMint).toEqual(false)
+    }, 30000)
+  })
+
+  describe('initialize_pool_apy', () => {
+    it('Pays The Quoted Yield Over A Year', async () => {
+      const testPool = await createPool(new BN(0), { apyBps: 1_000 })
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.apyBps).toEqual(1_000)
+      expect(pool.fixedEmission).toEqual(false)
+      // 10% a year is 3.17 per 1e9 units a second, stored rounded down
+      expect(pool.rewardRate.toNumber()).toEqual(3)
+
+      const amount = new BN(100_000_000_000)
+      const year = new BN(31_536_000)
+      const preview: BN = await program.methods
+        .previewStake(amount, year)
+        .accounts({ pool: testPool.pool })
+        .view()
+      expect(preview.toString()).toEqual(amount.divn(10).toString())
+    }, 30000)
+
+    it('Accrues The Yield For Each Second Staked', async () => {
+      const testPool = await createPool(new BN(0), { apyBps: 1_000 })
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount)
+      const start = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+      await sleep(2000)
+      await unstakeAs(testPool, staker, amount)
+      const end = (await program.account.stakingPool.fetch(testPool.pool)).lastUpdateTime
+
+      const expected = amount.muln(1_000).mul(end.sub(start)).div(new BN(10_000 * 31_536_000))
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.pendingRewards.sub(expected).abs().lten(1)).toBe(true)
+    }, 30000)
+  })
+
+  describe('pool metadata', () => {
+    async function setMetadata(testPool: TestPool, name: number[], metadataUri: number[]) {
+      await program.methods
+        .setPoolMetadata(name, metadataUri)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+    }
+
+    it('Stores The Name And URI And Lets The Authority Update Them', async () => {
+      const testPool = await createPool(new BN(1_000_000), {
+        name: 'SOL Stakers',
+        metadataUri: 'https://example.com/pools/sol.json',
+      })
+      const created = await program.account.stakingPool.fetch(testPool.pool)
+      expect(unpadded(created.name)).toEqual('SOL Stakers')
+      expect(unpadded(created.metadataUri)).toEqual('https://example.com/pools/sol.json')
+
+      await setMetadata(testPool, fixedBytes('SOL Stakers v2', 32), fixedBytes('ipfs://pool-v2', 128))
+      const updated = await program.account.stakingPool.fetch(testPool.pool)
+      expect(unpadded(updated.name)).toEqual('SOL Stakers v2')
+      expect(unpadded(updated.metadataUri)).toEqual('ipfs://pool-v2')
+    }, 30000)
+
+    it('Rejects An Empty Name Or Invalid UTF-8', async () => {
+      try {
+        await createPool(new BN(1_000_000), { name: '' })
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InvalidPoolMetadata/)
+      }
+
+      const testPool = await createPool(new BN(1_000_000))
+      const invalid = fixedBytes('', 32)
+      invalid[0] = 0xff
+      try {
+        await setMetadata(testPool, invalid, fixedBytes('', 128))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InvalidPoolMetadata/)
+      }
+    }, 30000)
+
+    it('Rejects Metadata Updates From Non-Authority', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const intruder = Keypair.generate()
+
+      try {
+        await program.methods
+          .setPoolMetadata(fixedBytes('Hijacked', 32), fixedBytes('', 128))
+          .accounts({ authority: intruder.publicKey, pool: testPool.pool })
+          .signers([intruder])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UnauthorizedAuthority/)
+      }
+    }, 30000)
+  })
+
+  describe('stake vault shortfall', () => {
+    it('Pays Each Exit Its Pro-Rata Share Of A Short Vault', async () => {
+      const testPool = await createPool(new BN(1_000_000), {
+        tokenProgram: TOKEN_2022_PROGRAM_ID,
+        stakePermanentDelegate: true,
+      })
+      const alice = await createStaker(testPool)
+      const bob = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+      await stakeAs(testPool, alice, amount)
+      await stakeAs(testPool, bob, amount)
+
+      // A quarter of the vault leaves behind the program's back
+      const sink = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        testPool.stakeMint,
+        payer.publicKey,
+        false,
+        undefined,
+        undefined,
+        TOKEN_2022_PROGRAM_ID
+      )
+      await transferChecked(
+        provider.connection,
+        payer.payer,
+        testPool.stakeVault,
+        testPool.stakeMint,
+        sink.address,
+        payer.payer,
+        50_000_000_000,
+        9,
+        [],
+        undefined,
+        TOKEN_2022_PROGRAM_ID
+      )
+
+      const received: bigint[] = []
+      for (const staker of [alice, bob]) {
+        const before = await getAccount(provider.connection, staker.stakeToken, undefined, TOKEN_2022_PROGRAM_ID)
+        await unstakeAs(testPool, staker, amount)
+        const after = await getAccount(provider.connection, staker.stakeToken, undefined, TOKEN_2022_PROGRAM_ID)
+        received.push(after.amount - before.amount)
+      }
+
+      // Neither the first nor the last out takes more than three quarters of their stake
+      expect(received[0].toString()).toEqual('75000000000')
+      expect(received[1].toString()).toEqual('75000000000')
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalStaked.toNumber()).toEqual(0)
+    }, 30000)
+  })
+
+  describe('update_min_duration', () => {
+    it('Keeps Existing Positions On Their Original Duration', async () => {
+      const testPool = await createPool(new BN(1_000_000), { minStakeDuration: new BN(2) })
+      const early = await createStaker(testPool)
+      const late = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+
+      await stakeAs(testPool, early, amount)
+      expect((await program.account.userStake.fetch(early.userStake)).lockedDuration!.toNumber()).toEqual(2)
+
+      await program.methods
+        .updateMinDuration(new BN(3600))
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+      expect((await program.account.stakingPool.fetch(testPool.pool)).minStakeDuration.toNumber()).toEqual(3600)
+
+      await stakeAs(testPool, late, amount)
+      await sleep(3000)
+
+      // The early position unlocks on the schedule it staked under
+      await unstakeAs(testPool, early, amount)
+      expect((await program.account.userStake.fetch(early.userStake)).amount.toNumber()).toEqual(0)
+
+      // The later one is held to the new duration
+      try {
+        await unstakeAs(testPool, late, amount)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/StakeDurationNotMet/)
+      }
+    }, 30000)
+
+    it('Rejects Duration Changes From Non-Authority', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const intruder = Keypair.generate()
+
+      try {
+        await program.methods
+          .updateMinDuration(new BN(0))
+          .accounts({ authority: intruder.publicKey, pool: testPool.pool })
+          .signers([intruder])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UnauthorizedAuthority/)
+      }
+    }, 30000)
+
+    it('Rejects A Negative Duration', async () => {
+      try {
+        await createPool(new BN(1_000_000), { minStakeDuration: new BN(-1) })
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InvalidDuration/)
+      }
+
+      const testPool = await createPool(new BN(1_000_000))
+      try {
+        await program.methods
+          .updateMinDuration(new BN(-1))
+          .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+          .signers([testPool.authority])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InvalidDuration/)
+      }
+    }, 30000)
+  })
+
+  describe('Reward Snapshots', () => {
+    function snapshotsPda(pool: PublicKey) {
+      const [snapshots] = PublicKey.findProgramAddressSync(
+        [Buffer.from('snapshots'), pool.toBuffer()],
+        program.programId
+      )
+      return snapshots
+    }
+
+    async function takeSnapshot(testPool: TestPool) {
+      await program.methods
+        .snapshot()
+        .accounts({ payer: payer.publicKey, pool: testPool.pool })
+        .rpc()
+    }
+
+    it('Wraps The Ring Buffer Once Full', async () => {
+      const testPool = await createPool(new BN(1_000_000), { snapshotCapacity: 3 })
+      const staker = await createStaker(testPool)
+
+      // Stake i tokens before snapshot i so every entry records a distinct total
+      for (let i = 1; i <= 5; i++) {
+        await stakeAs(testPool, staker, new BN(i * 1_000_000_000))
+        await sleep(1000)
+        await takeSnapshot(testPool)
+      }
+
+      const snapshots = await program.account.rewardSnapshots.fetch(snapshotsPda(testPool.pool))
+      expect(snapshots.capacity).toEqual(3)
+      expect(snapshots.totalRecorded.toNumber()).toEqual(5)
+      expect(snapshots.entries.length).toEqual(3)
+      // Snapshots 4 and 5 overwrote slots 0 and 1, so slot 2 holds the oldest
+      expect(snapshots.nextIndex).toEqual(2)
+      expect(snapshots.entries.map((entry) => entry.totalStaked.toNumber())).toEqual([
+        10_000_000_000, 15_000_000_000, 6_000_000_000,
+      ])
+
+      const ordered = [2, 0, 1].map((i) => snapshots.entries[i])
+      for (let i = 1; i < ordered.length; i++) {
+        expect(ordered[i].timestamp.gte(ordered[i - 1].timestamp)).toBe(true)
+        expect(ordered[i].accRewardPerShare.gte(ordered[i - 1].accRewardPerShare)).toBe(true)
+      }
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(snapshots.entries[1].accRewardPerShare.toString()).toEqual(pool.accRewardPerShare.toString())
+    }, 60000)
+
+    it('Rejects Snapshots On Pools Created Without Them', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+
+      try {
+        await takeSnapshot(testPool)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/SnapshotsDisabled/)
+      }
+    }, 30000)
+
+    it('Rejects Oversized Snapshot Capacity', async () => {
+      try {
+        await createPool(new BN(1_000_000), { snapshotCapacity: 257 })
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InvalidSnapshotCapacity/)
+      }
+    }, 30000)
+  })
+
+  describe('Vault Self-Transfers', () => {
+    async function vaultBalance(testPool: TestPool, vault: PublicKey) {
+      return (await getAccount(provider.connection, vault, undefined, testPool.tokenProgram)).amount
+    }
+
+    it('Rejects The Stake Vault As The User Account', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+      await stakeAs(testPool, staker, amount)
+      const before = await vaultBalance(testPool, testPool.stakeVault)
+      const vaultAsUser = { ...staker, stakeToken: testPool.stakeVault }
+
+      for (const attempt of [
+        () => stakeAs(testPool, vaultAsUser, amount),
+        () => unstakeAs(testPool, vaultAsUser, amount),
+      ]) {
+        try {
+          await attempt()
+          throw new Error('Should have thrown an error')
+        } catch (error) {
+          expect((error as Error).message).toMatch(/SelfTransferNotAllowed/)
+        }
+      }
+
+      expect(await vaultBalance(testPool, testPool.stakeVault)).toEqual(before)
+      expect((await program.account.userStake.fetch(staker.userStake)).amount.toString()).toEqual(amount.toString())
+    }, 30000)
+
+    it('Rejects The Reward Vault As The User Account', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(2000)
+      const before = await vaultBalance(testPool, testPool.rewardVault)
+
+      try {
+        await claimAs(testPool, { ...staker, rewardToken: testPool.rewardVault })
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/SelfTransferNotAllowed/)
+      }
+
+      expect(await vaultBalance(testPool, testPool.rewardVault)).toEqual(before)
+    }, 30000)
+  })
+
+  describe('Governance Boost', () => {
+    async function boostedPool() {
+      const testPool = await createPool(new BN(1_000_000))
+      const boostMint = await createMint(provider.connection, payer.payer, payer.publicKey, null, 6)
+      // +10% per whole token, up to +30%
+      await program.methods
+        .setBoostConfig(1_000, 3_000)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool, boostMint })
+        .signers([testPool.authority])
+        .rpc()
+      return { testPool, boostMint }
+    }
+
+    async function boostAccount(boostMint: PublicKey, staker: TestStaker, balance: number) {
+      const ata = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        boostMint,
+        staker.keypair.publicKey
+      )
+      if (balance > 0) {
+        await mintTo(provider.connection, payer.payer, boostMint, ata.address, payer.publicKey, balance)
+      }
+      return ata.address
+    }
+
+    async function boostedClaim(testPool: TestPool, staker: TestStaker, boostToken: PublicKey) {
+      await program.methods
+        .claimRewards(staker.positionId, null)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          userRewardToken: staker.rewardToken,
+          poolRewardVault: testPool.rewardVault,
+          config: configPda,
+          treasuryRewardToken: await treasuryAccount(testPool.rewardMint, testPool.tokenProgram),
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+          boostToken,
+        })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    // Freezes accrual so the claim pays exactly what was owed plus its boost
+    async function claimAfterPause(testPool: TestPool, staker: TestStaker, boostToken: PublicKey) {
+      await program.methods
+        .setRewardsPause(true)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+      const before = await program.account.stakingPool.fetch(testPool.pool)
+      await boostedClaim(testPool, staker, boostToken)
+      const after = await program.account.stakingPool.fetch(testPool.pool)
+
+      const paid = after.totalRewardsDistributed.sub(before.totalRewardsDistributed)
+      const boost = after.totalRewardsEmitted.sub(before.totalRewardsEmitted)
+      return { owed: paid.sub(boost), boost }
+    }
+
+    for (const [label, balance, expectedBps] of [
+      ['Zero', 0, 0],
+      ['Partial', 2_000_000, 2_000],
+      ['Capped', 10_000_000, 3_000],
+    ] as const) {
+      it(`Scales Claims By A ${label} Boost Balance`, async () => {
+        const { testPool, boostMint } = await boostedPool()
+        const staker = await createStaker(testPool)
+        const boostToken = await boostAccount(boostMint, staker, balance)
+        await stakeAs(testPool, staker, new BN(1_000_000_000))
+        await sleep(2000)
+
+        const { owed, boost } = await claimAfterPause(testPool, staker, boostToken)
+        expect(owed.gtn(0)).toBe(true)
+        expect(boost.toString()).toEqual(owed.muln(expectedBps).divn(10_000).toString())
+        expect((await program.account.userStake.fetch(staker.userStake)).pendingRewards.toNumber()).toEqual(0)
+      }, 30000)
+    }
+
+    it('Reads The Boost Balance At Claim Time', async () => {
+      const { testPool, boostMint } = await boostedPool()
+      const staker = await createStaker(testPool)
+      const boostToken = await boostAccount(boostMint, staker, 0)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(2000)
+
+      // Bought after staking, still counted
+      await mintTo(provider.connection, payer.payer, boostMint, boostToken, payer.publicKey, 1_000_000)
+      const { owed, boost } = await claimAfterPause(testPool, staker, boostToken)
+      expect(boost.toString()).toEqual(owed.muln(1_000).divn(10_000).toString())
+    }, 30000)
+
+    it('Rejects A Boost Account For Another Mint', async () => {
+      const { testPool } = await boostedPool()
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(2000)
+
+      try {
+        await boostedClaim(testPool, staker, staker.stakeToken)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/BoostAccountMintMismatch/)
+      }
+    }, 30000)
+  })
+
+  describe('Empty Pool Windows', () => {
+    it('Accrues Nothing While Total Stake Is Zero', async () => {
+      const rate = new BN(1_000_000)
+      const testPool = await createPool(rate)
+      const first = await createStaker(testPool)
+      const second = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+
+      await stakeAs(testPool, first, amount)
+      await sleep(1000)
+      await unstakeAs(testPool, first, amount)
+      const emptied = await program.account.stakingPool.fetch(testPool.pool)
+      expect(emptied.totalStaked.toNumber()).toEqual(0)
+
+      await sleep(3000)
+      await stakeAs(testPool, second, amount)
+
+      // The empty window moved the clock but credited nobody
+      const restaked = await program.account.stakingPool.fetch(testPool.pool)
+      expect(restaked.lastUpdateTime.gt(emptied.lastUpdateTime)).toBe(true)
+      expect(restaked.accRewardPerShare.toString()).toEqual(emptied.accRewardPerShare.toString())
+      expect(restaked.totalRewardsEmitted.toString()).toEqual(emptied.totalRewardsEmitted.toString())
+
+      // The new staker earns only from its own stake onwards
+      await sleep(2000)
+      await claimAs(testPool, second)
+      const claimed = await program.account.stakingPool.fetch(testPool.pool)
+      const elapsed = claimed.lastUpdateTime.sub(restaked.lastUpdateTime)
+      const paid = claimed.totalRewardsDistributed.sub(restaked.totalRewardsDistributed)
+      expect(rate.mul(elapsed).sub(paid).toNumber()).toBeLessThanOrEqual(1)
+      expect(paid.lte(rate.mul(elapsed))).toBe(true)
+    }, 30000)
+  })
+
+  describe('fund_rewards_from_pda', () => {
+    // An escrow PDA holding reward tokens, as an emission contract would set up
+    async function createEscrow(testPool: TestPool, seed: Buffer, amount: number) {
+      const [escrowAuthority, bump] = PublicKey.findProgramAddressSync(
+        [Buffer.from('reward_escrow'), testPool.pool.toBuffer(), seed],
+        program.programId
+      )
+      const escrowToken = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        testPool.rewardMint,
+        escrowAuthority,
+        true,
+        undefined,
+        undefined,
+        testPool.tokenProgram
+      )
+      await mintTo(
+        provider.connection,
+        payer.payer,
+        testPool.rewardMint,
+        escrowToken.address,
+        payer.publicKey,
+        amount,
+        [],
+        undefined,
+        testPool.tokenProgram
+      )
+      return { escrowAuthority, escrowToken: escrowToken.address, bump }
+    }
+
+    async function fundFromEscrow(
+      testPool: TestPool,
+      escrow: Awaited<ReturnType<typeof createEscrow>>,
+      amount: number,
+      seed: Buffer,
+      bump: number
+    ) {
+      const [funderRecord] = PublicKey.findProgramAddressSync(
+        [Buffer.from('funder'), testPool.pool.toBuffer(), escrow.escrowAuthority.toBuffer()],
+        program.programId
+      )
+      await program.methods
+        .fundRewardsFromPda(new BN(amount), seed, bump)
+        .accounts({
+          payer: payer.publicKey,
+          pool: testPool.pool,
+          escrowAuthority: escrow.escrowAuthority,
+          escrowToken: escrow.escrowToken,
+          poolRewardVault: testPool.rewardVault,
+          funderRecord,
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .rpc()
+      return funderRecord
+    }
+
+    it('Funds The Reward Vault From An Escrow PDA', async () => {
+      const testPool = await createPool(new BN(1_000_000), { fundAmount: 0 })
+      const seed = Buffer.from('emissions')
+      const escrow = await createEscrow(testPool, seed, 5_000_000)
+
+      const funderRecord = await fundFromEscrow(testPool, escrow, 3_000_000, seed, escrow.bump)
+
+      const vault = await getAccount(provider.connection, testPool.rewardVault, undefined, testPool.tokenProgram)
+      const escrowToken = await getAccount(provider.connection, escrow.escrowToken, undefined, testPool.tokenProgram)
+      expect(vault.amount.toString()).toEqual('3000000')
+      expect(escrowToken.amount.toString()).toEqual('2000000')
+
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalRewardsFunded.toNumber()).toEqual(3_000_000)
+      const record = await program.account.funderRecord.fetch(funderRecord)
+      expect(record.funder.toString()).toEqual(escrow.escrowAuthority.toString())
+      expect(record.cumulativeFunded.toNumber()).toEqual(3_000_000)
+    }, 30000)
+
+    it('Rejects Seeds That Do Not Derive The Escrow Authority', async () => {
+      const testPool = await createPool(new BN(1_000_000), { fundAmount: 0 })
+      const seed = Buffer.from('emissions')
+      const escrow = await createEscrow(testPool, seed, 5_000_000)
+
+      try {
+        await fundFromEscrow(testPool, escrow, 1_000_000, Buffer.from('other'), escrow.bump)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InvalidEscrowAuthority/)
+      }
+    }, 30000)
+  })
+
+  describe('Partial Unstake Accrual', () => {
+    it('Pays The Full Stake Before And The Remainder After A Partial Unstake', async () => {
+      // Per-token emission, so what a position earns tracks its size: 1_000 per second per whole token
+      const rate = new BN(1_000)
+      const testPool = await createPool(rate, { fixedEmission: false })
+      const staker = await createStaker(testPool)
+      const wholeToken = new BN(1_000_000_000)
+
+      await stakeAs(testPool, staker, wholeToken.muln(200))
+      const staked = await program.account.stakingPool.fetch(testPool.pool)
+      await sleep(2000)
+      await unstakeAs(testPool, staker, wholeToken.muln(100))
+      const unstaked = await program.account.stakingPool.fetch(testPool.pool)
+      await sleep(2000)
+      await claimAs(testPool, staker)
+      const claimed = await program.account.stakingPool.fetch(testPool.pool)
+
+      const firstWindow = unstaked.lastUpdateTime.sub(staked.lastUpdateTime)
+      const secondWindow = claimed.lastUpdateTime.sub(unstaked.lastUpdateTime)
+      expect(firstWindow.gtn(0)).toBe(true)
+      expect(secondWindow.gtn(0)).toBe(true)
+
+      const expected = rate.muln(200).mul(firstWindow).add(rate.muln(100).mul(secondWindow))
+      const paid = claimed.totalRewardsDistributed.sub(staked.totalRewardsDistributed)
+      // Only the accumulator's rounding may fall short
+      expect(expected.sub(paid).toNumber()).toBeGreaterThanOrEqual(0)
+      expect(expected.sub(paid).toNumber()).toBeLessThanOrEqual(2)
+      expect((await program.account.userStake.fetch(staker.userStake)).pendingRewards.toNumber()).toEqual(0)
+    }, 30000)
+  })
+
+  describe('pool_stats', () => {
+    it('Returns Live Pool Figures', async () => {
+      const now = Math.floor(Date.now() / 1000)
+      const testPool = await createPool(new BN(1_000_000), { rewardEndTime: new BN(now + 3600) })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(5_000_000_000))
+      await sleep(1000)
+      await claimAs(testPool, staker)
+
+      const stats = await program.methods
+        .poolStats()
+        .accounts({ pool: testPool.pool, poolRewardVault: testPool.rewardVault })
+        .view()
+
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      const vault = await getAccount(provider.connection, testPool.rewardVault, undefined, testPool.tokenProgram)
+      expect(stats.totalStaked.toString()).toEqual(pool.totalStaked.toString())
+      expect(stats.rewardRate.toString()).toEqual(pool.rewardRate.toString())
+      expect(stats.totalRewardsDistributed.toString()).toEqual(pool.totalRewardsDistributed.toString())
+      expect(stats.totalRewardsDistributed.gtn(0)).toBe(true)
+      expect(stats.rewardVaultBalance.toString()).toEqual(vault.amount.toString())
+      expect(stats.secondsRemaining.toNumber()).toBeGreaterThan(0)
+      expect(stats.secondsRemaining.toNumber()).toBeLessThanOrEqual(3600)
+      expect(stats.lastUpdateTime.toString()).toEqual(pool.lastUpdateTime.toString())
+    }, 30000)
+  })
+
+  describe('reward_token_info', () => {
+    it('Returns The Reward Mint Decimals And Supply', async () => {
+      const testPool = await createPool(new BN(1_000_000), { rewardDecimals: 6 })
+
+      const info = await program.methods
+        .rewardTokenInfo()
+        .accounts({ pool: testPool.pool, rewardTokenMint: testPool.rewardMint })
+        .view()
+
+      const mint = await getMint(provider.connection, testPool.rewardMint, undefined, testPool.tokenProgram)
+      expect(info.mint.toString()).toEqual(testPool.rewardMint.toString())
+      expect(info.decimals).toEqual(6)
+      expect(info.supply.toString()).toEqual(mint.supply.toString())
+      expect(info.supply.gtn(0)).toBe(true)
+    }, 30000)
+
+    it('Rejects A Mint That Is Not The Pool Reward Mint', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      try {
+        await program.methods
+          .rewardTokenInfo()
+          .accounts({ pool: testPool.pool, rewardTokenMint: testPool.stakeMint })
+          .view()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/ConstraintAddress/)
+      }
+    }, 30000)
+  })
+
+  describe('reinvest_with_bonus', () => {
+    async function reinvestAs(testPool: TestPool, staker: TestStaker, newPositionId: BN, lockPeriod: BN) {
+      await program.methods
+        .reinvestWithBonus(staker.positionId, newPositionId, lockPeriod)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          newUserStake: positionPda(testPool.pool, staker.keypair.publicKey, newPositionId),
+          poolStakeVault: testPool.stakeVault,
+          poolRewardVault: testPool.rewardVault,
+          stakeTokenMint: testPool.stakeMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    // Stops accrual so the rewards to reinvest are known exactly
+    async function owedAfterPause(testPool: TestPool, staker: TestStaker): Promise<BN> {
+      await program.methods
+        .setRewardsPause(true)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+      return program.methods
+        .getPendingRewards()
+        .accounts({ pool: testPool.pool, userStake: staker.userStake })
+        .view()
+    }
+
+    it('Opens A Locked Position Holding The Rewards Plus The Bonus', async () => {
+      const testPool = await createPool(new BN(1_000_000), { sameMint: true, reinvestBonusBps: 1_000 })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(2000)
+      const owed = await owedAfterPause(testPool, staker)
+
+      const newPositionId = new BN(1)
+      await reinvestAs(testPool, staker, newPositionId, new BN(3600))
+
+      const bonus = owed.muln(1_000).divn(10_000)
+      const reinvested = await program.account.userStake.fetch(
+        positionPda(testPool.pool, staker.keypair.publicKey, newPositionId)
+      )
+      expect(reinvested.amount.toString()).toEqual(owed.add(bonus).toString())
+      expect(reinvested.lockEnd.gt(reinvested.lastStakeTime)).toBe(true)
+      expect((await program.account.userStake.fetch(staker.userStake)).pendingRewards.toNumber()).toEqual(0)
+
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalStaked.toString()).toEqual(owed.add(bonus).addn(1_000_000_000).toString())
+    }, 30000)
+
+    it('Rejects A Reinvest The Vault Cannot Cover With The Bonus', async () => {
+      const testPool = await createPool(new BN(1_000_000), { sameMint: true, reinvestBonusBps: 1_000, fundAmount: 0 })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(2000)
+      const owed = await owedAfterPause(testPool, staker)
+
+      // Enough for the rewards alone
+      await fundPool(testPool, owed.toNumber())
+      try {
+        await reinvestAs(testPool, staker, new BN(1), new BN(3600))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/ReinvestBonusUnfunded/)
+      }
+
+      await fundPool(testPool, owed.muln(1_000).divn(10_000).toNumber())
+      await reinvestAs(testPool, staker, new BN(1), new BN(3600))
+    }, 30000)
+  })
+
+  describe('Reward Rounding', () => {
+    // One staker of 7 whole tokens, so 1_000_000 per second never splits evenly into the
+    // accumulator for the 2-4 seconds that pass, and is frozen before the claim
+    async function claimUneven(roundUpRewards: boolean) {
+      const testPool = await createPool(new BN(1_000_000), { roundUpRewards, fixedEmission: true })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(7_000_000_000))
+      await sleep(2000)
+      await program.methods
+        .setRewardsPause(true)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+
+      await claimAs(testPool, staker)
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      const position = await program.account.userStake.fetch(staker.userStake)
+      return { emitted: pool.totalRewardsEmitted, paid: pool.totalRewardsDistributed, position }
+    }
+
+    it('Rounds Down And Carries The Fraction By Default', async () => {
+      const { emitted, paid, position } = await claimUneven(false)
+      expect(emitted.sub(paid).toNumber()).toEqual(1)
+      expect(position.rewardRemainder.gtn(0)).toBe(true)
+    }, 30000)
+
+    it('Rounds Up In The Staker\'s Favor When Enabled', async () => {
+      const { emitted, paid, position } = await claimUneven(true)
+      expect(paid.toString()).toEqual(emitted.toString())
+      expect(position.rewardRemainder.toNumber()).toEqual(0)
+      expect(position.pendingRewards.toNumber()).toEqual(0)
+    }, 30000)
+  })
+
+  describe('Child Pools', () => {
+    async function linkedPools() {
+      const parent = await createPool(new BN(1_000_000))
+      const child = await createPool(new BN(1_000_000), { fundAmount: 0, rewardMint: parent.rewardMint })
+      await program.methods
+        .linkChildPool()
+        .accounts({
+          parentAuthority: parent.authority.publicKey,
+          childAuthority: child.authority.publicKey,
+          parentPool: parent.pool,
+          childPool: child.pool,
+        })
+        .signers([parent.authority, child.authority])
+        .rpc()
+      return { parent, child }
+    }
+
+    async function childClaim(parent: TestPool | null, child: TestPool, staker: TestStaker) {
+      const parentAccounts = parent
+        ? {
+            parentPool: parent.pool,
+            parentVaultAuthority: PublicKey.findProgramAddressSync(
+              [Buffer.from('vault_authority'), parent.pool.toBuffer()],
+              program.programId
+            )[0],
+            parentRewardVault: parent.rewardVault,
+          }
+        : {}
+      await program.methods
+        .claimRewards(staker.positionId, null)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: child.pool,
+          userStake: staker.userStake,
+          userRewardToken: staker.rewardToken,
+          poolRewardVault: child.rewardVault,
+          config: configPda,
+          treasuryRewardToken: await treasuryAccount(child.rewardMint, child.tokenProgram),
+          rewardTokenMint: child.rewardMint,
+          tokenProgram: child.tokenProgram,
+          ...parentAccounts,
+        })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    it('Pays Child Pool Stakers From The Parent Vault', async () => {
+      const { parent, child } = await linkedPools()
+      expect((await program.account.stakingPool.fetch(child.pool)).parentPool?.toString()).toEqual(parent.pool.toString())
+      const staker = await createStaker(child)
+      await stakeAs(child, staker, new BN(1_000_000_000))
+      await sleep(2000)
+
+      const parentVaultBefore = await getAccount(provider.connection, parent.rewardVault)
+      const walletBefore = await getAccount(provider.connection, staker.rewardToken)
+      await childClaim(parent, child, staker)
+      const parentVaultAfter = await getAccount(provider.connection, parent.rewardVault)
+      const walletAfter = await getAccount(provider.connection, staker.rewardToken)
+
+      const paid = parentVaultBefore.amount - parentVaultAfter.amount
+      expect(paid > BigInt(0)).toBe(true)
+      expect(walletAfter.amount > walletBefore.amount).toBe(true)
+      expect((await getAccount(provider.connection, child.rewardVault)).amount).toEqual(BigInt(0))
+
+      // The payout is charged to the parent's shared budget
+      const parentPool = await program.account.stakingPool.fetch(parent.pool)
+      expect(parentPool.totalRewardsDistributed.toString()).toEqual(paid.toString())
+      expect(parentPool.totalRewardsEmitted.toString()).toEqual(paid.toString())
+    }, 30000)
+
+    it('Requires The Parent Accounts For Child Claims', async () => {
+      const { child } = await linkedPools()
+      const staker = await createStaker(child)
+      await stakeAs(child, staker, new BN(1_000_000_000))
+      await sleep(2000)
+
+      try {
+        await childClaim(null, child, staker)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/ParentPoolRequired/)
+      }
+    }, 30000)
+
+    it('Rejects Linking Without The Child Authority', async () => {
+      const parent = await createPool(new BN(1_000_000))
+      const child = await createPool(new BN(1_000_000), { rewardMint: parent.rewardMint })
+      const intruder = Keypair.generate()
+
+      try {
+        await program.methods
+          .linkChildPool()
+          .accounts({
+            parentAuthority: parent.authority.publicKey,
+            childAuthority: intruder.publicKey,
+            parentPool: parent.pool,
+            childPool: child.pool,
+          })
+          .signers([parent.authority, intruder])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UnauthorizedAuthority/)
+      }
+    }, 30000)
+  })
+
+  describe('current_apr_bps', () => {
+    const SECONDS_PER_YEAR = BigInt(31_536_000)
+    const BPS = BigInt(10_000)
+
+    async function currentApr(testPool: TestPool) {
+      const apr = await program.methods.currentAprBps().accounts({ pool: testPool.pool }).view()
+      return BigInt(apr.toString())
+    }
+
+    it('Returns 0 For An Emission Pool With Nothing Staked', async () => {
+      const testPool = await createPool(new BN(1_000_000), { fixedEmission: true })
+      expect(await currentApr(testPool)).toEqual(BigInt(0))
+    }, 30000)
+
+    it('Matches The Realized Yearly Emission At Several TVL Levels', async () => {
+      const rate = BigInt(1_000_000)
+      const testPool = await createPool(new BN(rate.toString()), { fixedEmission: true })
+
+      let previous: bigint | null = null
+      for (const amount of [1_000_000_000, 4_000_000_000, 15_000_000_000]) {
+        const staker = await createStaker(testPool)
+        await stakeAs(testPool, staker, new BN(amount))
+        const pool = await program.account.stakingPool.fetch(testPool.pool)
+        const tvl = BigInt(pool.totalStaked.toString())
+
+        // A year of emission over the current stake, as a bps return
+        const apr = await currentApr(testPool)
+        expect(apr).toEqual((rate * SECONDS_PER_YEAR * BPS) / tvl)
+        if (previous !== null) {
+          expect(apr < previous).toBe(true)
+        }
+        previous = apr
+      }
+    }, 60000)
+
+    it('Returns The Direct Annualized Rate For Per-Token Pools At Any TVL', async () => {
+      const rate = BigInt(50)
+      const testPool = await createPool(new BN(rate.toString()), { fixedEmission: false })
+      const expected = (rate * SECONDS_PER_YEAR * BPS) / BigInt(1_000_000_000)
+
+      expect(await currentApr(testPool)).toEqual(expected)
+      for (const amount of [1_000_000_000, 9_000_000_000]) {
+        const staker = await createStaker(testPool)
+        await stakeAs(testPool, staker, new BN(amount))
+        expect(await currentApr(testPool)).toEqual(expected)
+      }
+    }, 60000)
+
+    it('Returns The Configured Yield For APY Pools', async () => {
+      const testPool = await createPool(new BN(0), { apyBps: 1_200 })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(2_000_000_000))
+      expect(await currentApr(testPool)).toEqual(BigInt(1_200))
+    }, 30000)
+  })
+
+  describe('Claim Circuit Breaker', () => {
+    it('Pays Exactly The Cap And Leaves The Remainder Claimable', async () => {
+      const cap = 500_000
+      const testPool = await createPool(new BN(1_000_000), { maxClaimPerTx: new BN(cap) })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(2000)
+      // Freeze accrual so the owed total is fixed across both claims
+      await program.methods
+        .setRewardsPause(true)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+
+      expect(await claimAs(testPool, staker)).toEqual(BigInt(cap))
+      const pending = BigInt((await program.account.userStake.fetch(staker.userStake)).pendingRewards.toString())
+      expect(pending > BigInt(0)).toBe(true)
+
+      const second = await claimAs(testPool, staker)
+      expect(second).toEqual(pending < BigInt(cap) ? pending : BigInt(cap))
+      const after = BigInt((await program.account.userStake.fetch(staker.userStake)).pendingRewards.toString())
+      expect(after).toEqual(pending - second)
+    }, 30000)
+
+    it('Caps The Reward Leg Of An Exit', async () => {
+      const cap = 500_000
+      const testPool = await createPool(new BN(1_000_000), { maxClaimPerTx: new BN(cap), minStakeDuration: new BN(1) })
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+      await stakeAs(testPool, staker, amount)
+      await sleep(2000)
+
+      await program.methods
+        .exit(staker.positionId, amount)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          userStakeToken: staker.stakeToken,
+          userRewardToken: staker.rewardToken,
+          poolStakeVault: testPool.stakeVault,
+          poolRewardVault: testPool.rewardVault,
+          config: configPda,
+          treasuryRewardToken: await treasuryAccount(testPool.rewardMint, testPool.tokenProgram),
+          stakeTokenMint: testPool.stakeMint,
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([staker.keypair])
+        .rpc()
+
+      const paid = (await getAccount(provider.connection, staker.rewardToken, undefined, testPool.tokenProgram)).amount
+      expect(paid).toEqual(BigInt(cap))
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.pendingRewards.gtn(0)).toBe(true)
+    }, 30000)
+
+    it('Leaves Claims Unlimited When The Cap Is 0', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(2000)
+
+      expect(await claimAs(testPool, staker) > BigInt(500_000)).toBe(true)
+      expect((await program.account.userStake.fetch(staker.userStake)).pendingRewards.toNumber()).toEqual(0)
+    }, 30000)
+  })
+
+  describe('stake_delegated', () => {
+    const vaultAuthority = (testPool: TestPool) =>
+      PublicKey.findProgramAddressSync([Buffer.from('vault_authority'), testPool.pool.toBuffer()], program.programId)[0]
+
+    async function approveVault(testPool: TestPool, staker: TestStaker, amount: number) {
+      await approve(
+        provider.connection,
+        payer.payer,
+        staker.stakeToken,
+        vaultAuthority(testPool),
+        staker.keypair,
+        amount,
+        [],
+        undefined,
+        testPool.tokenProgram
+      )
+    }
+
+    async function relayStake(testPool: TestPool, staker: TestStaker, relayer: Keypair, amount: BN) {
+      await program.methods
+        .stakeDelegated(staker.positionId, amount, new BN(0), [])
+        .accounts({
+          relayer: relayer.publicKey,
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          userStakeToken: staker.stakeToken,
+          poolStakeVault: testPool.stakeVault,
+          poolRewardVault: testPool.rewardVault,
+          stakeTokenMint: testPool.stakeMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([relayer, staker.keypair])
+        .rpc()
+    }
+
+    it('Lets A Relayer Stake From An Approved Delegate Allowance', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      const relayer = Keypair.generate()
+      await airdrop(relayer.publicKey)
+      await approveVault(testPool, staker, 2_000_000_000)
+
+      const stakerLamports = await provider.connection.getBalance(staker.keypair.publicKey)
+      await relayStake(testPool, staker, relayer, new BN(2_000_000_000))
+
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.user.toString()).toEqual(staker.keypair.publicKey.toString())
+      expect(position.amount.toString()).toEqual('2000000000')
+      // The user paid nothing, the relayer covered the position's rent, and the allowance is spent
+      expect(await provider.connection.getBalance(staker.keypair.publicKey)).toEqual(stakerLamports)
+      const tokenAccount = await getAccount(provider.connection, staker.stakeToken, undefined, testPool.tokenProgram)
+      expect(tokenAccount.delegatedAmount).toEqual(BigInt(0))
+    }, 30000)
+
+    it('Rejects A Stake Above The Approved Allowance', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      const relayer = Keypair.generate()
+      await airdrop(relayer.publicKey)
+      await approveVault(testPool, staker, 1_000_000_000)
+
+      try {
+        await relayStake(testPool, staker, relayer, new BN(2_000_000_000))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/DelegateApprovalInsufficient/)
+      }
+    }, 30000)
+  })
+
+  describe('Funding Events', () => {
+    it('Emits RewardsFundedEvent And Sums Fundings Into total_rewards_funded', async () => {
+      const testPool = await createPool(new BN(1_000_000), { fundAmount: 0 })
+      const events: any[] = []
+      const listener = program.addEventListener('rewardsFundedEvent', (event) => {
+        if (event.pool.equals(testPool.pool)) events.push(event)
+      })
+
+      try {
+        await fundPool(testPool, 1_000_000)
+        await fundPool(testPool, 2_500_000)
+        await sleep(2000)
+      } finally {
+        await program.removeEventListener(listener)
+      }
+
+      expect(events.length).toEqual(2)
+      expect(events.map((event) => event.amount.toNumber())).toEqual([1_000_000, 2_500_000])
+      expect(events.map((event) => event.vaultBalance.toNumber())).toEqual([1_000_000, 3_500_000])
+      expect(events.map((event) => event.totalRewardsFunded.toNumber())).toEqual([1_000_000, 3_500_000])
+      for (const event of events) {
+        expect(event.funder.toString()).toEqual(payer.publicKey.toString())
+        expect(event.timestamp.toNumber()).toBeGreaterThan(0)
+      }
+
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalRewardsFunded.toNumber()).toEqual(3_500_000)
+    }, 30000)
+  })
+
+  describe('Zero Elapsed Time', () => {
+    // The second instruction reads the same clock the first one just stored as
+    // last_update_time, the boundary where an earlier timestamp would otherwise underflow
+    it('Settles Without Error When The Clock Has Not Advanced', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(2000)
+
+      const accounts = {
+        user: staker.keypair.publicKey,
+        pool: testPool.pool,
+        userStake: staker.userStake,
+        userStakeToken: staker.stakeToken,
+        poolStakeVault: testPool.stakeVault,
+        poolRewardVault: testPool.rewardVault,
+        stakeTokenMint: testPool.stakeMint,
+        tokenProgram: testPool.tokenProgram,
+      }
+      const topUp = (amount: number) =>
+        program.methods
+          .stake(staker.positionId, new BN(amount), new BN(0), staker.keypair.publicKey, [])
+          .accounts(accounts)
+          .instruction()
+      const tx = new Transaction().add(await topUp(1_000_000_000), await topUp(2_000_000_000))
+      await provider.sendAndConfirm(tx, [staker.keypair])
+
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.amount.toString()).toEqual('4000000000')
+      expect(position.pendingRewards.gtn(0)).toBe(true)
+      expect(pool.lastUpdateTime.toString()).toEqual(position.lastStakeTime.toString())
+    }, 30000)
+  })
+
+  describe('Second Reward Token', () => {
+    async function withSecondReward(rate2: number, fund2: number) {
+      const testPool = await createPool(new BN(1_000_000))
+      const rewardMint2 = await createMint(
+        provider.connection,
+        payer.payer,
+        payer.publicKey,
+        null,
+        9,
+        undefined,
+        undefined,
+        testPool.tokenProgram
+      )
+      const [rewardVault2] = PublicKey.findProgramAddressSync(
+        [Buffer.from('reward_vault_2'), testPool.pool.toBuffer()],
+        program.programId
+      )
+      await program.methods
+        .initializeSecondReward(new BN(rate2))
+        .accounts({
+          authority: testPool.authority.publicKey,
+          pool: testPool.pool,
+          rewardTokenMint2: rewardMint2,
+          poolRewardVault2: rewardVault2,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([testPool.authority])
+        .rpc()
+
+      const funderAta = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        rewardMint2,
+        payer.publicKey,
+        false,
+        undefined,
+        undefined,
+        testPool.tokenProgram
+      )
+      await mintTo(provider.connection, payer.payer, rewardMint2, funderAta.address, payer.publicKey, fund2, [], undefined, testPool.tokenProgram)
+      await program.methods
+        .fundSecondReward(new BN(fund2))
+        .accounts({
+          funder: payer.publicKey,
+          pool: testPool.pool,
+          funderTokenAccount: funderAta.address,
+          poolRewardVault2: rewardVault2,
+          rewardTokenMint2: rewardMint2,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .rpc()
+      return { testPool, rewardMint2, rewardVault2 }
+    }
+
+    it('Close Pool Sweeps And Closes The Second Vault', async () => {
+      const { testPool, rewardMint2, rewardVault2 } = await withSecondReward(250_000, 1_000_000_000)
+      const authorityToken = async (mint: PublicKey) =>
+        (
+          await getOrCreateAssociatedTokenAccount(
+            provider.connection,
+            payer.payer,
+            mint,
+            testPool.authority.publicKey,
+            false,
+            undefined,
+            undefined,
+            testPool.tokenProgram
+          )
+        ).address
+      const accounts = {
+        authority: testPool.authority.publicKey,
+        pool: testPool.pool,
+        poolStakeVault: testPool.stakeVault,
+        poolRewardVault: testPool.rewardVault,
+        authorityStakeToken: await authorityToken(testPool.stakeMint),
+        authorityRewardToken: await authorityToken(testPool.rewardMint),
+        stakeTokenMint: testPool.stakeMint,
+        rewardTokenMint: testPool.rewardMint,
+        tokenProgram: testPool.tokenProgram,
+      }
+      const authorityRewardToken2 = await authorityToken(rewardMint2)
+
+      try {
+        await program.methods.closePool().accounts(accounts).signers([testPool.authority]).rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/SecondRewardAccountsRequired/)
+      }
+
+      const vault2Rent = await provider.connection.getBalance(rewardVault2)
+      const lamportsBefore = await provider.connection.getBalance(testPool.authority.publicKey)
+      await program.methods
+        .closePool()
+        .accounts({
+          ...accounts,
+          poolRewardVault2: rewardVault2,
+          authorityRewardToken2,
+          rewardTokenMint2: rewardMint2,
+        })
+        .signers([testPool.authority])
+        .rpc()
+
+      expect((await getAccount(provider.connection, authorityRewardToken2, undefined, testPool.tokenProgram)).amount).toEqual(
+        BigInt(1_000_000_000)
+      )
+      expect(await provider.connection.getAccountInfo(rewardVault2)).toBeNull()
+      const lamportsAfter = await provider.connection.getBalance(testPool.authority.publicKey)
+      expect(lamportsAfter - lamportsBefore).toBeGreaterThan(vault2Rent)
+    }, 30000)
+
+    it('Pays Both Reward Tokens At Their Own Rates From One Stake', async () => {
+      const { testPool, rewardMint2, rewardVault2 } = await withSecondReward(250_000, 1_000_000_000)
+      const staker = await createStaker(testPool)
+      const rewardAta2 = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        rewardMint2,
+        staker.keypair.publicKey,
+        false,
+        undefined,
+        undefined,
+        testPool.tokenProgram
+      )
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(2000)
+      // Freeze both emissions so the claim settles a fixed window
+      await program.methods
+        .setRewardsPause(true)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+
+      await program.methods
+        .claimRewards(staker.positionId, null)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          userRewardToken: staker.rewardToken,
+          poolRewardVault: testPool.rewardVault,
+          config: configPda,
+          treasuryRewardToken: await treasuryAccount(testPool.rewardMint, testPool.tokenProgram),
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+          poolRewardVault2: rewardVault2,
+          userRewardToken2: rewardAta2.address,
+          rewardTokenMint2: rewardMint2,
+        })
+        .signers([staker.keypair])
+        .rpc()
+
+      const paid1 = (await getAccount(provider.connection, staker.rewardToken, undefined, testPool.tokenProgram)).amount
+      const paid2 = (await getAccount(provider.connection, rewardAta2.address, undefined, testPool.tokenProgram)).amount
+      expect(paid2 > BigInt(0)).toBe(true)
+      // The same window at a quarter of the rate, give or take a unit of rounding per token
+      const diff = paid1 - paid2 * BigInt(4)
+      expect(diff >= BigInt(-4) && diff <= BigInt(4)).toBe(true)
+
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.pendingRewards.toNumber()).toEqual(0)
+      expect(position.pendingRewards2.toNumber()).toEqual(0)
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalRewardsDistributed2.toString()).toEqual(paid2.toString())
+    }, 30000)
+
+    it('Pays The Second Reward On Its Own Once The First Stops Emitting', async () => {
+      const { testPool, rewardMint2, rewardVault2 } = await withSecondReward(250_000, 1_000_000_000)
+      await program.methods
+        .updateRewardRate(new BN(0))
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+      const staker = await createStaker(testPool)
+      const rewardAta2 = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        rewardMint2,
+        staker.keypair.publicKey,
+        false,
+        undefined,
+        undefined,
+        testPool.tokenProgram
+      )
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(2000)
+
+      await program.methods
+        .claimRewards(staker.positionId, null)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          userRewardToken: staker.rewardToken,
+          poolRewardVault: testPool.rewardVault,
+          config: configPda,
+          treasuryRewardToken: await treasuryAccount(testPool.rewardMint, testPool.tokenProgram),
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+          poolRewardVault2: rewardVault2,
+          userRewardToken2: rewardAta2.address,
+          rewardTokenMint2: rewardMint2,
+        })
+        .signers([staker.keypair])
+        .rpc()
+
+      const paid1 = (await getAccount(provider.connection, staker.rewardToken, undefined, testPool.tokenProgram)).amount
+      const paid2 = (await getAccount(provider.connection, rewardAta2.address, undefined, testPool.tokenProgram)).amount
+      expect(paid1).toEqual(BigInt(0))
+      expect(paid2 > BigInt(0)).toBe(true)
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.pendingRewards2.toNumber()).toEqual(0)
+    }, 30000)
+
+    it('Rejects A Second Reward Rate Above MAX_REWARD_RATE', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const rewardMint2 = await createMint(
+        provider.connection,
+        payer.payer,
+        payer.publicKey,
+        null,
+        9,
+        undefined,
+        undefined,
+        testPool.tokenProgram
+      )
+      const [rewardVault2] = PublicKey.findProgramAddressSync(
+        [Buffer.from('reward_vault_2'), testPool.pool.toBuffer()],
+        program.programId
+      )
+
+      try {
+        await program.methods
+          .initializeSecondReward(new BN('107902830708060142'))
+          .accounts({
+            authority: testPool.authority.publicKey,
+            pool: testPool.pool,
+            rewardTokenMint2: rewardMint2,
+            poolRewardVault2: rewardVault2,
+            tokenProgram: testPool.tokenProgram,
+          })
+          .signers([testPool.authority])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/RewardRateTooHigh/)
+      }
+    }, 30000)
+
+    it('Records Second Reward Deposits Apart From The First', async () => {
+      const { testPool } = await withSecondReward(250_000, 750_000_000)
+
+      const [secondRecord] = PublicKey.findProgramAddressSync(
+        [Buffer.from('funder_2'), testPool.pool.toBuffer(), payer.publicKey.toBuffer()],
+        program.programId
+      )
+      const record = await program.account.funderRecord.fetch(secondRecord)
+      expect(record.funder.toString()).toEqual(payer.publicKey.toString())
+      expect(record.cumulativeFunded.toNumber()).toEqual(750_000_000)
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalRewardsFunded2.toNumber()).toEqual(750_000_000)
+    }, 30000)
+
+    it('Requires The Second Reward Accounts To Claim', async () => {
+      const { testPool } = await withSecondReward(250_000, 1_000_000_000)
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(2000)
+
+      try {
+        await claimAs(testPool, staker)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/SecondRewardAccountsRequired/)
+      }
+    }, 30000)
+  })
+
+  describe('fund_rewards_streamed', () => {
+    async function fundStreamed(testPool: TestPool, amount: number, duration: number) {
+      const funderAta = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        testPool.rewardMint,
+        payer.publicKey,
+        false,
+        undefined,
+        undefined,
+        testPool.tokenProgram
+      )
+      await mintTo(provider.connection, payer.payer, testPool.rewardMint, funderAta.address, payer.publicKey, amount, [], undefined, testPool.tokenProgram)
+      await program.methods
+        .fundRewardsStreamed(new BN(amount), new BN(duration))
+        .accounts({
+          funder: payer.publicKey,
+          pool: testPool.pool,
+          funderTokenAccount: funderAta.address,
+          poolRewardVault: testPool.rewardVault,
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .rpc()
+    }
+
+    async function emittedAfterPoke(testPool: TestPool) {
+      await program.methods.poke().accounts({ pool: testPool.pool }).rpc()
+      return (await program.account.stakingPool.fetch(testPool.pool)).totalRewardsEmitted.toNumber()
+    }
+
+    it('Vests A Streamed Deposit Gradually Across Its Duration', async () => {
+      // A 1 unit/s base rate keeps regular emission out of the way
+      const testPool = await createPool(new BN(1), { fundAmount: 0 })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await fundStreamed(testPool, 6_000_000, 6)
+
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.rewardStream.amount.toNumber()).toEqual(6_000_000)
+      expect(pool.rewardStream.endTime.sub(pool.rewardStream.startTime).toNumber()).toEqual(6)
+
+      await sleep(2000)
+      const midway = await emittedAfterPoke(testPool)
+      expect(midway).toBeGreaterThan(0)
+      expect(midway).toBeLessThan(6_000_000)
+
+      await sleep(6000)
+      const done = await emittedAfterPoke(testPool)
+      expect(done).toBeGreaterThanOrEqual(6_000_000)
+      expect(done).toBeLessThan(6_000_100)
+    }, 60000)
+
+    it('Keeps The Unvested Deposit Out Of withdraw_excess_rewards', async () => {
+      const testPool = await createPool(new BN(1), { fundAmount: 0 })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await fundStreamed(testPool, 6_000_000, 600)
+
+      const authorityRewardToken = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        testPool.rewardMint,
+        testPool.authority.publicKey
+      )
+      try {
+        await program.methods
+          .withdrawExcessRewards(new BN(1_000_000))
+          .accounts({
+            authority: testPool.authority.publicKey,
+            pool: testPool.pool,
+            poolRewardVault: testPool.rewardVault,
+            authorityRewardToken: authorityRewardToken.address,
+            rewardTokenMint: testPool.rewardMint,
+            tokenProgram: testPool.tokenProgram,
+          })
+          .signers([testPool.authority])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/ExcessWithdrawTooLarge/)
+      }
+    }, 30000)
+  })
+
+  describe('estimate_penalty', () => {
+    async function estimatePenalty(testPool: TestPool, staker: TestStaker, amount: BN) {
+      const penalty: BN = await program.methods
+        .estimatePenalty(amount)
+        .accounts({ pool: testPool.pool, userStake: staker.userStake, poolStakeVault: testPool.stakeVault })
+        .view()
+      return BigInt(penalty.toString())
+    }
+
+    async function penaltyPool(minStakeDuration: number) {
+      const testPool = await createPool(new BN(1_000), {
+        sameMint: true,
+        minStakeDuration: new BN(minStakeDuration),
+        earlyUnstakePenaltyBps: 1_000,
+      })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(100_000_000_000))
+      return { testPool, staker }
+    }
+
+    // The penalty is whatever the withdrawal left behind in the pool
+    async function chargedPenalty(testPool: TestPool, staker: TestStaker, amount: BN) {
+      const before = await getAccount(provider.connection, staker.stakeToken)
+      await unstakeAs(testPool, staker, amount)
+      const after = await getAccount(provider.connection, staker.stakeToken)
+      return BigInt(amount.toString()) - (after.amount - before.amount)
+    }
+
+    it('Matches The Penalty Charged At The Start Of The Lock Window', async () => {
+      const { testPool, staker } = await penaltyPool(3600)
+      const amount = new BN(100_000_000_000)
+
+      const estimate = await estimatePenalty(testPool, staker, amount)
+      expect(estimate).toEqual(BigInt(10_000_000_000))
+      expect(await chargedPenalty(testPool, staker, amount)).toEqual(estimate)
+    }, 30000)
+
+    it('Matches The Penalty Charged On A Partial Unstake Midway Through The Window', async () => {
+      const { testPool, staker } = await penaltyPool(3600)
+      const amount = new BN(25_000_000_000)
+      await sleep(2000)
+
+      const estimate = await estimatePenalty(testPool, staker, amount)
+      expect(estimate).toEqual(BigInt(2_500_000_000))
+      expect(await chargedPenalty(testPool, staker, amount)).toEqual(estimate)
+    }, 30000)
+
+    it('Returns 0 Once The Lock Window Has Elapsed', async () => {
+      const { testPool, staker } = await penaltyPool(2)
+      const amount = new BN(100_000_000_000)
+      await sleep(3000)
+
+      expect(await estimatePenalty(testPool, staker, amount)).toEqual(BigInt(0))
+      expect(await chargedPenalty(testPool, staker, amount)).toEqual(BigInt(0))
+    }, 30000)
+  })
+
+  describe('set_user_frozen', () => {
+    async function setFrozen(testPool: TestPool, staker: TestStaker, frozen: boolean) {
+      await program.methods
+        .setUserFrozen(frozen)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool, userStake: staker.userStake })
+        .signers([testPool.authority])
+        .rpc()
+    }
+
+    async function expectFrozen(action: Promise<unknown>) {
+      try {
+        await action
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UserFrozen/)
+      }
+    }
+
+    it('Blocks A Frozen Position And Restores Access On Unfreeze', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      const other = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await stakeAs(testPool, other, new BN(1_000_000_000))
+      await sleep(2000)
+
+      await setFrozen(testPool, staker, true)
+      expect((await program.account.userStake.fetch(staker.userStake)).frozen).toEqual(true)
+      await expectFrozen(claimAs(testPool, staker))
+      await expectFrozen(unstakeAs(testPool, staker, new BN(1_000_000_000)))
+      // Other stakers are unaffected
+      expect((await claimAs(testPool, other)) > BigInt(0)).toBe(true)
+
+      await setFrozen(testPool, staker, false)
+      expect((await claimAs(testPool, staker)) > BigInt(0)).toBe(true)
+      await unstakeAs(testPool, staker, new BN(1_000_000_000))
+      expect((await program.account.userStake.fetch(staker.userStake)).amount.toNumber()).toEqual(0)
+    }, 30000)
+
+    it('Blocks Re-Locking A Frozen Position', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+
+      await setFrozen(testPool, staker, true)
+      await expectFrozen(
+        program.methods
+          .restake(staker.positionId, new BN(60))
+          .accounts({ user: staker.keypair.publicKey, pool: testPool.pool, userStake: staker.userStake })
+          .signers([staker.keypair])
+          .rpc()
+      )
+      expect((await program.account.userStake.fetch(staker.userStake)).lockEnd.toNumber()).toBeLessThan(nowSeconds() + 60)
+    }, 30000)
+
+    it('Stops Accrual While Frozen When The Pool Asks For It', async () => {
+      const testPool = await createPool(new BN(1_000_000), { freezeStopsAccrual: true })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(1000)
+
+      await setFrozen(testPool, staker, true)
+      const frozenPending = (await program.account.userStake.fetch(staker.userStake)).pendingRewards
+      expect(frozenPending.gtn(0)).toBe(true)
+      expect((await program.account.stakingPool.fetch(testPool.pool)).totalWeightedStake.toNumber()).toEqual(0)
+      await sleep(2000)
+
+      await setFrozen(testPool, staker, false)
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.pendingRewards.toString()).toEqual(frozenPending.toString())
+      expect((await program.account.stakingPool.fetch(testPool.pool)).totalWeightedStake.toNumber()).toEqual(1_000_000_000)
+    }, 30000)
+
+    it('Rejects Freezing By Anyone But The Pool Authority', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+
+      try {
+        await program.methods
+          .setUserFrozen(true)
+          .accounts({ authority: staker.keypair.publicKey, pool: testPool.pool, userStake: staker.userStake })
+          .signers([staker.keypair])
+          .rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UnauthorizedAuthority/)
+      }
+    }, 30000)
+  })
+
+  describe('max_rewarded_stake', () => {
+    async function setRewardsPaused(testPool: TestPool, paused: boolean) {
+      await program.methods
+        .setRewardsPause(paused)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool })
+        .signers([testPool.authority])
+        .rpc()
+    }
+
+    it('Flattens Rewards At The Cap For A Position Above It', async () => {
+      // Per-token emission, so each position earns on its own rewarded balance
+      const testPool = await createPool(new BN(1_000_000), {
+        fixedEmission: false,
+        maxRewardedStake: new BN(2_000_000_000),
+      })
+      const whale = await createStaker(testPool)
+      const capped = await createStaker(testPool)
+
+      // Both positions earn over exactly the same window
+      await setRewardsPaused(testPool, true)
+      await stakeAs(testPool, whale, new BN(5_000_000_000))
+      await stakeAs(testPool, capped, new BN(2_000_000_000))
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalStaked.toString()).toEqual('7000000000')
+      expect(pool.totalWeightedStake.toString()).toEqual('4000000000')
+
+      await setRewardsPaused(testPool, false)
+      await sleep(2000)
+      await setRewardsPaused(testPool, true)
+
+      const whalePaid = await claimAs(testPool, whale)
+      const cappedPaid = await claimAs(testPool, capped)
+      expect(whalePaid > BigInt(0)).toBe(true)
+      const diff = whalePaid - cappedPaid
+      expect(diff >= BigInt(-1) && diff <= BigInt(1)).toBe(true)
+    }, 30000)
+  })
+
+  describe('bootstrap_pool', () => {
+    it('Creates And Funds A Pool In One Instruction So The First Claim Pays', async () => {
+      const fundAmount = 1_000_000_000
+      const testPool = await createPool(new BN(1_000_000), { bootstrap: true, fundAmount })
+
+      const vault = await getAccount(provider.connection, testPool.rewardVault)
+      expect(vault.amount).toEqual(BigInt(fundAmount))
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalRewardsFunded.toNumber()).toEqual(fundAmount)
+      expect(pool.authority.toString()).toEqual(testPool.authority.publicKey.toString())
+      const [funderRecord] = PublicKey.findProgramAddressSync(
+        [Buffer.from('funder'), testPool.pool.toBuffer(), testPool.authority.publicKey.toBuffer()],
+        program.programId
+      )
+      expect((await program.account.funderRecord.fetch(funderRecord)).cumulativeFunded.toNumber()).toEqual(fundAmount)
+
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(2000)
+      const quote: BN = await program.methods
+        .quoteRewards()
+        .accounts({
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          poolRewardVault: testPool.rewardVault,
+          config: configPda,
+        })
+        .view()
+      expect(quote.gtn(0)).toBe(true)
+      expect((await claimAs(testPool, staker)) > BigInt(0)).toBe(true)
+    }, 30000)
+
+    it('Rejects A Bootstrap Without Funding', async () => {
+      try {
+        await createPool(new BN(1_000_000), { bootstrap: true, fundAmount: 0 })
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InvalidAmount/)
+      }
+    }, 30000)
+  })
+
+  describe('migrate_stake', () => {
+    // Pool addresses derive from the creating key, so the second pool is handed to the first's authority
+    async function createPoolPair() {
+      const poolA = await createPool(new BN(1_000_000))
+      const poolB = await createPool(new BN(1_000_000), { stakeMint: poolA.stakeMint })
+      await program.methods
+        .proposeAuthority(poolA.authority.publicKey)
+        .accounts({ authority: poolB.authority.publicKey, pool: poolB.pool })
+        .signers([poolB.authority])
+        .rpc()
+      await program.methods
+        .acceptAuthority()
+        .accounts({ newAuthority: poolA.authority.publicKey, pool: poolB.pool })
+        .signers([poolA.authority])
+        .rpc()
+      return { poolA, poolB }
+    }
+
+    async function migrateAs(from: TestPool, to: TestPool, staker: TestStaker, newPositionId: BN) {
+      await program.methods
+        .migrateStake(staker.positionId, newPositionId, [])
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: from.pool,
+          userStake: staker.userStake,
+          poolStakeVault: from.stakeVault,
+          newPool: to.pool,
+          newUserStake: positionPda(to.pool, staker.keypair.publicKey, newPositionId),
+          newPoolStakeVault: to.stakeVault,
+          newPoolRewardVault: to.rewardVault,
+          stakeTokenMint: from.stakeMint,
+          tokenProgram: from.tokenProgram,
+        })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    it('Moves A Position Between Pools Without Touching The Wallet', async () => {
+      const { poolA, poolB } = await createPoolPair()
+      const staker = await createStaker(poolA)
+      const amount = new BN(5_000_000_000)
+      await stakeAs(poolA, staker, amount, new BN(3600))
+      await sleep(2000)
+
+      const walletBefore = await getAccount(provider.connection, staker.stakeToken)
+      const newPositionId = new BN(7)
+      await migrateAs(poolA, poolB, staker, newPositionId)
+      const walletAfter = await getAccount(provider.connection, staker.stakeToken)
+      expect(walletAfter.amount).toEqual(walletBefore.amount)
+
+      const oldPosition = await program.account.userStake.fetch(staker.userStake)
+      expect(oldPosition.amount.toNumber()).toEqual(0)
+      expect(oldPosition.pendingRewards.gtn(0)).toBe(true)
+      const newPosition = await program.account.userStake.fetch(
+        positionPda(poolB.pool, staker.keypair.publicKey, newPositionId)
+      )
+      expect(newPosition.amount.toString()).toEqual(amount.toString())
+      expect(newPosition.pool.toString()).toEqual(poolB.pool.toString())
+      // The new position keeps what was left of the lock
+      expect(newPosition.lockEnd.toNumber()).toEqual(oldPosition.lockEnd.toNumber())
+
+      expect((await program.account.stakingPool.fetch(poolA.pool)).totalStaked.toNumber()).toEqual(0)
+      expect((await program.account.stakingPool.fetch(poolB.pool)).totalStaked.toString()).toEqual(amount.toString())
+      expect((await getAccount(provider.connection, poolA.stakeVault)).amount).toEqual(BigInt(0))
+      expect((await getAccount(provider.connection, poolB.stakeVault)).amount.toString()).toEqual(amount.toString())
+
+      // Rewards earned before the move are still paid by the old pool
+      expect((await claimAs(poolA, staker)) > BigInt(0)).toBe(true)
+    }, 30000)
+
+    it('Rejects A Pool With A Different Authority', async () => {
+      const poolA = await createPool(new BN(1_000_000))
+      const poolC = await createPool(new BN(1_000_000), { stakeMint: poolA.stakeMint })
+      const staker = await createStaker(poolA)
+      await stakeAs(poolA, staker, new BN(1_000_000_000))
+
+      try {
+        await migrateAs(poolA, poolC, staker, new BN(0))
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/InvalidMigrationTarget/)
+      }
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.amount.toNumber()).toEqual(1_000_000_000)
+    }, 30000)
+  })
+
+  describe('large pool reward math', () => {
+    it('Accrues Without Overflow When Stake, Rate And Elapsed Time Are All Large', async () => {
+      // rate * elapsed * total stake runs past u128 here; the staged math divides first
+      const rate = new BN('1000000000000')
+      const testPool = await createPool(rate, { fixedEmission: false })
+      const whaleAmount = new BN('10000000000000000000')
+      const whale = await createStaker(testPool, 10_000_000_000_000_000_000)
+      await stakeAs(testPool, whale, whaleAmount)
+
+      const day = 86_400
+      for (const duration of [day, 365 * day, 10 * 365 * day]) {
+        for (const amount of [new BN(1_000), new BN(1_000_000)]) {
+          const preview: BN = await program.methods
+            .previewStake(amount, new BN(duration))
+            .accounts({ pool: testPool.pool })
+            .view()
+          // amount * rate / PER_TOKEN_RATE_PRECISION per second, less rounding dust
+          const expected = amount.mul(rate).mul(new BN(duration)).div(new BN(1_000_000_000))
+          expect(preview.lte(expected)).toBe(true)
+          expect(expected.sub(preview).lten(1)).toBe(true)
+        }
+      }
+
+      // The live accumulator keeps advancing as well
+      await sleep(1000)
+      await program.methods.poke().accounts({ pool: testPool.pool }).rpc()
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.accRewardPerShare.gtn(0)).toBe(true)
+    }, 30000)
+  })
+
+  describe('assert_fresh', () => {
+    it('Rejects An Idle Pool Until It Is Poked', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(3000)
+
+      const assertFresh = () => program.methods.assertFresh(new BN(1)).accounts({ pool: testPool.pool })
+      try {
+        await assertFresh().rpc()
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/PoolStale/)
+      }
+
+      // A poke earlier in the same transaction brings the accumulator up to date
+      const tx = new Transaction().add(
+        await program.methods.poke().accounts({ pool: testPool.pool }).instruction(),
+        await assertFresh().instruction()
+      )
+      await provider.sendAndConfirm(tx)
+
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(Math.floor(Date.now() / 1000) - pool.lastUpdateTime.toNumber()).toBeLessThan(3)
+    }, 30000)
+  })
+
+  describe('set_auto_compound', () => {
+    async function setAutoCompound(testPool: TestPool, staker: TestStaker, enabled: boolean) {
+      await program.methods
+        .setAutoCompound(staker.positionId, enabled)
+        .accounts({ user: staker.keypair.publicKey, pool: testPool.pool, userStake: staker.userStake })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    async function claimIntoStake(testPool: TestPool, staker: TestStaker, poolStakeVault: PublicKey | null) {
+      await program.methods
+        .claimRewards(staker.positionId, null)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          userRewardToken: staker.rewardToken,
+          poolRewardVault: testPool.rewardVault,
+          config: configPda,
+          treasuryRewardToken: await treasuryAccount(testPool.rewardMint, testPool.tokenProgram),
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+          poolStakeVault,
+        })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    it('Restakes Claimed Rewards Instead Of Paying Them Out', async () => {
+      const testPool = await createPool(new BN(1_000_000), { sameMint: true })
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+      await stakeAs(testPool, staker, amount)
+      await setAutoCompound(testPool, staker, true)
+      await sleep(2000)
+
+      const walletBefore = await getAccount(provider.connection, staker.stakeToken)
+      await claimIntoStake(testPool, staker, testPool.stakeVault)
+      const walletAfter = await getAccount(provider.connection, staker.stakeToken)
+      expect(walletAfter.amount).toEqual(walletBefore.amount)
+
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.autoCompound).toBe(true)
+      expect(position.amount.gt(amount)).toBe(true)
+      expect(position.pendingRewards.toNumber()).toEqual(0)
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalStaked.toString()).toEqual(position.amount.toString())
+      const vault = await getAccount(provider.connection, testPool.stakeVault)
+      expect(vault.amount.toString()).toEqual(position.amount.toString())
+
+      // Turned off, claims pay out to the wallet again
+      await setAutoCompound(testPool, staker, false)
+      await sleep(1000)
+      expect((await claimAs(testPool, staker)) > BigInt(0)).toBe(true)
+      expect((await program.account.userStake.fetch(staker.userStake)).amount.toString()).toEqual(
+        position.amount.toString()
+      )
+    }, 30000)
+
+    it('Rejects Toggling A Frozen Position', async () => {
+      const testPool = await createPool(new BN(1_000_000), { sameMint: true })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await program.methods
+        .setUserFrozen(true)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool, userStake: staker.userStake })
+        .signers([testPool.authority])
+        .rpc()
+
+      try {
+        await setAutoCompound(testPool, staker, true)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UserFrozen/)
+      }
+      expect((await program.account.userStake.fetch(staker.userStake)).autoCompound).toBe(false)
+    }, 30000)
+
+    it('Requires The Stake Vault To Claim', async () => {
+      const testPool = await createPool(new BN(1_000_000), { sameMint: true })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await setAutoCompound(testPool, staker, true)
+      await sleep(1000)
+
+      try {
+        await claimIntoStake(testPool, staker, null)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/StakeVaultRequired/)
+      }
+    }, 30000)
+
+    it('Rejects Pools With Different Stake And Reward Mints', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+
+      try {
+        await setAutoCompound(testPool, staker, true)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/CompoundNotSupported/)
+      }
+    }, 30000)
+  })
+
+  describe('set_emergency_mode', () => {
+    async function setEmergencyMode(testPool: TestPool, enabled: boolean, signer = testPool.authority) {
+      await program.methods
+        .setEmergencyMode(enabled)
+        .accounts({ authority: signer.publicKey, pool: testPool.pool })
+        .signers([signer])
+        .rpc()
+    }
+
+    it('Lets A Locked Position Withdraw At Once Without A Penalty', async () => {
+      const testPool = await createPool(new BN(1_000_000), {
+        minStakeDuration: new BN(3600),
+        earlyUnstakePenaltyBps: 1_000,
+      })
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+      await stakeAs(testPool, staker, amount, new BN(3600))
+
+      try {
+        await unstakeAs(testPool, staker, amount)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/LockNotExpired/)
+      }
+
+      await setEmergencyMode(testPool, true)
+      const penalty: BN = await program.methods
+        .estimatePenalty(amount)
+        .accounts({ pool: testPool.pool, userStake: staker.userStake, poolStakeVault: testPool.stakeVault })
+        .view()
+      expect(penalty.toNumber()).toEqual(0)
+
+      const walletBefore = await getAccount(provider.connection, staker.stakeToken)
+      const rewardVaultBefore = await getAccount(provider.connection, testPool.rewardVault)
+      await unstakeAs(testPool, staker, amount)
+      const walletAfter = await getAccount(provider.connection, staker.stakeToken)
+      expect((walletAfter.amount - walletBefore.amount).toString()).toEqual(amount.toString())
+      expect((await getAccount(provider.connection, testPool.rewardVault)).amount).toEqual(rewardVaultBefore.amount)
+      expect((await program.account.stakingPool.fetch(testPool.pool)).totalStaked.toNumber()).toEqual(0)
+    }, 30000)
+
+    it('Only Lets The Authority Switch It', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      const intruder = Keypair.generate()
+      await airdrop(intruder.publicKey)
+
+      try {
+        await setEmergencyMode(testPool, true, intruder)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UnauthorizedAuthority/)
+      }
+      expect((await program.account.stakingPool.fetch(testPool.pool)).emergencyMode).toBe(false)
+    }, 30000)
+  })
+
+  describe('same-transaction stake and unstake', () => {
+    it('Earns Nothing And Leaves Totals Consistent', async () => {
+      const testPool = await createPool(new BN(1_000_000))
+      // Someone already earning keeps the accumulator moving
+      const incumbent = await createStaker(testPool)
+      const incumbentAmount = new BN(2_000_000_000)
+      await stakeAs(testPool, incumbent, incumbentAmount)
+      await sleep(2000)
+
+      const staker = await createStaker(testPool)
+      const stakeAccounts = {
+        user: staker.keypair.publicKey,
+        pool: testPool.pool,
+        userStake: staker.userStake,
+        userStakeToken: staker.stakeToken,
+        poolStakeVault: testPool.stakeVault,
+        poolRewardVault: testPool.rewardVault,
+        stakeTokenMint: testPool.stakeMint,
+        tokenProgram: testPool.tokenProgram,
+      }
+      const stakeIx = (amount: number) =>
+        program.methods
+          .stake(staker.positionId, new BN(amount), new BN(0), staker.keypair.publicKey, [])
+          .accounts(stakeAccounts)
+          .instruction()
+      const walletBefore = await getAccount(provider.connection, staker.stakeToken)
+
+      // Two stakes and a full unstake share one clock reading
+      const tx = new Transaction().add(
+        await stakeIx(1_000_000_000),
+        await stakeIx(500_000_000),
+        await program.methods.unstake(staker.positionId, new BN(1_500_000_000)).accounts(stakeAccounts).instruction()
+      )
+      await provider.sendAndConfirm(tx, [staker.keypair])
+
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.amount.toNumber()).toEqual(0)
+      expect(position.pendingRewards.toNumber()).toEqual(0)
+      expect(position.rewardRemainder.toNumber()).toEqual(0)
+      expect(position.lastStakeTime.toNumber()).toEqual(position.firstStakeTime.toNumber())
+      expect(position.lastSettleTime.toNumber()).toEqual(position.lastStakeTime.toNumber())
+      const walletAfter = await getAccount(provider.connection, staker.stakeToken)
+      expect(walletAfter.amount).toEqual(walletBefore.amount)
+
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.totalStaked.toString()).toEqual(incumbentAmount.toString())
+      expect(pool.totalWeightedStake.toString()).toEqual(incumbentAmount.toString())
+      const vault = await getAccount(provider.connection, testPool.stakeVault)
+      expect(vault.amount.toString()).toEqual(incumbentAmount.toString())
+    }, 30000)
+  })
+
+  describe('rate_scale', () => {
+    it('Accrues A Per-Token Rate Finer Than The Default Scale', async () => {
+      // Half a reward unit per 1e9 stake units a second rounds to 0 at the default scale
+      const testPool = await createPool(new BN(500), { fixedEmission: false, rateScale: new BN('1000000000000') })
+      const amount = new BN('1000000000000')
+
+      const preview: BN = await program.methods
+        .previewStake(amount, new BN(1_000))
+        .accounts({ pool: testPool.pool })
+        .view()
+      expect(preview.toNumber()).toEqual(500_000)
+
+      const staker = await createStaker(testPool, 2_000_000_000_000)
+      await stakeAs(testPool, staker, amount)
+      await sleep(2000)
+      expect((await claimAs(testPool, staker)) > BigInt(0)).toBe(true)
+    }, 30000)
+
+    it('Rejects A Scale That Is Not A Power Of Ten', async () => {
+      for (const rateScale of [new BN(0), new BN(2_000_000_000)]) {
+        try {
+          await createPool(new BN(500), { fixedEmission: false, rateScale })
+          throw new Error('Should have thrown an error')
+        } catch (error) {
+          expect((error as Error).message).toMatch(/InvalidRateScale/)
+        }
+      }
+    }, 30000)
+  })
+
+  describe('merkle allowlist', () => {
+    function sha256(...parts: Buffer[]) {
+      return createHash('sha256').update(Buffer.concat(parts)).digest()
+    }
+
+    // Same tree as verify_allowlist: sha256 leaves, parents hash their children in sorted order
+    function allowlist(members: PublicKey[]) {
+      let level = members.map((member) => sha256(member.toBuffer()))
+      const proofs: Buffer[][] = members.map(() => [])
+      const indexes = members.map((_, i) => i)
+      while (level.length > 1) {
+        const next: Buffer[] = []
+        for (let i = 0; i < level.length; i += 2) {
+          const [left, right] = [level[i], level[i + 1]]
+          next.push(!right ? left : Buffer.compare(left, right) <= 0 ? sha256(left, right) : sha256(right, left))
+        }
+        indexes.forEach((index, member) => {
+          if ((index ^ 1) < level.length) proofs[member].push(level[index ^ 1])
+          indexes[member] = index >> 1
+        })
+        level = next
+      }
+      return { root: [...level[0]], proofs: proofs.map((proof) => proof.map((node) => [...node])) }
+    }
+
+    it('Lets A Listed Staker In With A Valid Proof', async () => {
+      const others = [Keypair.generate(), Keypair.generate(), Keypair.generate()].map((k) => k.publicKey)
+      // The root is fixed at creation, so the staker and its stake mint come from an earlier pool
+      const mintSource = await createPool(new BN(1_000_000))
+      const staker = await createStaker(mintSource)
+      const { root, proofs } = allowlist([others[0], staker.keypair.publicKey, others[1], others[2]])
+
+      const testPool = await createPool(new BN(1_000_000), { merkleRoot: root, stakeMint: mintSource.stakeMint })
+      const listed = { ...staker, userStake: positionPda(testPool.pool, staker.keypair.publicKey, staker.positionId) }
+      await stakeAs(testPool, listed, new BN(1_000_000_000), new BN(0), proofs[1])
+
+      const position = await program.account.userStake.fetch(listed.userStake)
+      expect(position.amount.toNumber()).toEqual(1_000_000_000)
+      const pool = await program.account.stakingPool.fetch(testPool.pool)
+      expect(pool.merkleRoot).toEqual(root)
+    }, 30000)
+
+    it('Rejects A Missing Or Wrong Proof', async () => {
+      const members = [Keypair.generate(), Keypair.generate()].map((k) => k.publicKey)
+      const { root, proofs } = allowlist(members)
+      const testPool = await createPool(new BN(1_000_000), { merkleRoot: root })
+      const outsider = await createStaker(testPool)
+
+      for (const proof of [[], proofs[0], proofs[1]]) {
+        try {
+          await stakeAs(testPool, outsider, new BN(1_000_000_000), new BN(0), proof)
+          throw new Error('Should have thrown an error')
+        } catch (error) {
+          expect((error as Error).message).toMatch(/NotWhitelisted/)
+        }
+      }
+    }, 30000)
+  })
+
+  describe('claim receipts', () => {
+    function receiptPda(testPool: TestPool, staker: TestStaker, sequence: number) {
+      return PublicKey.findProgramAddressSync(
+        [
+          Buffer.from('claim_receipt'),
+          testPool.pool.toBuffer(),
+          staker.keypair.publicKey.toBuffer(),
+          staker.positionId.toArrayLike(Buffer, 'le', 8),
+          new BN(sequence).toArrayLike(Buffer, 'le', 8),
+        ],
+        program.programId
+      )[0]
+    }
+
+    async function claimWithReceipt(testPool: TestPool, staker: TestStaker, claimReceipt: PublicKey | null) {
+      await program.methods
+        .claimRewards(staker.positionId, null)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          userRewardToken: staker.rewardToken,
+          poolRewardVault: testPool.rewardVault,
+          config: configPda,
+          treasuryRewardToken: await treasuryAccount(testPool.rewardMint, testPool.tokenProgram),
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+          claimReceipt,
+        })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    it('Records Each Claim In Its Own Numbered Receipt', async () => {
+      const testPool = await createPool(new BN(1_000_000), { emitReceipts: true })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+
+      const receipts = [receiptPda(testPool, staker, 0), receiptPda(testPool, staker, 1)]
+      expect(receipts[0].toString()).not.toEqual(receipts[1].toString())
+      for (const receipt of receipts) {
+        await sleep(1500)
+        const before = await getAccount(provider.connection, staker.rewardToken)
+        await claimWithReceipt(testPool, staker, receipt)
+        const after = await getAccount(provider.connection, staker.rewardToken)
+
+        const record = await program.account.claimReceipt.fetch(receipt)
+        expect(record.amount.sub(record.fee).toString()).toEqual((after.amount - before.amount).toString())
+        expect(record.amount.gtn(0)).toBe(true)
+      }
+
+      const [first, second] = await Promise.all(receipts.map((r) => program.account.claimReceipt.fetch(r)))
+      expect(first.sequence.toNumber()).toEqual(0)
+      expect(second.sequence.toNumber()).toEqual(1)
+      expect(second.timestamp.toNumber()).toBeGreaterThan(first.timestamp.toNumber())
+      expect(first.rewardMint.toString()).toEqual(testPool.rewardMint.toString())
+      expect(first.user.toString()).toEqual(staker.keypair.publicKey.toString())
+      expect(first.pool.toString()).toEqual(testPool.pool.toString())
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.claimCount.toNumber()).toEqual(2)
+    }, 30000)
+
+    it('Requires The Receipt Account On Receipt Pools', async () => {
+      const testPool = await createPool(new BN(1_000_000), { emitReceipts: true })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(1000)
+
+      try {
+        await claimWithReceipt(testPool, staker, null)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/ReceiptAccountRequired/)
+      }
+    }, 30000)
+  })
+
+  describe('sweep_expired_rewards', () => {
+    async function sweep(testPool: TestPool) {
+      const authorityRewardToken = await getOrCreateAssociatedTokenAccount(
+        provider.connection,
+        payer.payer,
+        testPool.rewardMint,
+        testPool.authority.publicKey
+      )
+      await program.methods
+        .sweepExpiredRewards()
+        .accounts({
+          authority: testPool.authority.publicKey,
+          pool: testPool.pool,
+          poolRewardVault: testPool.rewardVault,
+          authorityRewardToken: authorityRewardToken.address,
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([testPool.authority])
+        .rpc()
+      return authorityRewardToken.address
+    }
+
+    it('Keeps Claims Open Through The Grace And Sweeps After It', async () => {
+      const now = nowSeconds()
+      const testPool = await createPool(new BN(1_000_000), {
+        rewardStartTime: new BN(now - 60),
+        rewardEndTime: new BN(now + 3),
+        finalClaimGrace: new BN(4),
+      })
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(1_000_000_000))
+      await sleep(4000)
+
+      // The campaign has ended but the grace is still running
+      try {
+        await sweep(testPool)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/SweepTooEarly/)
+      }
+      await claimAs(testPool, staker)
+      expect((await getAccount(provider.connection, staker.rewardToken)).amount).toBeGreaterThan(BigInt(0))
+
+      await sleep(4000)
+      const left = (await getAccount(provider.connection, testPool.rewardVault)).amount
+      expect(left).toBeGreaterThan(BigInt(0))
+      const destination = await sweep(testPool)
+      expect((await getAccount(provider.connection, destination)).amount).toEqual(left)
+      expect((await getAccount(provider.connection, testPool.rewardVault)).amount).toEqual(BigInt(0))
+
+      try {
+        await claimAs(testPool, staker)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/ClaimWindowClosed/)
+      }
+    }, 30000)
+
+    it('Exits After The Grace Return The Stake But No Rewards', async () => {
+      const now = nowSeconds()
+      const testPool = await createPool(new BN(1_000_000), {
+        rewardStartTime: new BN(now - 60),
+        rewardEndTime: new BN(now + 3),
+        finalClaimGrace: new BN(2),
+        minStakeDuration: new BN(1),
+      })
+      const staker = await createStaker(testPool)
+      const amount = new BN(1_000_000_000)
+      await stakeAs(testPool, staker, amount)
+      await sleep(7000)
+
+      const stakeBefore = (await getAccount(provider.connection, staker.stakeToken)).amount
+      const vaultBefore = (await getAccount(provider.connection, testPool.rewardVault)).amount
+      await program.methods
+        .exit(staker.positionId, amount)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+          userStakeToken: staker.stakeToken,
+          userRewardToken: staker.rewardToken,
+          poolStakeVault: testPool.stakeVault,
+          poolRewardVault: testPool.rewardVault,
+          config: configPda,
+          treasuryRewardToken: await treasuryAccount(testPool.rewardMint, testPool.tokenProgram),
+          stakeTokenMint: testPool.stakeMint,
+          rewardTokenMint: testPool.rewardMint,
+          tokenProgram: testPool.tokenProgram,
+        })
+        .signers([staker.keypair])
+        .rpc()
+
+      const stakeAfter = (await getAccount(provider.connection, staker.stakeToken)).amount
+      expect((stakeAfter - stakeBefore).toString()).toEqual(amount.toString())
+      expect((await getAccount(provider.connection, staker.rewardToken)).amount).toEqual(BigInt(0))
+      expect((await getAccount(provider.connection, testPool.rewardVault)).amount).toEqual(vaultBefore)
+      const position = await program.account.userStake.fetch(staker.userStake)
+      expect(position.pendingRewards.toString()).toEqual('0')
+
+      // Everything the exit left behind goes to the sweep
+      const destination = await sweep(testPool)
+      expect((await getAccount(provider.connection, destination)).amount).toEqual(vaultBefore)
+    }, 30000)
+
+    it('Never Sweeps A Pool Without A Grace', async () => {
+      const now = nowSeconds()
+      const testPool = await createPool(new BN(1_000_000), {
+        rewardStartTime: new BN(now - 60),
+        rewardEndTime: new BN(now + 1),
+      })
+      await sleep(2000)
+
+      try {
+        await sweep(testPool)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/SweepTooEarly/)
+      }
+    }, 30000)
+  })
+
+  describe('upgrade_lock', () => {
+    const upgradeTiers = {
+      lockTierDurations: [new BN(2), new BN(20), new BN(40), new BN(0)],
+      lockTierMultipliersBps: [12_500, 15_000, 20_000, 0],
+    }
+
+    async function upgradeLock(testPool: TestPool, staker: TestStaker, newTier: number) {
+      await program.methods
+        .upgradeLock(staker.positionId, newTier)
+        .accounts({
+          user: staker.keypair.publicKey,
+          pool: testPool.pool,
+          userStake: staker.userStake,
+        })
+        .signers([staker.keypair])
+        .rpc()
+    }
+
+    it('Upgrades From Tier 1 To Tier 3 Without Moving Tokens', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), upgradeTiers)
+      const staker = await createStaker(testPool)
+      const amount = new BN(100_000_000_000)
+
+      await stakeAs(testPool, staker, amount, new BN(2))
+      const before = await program.account.userStake.fetch(staker.userStake)
+      expect(before.rewardMultiplierBps).toEqual(12_500)
+      const vaultBefore = (await getAccount(provider.connection, testPool.stakeVault)).amount
+      const walletBefore = (await getAccount(provider.connection, staker.stakeToken)).amount
+
+      await sleep(1000)
+      await upgradeLock(testPool, staker, 3)
+
+      const after = await program.account.userStake.fetch(staker.userStake)
+      expect(after.rewardMultiplierBps).toEqual(20_000)
+      // Tier 3 locks 40s from the upgrade, which is at least 38s past the 2s tier 1 lock
+      expect(after.lockEnd.toNumber()).toBeGreaterThanOrEqual(before.lockEnd.toNumber() + 38)
+      expect(after.amount.toString()).toEqual(amount.toString())
+      expect(after.pendingRewards.toNumber()).toBeGreaterThan(0)
+      expect((await getAccount(provider.connection, testPool.stakeVault)).amount).toEqual(vaultBefore)
+      expect((await getAccount(provider.connection, staker.stakeToken)).amount).toEqual(walletBefore)
+    }, 30000)
+
+    it('Rejects A Downgrade Or The Same Tier', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), upgradeTiers)
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(100_000_000_000), new BN(20))
+
+      for (const tier of [1, 2]) {
+        try {
+          await upgradeLock(testPool, staker, tier)
+          throw new Error('Should have thrown an error')
+        } catch (error) {
+          expect((error as Error).message).toMatch(/CannotDowngradeLock/)
+        }
+      }
+    }, 30000)
+
+    it('Rejects Upgrading A Frozen Position', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), upgradeTiers)
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(100_000_000_000), new BN(2))
+      await program.methods
+        .setUserFrozen(true)
+        .accounts({ authority: testPool.authority.publicKey, pool: testPool.pool, userStake: staker.userStake })
+        .signers([testPool.authority])
+        .rpc()
+
+      try {
+        await upgradeLock(testPool, staker, 3)
+        throw new Error('Should have thrown an error')
+      } catch (error) {
+        expect((error as Error).message).toMatch(/UserFrozen/)
+      }
+      expect((await program.account.userStake.fetch(staker.userStake)).rewardMultiplierBps).toEqual(12_500)
+    }, 30000)
+
+    it('Rejects A Tier The Pool Does Not Have', async () => {
+      const testPool = await createPool(new BN(1_000_000_000), upgradeTiers)
+      const staker = await createStaker(testPool)
+      await stakeAs(testPool, staker, new BN(100_000_000_000), new BN(2))
+
+      for (const tier of [0, 4]) {
+        try {
+          await upgradeLock(testPool, staker, tier)
+          throw new Error('Should have thrown an error')
+        } catch (error) {
+          expect((error as Error).message).toMatch(/InvalidLockTier/)
+        }
+      }
+    }, 30000)
+  })
 })